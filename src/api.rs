@@ -0,0 +1,38 @@
+//! The interface for node types built outside of this crate.
+//!
+//! A custom build of the filter depends on this crate without its
+//! default `main` feature, and provides its own entry point, which
+//! registers its node types along with the built-in ones:
+//!
+//! ```ignore
+//! proxy_wasm::main! {{
+//!     datakit::register_builtin_nodes();
+//!     datakit::register_nodes! {
+//!         "my_node" => MyNodeFactory {},
+//!     }
+//!     datakit::start();
+//! }}
+//! ```
+//!
+//! Node types implement `NodeFactory`, which validates the configuration
+//! of a node into a `NodeConfig`, and builds a `Node` from it once per
+//! filter configuration. Nodes get the payloads of their inputs in an
+//! `Input`, and report their outcome as a `State`.
+
+pub use crate::config::get_config_value;
+pub use crate::data::{Bytes, Input, Payload, Phase, State};
+pub use crate::nodes::{node_state, ChunkBuffer, Node, NodeConfig, NodeFactory};
+
+/// Registers a node type under the given name, replacing any
+/// node type previously registered with it.
+pub fn register_node(name: &str, factory: Box<dyn NodeFactory>) {
+    crate::nodes::register_node(name, factory);
+}
+
+/// Registers node types, given as `"name" => factory` pairs.
+#[macro_export]
+macro_rules! register_nodes {
+    ($($name:literal => $factory:expr),* $(,)?) => {
+        $($crate::api::register_node($name, Box::new($factory));)*
+    };
+}
@@ -0,0 +1,42 @@
+use proxy_wasm::traits::*;
+use serde::Serialize;
+
+use crate::config;
+use crate::migrate::CONFIG_FORMAT_VERSIONS;
+use crate::nodes;
+
+/// Shared data key under which the capabilities are published,
+/// so that the host can check whether a configuration only uses
+/// features supported by the deployed filter.
+pub const SHARED_DATA_KEY: &str = "datakit_capabilities";
+
+#[derive(Serialize)]
+struct Capabilities {
+    version: &'static str,
+    config_format_versions: &'static [u32],
+    node_types: Vec<String>,
+    implicit_nodes: Vec<&'static str>,
+}
+
+pub fn to_json() -> String {
+    let caps = Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        config_format_versions: CONFIG_FORMAT_VERSIONS,
+        node_types: nodes::node_type_names(),
+        implicit_nodes: config::implicit_node_names(),
+    };
+
+    serde_json::to_string(&caps).expect("serializable capabilities")
+}
+
+pub fn publish(ctx: &dyn Context) {
+    let json = to_json();
+    log::debug!("capabilities: {json}");
+
+    if let Err(status) = ctx.set_shared_data(SHARED_DATA_KEY, Some(json.as_bytes()), None) {
+        log::warn!(
+            "capabilities: failed publishing to shared data: {:?}",
+            status
+        );
+    }
+}
@@ -1,37 +1,247 @@
+use crate::debug::IpRange;
+use crate::migrate;
 use crate::nodes;
-use crate::nodes::{NodeConfig, NodeMap};
+use crate::nodes::{NodeConfig, NodeList};
 use crate::DependencyGraph;
 use lazy_static::lazy_static;
 use serde::de::{Error, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
 use serde_json_wasm::de;
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
 
 lazy_static! {
     static ref RESERVED_NODE_NAMES: HashSet<&'static str> = [
+        "client_info",
+        "kong_context",
         "request_headers",
         "request_body",
+        "request_method",
+        "request_cookies",
+        "request_path",
+        "request_query",
+        "request_scheme",
+        "request_trailers",
+        "route_params",
         "service_request_headers",
         "service_request_body",
+        "service_request_method",
+        "service_request_path",
+        "service_request_query",
         "service_response_headers",
         "service_response_body",
+        "service_response_status",
+        "service_response_trailers",
         "response_headers",
         "response_body",
+        "response_status",
+        "response_trailers",
+        "response_cookies",
     ]
     .iter()
     .copied()
     .collect();
 }
 
+/// Implicit nodes which consume a single payload, and which can
+/// therefore have at most one provider.
+pub const IMPLICIT_SINKS: [&str; 10] = [
+    "service_request_headers",
+    "service_request_body",
+    "service_request_method",
+    "service_request_path",
+    "service_request_query",
+    "response_headers",
+    "response_body",
+    "response_status",
+    "response_trailers",
+    "response_cookies",
+];
+
+/// Implicit nodes that set headers, which can be configured
+/// with a mode in the top-level `header_modes` map.
+const HEADER_SINKS: [&str; 2] = ["service_request_headers", "response_headers"];
+
+/// How the headers given to a header sink are applied.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderMode {
+    /// The given headers replace all existing headers.
+    Replace,
+    /// The given headers are set, and other headers are kept.
+    #[default]
+    Merge,
+    /// Like `Merge`, but headers given as `null` are removed.
+    Patch,
+}
+
+/// What to do with bodies larger than the configured maximum sizes.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedBody {
+    /// The body is forwarded untouched, and nodes depending on it do not run.
+    #[default]
+    Passthrough,
+    /// The request is rejected with a `413` (or `502`, for service responses).
+    Reject,
+}
+
+/// What to do when a request exceeds `max_total_call_time_ms`
+/// or `max_node_executions`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetExceeded {
+    /// The remaining nodes are skipped, and the request goes on unchanged.
+    #[default]
+    Continue,
+    /// The request is rejected with a `503`.
+    Reject,
+}
+
+/// Where debug traces are sent, as set with `trace_sink`.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TraceSink {
+    /// The trace replaces the response body.
+    #[default]
+    Body,
+    /// The trace is written to the proxy log.
+    Log {
+        #[serde(default)]
+        level: TraceLevel,
+    },
+    /// The trace is sent in a `POST` request to a collector.
+    Collector {
+        url: String,
+        #[serde(default = "default_collector_timeout")]
+        timeout: u32,
+    },
+    /// The trace is written to the proxy log, and its id is returned
+    /// in the `X-DataKit-Trace-Id` response header.
+    Header {
+        #[serde(default)]
+        level: TraceLevel,
+    },
+}
+
+fn default_collector_timeout() -> u32 {
+    5
+}
+
+/// Where spans for node runs are exported, as set with `otel`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OtelConfig {
+    /// The OTLP/HTTP traces endpoint, such as `http://collector:4318/v1/traces`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_collector_timeout")]
+    pub timeout: u32,
+}
+
+fn default_service_name() -> String {
+    "datakit".to_string()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<TraceLevel> for log::Level {
+    fn from(level: TraceLevel) -> log::Level {
+        match level {
+            TraceLevel::Debug => log::Level::Debug,
+            TraceLevel::Info => log::Level::Info,
+            TraceLevel::Warn => log::Level::Warn,
+            TraceLevel::Error => log::Level::Error,
+        }
+    }
+}
+
+/// The stage of the request lifecycle a node runs in, as set with `run_on`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOn {
+    Request,
+    Response,
+}
+
+/// What happens when a node fails, as set with `on_failure`, either at the
+/// top level, for all nodes, or for a single node.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailure {
+    /// The node produces no value, and the nodes depending on it run.
+    Continue,
+    /// The nodes depending on the node do not run, and the others do.
+    SkipDownstream,
+    /// The request is answered with an error with the given status.
+    Respond(u32),
+}
+
+fn parse_on_failure(value: Value) -> Result<OnFailure, String> {
+    let on_failure = serde_json::from_value(value).map_err(|_| {
+        "'on_failure' must be 'continue', 'skip_downstream' or 'respond: <status>'".to_string()
+    })?;
+    if let OnFailure::Respond(status) = on_failure {
+        if !(100..=599).contains(&status) {
+            return Err(format!("'on_failure': invalid status {status}"));
+        }
+    }
+    Ok(on_failure)
+}
+
+/// Implicit nodes which are only available during the response.
+const RESPONSE_INPUTS: [&str; 4] = [
+    "service_response_headers",
+    "service_response_body",
+    "service_response_status",
+    "service_response_trailers",
+];
+
+/// Implicit nodes which must be provided during the request.
+const REQUEST_SINKS: [&str; 5] = [
+    "service_request_headers",
+    "service_request_body",
+    "service_request_method",
+    "service_request_path",
+    "service_request_query",
+];
+
+pub fn implicit_node_names() -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = RESERVED_NODE_NAMES.iter().copied().collect();
+    names.sort();
+    names
+}
+
 pub struct UserNodeConfig {
     node_type: String,
     name: String,
     bt: BTreeMap<String, serde_json::Value>,
     inputs: Vec<String>,
+    input_roles: Option<Vec<String>>,
     outputs: Vec<String>,
+    output_roles: Option<Vec<String>>,
+    enabled: Option<Value>,
+    run_on: Option<RunOn>,
+    on_failure: Option<OnFailure>,
 }
 
 impl<'a> Deserialize<'a> for UserNodeConfig {
@@ -56,7 +266,12 @@ impl<'a> Deserialize<'a> for UserNodeConfig {
                 let mut typ: Option<String> = None;
                 let mut name: Option<String> = None;
                 let mut inputs = Vec::new();
+                let mut input_roles = None;
                 let mut outputs = Vec::new();
+                let mut output_roles = None;
+                let mut enabled = None;
+                let mut run_on = None;
+                let mut on_failure = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         "type" => {
@@ -74,25 +289,64 @@ impl<'a> Deserialize<'a> for UserNodeConfig {
                                 inputs.push(value);
                             }
                         }
-                        "inputs" => {
-                            if let Ok(values) = map.next_value() {
+                        "inputs" => match map.next_value() {
+                            Ok(serde_json::Value::Object(role_map)) => {
+                                let mut roles = Vec::new();
+                                inputs.clear();
+                                for (role, value) in role_map {
+                                    if let serde_json::Value::String(input) = value {
+                                        roles.push(role);
+                                        inputs.push(input);
+                                    }
+                                }
+                                input_roles = Some(roles);
+                            }
+                            Ok(values) => {
                                 if let Ok(v) = serde_json::from_value::<Vec<String>>(values) {
                                     inputs = v;
                                 }
                             }
-                        }
+                            Err(_) => {}
+                        },
                         "output" => {
                             if let Ok(serde_json::Value::String(value)) = map.next_value() {
                                 outputs.push(value);
                             }
                         }
-                        "outputs" => {
-                            if let Ok(values) = map.next_value() {
+                        "run_on" => {
+                            let value: serde_json::Value = map.next_value()?;
+                            run_on = Some(serde_json::from_value(value).map_err(|_| {
+                                Error::custom("'run_on' must be 'request' or 'response'")
+                            })?);
+                        }
+                        "enabled" => {
+                            if let Ok(value) = map.next_value() {
+                                enabled = Some(value);
+                            }
+                        }
+                        "on_failure" => {
+                            let value: serde_json::Value = map.next_value()?;
+                            on_failure = Some(parse_on_failure(value).map_err(Error::custom)?);
+                        }
+                        "outputs" => match map.next_value() {
+                            Ok(serde_json::Value::Object(role_map)) => {
+                                let mut roles = Vec::new();
+                                outputs.clear();
+                                for (role, value) in role_map {
+                                    if let serde_json::Value::String(output) = value {
+                                        roles.push(role);
+                                        outputs.push(output);
+                                    }
+                                }
+                                output_roles = Some(roles);
+                            }
+                            Ok(values) => {
                                 if let Ok(v) = serde_json::from_value::<Vec<String>>(values) {
                                     outputs = v;
                                 }
                             }
-                        }
+                            Err(_) => {}
+                        },
                         _ => {
                             if let Ok(value) = map.next_value() {
                                 bt.insert(key, value);
@@ -108,7 +362,12 @@ impl<'a> Deserialize<'a> for UserNodeConfig {
                         name,
                         bt,
                         inputs,
+                        input_roles,
                         outputs,
+                        output_roles,
+                        enabled,
+                        run_on,
+                        on_failure,
                     })
                 } else {
                     Err(Error::missing_field("type"))
@@ -125,19 +384,558 @@ pub struct UserConfig {
     nodes: Vec<UserNodeConfig>,
     #[serde(default)]
     debug: bool,
+    #[serde(default)]
+    preserve_header_case: bool,
+    #[serde(default)]
+    header_modes: BTreeMap<String, HeaderMode>,
+    #[serde(default)]
+    strict: bool,
+    #[serde(default)]
+    streaming: bool,
+    #[serde(default)]
+    max_request_body_bytes: Option<usize>,
+    #[serde(default)]
+    max_response_body_bytes: Option<usize>,
+    #[serde(default)]
+    oversized_body: OversizedBody,
+    #[serde(default)]
+    compress_response: bool,
+    #[serde(default)]
+    trace_sink: TraceSink,
+    #[serde(default)]
+    otel: Option<OtelConfig>,
+    #[serde(default = "default_sample_rate")]
+    debug_trace_sample_rate: f64,
+    #[serde(default)]
+    max_trace_value_bytes: Option<usize>,
+    #[serde(default)]
+    max_trace_operations: Option<usize>,
+    #[serde(default)]
+    debug_trace_token: Option<String>,
+    #[serde(default)]
+    debug_trace_clients: Vec<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    max_total_call_time_ms: Option<u64>,
+    #[serde(default)]
+    max_node_executions: Option<usize>,
+    #[serde(default)]
+    on_budget_exceeded: BudgetExceeded,
+    #[serde(default)]
+    on_failure: Option<Value>,
+    #[serde(default)]
+    features: BTreeMap<String, bool>,
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+    #[serde(default)]
+    templates: BTreeMap<String, String>,
+    #[serde(default)]
+    jq_definitions: Option<String>,
+    #[serde(default)]
+    base_path: Option<String>,
 }
 
 struct NodeInfo {
     name: String,
     node_type: String,
     node_config: Box<dyn NodeConfig>,
+    enabled: bool,
+    run_on: Option<RunOn>,
 }
 
 pub struct Config {
     node_list: Vec<NodeInfo>,
     node_names: Vec<String>,
-    graph: DependencyGraph,
+    execution_order: Vec<usize>,
+    dependents: BTreeMap<String, Vec<usize>>,
+    graph: Rc<DependencyGraph>,
     debug: bool,
+    preserve_header_case: bool,
+    header_modes: BTreeMap<String, HeaderMode>,
+    run_on: BTreeMap<String, RunOn>,
+    output_roles: BTreeMap<String, Vec<String>>,
+    streaming_chain: Option<Vec<String>>,
+    max_request_body_bytes: Option<usize>,
+    max_response_body_bytes: Option<usize>,
+    oversized_body: OversizedBody,
+    compress_response: bool,
+    trace_sink: TraceSink,
+    otel: Option<OtelConfig>,
+    debug_trace_sample_rate: f64,
+    max_trace_value_bytes: Option<usize>,
+    max_trace_operations: Option<usize>,
+    debug_trace_token: Option<String>,
+    debug_trace_clients: Vec<IpRange>,
+    dry_run: bool,
+    max_total_call_time_ms: Option<u64>,
+    max_node_executions: Option<usize>,
+    on_budget_exceeded: BudgetExceeded,
+    on_failure: BTreeMap<String, OnFailure>,
+    default_on_failure: Option<OnFailure>,
+}
+
+/// Adds the inputs of a node to the graph. When inputs are given as a map
+/// from roles to node names, and the node type has positional inputs with
+/// well-known roles (such as `body` and `headers`), they are connected in
+/// the positions the node expects; otherwise they are connected in order.
+fn add_inputs(unc: &UserNodeConfig, graph: &mut DependencyGraph) -> Result<(), String> {
+    let name: &str = &unc.name;
+
+    let (Some(roles), Some(expected)) = (&unc.input_roles, nodes::input_roles(&unc.node_type))
+    else {
+        for input in &unc.inputs {
+            // each role has its own slot, even for the same input
+            match unc.input_roles {
+                Some(_) => graph.add_slot(input, name),
+                None => graph.add(input, name),
+            }
+        }
+        return Ok(());
+    };
+
+    if let Some(role) = roles.iter().find(|r| !expected.contains(&r.as_str())) {
+        return Err(format!(
+            "node '{name}': invalid input role '{role}', expected one of: {}",
+            expected.join(", ")
+        ));
+    }
+
+    let last = expected
+        .iter()
+        .rposition(|e| roles.iter().any(|r| r == e))
+        .unwrap_or(0);
+
+    for role in &expected[..=last] {
+        match roles.iter().position(|r| r == role) {
+            Some(i) => graph.add_slot(&unc.inputs[i], name),
+            None => graph.add_unconnected(name),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the closest known name to a misspelled one, if any is close enough.
+fn suggest<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    fn distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut prev = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let cur = row[j + 1];
+                row[j + 1] = if ca == *cb {
+                    prev
+                } else {
+                    1 + prev.min(row[j]).min(cur)
+                };
+                prev = cur;
+            }
+        }
+        row[b.len()]
+    }
+
+    known
+        .map(|k| (distance(name, k), k))
+        .filter(|(d, _)| *d <= 2)
+        .min()
+        .map(|(_, k)| k)
+}
+
+/// Checks that every input and output of the user's nodes references either
+/// a declared node (or one of its named outputs, as in `NODE.output`) or
+/// an implicit node.
+fn validate_references(user_config: &UserConfig, node_names: &[String]) -> Result<(), String> {
+    let is_known = |reference: &str| {
+        let node = reference.split_once('.').map_or(reference, |(n, _)| n);
+        RESERVED_NODE_NAMES.contains(reference) || node_names.iter().any(|n| n == node)
+    };
+
+    for unc in &user_config.nodes {
+        for (kind, reference) in unc
+            .inputs
+            .iter()
+            .map(|i| ("input", i))
+            .chain(unc.outputs.iter().map(|o| ("output", o)))
+        {
+            if is_known(reference) {
+                continue;
+            }
+            let known = node_names
+                .iter()
+                .map(|n| n.as_str())
+                .chain(RESERVED_NODE_NAMES.iter().copied());
+            let hint = match suggest(reference, known) {
+                Some(s) => format!(" (did you mean '{s}'?)"),
+                None => String::new(),
+            };
+            return Err(format!(
+                "node '{}': unknown {kind} node '{reference}'{hint}",
+                unc.name
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_sinks(graph: &DependencyGraph) -> Result<(), String> {
+    for sink in IMPLICIT_SINKS {
+        let providers = graph.get_input_names(sink);
+        if providers.len() > 1 {
+            return Err(format!(
+                "implicit node '{sink}' can only have one input, but has {}: {}",
+                providers.len(),
+                providers.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Finds the nodes whose outputs do not contribute to any sink, either
+/// an implicit node or a node with effects of its own.
+fn find_unused_nodes(node_list: &[NodeInfo], graph: &DependencyGraph) -> Vec<String> {
+    let mut used: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<&str> = IMPLICIT_SINKS.to_vec();
+    pending.extend(
+        node_list
+            .iter()
+            .filter(|info| info.node_config.is_sink())
+            .map(|info| info.name.as_str()),
+    );
+
+    while let Some(name) = pending.pop() {
+        // `node.output` references are produced by `node`
+        let name = name.split_once('.').map_or(name, |(n, _)| n);
+        if used.insert(name) {
+            pending.extend(graph.each_input(name).map(|s| s.as_str()));
+        }
+    }
+
+    node_list
+        .iter()
+        .filter(|info| !used.contains(info.name.as_str()))
+        .map(|info| info.name.clone())
+        .collect()
+}
+
+/// A node is enabled by `enabled: true` (the default) or by the name of
+/// one of the feature toggles declared in the top-level `features` map.
+fn is_enabled(unc: &UserNodeConfig, features: &BTreeMap<String, bool>) -> Result<bool, String> {
+    match &unc.enabled {
+        None => Ok(true),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(Value::String(feature)) => match features.get(feature) {
+            Some(b) => Ok(*b),
+            None => Err(format!("node '{}': unknown feature '{feature}'", unc.name)),
+        },
+        _ => Err(format!(
+            "node '{}': 'enabled' must be a boolean or a feature name",
+            unc.name
+        )),
+    }
+}
+
+/// Replaces `${NAME}` placeholders in string values, recursively, using
+/// the given lookup function, which receives the placeholder's contents,
+/// such as `NAME` or `property:some.key`. A literal `${` is written `$${`.
+fn interpolate(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), String> {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            let mut out = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some(pos) = rest.find("${") {
+                if rest[..pos].ends_with('$') {
+                    out.push_str(&rest[..pos - 1]);
+                    out.push_str("${");
+                    rest = &rest[pos + 2..];
+                    continue;
+                }
+                out.push_str(&rest[..pos]);
+                let Some(end) = rest[pos..].find('}') else {
+                    return Err(format!("unterminated placeholder in '{s}'"));
+                };
+                let key = &rest[pos + 2..pos + end];
+                match lookup(key) {
+                    Some(v) => out.push_str(&v),
+                    None => return Err(format!("cannot resolve placeholder '${{{key}}}'")),
+                }
+                rest = &rest[pos + end + 1..];
+            }
+            out.push_str(rest);
+            *s = out;
+        }
+        Value::Array(items) => {
+            for item in items {
+                interpolate(item, lookup)?;
+            }
+        }
+        Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                interpolate(v, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `<key>_file` entries in a node's configuration with the
+/// contents of the named file as `<key>`. Files are looked up in the
+/// top-level `files` map first, and then relative to `base_path`.
+fn load_files(
+    name: &str,
+    mut bt: BTreeMap<String, Value>,
+    user_config: &UserConfig,
+) -> Result<BTreeMap<String, Value>, String> {
+    let file_keys: Vec<String> = bt
+        .keys()
+        .filter(|k| k.ends_with("_file"))
+        .cloned()
+        .collect();
+
+    for key in file_keys {
+        let target = key.strip_suffix("_file").expect("file key");
+        let Some(Value::String(file)) = bt.remove(&key) else {
+            return Err(format!("node '{name}': '{key}' must be a file name"));
+        };
+        if bt.contains_key(target) {
+            return Err(format!(
+                "node '{name}': cannot use both '{target}' and '{key}'"
+            ));
+        }
+
+        let contents = match (user_config.files.get(&file), &user_config.base_path) {
+            (Some(contents), _) => contents.clone(),
+            (None, Some(base)) => std::fs::read_to_string(Path::new(base).join(&file))
+                .map_err(|e| format!("node '{name}': cannot read file '{file}': {e}"))?,
+            (None, None) => return Err(format!("node '{name}': unknown file '{file}'")),
+        };
+
+        bt.insert(target.to_string(), Value::String(contents));
+    }
+
+    Ok(bt)
+}
+
+/// Makes the top-level `templates` available as partials to `template`
+/// nodes, and the top-level `jq_definitions` to `jq` nodes, including those
+/// in the chain of a `foreach` node. Partials given in the node itself take
+/// precedence, and definitions given in the node itself come last, so that
+/// they can use or redefine the shared ones. Node types keeping shared data
+/// get the given `namespace` of the configuration, unless they set their own.
+fn add_shared(
+    node_type: &str,
+    bt: &mut BTreeMap<String, Value>,
+    user_config: &UserConfig,
+    namespace: &str,
+) {
+    match node_type {
+        "template" if !user_config.templates.is_empty() => {
+            let mut partials: serde_json::Map<String, Value> = user_config
+                .templates
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect();
+            if let Some(Value::Object(own)) = bt.get("partials") {
+                partials.extend(own.clone());
+            }
+            bt.insert("partials".to_string(), Value::Object(partials));
+        }
+        "jq" => {
+            if let Some(shared) = &user_config.jq_definitions {
+                let definitions = match bt.get("definitions") {
+                    Some(Value::String(own)) => format!("{shared}\n{own}"),
+                    _ => shared.clone(),
+                };
+                bt.insert("definitions".to_string(), Value::String(definitions));
+            }
+        }
+        "foreach" => {
+            if let Some(Value::Array(sub_nodes)) = bt.get_mut("nodes") {
+                for sub in sub_nodes.iter_mut().filter_map(|n| n.as_object_mut()) {
+                    let sub_type = sub
+                        .get("type")
+                        .and_then(|t| t.as_str())
+                        .unwrap_or("")
+                        .to_string();
+                    let mut sub_bt: BTreeMap<String, Value> =
+                        std::mem::take(sub).into_iter().collect();
+                    add_shared(&sub_type, &mut sub_bt, user_config, namespace);
+                    *sub = sub_bt.into_iter().collect();
+                }
+            }
+        }
+        "rate_limit" | "circuit_breaker" if !bt.contains_key("namespace") => {
+            bt.insert(
+                "namespace".to_string(),
+                Value::String(namespace.to_string()),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Returns the response input a node depends on, directly or through
+/// other nodes, if any: such a node can only run during the response.
+/// Nodes already in `visited` are not looked at again.
+fn response_dependency<'a>(
+    name: &'a str,
+    run_on: &BTreeMap<&str, RunOn>,
+    graph: &'a DependencyGraph,
+    visited: &mut HashSet<&'a str>,
+) -> Option<&'a str> {
+    let node = name.split_once('.').map_or(name, |(n, _)| n);
+    if !visited.insert(node) {
+        return None;
+    }
+    if RESPONSE_INPUTS.contains(&node) || run_on.get(node) == Some(&RunOn::Response) {
+        return Some(node);
+    }
+    graph
+        .each_input(node)
+        .find_map(|input| response_dependency(input, run_on, graph, visited))
+}
+
+/// Orders the nodes, by their index, so that each one comes after the
+/// nodes it depends on, and otherwise in the order they were configured.
+/// Nodes on a cycle cannot be ordered, which is an error.
+fn execution_order(node_names: &[String], graph: &DependencyGraph) -> Result<Vec<usize>, String> {
+    let index = |input: &str| {
+        let node = input.split_once('.').map_or(input, |(n, _)| n);
+        node_names.iter().position(|n| n == input || n == node)
+    };
+    let providers: Vec<Vec<usize>> = node_names
+        .iter()
+        .map(|name| graph.each_input(name).filter_map(|i| index(i)).collect())
+        .collect();
+
+    let mut order = Vec::with_capacity(node_names.len());
+    let mut placed = vec![false; node_names.len()];
+    while let Some(i) =
+        (0..node_names.len()).find(|&i| !placed[i] && providers[i].iter().all(|&p| placed[p]))
+    {
+        placed[i] = true;
+        order.push(i);
+    }
+
+    match (0..node_names.len()).find(|&i| !placed[i]) {
+        Some(i) => Err(format!(
+            "node '{}': cannot be ordered after its inputs, which depend on it",
+            node_names[i]
+        )),
+        None => Ok(order),
+    }
+}
+
+/// Maps each node, named output and implicit node to the positions, in
+/// the execution order, of the nodes that depend on it.
+fn dependents(
+    node_names: &[String],
+    execution_order: &[usize],
+    graph: &DependencyGraph,
+) -> BTreeMap<String, Vec<usize>> {
+    let mut dependents: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (src, dst) in graph.edges() {
+        let Some(i) = node_names.iter().position(|n| n == dst) else {
+            continue;
+        };
+        if let Some(pos) = execution_order.iter().position(|&o| o == i) {
+            dependents.entry(src.to_string()).or_default().push(pos);
+        }
+    }
+    for positions in dependents.values_mut() {
+        positions.sort_unstable();
+    }
+    dependents
+}
+
+/// Checks that nodes can run in the phases they are constrained to:
+/// nodes set to run on the request cannot depend on the response,
+/// and nodes running on the response cannot provide request sinks.
+fn validate_phases(node_list: &[NodeInfo], graph: &DependencyGraph) -> Result<(), String> {
+    let run_on: BTreeMap<&str, RunOn> = node_list
+        .iter()
+        .filter_map(|info| info.run_on.map(|r| (info.name.as_str(), r)))
+        .collect();
+
+    for info in node_list {
+        let name = info.name.as_str();
+        let mut visited = HashSet::new();
+        let dependency = graph
+            .each_input(name)
+            .find_map(|input| response_dependency(input, &run_on, graph, &mut visited));
+
+        if let (Some(RunOn::Request), Some(dep)) = (info.run_on, dependency) {
+            return Err(format!(
+                "node '{name}': set to run on request, but depends on '{dep}', \
+                 which is only available on response"
+            ));
+        }
+    }
+
+    for sink in REQUEST_SINKS {
+        let mut visited = HashSet::new();
+        if let Some(dep) = graph
+            .each_input(sink)
+            .find_map(|input| response_dependency(input, &run_on, graph, &mut visited))
+        {
+            return Err(format!(
+                "implicit node '{sink}' is set on request, but depends on '{dep}', \
+                 which is only available on response"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// In streaming mode, the service response body must flow into the
+/// response body through a chain of nodes that support streaming, each
+/// with a single input and a single output. Returns the chain in order.
+fn find_streaming_chain(
+    node_list: &[NodeInfo],
+    graph: &DependencyGraph,
+) -> Result<Vec<String>, String> {
+    let err = |msg: &str| Err(format!("streaming: {msg}"));
+    let single = |names: &[String]| match names {
+        [name] => Some(name.clone()),
+        _ => None,
+    };
+
+    let mut chain = vec![];
+    let mut current = "service_response_body".to_string();
+    loop {
+        let dependents = graph.get_dependents(&current);
+        let Some(next) = single(dependents) else {
+            return err(&format!(
+                "'{current}' must have a single output, but has {}",
+                dependents.len()
+            ));
+        };
+        if graph.get_input_names(&next).len() != 1 {
+            return err(&format!("node '{next}' must have a single input"));
+        }
+        if next == "response_body" {
+            break;
+        }
+
+        match node_list.iter().find(|info| info.name == next) {
+            Some(info) if info.enabled && info.node_config.supports_streaming() => {}
+            _ => return err(&format!("node '{next}' does not support streaming")),
+        }
+        chain.push(next.clone());
+        current = next;
+    }
+
+    if chain.is_empty() {
+        return err("no nodes between 'service_response_body' and 'response_body'");
+    }
+
+    Ok(chain)
 }
 
 fn add_default_connections(unc: &UserNodeConfig, nc: &dyn NodeConfig, graph: &mut DependencyGraph) {
@@ -158,10 +956,42 @@ fn add_default_connections(unc: &UserNodeConfig, nc: &dyn NodeConfig, graph: &mu
     }
 }
 
+/// Reads the top-level `fail_closed` option of a configuration, even one
+/// that is otherwise invalid, as it decides what to do when it is.
+pub fn is_fail_closed(config_bytes: &[u8]) -> bool {
+    de::from_slice::<Value>(config_bytes)
+        .ok()
+        .and_then(|value| value.get("fail_closed")?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Derives a namespace for the shared data of the nodes from the
+/// configuration, so that nodes of the same name in filter instances with
+/// different configurations, such as on different routes, do not share it.
+fn config_namespace(config_bytes: &[u8]) -> String {
+    Sha256::digest(config_bytes)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 impl Config {
-    pub fn new(config_bytes: Vec<u8>) -> Result<Config, String> {
-        match de::from_slice::<UserConfig>(&config_bytes) {
+    /// Parses the configuration, resolving `${...}` placeholders
+    /// in string values with the given lookup function.
+    pub fn new(
+        config_bytes: Vec<u8>,
+        lookup: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Config, String> {
+        let user_config = de::from_slice::<Value>(&config_bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|mut value| {
+                migrate::migrate(&mut value)?;
+                serde_json::from_value::<UserConfig>(value).map_err(|e| e.to_string())
+            });
+
+        match user_config {
             Ok(user_config) => {
+                let namespace = config_namespace(&config_bytes);
                 let mut node_list = Vec::new();
                 let mut node_names = Vec::new();
                 let mut graph: DependencyGraph = Default::default();
@@ -172,19 +1002,69 @@ impl Config {
                     if RESERVED_NODE_NAMES.contains(name) {
                         return Err(format!("cannot use reserved node name '{name}'"));
                     }
+                    // `node.output` refers to a named output of `node`
+                    if name.contains('.') {
+                        return Err(format!("node '{name}': node names cannot contain '.'"));
+                    }
 
                     node_names.push(name.to_string());
-                    for input in &unc.inputs {
-                        graph.add(input, name);
-                    }
-                    for output in &unc.outputs {
-                        graph.add(name, output);
+                    add_inputs(unc, &mut graph)?;
+                    match &unc.output_roles {
+                        // each entry of the node's output is sent through `node.role`
+                        Some(roles) => {
+                            for (role, output) in roles.iter().zip(&unc.outputs) {
+                                graph.add(&format!("{name}.{role}"), output);
+                            }
+                        }
+                        None => {
+                            for output in &unc.outputs {
+                                graph.add(name, output);
+                            }
+                        }
                     }
                 }
 
+                validate_references(&user_config, &node_names)?;
+
                 for unc in &user_config.nodes {
-                    let inputs = graph.get_input_names(&unc.name);
-                    match nodes::new_config(&unc.node_type, &unc.name, inputs, &unc.bt) {
+                    // nodes refer to their inputs by role, if given, or by node name
+                    let inputs = match &unc.input_roles {
+                        Some(roles) => roles,
+                        None => graph.get_input_names(&unc.name),
+                    };
+                    let mut bt = load_files(&unc.name, unc.bt.clone(), &user_config)?;
+                    add_shared(&unc.node_type, &mut bt, &user_config, &namespace);
+
+                    // `fallback` is the value of a node that can be disabled; it is
+                    // only passed on to node types that have an option of that name
+                    let fallback = match unc.enabled {
+                        Some(_) if nodes::has_config_key(&unc.node_type, "fallback") => {
+                            bt.get("fallback").cloned()
+                        }
+                        Some(_) => bt.remove("fallback"),
+                        None => None,
+                    };
+                    nodes::check_config_keys(&unc.node_type, &unc.name, &bt)?;
+
+                    if !is_enabled(unc, &user_config.features)? {
+                        node_list.push(NodeInfo {
+                            name: unc.name.to_string(),
+                            node_type: unc.node_type.to_string(),
+                            node_config: nodes::disabled::new_config(fallback),
+                            enabled: false,
+                            run_on: unc.run_on,
+                        });
+                        continue;
+                    }
+
+                    for key in nodes::placeholder_keys(&unc.node_type) {
+                        if let Some(value) = bt.get_mut(*key) {
+                            interpolate(value, lookup)
+                                .map_err(|e| format!("node '{}': {e}", unc.name))?;
+                        }
+                    }
+
+                    match nodes::new_config(&unc.node_type, &unc.name, inputs, &bt) {
                         Ok(nc) => {
                             add_default_connections(unc, &*nc, &mut graph);
 
@@ -192,6 +1072,8 @@ impl Config {
                                 name: unc.name.to_string(),
                                 node_type: unc.node_type.to_string(),
                                 node_config: nc,
+                                enabled: true,
+                                run_on: unc.run_on,
                             });
                         }
                         Err(err) => {
@@ -200,11 +1082,123 @@ impl Config {
                     };
                 }
 
+                validate_sinks(&graph)?;
+
+                if let Some(sink) = user_config
+                    .header_modes
+                    .keys()
+                    .find(|k| !HEADER_SINKS.contains(&k.as_str()))
+                {
+                    return Err(format!(
+                        "header_modes: '{sink}' is not one of: {}",
+                        HEADER_SINKS.join(", ")
+                    ));
+                }
+
+                if let TraceSink::Collector { url, .. } = &user_config.trace_sink {
+                    url::Url::parse(url)
+                        .map_err(|e| format!("trace_sink: invalid URL '{url}': {e}"))?;
+                }
+
+                let debug_trace_clients = user_config
+                    .debug_trace_clients
+                    .iter()
+                    .map(|s| {
+                        IpRange::parse(s)
+                            .ok_or_else(|| format!("debug_trace_clients: invalid range '{s}'"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let default_on_failure = match &user_config.on_failure {
+                    Some(value) => Some(parse_on_failure(value.clone())?),
+                    None => None,
+                };
+                let on_failure = user_config
+                    .nodes
+                    .iter()
+                    .filter_map(|unc| Some((unc.name.clone(), unc.on_failure?)))
+                    .collect();
+
+                let rate = user_config.debug_trace_sample_rate;
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err(format!(
+                        "debug_trace_sample_rate: {rate} is not between 0 and 1"
+                    ));
+                }
+
+                if let Some(OtelConfig { endpoint, .. }) = &user_config.otel {
+                    url::Url::parse(endpoint)
+                        .map_err(|e| format!("otel: invalid endpoint '{endpoint}': {e}"))?;
+                }
+
+                if let Some(cycle) = graph.find_cycle() {
+                    return Err(format!(
+                        "dependency cycle between nodes: {}",
+                        cycle.join(" -> ")
+                    ));
+                }
+
+                validate_phases(&node_list, &graph)?;
+                let execution_order = execution_order(&node_names, &graph)?;
+                let dependents = dependents(&node_names, &execution_order, &graph);
+
+                let unused = find_unused_nodes(&node_list, &graph);
+                if user_config.strict && !unused.is_empty() {
+                    return Err(format!(
+                        "nodes whose outputs are not used: {}",
+                        unused.join(", ")
+                    ));
+                }
+                for name in unused {
+                    log::warn!("node '{name}': output is not used");
+                }
+
+                let streaming_chain = if user_config.streaming {
+                    Some(find_streaming_chain(&node_list, &graph)?)
+                } else {
+                    None
+                };
+
+                let run_on = node_list
+                    .iter()
+                    .filter_map(|info| info.run_on.map(|r| (info.name.clone(), r)))
+                    .collect();
+
+                let output_roles = user_config
+                    .nodes
+                    .iter()
+                    .filter_map(|unc| Some((unc.name.clone(), unc.output_roles.clone()?)))
+                    .collect();
+
                 Ok(Config {
+                    run_on,
+                    output_roles,
+                    streaming_chain,
+                    max_request_body_bytes: user_config.max_request_body_bytes,
+                    max_response_body_bytes: user_config.max_response_body_bytes,
+                    oversized_body: user_config.oversized_body,
+                    compress_response: user_config.compress_response,
+                    trace_sink: user_config.trace_sink,
+                    otel: user_config.otel,
+                    debug_trace_sample_rate: user_config.debug_trace_sample_rate,
+                    max_trace_value_bytes: user_config.max_trace_value_bytes,
+                    max_trace_operations: user_config.max_trace_operations,
+                    debug_trace_token: user_config.debug_trace_token,
+                    debug_trace_clients,
+                    dry_run: user_config.dry_run,
+                    max_total_call_time_ms: user_config.max_total_call_time_ms,
+                    max_node_executions: user_config.max_node_executions,
+                    on_budget_exceeded: user_config.on_budget_exceeded,
+                    on_failure,
+                    default_on_failure,
                     node_list,
                     node_names,
-                    graph,
+                    execution_order,
+                    dependents,
+                    graph: Rc::new(graph),
                     debug: user_config.debug,
+                    preserve_header_case: user_config.preserve_header_case,
+                    header_modes: user_config.header_modes,
                 })
             }
             Err(err) => Err(format!(
@@ -218,37 +1212,176 @@ impl Config {
         self.debug
     }
 
+    pub fn preserve_header_case(&self) -> bool {
+        self.preserve_header_case
+    }
+
+    pub fn header_mode(&self, sink: &str) -> HeaderMode {
+        self.header_modes.get(sink).copied().unwrap_or_default()
+    }
+
     pub fn get_node_names(&self) -> &Vec<String> {
         &self.node_names
     }
 
+    /// The indices of the nodes, in `get_node_names` and in the list
+    /// returned by `build_nodes`, in the order they are run.
+    pub fn execution_order(&self) -> &[usize] {
+        &self.execution_order
+    }
+
+    /// Whether the payload of an implicit node is only connected to
+    /// implicit sinks, so that it can be passed through without parsing it.
+    pub fn is_passthrough(&self, name: &str) -> bool {
+        self.graph
+            .get_dependents(name)
+            .iter()
+            .all(|d| IMPLICIT_SINKS.contains(&d.as_str()))
+    }
+
+    /// The positions, in the execution order, of the nodes depending
+    /// on a node, a named output or an implicit node.
+    pub fn dependents(&self, name: &str) -> &[usize] {
+        self.dependents.get(name).map_or(&[], |d| d.as_slice())
+    }
+
+    pub fn node_index(&self, name: &str) -> Option<usize> {
+        self.node_names.iter().position(|n| n == name)
+    }
+
     pub fn node_types(&self) -> impl Iterator<Item = (&str, &str)> {
         self.node_list
             .iter()
             .map(|info| (info.name.as_ref(), info.node_type.as_ref()))
     }
 
+    pub fn node_type(&self, name: &str) -> Option<&str> {
+        self.node_list
+            .iter()
+            .find(|info| info.name == name)
+            .map(|info| info.node_type.as_ref())
+    }
+
+    pub fn max_request_body_bytes(&self) -> Option<usize> {
+        self.max_request_body_bytes
+    }
+
+    pub fn max_response_body_bytes(&self) -> Option<usize> {
+        self.max_response_body_bytes
+    }
+
+    pub fn oversized_body(&self) -> OversizedBody {
+        self.oversized_body
+    }
+
+    pub fn compress_response(&self) -> bool {
+        self.compress_response
+    }
+
+    pub fn trace_sink(&self) -> &TraceSink {
+        &self.trace_sink
+    }
+
+    pub fn otel(&self) -> Option<&OtelConfig> {
+        self.otel.as_ref()
+    }
+
+    /// The fraction of requests asking for a debug trace that get one.
+    pub fn debug_trace_sample_rate(&self) -> f64 {
+        self.debug_trace_sample_rate
+    }
+
+    pub fn max_trace_value_bytes(&self) -> Option<usize> {
+        self.max_trace_value_bytes
+    }
+
+    pub fn max_trace_operations(&self) -> Option<usize> {
+        self.max_trace_operations
+    }
+
+    /// The token the debug header must give to enable tracing, if any.
+    pub fn debug_trace_token(&self) -> Option<&str> {
+        self.debug_trace_token.as_deref()
+    }
+
+    /// How long the nodes of a request may take, including their calls.
+    pub fn max_total_call_time(&self) -> Option<Duration> {
+        self.max_total_call_time_ms.map(Duration::from_millis)
+    }
+
+    /// How many times the nodes of a request may run or resume in total.
+    pub fn max_node_executions(&self) -> Option<usize> {
+        self.max_node_executions
+    }
+
+    pub fn on_budget_exceeded(&self) -> BudgetExceeded {
+        self.on_budget_exceeded
+    }
+
+    /// The failure policy of a node, given for the node or for all nodes.
+    pub fn on_failure(&self, name: &str) -> Option<OnFailure> {
+        self.on_failure
+            .get(name)
+            .copied()
+            .or(self.default_on_failure)
+    }
+
+    /// The status of the response sent when a node fails without a
+    /// failure policy, if its type gives one (see `NodeConfig::failure_status`).
+    pub fn failure_status(&self, name: &str) -> Option<u32> {
+        self.node_list
+            .iter()
+            .find(|info| info.name == name)
+            .and_then(|info| info.node_config.failure_status())
+    }
+
+    /// Whether every request is a dry run, as with `X-DataKit-Dry-Run`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The client addresses allowed to enable tracing, if restricted.
+    pub fn debug_trace_clients(&self) -> &[IpRange] {
+        &self.debug_trace_clients
+    }
+
+    /// In streaming mode, the nodes transforming the response body.
+    pub fn streaming_chain(&self) -> Option<&[String]> {
+        self.streaming_chain.as_deref()
+    }
+
+    /// The stage a node is constrained to run in, if any.
+    pub fn run_on(&self, name: &str) -> Option<RunOn> {
+        self.run_on.get(name).copied()
+    }
+
+    /// The roles of a node whose outputs are given as a map from entries
+    /// of its output object to other nodes.
+    pub fn output_roles(&self, name: &str) -> Option<&Vec<String>> {
+        self.output_roles.get(name)
+    }
+
     pub fn get_graph(&self) -> &DependencyGraph {
         &self.graph
     }
 
-    pub fn build_nodes(&self) -> NodeMap {
-        let mut nodes = NodeMap::new();
-
-        for info in &self.node_list {
-            let name = &info.name;
+    /// The graph, shared with the data of each request.
+    pub fn shared_graph(&self) -> Rc<DependencyGraph> {
+        self.graph.clone()
+    }
 
-            match nodes::new_node(&info.node_type, &*info.node_config) {
-                Ok(node) => {
-                    nodes.insert(name.to_string(), node);
-                }
-                Err(err) => {
-                    log::error!("{err}");
+    /// Builds the nodes, in the order of `get_node_names`.
+    pub fn build_nodes(&self) -> NodeList {
+        self.node_list
+            .iter()
+            .map(|info| {
+                if !info.enabled {
+                    return nodes::disabled::new_node(&*info.node_config);
                 }
-            }
-        }
-
-        nodes
+                nodes::new_node(&info.node_type, &*info.node_config)
+                    .expect("node type was validated by new_config")
+            })
+            .collect()
     }
 }
 
@@ -259,3 +1392,270 @@ pub fn get_config_value<T: for<'de> serde::Deserialize<'de>>(
     bt.get(key)
         .and_then(|v| serde_json::from_value(v.clone()).ok())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unknown_input() {
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "input": "request_header", "jq": "." }
+        ] }"#;
+
+        assert_eq!(
+            Config::new(config.to_vec(), &|_| None).err(),
+            Some(
+                "node 'A': unknown input node 'request_header' (did you mean 'request_headers'?)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn dotted_node_name() {
+        let config = br#"{ "nodes": [
+            { "name": "a.b", "type": "jq", "jq": "." },
+            { "name": "C", "type": "jq", "input": "a.b", "jq": "." }
+        ] }"#;
+
+        assert_eq!(
+            Config::new(config.to_vec(), &|_| None).err(),
+            Some("node 'a.b': node names cannot contain '.'".to_string())
+        );
+    }
+
+    #[test]
+    fn cycle_through_named_output() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "input": "B", "jq": ".", "run_on": "request",
+              "outputs": { "body": "B", "headers": "service_request_headers" } },
+            { "name": "B", "type": "jq", "input": "A.body", "jq": "." }
+        ] }"#;
+        assert!(Config::new(config.to_vec(), &|_| None)
+            .err()
+            .is_some_and(|e| e.starts_with("dependency cycle between nodes")));
+
+        let mut graph = DependencyGraph::default();
+        graph.add("A.body", "B");
+        graph.add("B", "A");
+        let run_on = BTreeMap::new();
+        assert_eq!(
+            response_dependency("A", &run_on, &graph, &mut HashSet::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn execution_order() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "C", "type": "jq", "inputs": ["B.body", "A"], "jq": "." },
+            { "name": "A", "type": "jq", "jq": "." },
+            { "name": "B", "type": "jq", "input": "A", "jq": "." },
+            { "name": "D", "type": "jq", "jq": "." }
+        ] }"#;
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+        assert_eq!(config.execution_order(), &[1, 2, 0, 3]);
+        assert_eq!(config.dependents("A"), &[1, 2]);
+        assert_eq!(config.dependents("B.body"), &[2]);
+        assert!(config.dependents("D").is_empty());
+
+        let mut graph = DependencyGraph::default();
+        graph.add("A", "B");
+        graph.add("B.out", "A");
+        let names = ["A".to_string(), "B".to_string()];
+        assert!(super::execution_order(&names, &graph).is_err());
+    }
+
+    #[test]
+    fn on_failure() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "on_failure": "skip_downstream", "nodes": [
+            { "name": "A", "type": "jq", "jq": ".", "on_failure": { "respond": 503 } },
+            { "name": "B", "type": "jq", "input": "A", "jq": "." }
+        ] }"#;
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+        assert_eq!(config.on_failure("A"), Some(OnFailure::Respond(503)));
+        assert_eq!(config.on_failure("B"), Some(OnFailure::SkipDownstream));
+
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "jq": ".", "on_failure": "ignore" }
+        ] }"#;
+        assert!(Config::new(config.to_vec(), &|_| None)
+            .err()
+            .is_some_and(|e| e.contains("'on_failure' must be")));
+    }
+
+    #[test]
+    fn fail_closed() {
+        assert!(is_fail_closed(br#"{ "fail_closed": true, "nodes": 42 }"#));
+        assert!(!is_fail_closed(br#"{ "nodes": [] }"#));
+        assert!(!is_fail_closed(br#"{ "fail_closed": true "#));
+    }
+
+    #[test]
+    fn disabled_nodes() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "features": { "beta": false }, "nodes": [
+            { "name": "A", "type": "jq", "jq": ".", "output": "response_body",
+              "enabled": "beta", "fallback": { "beta": false } }
+        ] }"#;
+        assert!(Config::new(config.to_vec(), &|_| None).is_ok());
+
+        // `fallback` comes with `enabled`
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "jq": ".", "output": "response_body",
+              "fallback": 1 }
+        ] }"#;
+        assert_eq!(
+            Config::new(config.to_vec(), &|_| None).err(),
+            Some("jq: node 'A': unknown configuration key 'fallback'".to_string())
+        );
+
+        // disabled nodes are validated as well
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jqq", "jq": ".", "enabled": false }
+        ] }"#;
+        assert_eq!(
+            Config::new(config.to_vec(), &|_| None).err(),
+            Some("no such node type: jqq".to_string())
+        );
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "jqq": ".", "enabled": false }
+        ] }"#;
+        assert_eq!(
+            Config::new(config.to_vec(), &|_| None).err(),
+            Some("jq: node 'A': unknown configuration key 'jqq'".to_string())
+        );
+    }
+
+    #[test]
+    fn input_roles() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "input": "request_body", "jq": "." },
+            { "name": "B", "type": "jq", "inputs": { "x": "A", "y": "A" }, "jq": "[$x, $y]",
+              "output": "response_body" },
+            { "name": "C", "type": "call", "url": "http://example.com",
+              "inputs": { "headers": "A", "body": "A" } }
+        ] }"#;
+
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+        let graph = config.get_graph();
+        assert_eq!(graph.get_input_names("B"), &vec!["A", "A"]);
+        assert_eq!(graph.get_input_names("C"), &vec!["A", "A"]);
+        assert_eq!(graph.get_dependents("A"), &vec!["B", "C"]);
+
+        let config = br#"{ "nodes": [
+            { "name": "C", "type": "call", "url": "http://example.com",
+              "inputs": { "headers": "request_headers" } }
+        ] }"#;
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+        assert_eq!(
+            config.get_graph().get_input_names("C"),
+            &vec!["", "request_headers"]
+        );
+    }
+
+    #[test]
+    fn output_roles() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "input": "request_body", "jq": ".",
+              "outputs": { "headers": "service_request_headers", "body": "service_request_body" } }
+        ] }"#;
+
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+        assert_eq!(
+            config.output_roles("A"),
+            Some(&vec!["body".to_string(), "headers".to_string()])
+        );
+        assert_eq!(
+            config.get_graph().get_input_names("service_request_body"),
+            &vec!["A.body".to_string()]
+        );
+    }
+
+    #[test]
+    fn placeholders() {
+        let lookup = |key: &str| match key {
+            "HOST" => Some("example.com".to_string()),
+            "property:listener.port" => Some("8000".to_string()),
+            _ => None,
+        };
+
+        let mut value = serde_json::json!({
+            "url": "http://${HOST}:${property:listener.port}/$${literal}",
+            "headers": ["x-host: ${HOST}"],
+            "timeout": 10
+        });
+        interpolate(&mut value, &lookup).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "url": "http://example.com:8000/${literal}",
+                "headers": ["x-host: example.com"],
+                "timeout": 10
+            })
+        );
+
+        let mut value = serde_json::json!("${MISSING}");
+        assert!(interpolate(&mut value, &lookup).is_err());
+    }
+
+    #[test]
+    fn placeholder_keys() {
+        nodes::register_node("template", Box::new(nodes::template::TemplateFactory {}));
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+
+        let lookup = |key: &str| match key {
+            "HOST" => Some("example.com".to_string()),
+            _ => None,
+        };
+
+        let config = br#"{ "nodes": [
+            { "name": "PRICE", "type": "template", "template": "${{price}}",
+              "output": "response_body" },
+            { "name": "CALL", "type": "call", "url": "http://${HOST}/" },
+            { "name": "OFF", "type": "call", "url": "http://${MISSING}/", "enabled": false }
+        ] }"#;
+        assert!(Config::new(config.to_vec(), &lookup).is_ok());
+
+        let config = br#"{ "nodes": [
+            { "name": "CALL", "type": "call", "url": "http://${MISSING}/" }
+        ] }"#;
+        assert_eq!(
+            Config::new(config.to_vec(), &lookup).err(),
+            Some("node 'CALL': cannot resolve placeholder '${MISSING}'".to_string())
+        );
+
+        let config = br#"{ "nodes": [
+            { "name": "CALL", "type": "call", "url": "http://${HOST}/",
+              "headers": { "x-api-key": "${MISSING}" } }
+        ] }"#;
+        assert_eq!(
+            Config::new(config.to_vec(), &lookup).err(),
+            Some("node 'CALL': cannot resolve placeholder '${MISSING}'".to_string())
+        );
+    }
+
+    #[test]
+    fn suggestions() {
+        let known = ["FOO", "BAR", "response_body"];
+        assert_eq!(suggest("FO", known.into_iter()), Some("FOO"));
+        assert_eq!(
+            suggest("respons_bdy", known.into_iter()),
+            Some("response_body")
+        );
+        assert_eq!(suggest("QUUX", known.into_iter()), None);
+    }
+}
@@ -0,0 +1,212 @@
+use serde_json::{Map, Value};
+
+use crate::nodes::clock;
+
+/// Parses the values of `Cookie` headers into an object of cookie values,
+/// keyed by name. When a name is repeated, its first value is kept, as it
+/// is the one with the most specific path.
+pub fn parse(headers: &[&str]) -> Value {
+    let mut map = Map::new();
+    for pair in headers.iter().flat_map(|h| h.split(';')) {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() || map.contains_key(name) {
+            continue;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        map.insert(name.to_string(), Value::from(value));
+    }
+    Value::Object(map)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+fn is_cookie_value(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_graphic() && !b"\",;\\".contains(&b))
+}
+
+fn is_attribute_value(s: &str) -> bool {
+    s.bytes().all(|b| (b' '..=b'~').contains(&b) && b != b';')
+}
+
+/// Serializes a cookie, given either as its value or as an object with
+/// its `value` and attributes, as the value of a `Set-Cookie` header.
+/// A `null` cookie is removed from the client.
+fn set_cookie(name: &str, cookie: &Value) -> Result<String, String> {
+    if !is_token(name) {
+        return Err(format!("invalid cookie name '{name}'"));
+    }
+
+    let empty = Map::new();
+    let (value, attrs) = match cookie {
+        Value::Null => {
+            return Ok(format!(
+                "{name}=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+            ))
+        }
+        Value::Object(attrs) => (attrs.get("value").unwrap_or(&Value::Null), attrs),
+        value => (value, &empty),
+    };
+    let value = match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        Value::Number(_) | Value::Bool(_) => value.to_string(),
+        _ => return Err(format!("cookie '{name}': invalid value")),
+    };
+    if !is_cookie_value(&value) {
+        return Err(format!("cookie '{name}': invalid characters in value"));
+    }
+
+    const ATTRIBUTES: [&str; 9] = [
+        "value",
+        "path",
+        "domain",
+        "expires",
+        "max_age",
+        "http_only",
+        "same_site",
+        "partitioned",
+        "secure",
+    ];
+    if let Some(key) = attrs.keys().find(|k| !ATTRIBUTES.contains(&k.as_str())) {
+        return Err(format!("cookie '{name}': unknown attribute '{key}'"));
+    }
+    let invalid = |key: &str| format!("cookie '{name}': invalid '{key}'");
+    let flag = |key: &str| match attrs.get(key) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(invalid(key)),
+    };
+
+    let mut out = format!("{name}={value}");
+    for (key, attr) in [("path", "Path"), ("domain", "Domain")] {
+        match attrs.get(key) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(s)) if is_attribute_value(s) => {
+                out.push_str(&format!("; {attr}={s}"));
+            }
+            Some(_) => return Err(invalid(key)),
+        }
+    }
+    match attrs.get("expires") {
+        None | Some(Value::Null) => {}
+        Some(Value::Number(n)) if n.is_i64() => {
+            let secs = n.as_i64().unwrap_or_default();
+            let date = clock::strftime("%a, %d %b %Y %H:%M:%S GMT", secs.saturating_mul(1000))?;
+            out.push_str(&format!("; Expires={date}"));
+        }
+        Some(Value::String(s)) if is_attribute_value(s) => {
+            out.push_str(&format!("; Expires={s}"));
+        }
+        Some(_) => return Err(invalid("expires")),
+    }
+    match attrs.get("max_age") {
+        None | Some(Value::Null) => {}
+        Some(Value::Number(n)) if n.is_i64() => out.push_str(&format!("; Max-Age={n}")),
+        Some(_) => return Err(invalid("max_age")),
+    }
+    if flag("http_only")? {
+        out.push_str("; HttpOnly");
+    }
+
+    let mut secure = flag("secure")?;
+    match attrs.get("same_site") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(s)) => {
+            let same_site = match s.to_ascii_lowercase().as_str() {
+                "strict" => "Strict",
+                "lax" => "Lax",
+                // browsers reject cross-site cookies that are not secure
+                "none" => {
+                    secure = true;
+                    "None"
+                }
+                _ => return Err(invalid("same_site")),
+            };
+            out.push_str(&format!("; SameSite={same_site}"));
+        }
+        Some(_) => return Err(invalid("same_site")),
+    }
+    if flag("partitioned")? {
+        out.push_str("; Partitioned");
+        secure = true;
+    }
+    if secure {
+        out.push_str("; Secure");
+    }
+    Ok(out)
+}
+
+/// Serializes the cookies given to `response_cookies`, as an object keyed
+/// by cookie name, into the values of `Set-Cookie` headers.
+pub fn to_set_cookie_headers(cookies: &Value) -> Result<Vec<String>, String> {
+    match cookies {
+        Value::Object(map) => map
+            .iter()
+            .map(|(name, cookie)| set_cookie(name, cookie))
+            .collect(),
+        Value::Null => Ok(vec![]),
+        _ => Err("cookies must be an object keyed by cookie name".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_cookies() {
+        assert_eq!(
+            parse(&[
+                "session=abc123; theme=\"dark\"",
+                "session=other;  lang=en ; flag"
+            ]),
+            json!({ "session": "abc123", "theme": "dark", "lang": "en" })
+        );
+        assert_eq!(parse(&[]), json!({}));
+    }
+
+    #[test]
+    fn set_cookies() {
+        let headers = to_set_cookie_headers(&json!({
+            "old": null,
+            "session": {
+                "value": "abc123",
+                "path": "/",
+                "max_age": 3600,
+                "http_only": true,
+                "same_site": "none",
+            },
+            "theme": "dark",
+            "visit": { "value": 3, "expires": 784111777, "secure": true },
+        }));
+        assert_eq!(
+            headers,
+            Ok(vec![
+                "old=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+                "session=abc123; Path=/; Max-Age=3600; HttpOnly; SameSite=None; Secure".to_string(),
+                "theme=dark".to_string(),
+                "visit=3; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure".to_string(),
+            ])
+        );
+
+        assert!(to_set_cookie_headers(&json!({ "a b": "x" })).is_err());
+        assert!(to_set_cookie_headers(&json!({ "a": "x;y" })).is_err());
+        assert!(to_set_cookie_headers(&json!({ "a": { "path": "/;x" } })).is_err());
+        assert!(to_set_cookie_headers(&json!({ "a": { "same_site": "maybe" } })).is_err());
+        assert!(to_set_cookie_headers(&json!({ "a": { "colour": "red" } })).is_err());
+        assert!(to_set_cookie_headers(&json!(["a"])).is_err());
+    }
+}
@@ -1,26 +1,107 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::rc::Rc;
 
-use crate::dependency_graph::DependencyGraph;
+use crate::config::RunOn;
+use crate::dependency_graph::{DependencyGraph, UNCONNECTED};
+use crate::multipart;
 
 #[allow(clippy::enum_variant_names)]
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Phase {
     HttpRequestHeaders,
     HttpRequestBody,
+    HttpRequestTrailers,
     HttpResponseHeaders,
     HttpResponseBody,
+    HttpResponseTrailers,
     HttpCallResponse,
 }
 
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::HttpRequestHeaders => "request_headers",
+            Phase::HttpRequestBody => "request_body",
+            Phase::HttpRequestTrailers => "request_trailers",
+            Phase::HttpResponseHeaders => "response_headers",
+            Phase::HttpResponseBody => "response_body",
+            Phase::HttpResponseTrailers => "response_trailers",
+            Phase::HttpCallResponse => "call_response",
+        }
+    }
+
+    /// Whether the phase is part of the request, or of the response.
+    /// HTTP call responses can happen in either.
+    pub fn run_on(&self) -> Option<RunOn> {
+        match self {
+            Phase::HttpRequestHeaders | Phase::HttpRequestBody | Phase::HttpRequestTrailers => {
+                Some(RunOn::Request)
+            }
+            Phase::HttpResponseHeaders | Phase::HttpResponseBody | Phase::HttpResponseTrailers => {
+                Some(RunOn::Response)
+            }
+            Phase::HttpCallResponse => None,
+        }
+    }
+}
+
 pub struct Input<'a> {
     pub data: &'a [Option<&'a Payload>],
     pub phase: Phase,
+    /// The token of the HTTP call being resumed, if any.
+    pub token_id: Option<u32>,
+    /// In dry runs, nodes do not make calls or send responses.
+    pub dry_run: bool,
+    /// The state the node created for the request, if any.
+    pub state: Option<&'a dyn Any>,
 }
 
-#[derive(Debug)]
+/// An immutable buffer of bytes, shared by the payloads and bodies made
+/// from it, so that they can be passed around without being copied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bytes(Rc<Vec<u8>>);
+
+impl Bytes {
+    /// Takes the bytes, only copying them if they are still shared.
+    pub fn into_vec(self) -> Vec<u8> {
+        Rc::try_unwrap(self.0).unwrap_or_else(|shared| shared.as_ref().clone())
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Bytes {
+        Bytes(Rc::new(bytes))
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Payload {
-    Raw(Vec<u8>),
+    /// Bytes that are not parsed, with their content type if known.
+    Raw(Bytes, Option<String>),
     Json(serde_json::Value),
     Error(String),
 }
@@ -29,30 +110,63 @@ impl Payload {
     pub fn content_type(&self) -> Option<&str> {
         match &self {
             Payload::Json(_) => Some("application/json"),
-            _ => None,
+            Payload::Raw(_, content_type) => content_type.as_deref(),
+            Payload::Error(_) => None,
         }
     }
 
     pub fn from_bytes(bytes: Vec<u8>, content_type: Option<&str>) -> Option<Payload> {
-        match content_type {
-            Some(ct) => {
-                if ct == "application/json" {
-                    match serde_json::from_slice(&bytes) {
-                        Ok(v) => Some(Payload::Json(v)),
-                        Err(e) => Some(Payload::Error(e.to_string())),
-                    }
-                } else {
-                    Some(Payload::Raw(bytes))
+        let content_type = content_type?;
+        let media_type = MediaType::parse(content_type);
+        match media_type {
+            Some(mt) if mt.is_json() => match serde_json::from_slice(&bytes) {
+                Ok(v) => Some(Payload::Json(v)),
+                Err(e) => Some(Payload::Error(e.to_string())),
+            },
+            Some(mt) if mt.is_msgpack() => match rmp_serde::from_slice(&bytes) {
+                Ok(v) => Some(Payload::Json(v)),
+                Err(e) => Some(Payload::Error(format!("invalid MessagePack body: {e}"))),
+            },
+            Some(mt) if mt.is_cbor() => match ciborium::from_reader(bytes.as_slice()) {
+                Ok(v) => Some(Payload::Json(v)),
+                Err(e) => Some(Payload::Error(format!("invalid CBOR body: {e}"))),
+            },
+            Some(mt) if mt.is_yaml() => match serde_yaml::from_slice(&bytes) {
+                Ok(v) => Some(Payload::Json(v)),
+                Err(e) => Some(Payload::Error(format!("invalid YAML body: {e}"))),
+            },
+            Some(mt) if mt.is("application", "x-www-form-urlencoded") => {
+                let form = String::from_utf8_lossy(&bytes);
+                Some(Payload::Json(decode_form(&form)))
+            }
+            Some(mt) if mt.is("multipart", "form-data") => {
+                let parsed = mt
+                    .param("boundary")
+                    .ok_or_else(|| "missing boundary".to_string())
+                    .and_then(|boundary| multipart::parse(&bytes, boundary));
+                match parsed {
+                    Ok(v) => Some(Payload::Json(v)),
+                    Err(e) => Some(Payload::Error(format!("invalid multipart body: {e}"))),
                 }
             }
-            _ => None,
+            _ => Some(Payload::Raw(bytes.into(), Some(content_type.to_string()))),
         }
     }
 
+    /// Payloads kept as raw bytes with a JSON content type, such as
+    /// bodies that are passed through, are only parsed when needed.
+    pub fn unparsed(bytes: Vec<u8>, content_type: Option<&str>) -> Option<Payload> {
+        let content_type = content_type?;
+        Some(Payload::Raw(bytes.into(), Some(content_type.to_string())))
+    }
+
     pub fn to_json(&self) -> Result<serde_json::Value, String> {
         match &self {
             Payload::Json(value) => Ok(value.clone()),
-            Payload::Raw(vec) => match std::str::from_utf8(vec) {
+            Payload::Raw(vec, Some(ct)) if MediaType::parse(ct).is_some_and(|mt| mt.is_json()) => {
+                serde_json::from_slice(vec).map_err(|e| e.to_string())
+            }
+            Payload::Raw(vec, _) => match std::str::from_utf8(vec) {
                 Ok(s) => serde_json::to_value(s).map_err(|e| e.to_string()),
                 Err(e) => Err(e.to_string()),
             },
@@ -60,25 +174,100 @@ impl Payload {
         }
     }
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+    /// Like `to_json`, but raw payloads that are not valid UTF-8,
+    /// such as images, are converted to strings encoded in base64.
+    pub fn to_json_or_base64(&self) -> Result<serde_json::Value, String> {
         match &self {
-            Payload::Json(value) => match serde_json::to_string(value) {
-                Ok(s) => Ok(s.into_bytes()),
+            Payload::Raw(vec, _) if std::str::from_utf8(vec).is_err() => {
+                Ok(serde_json::Value::String(STANDARD.encode(vec)))
+            }
+            _ => self.to_json(),
+        }
+    }
+
+    /// Converts a payload to bytes: raw payloads share theirs, and JSON
+    /// values are serialized.
+    pub fn to_bytes(&self) -> Result<Bytes, String> {
+        match &self {
+            Payload::Json(value) => match serde_json::to_vec(value) {
+                Ok(v) => Ok(v.into()),
                 Err(e) => Err(e.to_string()),
             },
-            Payload::Raw(s) => Ok(s.clone()), // it would be nice to be able to avoid this copy
+            Payload::Raw(s, _) => Ok(s.clone()),
             Payload::Error(e) => Err(e.clone()),
         }
     }
 
+    /// Converts a payload to a body with the given content type. JSON
+    /// values are encoded for MessagePack, CBOR and YAML bodies; JSON objects
+    /// are form-encoded for `application/x-www-form-urlencoded` bodies,
+    /// encoded as parts for `multipart/form-data` bodies, and otherwise
+    /// serialized as JSON.
+    pub fn to_body(&self, content_type: Option<&str>) -> Result<Bytes, String> {
+        let Payload::Json(value) = self else {
+            return self.to_bytes();
+        };
+        let media_type = content_type.and_then(MediaType::parse);
+        let body = match media_type {
+            Some(mt) if mt.is_msgpack() => rmp_serde::to_vec(value).map_err(|e| e.to_string()),
+            Some(mt) if mt.is_cbor() => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+            Some(mt) if mt.is_yaml() => serde_yaml::to_string(value)
+                .map(String::into_bytes)
+                .map_err(|e| e.to_string()),
+            _ if !value.is_object() => return self.to_bytes(),
+            Some(mt) if mt.is("application", "x-www-form-urlencoded") => {
+                encode_form(value).map(String::into_bytes)
+            }
+            Some(mt) if mt.is("multipart", "form-data") => match mt.param("boundary") {
+                Some(boundary) => multipart::encode(value, boundary),
+                None => Err("multipart content type without boundary".to_string()),
+            },
+            _ => return self.to_bytes(),
+        };
+        body.map(Bytes::from)
+    }
+
+    /// Converts a payload holding a string, either as raw bytes
+    /// or as a JSON string, to a `String`.
+    pub fn to_plain_string(&self) -> Result<String, String> {
+        match self.to_json()? {
+            serde_json::Value::String(s) => Ok(s),
+            v => Err(format!("expected a string, got {v}")),
+        }
+    }
+
+    /// Converts a payload holding an HTTP status code, either as a
+    /// number or as a string, to the code.
+    pub fn to_status(&self) -> Result<u32, String> {
+        let value = self.to_json()?;
+        let status = match &value {
+            serde_json::Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+            serde_json::Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        match status {
+            Some(s @ 100..=599) => Ok(s),
+            _ => Err(format!("invalid status code: {value}")),
+        }
+    }
+
     pub fn len(&self) -> Option<usize> {
         match &self {
             Payload::Json(_) => None,
-            Payload::Raw(s) => Some(s.len()),
+            Payload::Raw(s, _) => Some(s.len()),
             Payload::Error(e) => Some(e.len()),
         }
     }
 
+    /// Whether the payload is empty, for payloads with a length (see `len`).
+    pub fn is_empty(&self) -> Option<bool> {
+        self.len().map(|len| len == 0)
+    }
+
     pub fn to_pwm_headers(&self) -> Vec<(&str, &str)> {
         match &self {
             Payload::Json(value) => {
@@ -115,6 +304,120 @@ impl Payload {
     }
 }
 
+/// Splits the parameters of a header value such as `Content-Type` or
+/// `Content-Disposition` (`form-data; name="a"; filename="b.txt"`) into
+/// lowercase names and unquoted values. The leading value is skipped.
+pub fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut rest = value.split_once(';').map_or("", |(_, r)| r);
+
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=').unwrap_or((rest, ""));
+        let after = after.trim_start();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                let next = quoted[end..].split_once(';').map_or("", |(_, r)| r);
+                (value, next)
+            }
+            None => {
+                let (value, next) = after.split_once(';').unwrap_or((after, ""));
+                (value.trim().to_string(), next)
+            }
+        };
+        let name = name.trim().to_ascii_lowercase();
+        if !name.is_empty() {
+            params.push((name, value));
+        }
+        rest = next;
+    }
+
+    params
+}
+
+/// A media type, as given in a `Content-Type` header, such as
+/// `application/problem+json; charset=utf-8`. The type, subtype and
+/// suffix are lowercase.
+#[derive(Debug, PartialEq)]
+pub struct MediaType {
+    pub kind: String,
+    pub subtype: String,
+    pub suffix: Option<String>,
+    pub params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub fn parse(s: &str) -> Option<MediaType> {
+        let essence = s.split(';').next()?.trim().to_ascii_lowercase();
+        let (kind, subtype) = essence.split_once('/')?;
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+        let suffix = subtype.rsplit_once('+').map(|(_, s)| s.to_string());
+
+        Some(MediaType {
+            kind: kind.to_string(),
+            subtype: subtype.to_string(),
+            suffix,
+            params: header_params(s),
+        })
+    }
+
+    pub fn is(&self, kind: &str, subtype: &str) -> bool {
+        self.kind == kind && self.subtype == subtype
+    }
+
+    /// Whether the media type is `application/json` or any type
+    /// with a `+json` suffix, such as `application/problem+json`.
+    pub fn is_json(&self) -> bool {
+        self.is("application", "json") || self.suffix.as_deref() == Some("json")
+    }
+
+    /// Whether the media type is one of the names in use for MessagePack
+    /// or any type with a `+msgpack` suffix.
+    pub fn is_msgpack(&self) -> bool {
+        self.kind == "application"
+            && matches!(
+                self.subtype.as_str(),
+                "msgpack" | "x-msgpack" | "vnd.msgpack"
+            )
+            || self.suffix.as_deref() == Some("msgpack")
+    }
+
+    /// Whether the media type is `application/cbor` or any type
+    /// with a `+cbor` suffix.
+    pub fn is_cbor(&self) -> bool {
+        self.is("application", "cbor") || self.suffix.as_deref() == Some("cbor")
+    }
+
+    /// Whether the media type is one of the names in use for YAML
+    /// or any type with a `+yaml` suffix.
+    pub fn is_yaml(&self) -> bool {
+        matches!(self.kind.as_str(), "application" | "text")
+            && matches!(self.subtype.as_str(), "yaml" | "x-yaml")
+            || self.suffix.as_deref() == Some("yaml")
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 enum StringOrVec {
@@ -145,20 +448,219 @@ pub fn from_pwm_headers(vec: Vec<(String, String)>) -> Payload {
     Payload::Json(value)
 }
 
+/// Builds the `route_params` payload from the router's URI captures,
+/// which are given as a JSON object holding both named and positional
+/// captures. Only the named groups are kept; if the route has no captures,
+/// the result is an empty object.
+pub fn from_uri_captures(bytes: Option<Vec<u8>>) -> Payload {
+    let mut map = serde_json::Map::new();
+
+    if let Some(serde_json::Value::Object(captures)) =
+        bytes.and_then(|b| serde_json::from_slice(&b).ok())
+    {
+        for (k, v) in captures {
+            if k.parse::<usize>().is_err() {
+                map.insert(k, v);
+            }
+        }
+    }
+
+    Payload::Json(serde_json::Value::Object(map))
+}
+
+/// Characters left unescaped in query string keys and values.
+pub const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+fn decode_query_component(s: &str) -> String {
+    let s = s.replace('+', " ");
+    percent_decode_str(&s).decode_utf8_lossy().to_string()
+}
+
+/// Decodes a query string or a form-encoded body into a JSON object.
+/// Keys that appear more than once produce arrays of values, and
+/// keys without a value produce empty strings.
+fn decode_form(query: &str) -> serde_json::Value {
+    let mut map: BTreeMap<String, StringOrVec> = BTreeMap::new();
+
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        let (k, v) = (decode_query_component(k), decode_query_component(v));
+        match map.get_mut(&k) {
+            Some(StringOrVec::Vec(vs)) => vs.push(v),
+            Some(StringOrVec::String(s)) => {
+                let first = std::mem::take(s);
+                map.insert(k, StringOrVec::Vec(vec![first, v]));
+            }
+            None => {
+                map.insert(k, StringOrVec::String(v));
+            }
+        }
+    }
+
+    serde_json::to_value(map).expect("serializable map")
+}
+
+/// Encodes a JSON object, in the form produced by `decode_form`, as a
+/// query string or a form-encoded body. Null values produce keys without
+/// a value.
+pub fn encode_form(value: &serde_json::Value) -> Result<String, String> {
+    let serde_json::Value::Object(map) = value else {
+        return Err("form must be an object".to_string());
+    };
+
+    let mut pairs = vec![];
+    for (k, v) in map {
+        let values = match v {
+            serde_json::Value::Array(vs) => vs.iter().collect(),
+            v => vec![v],
+        };
+        let key = utf8_percent_encode(k, QUERY_ENCODE_SET);
+        for v in values {
+            match v {
+                serde_json::Value::Null => pairs.push(key.to_string()),
+                serde_json::Value::String(s) => pairs.push(format!(
+                    "{key}={}",
+                    utf8_percent_encode(s, QUERY_ENCODE_SET)
+                )),
+                v => pairs.push(format!("{key}={v}")),
+            }
+        }
+    }
+
+    Ok(pairs.join("&"))
+}
+
+/// Builds the `request_query` payload from the request's `:path`.
+pub fn from_query_string(path: Option<&str>) -> Payload {
+    let query = path.and_then(|p| p.split_once('?')).map_or("", |(_, q)| q);
+    Payload::Json(decode_form(query))
+}
+
+/// Returns the path part of a `:path` pseudo-header, without the query string.
+pub fn path_without_query(path: &str) -> &str {
+    path.split_once('?').map_or(path, |(p, _)| p)
+}
+
+/// Replaces the path part of a `:path` pseudo-header, keeping its query string.
+pub fn replace_path(path: &str, new_path: &str) -> String {
+    match path.split_once('?') {
+        Some((_, query)) => format!("{new_path}?{query}"),
+        None => new_path.to_string(),
+    }
+}
+
+/// Encodes a `service_request_query` payload, a JSON object in the same
+/// form as `request_query`, and replaces the query string of `path` with it.
+pub fn replace_query_string(path: &str, payload: &Payload) -> Result<String, String> {
+    let value = payload.to_json()?;
+    if !value.is_object() {
+        return Err("query must be an object".to_string());
+    }
+
+    let query = encode_form(&value)?;
+    let base = path_without_query(path);
+    if query.is_empty() {
+        Ok(base.to_string())
+    } else {
+        Ok(format!("{base}?{query}"))
+    }
+}
+
+/// Records the original casing of header names, keyed by their
+/// lowercase form as used in header payloads.
+pub fn header_case_map(vec: &[(String, String)]) -> BTreeMap<String, String> {
+    vec.iter()
+        .map(|(k, _)| (k.to_lowercase(), k.clone()))
+        .collect()
+}
+
+/// Restores the casing of header names recorded with `header_case_map`.
+/// Headers not present in the map are left as they are.
+pub fn restore_header_case<'a>(
+    headers: Vec<(&'a str, &'a str)>,
+    case_map: &'a BTreeMap<String, String>,
+) -> Vec<(&'a str, &'a str)> {
+    headers
+        .into_iter()
+        .map(|(k, v)| (case_map.get(k).map_or(k, |orig| orig.as_str()), v))
+        .collect()
+}
+
+/// Returns the names of headers given as `null` in a headers payload,
+/// which are removed when headers are applied in `patch` mode.
+pub fn null_header_names(payload: &Payload) -> Vec<&str> {
+    match payload {
+        Payload::Json(serde_json::Value::Object(map)) => map
+            .iter()
+            .filter(|(_, v)| v.is_null())
+            .map(|(k, _)| k.as_str())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Decompresses a body according to its `Content-Encoding`. Bodies
+/// without an encoding, or with `identity`, are returned as they are.
+pub fn decode_content(bytes: Vec<u8>, encoding: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let res = match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("identity") => return Ok(bytes),
+        Some("gzip") | Some("x-gzip") => GzDecoder::new(bytes.as_slice()).read_to_end(&mut out),
+        Some("deflate") => ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut out),
+        Some(other) => return Err(format!("unsupported content encoding: {other}")),
+    };
+    res.map(|_| out)
+        .map_err(|e| format!("failed decompressing body: {e}"))
+}
+
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(bytes)
+        .and_then(|_| encoder.finish())
+        .expect("writing to a Vec does not fail")
+}
+
+/// Checks whether an `Accept-Encoding` header allows gzip responses.
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.is_some_and(|header| {
+        header.split(',').any(|item| {
+            let mut parts = item.split(';').map(|p| p.trim());
+            let coding = parts.next().unwrap_or("");
+            let rejected = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !rejected
+        })
+    })
+}
+
 pub fn to_pwm_headers(payload: Option<&Payload>) -> Vec<(&str, &str)> {
     payload.map_or_else(Vec::new, |p| p.to_pwm_headers())
 }
 
+/// Finds the value of a header in a headers vector, ignoring case.
+pub fn find_header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| *v)
+}
+
+/// Converts a payload to a body with the given content type.
 /// To use this result in proxy-wasm calls as an Option<&[u8]>, use:
-/// `data::to_pwm_body(p).as_deref()`.
-pub fn to_pwm_body(payload: Option<&Payload>) -> Result<Option<Box<[u8]>>, String> {
-    match payload {
-        Some(p) => match p.to_bytes() {
-            Ok(b) => Ok(Some(Vec::into_boxed_slice(b))),
-            Err(e) => Err(e),
-        },
-        None => Ok(None),
-    }
+/// `data::to_pwm_body(p, ct).as_deref()`.
+pub fn to_pwm_body(
+    payload: Option<&Payload>,
+    content_type: Option<&str>,
+) -> Result<Option<Bytes>, String> {
+    payload.map(|p| p.to_body(content_type)).transpose()
 }
 
 #[derive(Debug)]
@@ -170,22 +672,79 @@ pub enum State {
 
 #[derive(Default)]
 pub struct Data {
-    graph: DependencyGraph,
+    graph: Rc<DependencyGraph>,
     states: BTreeMap<String, State>,
+    /// The names whose payloads were set since `take_updated` was called.
+    updated: Vec<String>,
 }
 
 impl Data {
-    pub fn new(graph: DependencyGraph) -> Data {
+    pub fn new(graph: Rc<DependencyGraph>) -> Data {
         Data {
             graph,
             states: Default::default(),
+            updated: vec![],
         }
     }
 
     pub fn set(&mut self, name: &str, state: State) {
+        if matches!(state, State::Done(_)) {
+            self.updated.push(name.to_string());
+        }
         self.states.insert(name.to_string(), state);
     }
 
+    /// Takes the names whose payloads were set, and may let the nodes
+    /// depending on them run, since the last call.
+    pub fn take_updated(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.updated)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&State> {
+        self.states.get(name)
+    }
+
+    /// Whether any node is still waiting on an HTTP call.
+    pub fn is_waiting(&self) -> bool {
+        self.states.values().any(|s| matches!(s, State::Waiting(_)))
+    }
+
+    /// Marks the given nodes and their named outputs as failed with the
+    /// given error, unless they have failed already, so that no node or
+    /// sink uses them anymore.
+    pub fn fail_all(&mut self, names: &[String], error: &str) {
+        let ports: Vec<String> = self
+            .states
+            .keys()
+            .filter(|key| {
+                key.split_once('.')
+                    .is_some_and(|(n, _)| names.iter().any(|name| name == n))
+            })
+            .cloned()
+            .collect();
+
+        for name in names.iter().chain(&ports) {
+            if !matches!(self.states.get(name), Some(State::Fail(_))) {
+                let error = Payload::Error(error.to_string());
+                self.set(name, State::Fail(Some(error)));
+            }
+        }
+    }
+
+    /// Makes each entry of a node's output object available as the
+    /// payload of `node.role`. Missing entries, or outputs that are not
+    /// objects, produce no payload.
+    pub fn set_entries(&mut self, name: &str, roles: &[String], payload: Option<&Payload>) {
+        for role in roles {
+            let entry = match payload {
+                Some(Payload::Json(serde_json::Value::Object(map))) => map.get(role).cloned(),
+                _ => None,
+            };
+            let port = format!("{name}.{role}");
+            self.set(&port, State::Done(entry.map(Payload::Json)));
+        }
+    }
+
     fn can_trigger(&self, name: &str, waiting: Option<u32>) -> bool {
         // If node is Done, avoid producing inputs
         // and re-triggering its execution.
@@ -210,6 +769,9 @@ impl Data {
 
         // Check that all inputs have payloads available
         for input in self.graph.each_input(name) {
+            if input == UNCONNECTED {
+                continue;
+            }
             let val = self.states.get(input);
             match val {
                 Some(State::Done(_)) => {}
@@ -234,7 +796,9 @@ impl Data {
         // If so, allocate the vector with the result.
         let mut vec: Vec<Option<&Payload>> = Vec::new();
         for input in self.graph.each_input(name) {
-            if let Some(State::Done(p)) = self.states.get(input) {
+            if input == UNCONNECTED {
+                vec.push(None);
+            } else if let Some(State::Done(p)) = self.states.get(input) {
                 vec.push(p.as_ref());
             }
         }
@@ -260,6 +824,9 @@ impl Data {
         }
 
         for input in self.graph.each_input(name) {
+            if input == UNCONNECTED {
+                continue;
+            }
             if let Some(State::Done(p)) = self.states.get(input) {
                 return p.as_ref();
             }
@@ -288,3 +855,215 @@ pub fn to_json_error_body(message: &str, request_id: Option<Vec<u8>>) -> String
     .map(|v| v.to_string())
     .expect("JSON error object")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fail_all() {
+        let mut graph = DependencyGraph::default();
+        graph.add("A.body", "response_body");
+        graph.add("B", "response_headers");
+        let mut data = Data::new(Rc::new(graph));
+        data.set("A", State::Done(None));
+        data.set("A.body", State::Done(Some(Payload::Json(json!("ok")))));
+        assert!(data.first_input_for("response_body", None).is_some());
+        assert_eq!(data.take_updated(), ["A", "A.body"]);
+
+        data.fail_all(&["A".to_string(), "B".to_string()], "aborted");
+        assert!(data.take_updated().is_empty());
+        assert!(data.first_input_for("response_body", None).is_none());
+        assert!(data.first_input_for("response_headers", None).is_none());
+        assert!(matches!(data.get("B"), Some(State::Fail(_))));
+    }
+
+    #[test]
+    fn query_string() {
+        let Payload::Json(value) = from_query_string(Some("/path?a=1&b=x+y&a=2&c&d=%C3%A9%26"))
+        else {
+            panic!("expected JSON payload");
+        };
+        assert_eq!(
+            value,
+            json!({ "a": ["1", "2"], "b": "x y", "c": "", "d": "é&" })
+        );
+
+        let Payload::Json(value) = from_query_string(Some("/path")) else {
+            panic!("expected JSON payload");
+        };
+        assert_eq!(value, json!({}));
+    }
+
+    #[test]
+    fn media_types() {
+        let mt = MediaType::parse("Application/Problem+JSON; charset=\"utf-8\"").unwrap();
+        assert_eq!(mt.kind, "application");
+        assert_eq!(mt.subtype, "problem+json");
+        assert_eq!(mt.suffix.as_deref(), Some("json"));
+        assert_eq!(mt.param("charset"), Some("utf-8"));
+        assert!(mt.is_json());
+
+        assert!(MediaType::parse("application/json;charset=utf-8")
+            .unwrap()
+            .is_json());
+        assert!(!MediaType::parse("text/plain").unwrap().is_json());
+        assert_eq!(MediaType::parse("json"), None);
+
+        assert_eq!(
+            header_params("form-data; name=\"a\"; filename=\"x\\\"y;z\""),
+            vec![
+                ("name".to_string(), "a".to_string()),
+                ("filename".to_string(), "x\"y;z".to_string())
+            ]
+        );
+
+        let body = Payload::from_bytes(b"{}".to_vec(), Some("application/json; charset=utf-8"));
+        assert!(matches!(body, Some(Payload::Json(_))));
+    }
+
+    #[test]
+    fn binary_bodies() {
+        let value = json!({ "id": 7, "tags": ["a", "b"], "ok": true });
+        for ct in [
+            "application/msgpack",
+            "application/vnd.api+cbor",
+            "text/yaml",
+        ] {
+            let payload = Payload::Json(value.clone());
+            let bytes = payload.to_body(Some(ct)).unwrap();
+            assert_ne!(bytes, payload.to_bytes().unwrap());
+
+            let Some(Payload::Json(decoded)) = Payload::from_bytes(bytes.into_vec(), Some(ct))
+            else {
+                panic!("expected JSON payload");
+            };
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[test]
+    fn binary_raw() {
+        let text = Payload::Raw(b"hello".to_vec().into(), None);
+        assert_eq!(text.to_json_or_base64(), Ok(json!("hello")));
+
+        let binary = Payload::Raw(b"\x89PNG".to_vec().into(), None);
+        assert!(binary.to_json().is_err());
+        assert_eq!(binary.to_json_or_base64(), Ok(json!("iVBORw==")));
+    }
+
+    #[test]
+    fn unparsed_json() {
+        let body = br#"{ "b": 1, "a": [true] }"#.to_vec();
+        let payload = Payload::unparsed(body.clone(), Some("application/json")).unwrap();
+        assert_eq!(payload.to_bytes().unwrap().into_vec(), body);
+        assert_eq!(payload.to_json(), Ok(json!({ "a": [true], "b": 1 })));
+    }
+
+    #[test]
+    fn shared_bytes() {
+        let payload = Payload::Raw(b"body".to_vec().into(), None);
+        let bytes = payload.to_bytes().unwrap();
+        let Payload::Raw(raw, _) = &payload else {
+            panic!("expected raw payload");
+        };
+        assert_eq!(raw.as_ptr(), bytes.as_ptr());
+
+        drop(payload);
+        assert_eq!(bytes.into_vec(), b"body");
+    }
+
+    #[test]
+    fn raw_content_type() {
+        let html = b"<p>hello</p>".to_vec();
+        let payload = Payload::from_bytes(html.clone(), Some("text/html; charset=utf-8"));
+        let Some(Payload::Raw(bytes, _)) = &payload else {
+            panic!("expected raw payload");
+        };
+        assert_eq!(&bytes[..], &html[..]);
+        assert_eq!(
+            payload.unwrap().content_type(),
+            Some("text/html; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn form_body() {
+        let form = b"user=jane%40example.com&remember=on&tag=a&tag=b".to_vec();
+        let form_type = "application/x-www-form-urlencoded";
+        let Some(Payload::Json(value)) = Payload::from_bytes(form, Some(form_type)) else {
+            panic!("expected JSON payload");
+        };
+        assert_eq!(
+            value,
+            json!({ "user": "jane@example.com", "remember": "on", "tag": ["a", "b"] })
+        );
+
+        let payload = Payload::Json(value);
+        assert_eq!(
+            payload.to_body(Some(form_type)),
+            Ok(b"remember=on&tag=a&tag=b&user=jane%40example.com"
+                .to_vec()
+                .into())
+        );
+        assert_eq!(
+            payload.to_body(Some("application/json")),
+            payload.to_bytes()
+        );
+    }
+
+    #[test]
+    fn content_encoding() {
+        let body = b"hello, hello, hello".to_vec();
+        assert_eq!(decode_content(gzip(&body), Some("gzip")), Ok(body.clone()));
+        assert_eq!(decode_content(body.clone(), None), Ok(body.clone()));
+        assert!(decode_content(body.clone(), Some("gzip")).is_err());
+        assert!(decode_content(body, Some("br")).is_err());
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts_gzip(Some("gzip, deflate, br")));
+        assert!(accepts_gzip(Some("br;q=1.0, gzip;q=0.8")));
+        assert!(accepts_gzip(Some("*")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("br")));
+        assert!(!accepts_gzip(None));
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(Payload::Json(json!(404)).to_status(), Ok(404));
+        assert_eq!(Payload::Json(json!("201")).to_status(), Ok(201));
+        assert_eq!(
+            Payload::Raw(b"503".to_vec().into(), None).to_status(),
+            Ok(503)
+        );
+        assert!(Payload::Json(json!(42)).to_status().is_err());
+        assert!(Payload::Json(json!({})).to_status().is_err());
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(path_without_query("/a/b?x=1"), "/a/b");
+        assert_eq!(path_without_query("/a/b"), "/a/b");
+        assert_eq!(replace_path("/a/b?x=1", "/c"), "/c?x=1");
+        assert_eq!(replace_path("/a/b", "/c"), "/c");
+    }
+
+    #[test]
+    fn replace_query() {
+        let query = Payload::Json(json!({ "a": ["1", "2"], "b": "x y", "c": null, "n": 3 }));
+        assert_eq!(
+            replace_query_string("/path?old=1", &query),
+            Ok("/path?a=1&a=2&b=x%20y&c&n=3".to_string())
+        );
+
+        let empty = Payload::Json(json!({}));
+        assert_eq!(
+            replace_query_string("/path?old=1", &empty),
+            Ok("/path".to_string())
+        );
+    }
+}
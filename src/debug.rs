@@ -1,8 +1,12 @@
-use crate::config::Config;
-use crate::data::{Payload, State};
+use crate::config::{Config, IMPLICIT_SINKS};
+use crate::data::{Data, Payload, Phase, State};
+use crate::dependency_graph::node_of;
+use crate::nodes::secret;
 use serde::Serialize;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 pub enum RunMode {
     Run,
@@ -15,10 +19,31 @@ pub enum DataMode {
     Fail,
 }
 
+/// How long a node took to run or resume, and the call it resumed from
+/// or started waiting on, if any.
+pub struct Timing {
+    duration: Duration,
+    token_id: Option<u32>,
+}
+
+impl Timing {
+    pub fn new(started: Instant, token_id: Option<u32>) -> Timing {
+        Timing {
+            duration: started.elapsed(),
+            token_id,
+        }
+    }
+}
+
 struct RunOperation {
+    at: Duration,
     node_name: String,
     node_type: String,
     action: RunMode,
+    duration: Duration,
+    input_bytes: usize,
+    output_bytes: Option<usize>,
+    token_id: Option<u32>,
 }
 
 struct SetOperation {
@@ -26,18 +51,112 @@ struct SetOperation {
     data_type: String,
     status: DataMode,
     value: Option<Value>,
+    at: Duration,
+}
+
+/// Marks the start of a phase, in traces of version 2.
+struct PhaseOperation {
+    phase: &'static str,
+    at: Duration,
 }
 
 enum Operation {
     Run(RunOperation),
     Set(SetOperation),
+    Phase(PhaseOperation),
+}
+
+#[derive(Serialize, Default)]
+struct TraceAction<'a> {
+    action: &'static str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_us: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at_us: Option<u128>,
+}
+
+impl Operation {
+    /// The time since the start of the trace at which the operation
+    /// started.
+    fn at(&self) -> Duration {
+        match self {
+            Operation::Run(run) => run.at,
+            Operation::Set(set) => set.at,
+            Operation::Phase(p) => p.at,
+        }
+    }
+
+    fn to_action(&self) -> Option<TraceAction<'_>> {
+        Some(match self {
+            Operation::Run(run) => TraceAction {
+                action: match run.action {
+                    RunMode::Run => "run",
+                    RunMode::Resume => "resume",
+                },
+                name: &run.node_name,
+                r#type: Some(&run.node_type),
+                duration_us: Some(run.duration.as_micros()),
+                input_bytes: Some(run.input_bytes),
+                output_bytes: run.output_bytes,
+                token_id: run.token_id,
+                ..TraceAction::default()
+            },
+            Operation::Set(set) => match set.status {
+                DataMode::Done => TraceAction {
+                    action: "value",
+                    name: &set.node_name,
+                    r#type: Some(&set.data_type),
+                    value: set.value.as_ref(),
+                    ..TraceAction::default()
+                },
+                DataMode::Waiting => TraceAction {
+                    action: "wait",
+                    name: &set.node_name,
+                    ..TraceAction::default()
+                },
+                DataMode::Fail => TraceAction {
+                    action: "fail",
+                    name: &set.node_name,
+                    value: set.value.as_ref(),
+                    ..TraceAction::default()
+                },
+            },
+            Operation::Phase(_) => return None,
+        })
+    }
 }
 
 pub struct Debug {
     trace: bool,
+    wrap: bool,
+    v2: bool,
+    started: Instant,
+    phase: Option<Phase>,
     operations: Vec<Operation>,
     node_types: HashMap<String, String>,
+    inputs: HashMap<String, Vec<String>>,
+    /// The nodes and sinks whose values are redacted in traces.
+    redacted: HashSet<String>,
     orig_response_body_content_type: Option<String>,
+    orig_response_status: Option<u32>,
+    trace_id: Option<String>,
+    max_value_bytes: Option<usize>,
+    max_operations: Option<usize>,
+    dropped_operations: usize,
 }
 
 impl State {
@@ -63,52 +182,281 @@ fn payload_to_op_info(p: &Option<Payload>, default_type: &str) -> (String, Optio
     }
 }
 
+/// A range of client addresses allowed to request traces, given in CIDR
+/// notation (`10.0.0.0/8`, `fd00::/8`) or as a single address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub fn parse(s: &str) -> Option<IpRange> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse().ok()?, None),
+        };
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(IpRange { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // IPv4 clients may be seen as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            IpAddr::V4(_) => *ip,
+        };
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix).unwrap_or(0);
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = mask(32) as u32;
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = mask(128);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Options given in the value of the `X-DataKit-Debug-Trace` header.
+#[derive(Debug, Default, PartialEq)]
+pub struct TraceOptions {
+    /// The trace is returned in an envelope with the response.
+    pub wrap: bool,
+    /// The trace uses version 2 of the format.
+    pub v2: bool,
+}
+
+/// Splits the value of the `X-DataKit-Debug-Trace` header into options
+/// and the token it gives. The value is a list separated by `:`, such as
+/// `wrap:v2:<token>`, whose last element is the token.
+pub fn parse_trace_header(value: &str) -> (TraceOptions, &str) {
+    let mut options = TraceOptions::default();
+    for option in value.split(':') {
+        match option {
+            "wrap" => options.wrap = true,
+            "v2" => options.v2 = true,
+            _ => {}
+        }
+    }
+    let token = value.rsplit(':').next().unwrap_or_default();
+    (options, token)
+}
+
+/// Compares tokens in a time that does not depend on where they differ.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The nodes and implicit sinks whose values come from a `secret` node,
+/// directly or through other nodes, and must not appear in traces.
+fn secret_derived(config: &Config) -> HashSet<String> {
+    let graph = config.get_graph();
+    let mut derived: HashSet<String> = config
+        .node_types()
+        .filter(|(_, node_type)| *node_type == "secret")
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    let names: Vec<&str> = config
+        .node_types()
+        .map(|(name, _)| name)
+        .chain(IMPLICIT_SINKS)
+        .collect();
+    loop {
+        let before = derived.len();
+        for name in &names {
+            if graph
+                .each_input(name)
+                .any(|input| derived.contains(node_of(input)))
+            {
+                derived.insert(name.to_string());
+            }
+        }
+        if derived.len() == before {
+            return derived;
+        }
+    }
+}
+
+/// The outcome of a dry run: the state and value of every node, and the
+/// payloads the implicit sinks would have received.
+pub fn dry_run_report(config: &Config, data: &Data) -> Value {
+    let redacted = secret_derived(config);
+    let redact = |name: &str, value: Value| match redacted.contains(name) {
+        true => serde_json::json!(secret::REDACTED),
+        false => value,
+    };
+
+    let mut nodes = serde_json::Map::new();
+    for (name, _) in config.node_types() {
+        let (state, value) = match data.get(name) {
+            Some(State::Done(p)) => ("done", payload_to_op_info(p, "raw").1),
+            Some(State::Fail(p)) => ("fail", payload_to_op_info(p, "fail").1),
+            Some(State::Waiting(_)) => ("waiting", None),
+            None => ("not_run", None),
+        };
+        let value = value.map(|v| redact(name, v));
+        nodes.insert(
+            name.to_string(),
+            serde_json::json!({ "state": state, "value": value }),
+        );
+    }
+
+    let mut sinks = serde_json::Map::new();
+    for sink in IMPLICIT_SINKS {
+        if let Some(payload) = data.first_input_for(sink, None) {
+            let value = payload.to_json_or_base64().unwrap_or_else(Value::String);
+            let value = redact(sink, value);
+            sinks.insert(sink.to_string(), value);
+        }
+    }
+
+    serde_json::json!({ "nodes": nodes, "sinks": sinks })
+}
+
+/// Replaces a value whose JSON encoding is larger than `max` bytes with
+/// a string holding the start of the encoding and a truncation marker.
+fn truncate_value(value: Value, max: usize) -> Value {
+    let encoded = value.to_string();
+    if encoded.len() <= max {
+        return value;
+    }
+
+    let mut end = max;
+    while !encoded.is_char_boundary(end) {
+        end -= 1;
+    }
+    let marker = format!("...[truncated, {} bytes]", encoded.len());
+    Value::String(format!("{}{marker}", &encoded[..end]))
+}
+
 impl Debug {
     pub fn new(config: &Config) -> Debug {
         let mut node_types = HashMap::new();
+        let mut inputs = HashMap::new();
+        let graph = config.get_graph();
         for (name, node_type) in config.node_types() {
             node_types.insert(name.to_string(), node_type.to_string());
+            let names = graph.each_input(name).filter(|n| !n.is_empty()).cloned();
+            inputs.insert(name.to_string(), names.collect());
         }
 
         Debug {
             node_types,
+            inputs,
+            redacted: secret_derived(config),
             trace: false,
+            wrap: false,
+            v2: false,
+            started: Instant::now(),
+            phase: None,
             operations: vec![],
             orig_response_body_content_type: None,
+            orig_response_status: None,
+            trace_id: None,
+            max_value_bytes: config.max_trace_value_bytes(),
+            max_operations: config.max_trace_operations(),
+            dropped_operations: 0,
+        }
+    }
+
+    /// Records an operation, unless the maximum number of operations
+    /// has been recorded already, in which case it is only counted.
+    fn push(&mut self, op: Operation) {
+        if self
+            .max_operations
+            .is_some_and(|max| self.operations.len() >= max)
+        {
+            self.dropped_operations += 1;
+        } else {
+            self.operations.push(op);
         }
     }
 
     pub fn set_data(&mut self, name: &str, state: &State) {
         if self.trace {
-            let (data_type, value) = match state {
+            let (data_type, mut value) = match state {
                 State::Done(p) => payload_to_op_info(p, "raw"),
                 State::Waiting(_) => ("waiting".to_string(), None),
                 State::Fail(p) => payload_to_op_info(p, "fail"),
             };
 
-            self.operations.push(Operation::Set(SetOperation {
+            if self.redacted.contains(name) {
+                value = value.map(|_| serde_json::json!(secret::REDACTED));
+            }
+            if let Some(max) = self.max_value_bytes {
+                value = value.map(|v| truncate_value(v, max));
+            }
+
+            self.push(Operation::Set(SetOperation {
                 node_name: name.to_string(),
                 data_type,
                 status: state.to_data_mode(),
                 value,
+                at: self.started.elapsed(),
             }));
         }
     }
 
-    pub fn run(&mut self, name: &str, _args: &[Option<&Payload>], state: &State, action: RunMode) {
+    pub fn run(
+        &mut self,
+        name: &str,
+        args: &[Option<&Payload>],
+        state: &State,
+        action: RunMode,
+        timing: Timing,
+    ) {
         if self.trace {
-            let node_type = self.node_types.get(name).expect("node exists");
+            let node_type = self.node_types.get(name).expect("node exists").clone();
 
-            self.operations.push(Operation::Run(RunOperation {
+            let input_bytes = args.iter().flatten().filter_map(|p| p.len()).sum();
+            let (output_bytes, token_id) = match state {
+                State::Done(p) | State::Fail(p) => (p.as_ref().and_then(|p| p.len()), None),
+                State::Waiting(token_id) => (None, Some(*token_id)),
+            };
+
+            let at = self.started.elapsed().saturating_sub(timing.duration);
+            self.push(Operation::Run(RunOperation {
+                at,
                 action,
                 node_name: name.to_string(),
-                node_type: node_type.to_string(),
+                node_type,
+                duration: timing.duration,
+                input_bytes,
+                output_bytes,
+                token_id: timing.token_id.or(token_id),
             }));
 
             self.set_data(name, state);
         }
     }
 
+    /// Marks the start of a phase in the trace, unless it is the current
+    /// one, as when a body arrives in several chunks.
+    pub fn phase(&mut self, phase: Phase) {
+        if self.trace && self.phase != Some(phase) {
+            self.phase = Some(phase);
+            let at = self.started.elapsed();
+            self.push(Operation::Phase(PhaseOperation {
+                phase: phase.name(),
+                at,
+            }));
+        }
+    }
+
     pub fn save_response_body_content_type(&mut self, ct: Option<String>) {
         self.orig_response_body_content_type = ct;
     }
@@ -117,6 +465,10 @@ impl Debug {
         &self.orig_response_body_content_type
     }
 
+    pub fn save_response_status(&mut self, status: Option<u32>) {
+        self.orig_response_status = status;
+    }
+
     pub fn set_tracing(&mut self, enable: bool) {
         self.trace = enable;
     }
@@ -125,53 +477,234 @@ impl Debug {
         self.trace
     }
 
+    /// When wrapping, the trace is returned in a JSON envelope
+    /// alongside the response that would have been sent otherwise.
+    pub fn set_wrapping(&mut self, enable: bool) {
+        self.wrap = enable;
+    }
+
+    pub fn is_wrapping(&self) -> bool {
+        self.wrap
+    }
+
+    /// Selects version 2 of the trace format.
+    pub fn set_v2(&mut self, enable: bool) {
+        self.v2 = enable;
+    }
+
+    /// Traces that are not returned in the response body are identified
+    /// by an id, so that they can be found in the log or in a collector.
+    pub fn set_trace_id(&mut self, id: String) {
+        self.trace_id = Some(id);
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    /// The trace and its id, as sent to the log or to a collector.
+    pub fn get_trace_record(&self) -> String {
+        serde_json::json!({
+            "trace_id": self.trace_id,
+            "trace": self.trace_to_json(),
+        })
+        .to_string()
+    }
+
     pub fn get_trace(&self) -> String {
-        #[derive(Serialize)]
-        struct TraceAction<'a> {
-            action: &'static str,
-            name: &'a str,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            r#type: Option<&'a str>,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            value: Option<&'a Value>,
+        self.trace_to_json().to_string()
+    }
+
+    pub fn get_trace_envelope(&self, response: Option<Value>) -> String {
+        serde_json::json!({
+            "trace": self.trace_to_json(),
+            "response": response,
+            "status": self.orig_response_status,
+        })
+        .to_string()
+    }
+
+    fn trace_to_json(&self) -> Value {
+        if self.v2 {
+            return self.trace_to_json_v2();
         }
 
-        let mut actions: Vec<TraceAction> = vec![];
+        let mut actions: Vec<TraceAction> = self
+            .operations
+            .iter()
+            .filter_map(Operation::to_action)
+            .collect();
+        let dropped = serde_json::json!(self.dropped_operations);
 
-        for op in self.operations.iter() {
-            actions.push(match op {
-                Operation::Run(run) => TraceAction {
-                    action: match run.action {
-                        RunMode::Run => "run",
-                        RunMode::Resume => "resume",
-                    },
-                    name: &run.node_name,
-                    r#type: Some(&run.node_type),
-                    value: None,
-                },
-                Operation::Set(set) => match set.status {
-                    DataMode::Done => TraceAction {
-                        action: "value",
-                        name: &set.node_name,
-                        r#type: Some(&set.data_type),
-                        value: set.value.as_ref(),
-                    },
-                    DataMode::Waiting => TraceAction {
-                        action: "wait",
-                        name: &set.node_name,
-                        r#type: None,
-                        value: None,
-                    },
-                    DataMode::Fail => TraceAction {
-                        action: "fail",
-                        name: &set.node_name,
-                        r#type: None,
-                        value: set.value.as_ref(),
-                    },
-                },
+        if self.dropped_operations > 0 {
+            actions.push(TraceAction {
+                action: "truncated",
+                name: "operations",
+                value: Some(&dropped),
+                ..TraceAction::default()
             });
         }
 
-        serde_json::json!(actions).to_string()
+        serde_json::json!(actions)
+    }
+
+    /// The trace in version 2 of the format, where the operations are
+    /// grouped per node, along with the node inputs, and are timestamped
+    /// and tagged with the phase they happened in.
+    fn trace_to_json_v2(&self) -> Value {
+        let mut phases = vec![];
+        let mut nodes: Vec<(&str, Vec<TraceAction>)> = vec![];
+        let mut phase = None;
+
+        for op in self.operations.iter() {
+            if let Operation::Phase(p) = op {
+                phase = Some(p.phase);
+                phases.push(serde_json::json!({
+                    "phase": p.phase,
+                    "at_us": p.at.as_micros(),
+                }));
+                continue;
+            }
+            let Some(mut action) = op.to_action() else {
+                continue;
+            };
+
+            let name = action.name;
+            action.name = "";
+            action.phase = phase;
+            action.at_us = Some(op.at().as_micros());
+            match nodes.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, events)) => events.push(action),
+                None => nodes.push((name, vec![action])),
+            }
+        }
+
+        let nodes: Vec<Value> = nodes
+            .into_iter()
+            .map(|(name, events)| {
+                serde_json::json!({
+                    "name": name,
+                    "type": self.node_types.get(name).map_or("implicit", |t| t.as_str()),
+                    "inputs": self.inputs.get(name).cloned().unwrap_or_default(),
+                    "events": events,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "version": 2,
+            "phases": phases,
+            "nodes": nodes,
+            "dropped_operations": self.dropped_operations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nodes;
+    use serde_json::json;
+
+    #[test]
+    fn ip_ranges() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let range = IpRange::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains(&ip("10.1.200.3")));
+        assert!(range.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("fd00::1")));
+
+        assert!(IpRange::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&ip("192.0.2.1")));
+        assert!(IpRange::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(IpRange::parse("192.0.2.1")
+            .unwrap()
+            .contains(&ip("192.0.2.1")));
+        assert!(!IpRange::parse("192.0.2.1")
+            .unwrap()
+            .contains(&ip("192.0.2.2")));
+
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("example.com"), None);
+    }
+
+    #[test]
+    fn trace_header() {
+        let options = |wrap, v2| TraceOptions { wrap, v2 };
+        assert_eq!(parse_trace_header("wrap"), (options(true, false), "wrap"));
+        assert_eq!(parse_trace_header("v2"), (options(false, true), "v2"));
+        assert_eq!(parse_trace_header("wrap:v2"), (options(true, true), "v2"));
+        assert_eq!(
+            parse_trace_header("wrap:s3cr3t"),
+            (options(true, false), "s3cr3t")
+        );
+        assert_eq!(
+            parse_trace_header("v2:s3cr3t"),
+            (options(false, true), "s3cr3t")
+        );
+        assert_eq!(
+            parse_trace_header("s3cr3t"),
+            (options(false, false), "s3cr3t")
+        );
+        assert!(tokens_match("s3cr3t", "s3cr3t"));
+        assert!(!tokens_match("s3cr3", "s3cr3t"));
+        assert!(!tokens_match("s3cr3x", "s3cr3t"));
+    }
+
+    #[test]
+    fn trace_v2() {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "A", "type": "jq", "input": "request_body", "jq": "." }
+        ] }"#;
+        let config = Config::new(config.to_vec(), &|_| None).unwrap();
+
+        let mut debug = Debug::new(&config);
+        debug.set_tracing(true);
+        debug.set_v2(true);
+        debug.phase(Phase::HttpRequestBody);
+        let body = Payload::Raw(br#"{"a":1}"#.to_vec().into(), None);
+        debug.set_data("request_body", &State::Done(Some(body.clone())));
+        let timing = Timing::new(Instant::now(), None);
+        debug.run(
+            "A",
+            &[Some(&body)],
+            &State::Done(None),
+            RunMode::Run,
+            timing,
+        );
+
+        let trace: Value = serde_json::from_str(&debug.get_trace()).unwrap();
+        assert_eq!(trace["version"], 2);
+        assert_eq!(trace["phases"][0]["phase"], "request_body");
+        assert_eq!(trace["nodes"][0]["name"], "request_body");
+        assert_eq!(trace["nodes"][0]["type"], "implicit");
+        assert_eq!(trace["nodes"][1]["inputs"], json!(["request_body"]));
+
+        let events = &trace["nodes"][1]["events"];
+        assert_eq!(events[0]["action"], "run");
+        assert_eq!(events[0]["phase"], "request_body");
+        assert_eq!(events[0]["input_bytes"], 7);
+        assert!(events[0]["at_us"].is_u64());
+        assert!(events[0].get("name").is_none());
+        assert_eq!(events[1]["action"], "value");
+    }
+
+    #[test]
+    fn value_truncation() {
+        let value = json!({ "message": "hello" });
+        assert_eq!(truncate_value(value.clone(), 100), value);
+        assert_eq!(
+            truncate_value(value, 10),
+            json!(r#"{"message"...[truncated, 19 bytes]"#)
+        );
+        assert_eq!(
+            truncate_value(json!("héllo"), 3),
+            json!(r#""h...[truncated, 8 bytes]"#)
+        );
     }
 }
@@ -1,6 +1,10 @@
 use core::slice::Iter;
 use std::collections::BTreeMap;
 
+/// Name used in the list of inputs of a node for a positional input
+/// that is not connected to any other node.
+pub const UNCONNECTED: &str = "";
+
 #[derive(Default, Clone)]
 pub struct DependencyGraph {
     dependents: BTreeMap<String, Vec<String>>,
@@ -22,12 +26,86 @@ fn add_to(map: &mut BTreeMap<String, Vec<String>>, key: &str, value: &str) {
     };
 }
 
+/// The node of a reference to one of its named outputs, as in `node.output`.
+pub fn node_of(reference: &str) -> &str {
+    reference.split_once('.').map_or(reference, |(n, _)| n)
+}
+
 impl DependencyGraph {
     pub fn add(&mut self, src: &str, dst: &str) {
         add_to(&mut self.dependents, src, dst);
         add_to(&mut self.providers, dst, src);
     }
 
+    /// Adds an input slot to `dst` connected to `src`, even if `src`
+    /// already provides another one, as a node can take the same input
+    /// under several roles.
+    pub fn add_slot(&mut self, src: &str, dst: &str) {
+        add_to(&mut self.dependents, src, dst);
+        self.providers
+            .entry(dst.to_string())
+            .or_default()
+            .push(src.to_string());
+    }
+
+    /// Adds an input slot to `dst` which is not connected to any node,
+    /// so that the following inputs keep their positions.
+    pub fn add_unconnected(&mut self, dst: &str) {
+        match self.providers.get_mut(dst) {
+            Some(items) => items.push(UNCONNECTED.to_string()),
+            None => {
+                self.providers
+                    .insert(dst.to_string(), vec![UNCONNECTED.to_string()]);
+            }
+        }
+    }
+
+    /// Looks for a cycle in the graph, returning the names of the nodes
+    /// along it, starting and ending with the same node. Edges from the
+    /// named outputs of a node, as in `node.output`, start at the node.
+    pub fn find_cycle(&self) -> Option<Vec<String>> {
+        let mut done: Vec<&str> = vec![];
+        for start in self.dependents.keys() {
+            let mut path = vec![];
+            if let Some(cycle) = self.visit(node_of(start), &mut path, &mut done) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    fn visit<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut Vec<&'a str>,
+    ) -> Option<Vec<String>> {
+        if let Some(pos) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[pos..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if done.contains(&name) {
+            return None;
+        }
+
+        path.push(name);
+        let outputs = self
+            .dependents
+            .iter()
+            .filter(|(src, _)| node_of(src) == name)
+            .flat_map(|(_, dsts)| dsts);
+        for dependent in outputs {
+            if let Some(cycle) = self.visit(dependent, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.push(name);
+
+        None
+    }
+
     pub fn has_dependents(&self, name: &str) -> bool {
         self.dependents.contains_key(name)
     }
@@ -36,6 +114,14 @@ impl DependencyGraph {
         self.providers.contains_key(name)
     }
 
+    pub fn get_dependents(&self, name: &str) -> &Vec<String> {
+        if let Some(items) = self.dependents.get(name) {
+            items
+        } else {
+            &self.empty
+        }
+    }
+
     pub fn get_input_names(&self, name: &str) -> &Vec<String> {
         if let Some(items) = self.providers.get(name) {
             items
@@ -44,6 +130,13 @@ impl DependencyGraph {
         }
     }
 
+    /// Iterates over the edges of the graph, from providers to dependents.
+    pub fn edges(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.dependents
+            .iter()
+            .flat_map(|(src, dsts)| dsts.iter().map(move |dst| (src.as_str(), dst.as_str())))
+    }
+
     pub fn each_input(&self, name: &str) -> Iter<String> {
         if let Some(items) = self.providers.get(name) {
             items.iter()
@@ -53,3 +146,58 @@ impl DependencyGraph {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_cycle() {
+        let mut graph = DependencyGraph::default();
+        graph.add("request_body", "A");
+        graph.add("A", "B");
+        graph.add("A", "C");
+        graph.add("B", "C");
+        graph.add("C", "response_body");
+
+        assert_eq!(graph.find_cycle(), None);
+    }
+
+    #[test]
+    fn cycle() {
+        let mut graph = DependencyGraph::default();
+        graph.add("request_body", "A");
+        graph.add("A", "B");
+        graph.add("B", "C");
+        graph.add("C", "A");
+
+        assert_eq!(
+            graph.find_cycle(),
+            Some(
+                vec!["A", "B", "C", "A"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn cycle_through_named_output() {
+        let mut graph = DependencyGraph::default();
+        graph.add("request_body", "A");
+        graph.add("A", "B");
+        graph.add("B.body", "C");
+        graph.add("C.headers", "A");
+
+        assert_eq!(
+            graph.find_cycle(),
+            Some(
+                vec!["A", "B", "C", "A"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+    }
+}
@@ -1,41 +1,96 @@
 use proxy_wasm::{traits::*, types::*};
+use std::collections::{BTreeMap, BTreeSet};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+use url::Url;
 
+pub mod api;
+mod capabilities;
 mod config;
+mod cookies;
 mod data;
 mod debug;
 mod dependency_graph;
+mod graph_view;
+mod migrate;
+mod multipart;
 mod nodes;
+mod otel;
+mod properties;
+mod status;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_host;
 
-use crate::config::Config;
+use crate::config::{
+    BudgetExceeded, Config, HeaderMode, OnFailure, OversizedBody, RunOn, TraceSink,
+};
 use crate::data::{Data, Input, Payload, Phase, Phase::*, State};
-use crate::debug::{Debug, RunMode};
+use crate::debug::{Debug, RunMode, Timing};
 use crate::dependency_graph::DependencyGraph;
-use crate::nodes::{Node, NodeMap};
+use crate::graph_view::GraphFormat;
+use crate::nodes::{Node, NodeList, NodeStates};
 
 // -----------------------------------------------------------------------------
 // Root Context
 // -----------------------------------------------------------------------------
 
 struct DataKitFilterRootContext {
+    context_id: u32,
     config: Option<Rc<Config>>,
+    /// The nodes of the configuration, built once and shared by all requests.
+    nodes: Option<Rc<NodeList>>,
+    /// With `fail_closed`, the id of the error of an invalid configuration.
+    config_error: Option<String>,
 }
 
 impl Context for DataKitFilterRootContext {}
 
+impl DataKitFilterRootContext {
+    /// Resolves a `${...}` placeholder of the configuration: either
+    /// `property:a.b.c`, for a proxy property, or an environment variable.
+    fn lookup(&self, key: &str) -> Option<String> {
+        match key.strip_prefix("property:") {
+            Some(path) => self
+                .get_property(path.split('.').collect())
+                .and_then(|bytes| String::from_utf8(bytes).ok()),
+            None => std::env::var(key).ok(),
+        }
+    }
+}
+
 impl RootContext for DataKitFilterRootContext {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        capabilities::publish(self);
+        true
+    }
+
     fn on_configure(&mut self, _config_size: usize) -> bool {
         match self.get_plugin_configuration() {
-            Some(config_bytes) => match Config::new(config_bytes) {
-                Ok(config) => {
-                    self.config = Some(Rc::new(config));
-                    true
-                }
-                Err(err) => {
-                    log::warn!("on_configure: {err}");
-                    false
+            Some(config_bytes) => {
+                let fail_closed = config::is_fail_closed(&config_bytes);
+                let config = Config::new(config_bytes, &|key| self.lookup(key));
+                match config {
+                    Ok(config) => {
+                        status::publish(self, self.context_id, Ok(()));
+                        self.nodes = Some(Rc::new(config.build_nodes()));
+                        self.config = Some(Rc::new(config));
+                        self.config_error = None;
+                        true
+                    }
+                    Err(err) => {
+                        let error_id = status::error_id(&err);
+                        log::warn!("on_configure: error {error_id}: {err}");
+                        status::publish(self, self.context_id, Err(&err));
+                        self.config = None;
+                        self.nodes = None;
+                        if fail_closed {
+                            // keep the filter, to reject requests
+                            self.config_error = Some(error_id);
+                        }
+                        fail_closed
+                    }
                 }
-            },
+            }
             None => {
                 log::warn!("on_configure: failed getting configuration");
                 false
@@ -50,61 +105,240 @@ impl RootContext for DataKitFilterRootContext {
     fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
         log::debug!("DataKitFilterRootContext: create http context id: {context_id}");
 
+        if let Some(error_id) = &self.config_error {
+            return Some(Box::new(ConfigErrorFilter {
+                error_id: error_id.clone(),
+            }));
+        }
+
         let config = self.config.clone()?;
+        let nodes = self.nodes.clone()?;
 
-        let nodes = config.build_nodes();
+        let states = nodes::new_states(&nodes);
         let graph = config.get_graph();
         let debug = config.debug().then(|| Debug::new(&config));
+        let data = Data::new(config.shared_graph());
 
-        // FIXME: is it possible to do lifetime annotations
-        // to avoid cloning every time?
-        let data = Data::new(graph.clone());
-
+        let do_client_info = graph.has_dependents("client_info");
+        let do_kong_context = graph.has_dependents("kong_context");
         let do_request_headers = graph.has_dependents("request_headers");
         let do_request_body = graph.has_dependents("request_body");
+        let do_request_method = graph.has_dependents("request_method");
+        let do_request_path = graph.has_dependents("request_path");
+        let do_request_query = graph.has_dependents("request_query");
+        let do_request_cookies = graph.has_dependents("request_cookies");
+        let do_request_scheme = graph.has_dependents("request_scheme");
+        let do_request_trailers = graph.has_dependents("request_trailers");
+        let do_route_params = graph.has_dependents("route_params");
         let do_service_request_headers = graph.has_providers("service_request_headers");
         let do_service_request_body = graph.has_providers("service_request_body");
+        let do_service_request_method = graph.has_providers("service_request_method");
+        let do_service_request_path = graph.has_providers("service_request_path");
+        let do_service_request_query = graph.has_providers("service_request_query");
         let do_service_response_headers = graph.has_dependents("service_response_headers");
         let do_service_response_body = graph.has_dependents("service_response_body");
+        let do_service_response_status = graph.has_dependents("service_response_status");
+        let do_service_response_trailers = graph.has_dependents("service_response_trailers");
         let do_response_headers = graph.has_providers("response_headers");
         let do_response_body = graph.has_providers("response_body");
+        let do_response_status = graph.has_providers("response_status");
+        let do_response_trailers = graph.has_providers("response_trailers");
+        let do_response_cookies = graph.has_providers("response_cookies");
+        let streaming = config.streaming_chain().is_some();
 
         Some(Box::new(DataKitFilter {
+            context_id,
             config,
+            ready: (0..nodes.len()).collect(),
             nodes,
+            states,
             debug,
+            spent: Duration::ZERO,
+            dispatched: BTreeMap::new(),
+            executions: 0,
+            aborted: false,
+            dry_run: false,
+            tracer: None,
+            ignored_calls: vec![],
             data,
             failed: false,
+            responded: false,
+            streaming,
+            on_response: false,
+            header_case: BTreeMap::new(),
+            request_encoding: None,
+            service_response_encoding: None,
+            compress_response: false,
+            do_client_info,
+            do_kong_context,
             do_request_headers,
             do_request_body,
+            do_request_method,
+            do_request_path,
+            do_request_query,
+            do_request_cookies,
+            do_request_scheme,
+            do_request_trailers,
+            do_route_params,
             do_service_request_headers,
             do_service_request_body,
+            do_service_request_method,
+            do_service_request_path,
+            do_service_request_query,
             do_service_response_headers,
             do_service_response_body,
+            do_service_response_status,
+            do_service_response_trailers,
             do_response_headers,
             do_response_body,
+            do_response_status,
+            do_response_trailers,
+            do_response_cookies,
         }))
     }
 }
 
+// -----------------------------------------------------------------------------
+// Configuration Error Context
+// -----------------------------------------------------------------------------
+
+/// With `fail_closed`, requests are rejected while the configuration is
+/// invalid, instead of being proxied as if the filter was not there.
+struct ConfigErrorFilter {
+    error_id: String,
+}
+
+impl Context for ConfigErrorFilter {}
+
+impl HttpContext for ConfigErrorFilter {
+    fn on_http_request_headers(&mut self, _nheaders: usize, _eof: bool) -> Action {
+        let message = format!("invalid DataKit configuration (error {})", self.error_id);
+        let request_id = self.get_property(vec!["ngx", "kong_request_id"]);
+        let body = data::to_json_error_body(&message, request_id);
+        self.send_http_response(
+            500,
+            vec![("Content-Type", "application/json")],
+            Some(body.as_bytes()),
+        );
+        Action::Pause
+    }
+}
+
 // -----------------------------------------------------------------------------
 // Filter Context
 // -----------------------------------------------------------------------------
 
 pub struct DataKitFilter {
+    context_id: u32,
     config: Rc<Config>,
-    nodes: NodeMap,
+    nodes: Rc<NodeList>,
+    states: NodeStates,
+    /// The positions, in the execution order, of the nodes to visit.
+    ready: BTreeSet<usize>,
     data: Data,
     debug: Option<Debug>,
+    /// The time spent running nodes and waiting for their calls,
+    /// which counts against `max_total_call_time_ms`.
+    spent: Duration,
+    /// When each call in progress was dispatched, by token.
+    dispatched: BTreeMap<u32, Instant>,
+    executions: usize,
+    aborted: bool,
+    dry_run: bool,
+    tracer: Option<otel::Tracer>,
+    ignored_calls: Vec<u32>,
     failed: bool,
+    responded: bool,
+    streaming: bool,
+    on_response: bool,
+    header_case: BTreeMap<String, String>,
+    request_encoding: Option<String>,
+    service_response_encoding: Option<String>,
+    compress_response: bool,
+    do_client_info: bool,
+    do_kong_context: bool,
     do_request_headers: bool,
     do_request_body: bool,
+    do_request_method: bool,
+    do_request_path: bool,
+    do_request_query: bool,
+    do_request_cookies: bool,
+    do_request_scheme: bool,
+    do_request_trailers: bool,
+    do_route_params: bool,
     do_service_request_headers: bool,
     do_service_request_body: bool,
+    do_service_request_method: bool,
+    do_service_request_path: bool,
+    do_service_request_query: bool,
     do_service_response_headers: bool,
     do_service_response_body: bool,
+    do_service_response_status: bool,
+    do_service_response_trailers: bool,
     do_response_headers: bool,
     do_response_body: bool,
+    do_response_status: bool,
+    do_response_trailers: bool,
+    do_response_cookies: bool,
+}
+
+/// Applies headers to the request or response according to the
+/// configured mode, using the given functions to modify them. Only the
+/// headers whose values differ from the `current` ones are modified.
+fn apply_headers(
+    headers: Vec<(&str, &str)>,
+    removed: Vec<&str>,
+    mode: HeaderMode,
+    current: Vec<(String, String)>,
+    set: impl Fn(&str, Option<&str>),
+    add: impl Fn(&str, &str),
+) {
+    let current_values = |name: &str| -> Vec<&str> {
+        current
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    };
+    let is_given = |name: &str| headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+
+    let mut removed: Vec<&str> = match mode {
+        // pseudo-headers, such as `:path`, are managed by the proxy
+        HeaderMode::Replace => current
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .filter(|&n| !n.starts_with(':') && !is_given(n))
+            .collect(),
+        HeaderMode::Patch => removed,
+        HeaderMode::Merge => vec![],
+    };
+    removed.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+    for name in removed {
+        if !current_values(name).is_empty() {
+            set(name, None);
+        }
+    }
+
+    let mut seen: Vec<&str> = vec![];
+    for &(name, _) in &headers {
+        if seen.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        seen.push(name);
+
+        let values: Vec<&str> = headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+            .collect();
+        if values == current_values(name) {
+            continue;
+        }
+        set(name, Some(values[0]));
+        for &value in &values[1..] {
+            add(name, value);
+        }
+    }
 }
 
 fn header_to_bool(header_value: &Option<String>) -> bool {
@@ -117,19 +351,143 @@ fn header_to_bool(header_value: &Option<String>) -> bool {
 impl DataKitFilter {
     fn debug_init(&mut self) {
         let trace_header = &self.get_http_request_header("X-DataKit-Debug-Trace");
-        if header_to_bool(trace_header) {
+        if !header_to_bool(trace_header) {
+            return;
+        }
+
+        let (options, token) =
+            debug::parse_trace_header(trace_header.as_deref().unwrap_or_default());
+        if !self.is_trace_allowed(token) {
+            log::debug!("DataKitFilter: debug trace not allowed for this request");
+            return;
+        }
+
+        let rate = self.config.debug_trace_sample_rate();
+        if rate < 1.0 {
+            let mut seed = self.random_seed();
+            if (otel::next_id(&mut seed) as f64) >= rate * (u64::MAX as f64) {
+                return;
+            }
+        }
+
+        if *self.config.trace_sink() != TraceSink::Body {
+            // the trace is sent elsewhere, leaving the response untouched
+            let trace_id = self
+                .get_property(vec!["ngx", "kong_request_id"])
+                .and_then(|id| String::from_utf8(id).ok())
+                .unwrap_or_else(|| self.context_id.to_string());
             if let Some(ref mut debug) = self.debug {
                 debug.set_tracing(true);
+                debug.set_v2(options.v2);
+                debug.set_trace_id(trace_id);
             }
-            self.do_response_body = true;
+            return;
         }
+
+        if let Some(ref mut debug) = self.debug {
+            debug.set_tracing(true);
+            debug.set_wrapping(options.wrap);
+            debug.set_v2(options.v2);
+        }
+        // tracing needs the whole body
+        self.streaming = false;
+        self.do_response_body = true;
+    }
+
+    /// When a token or client ranges are configured, tracing is only enabled
+    /// for requests that give the token or come from one of the ranges.
+    fn is_trace_allowed(&self, token: &str) -> bool {
+        let expected = self.config.debug_trace_token();
+        let clients = self.config.debug_trace_clients();
+        if expected.is_none() && clients.is_empty() {
+            return true;
+        }
+
+        if expected.is_some_and(|expected| debug::tokens_match(token, expected)) {
+            return true;
+        }
+        properties::client_address(self)
+            .is_some_and(|ip| clients.iter().any(|range| range.contains(&ip)))
+    }
+
+    /// Responds with a rendering of the dependency graph, when requested
+    /// with the `X-DataKit-Debug-Graph` header, returning `true` if so.
+    fn debug_graph(&mut self) -> bool {
+        let Some(value) = self.get_http_request_header("X-DataKit-Debug-Graph") else {
+            return false;
+        };
+        let (format, token) = value.split_once(':').unwrap_or((value.as_str(), ""));
+        if !self.is_trace_allowed(token) {
+            log::debug!("DataKitFilter: debug graph not allowed for this request");
+            return false;
+        }
+
+        match GraphFormat::parse(format) {
+            Some(format) => {
+                let body = graph_view::render(&self.config, format);
+                self.send_http_response(
+                    200,
+                    vec![("Content-Type", format.content_type())],
+                    Some(body.as_bytes()),
+                );
+            }
+            None => self.send_error_response(
+                400,
+                &format!("X-DataKit-Debug-Graph: unknown format '{format}'"),
+            ),
+        }
+        self.responded = true;
+        true
+    }
+
+    /// Whether the request asks for a dry run with `X-DataKit-Dry-Run`,
+    /// which is allowed to the same clients as tracing.
+    fn is_dry_run_requested(&self) -> bool {
+        let value = self.get_http_request_header("X-DataKit-Dry-Run");
+        header_to_bool(&value) && self.is_trace_allowed(value.as_deref().unwrap_or_default())
+    }
+
+    /// Ends a dry run by responding with the outcome of the nodes that ran,
+    /// instead of proxying the request.
+    fn send_dry_run_report(&mut self) {
+        let report = debug::dry_run_report(&self.config, &self.data).to_string();
+        self.send_http_response(
+            200,
+            vec![("Content-Type", "application/json")],
+            Some(report.as_bytes()),
+        );
+        self.responded = true;
+    }
+
+    fn debug_phase(&mut self, phase: Phase) {
+        if let Some(ref mut debug) = self.debug {
+            debug.phase(phase);
+        }
+    }
+
+    /// Whether the trace being recorded replaces the response body.
+    fn is_tracing_to_body(&self) -> bool {
+        self.debug.as_ref().is_some_and(|d| d.is_tracing())
+            && *self.config.trace_sink() == TraceSink::Body
     }
 
     fn debug_done_headers(&mut self) {
+        if let TraceSink::Header { .. } = self.config.trace_sink() {
+            let trace_id = self.debug.as_ref().and_then(|d| d.trace_id());
+            if let Some(trace_id) = trace_id {
+                self.set_http_response_header("X-DataKit-Trace-Id", Some(trace_id));
+            }
+        }
+        if !self.is_tracing_to_body() {
+            return;
+        }
+
         let ct = self.get_http_response_header("Content-Type");
+        let status = self.get_http_response_header(":status");
         if let Some(ref mut debug) = self.debug {
             if debug.is_tracing() {
                 debug.save_response_body_content_type(ct);
+                debug.save_response_status(status.and_then(|s| s.parse().ok()));
                 self.set_http_response_header("Content-Type", Some("application/json"));
                 self.set_http_response_header("Content-Length", None);
                 self.set_http_response_header("Content-Encoding", None);
@@ -137,28 +495,269 @@ impl DataKitFilter {
         }
     }
 
-    fn debug_done(&mut self) {
+    fn debug_response_body(&self, body_size: usize) -> Option<serde_json::Value> {
+        if let Some(payload) = self.data.first_input_for("response_body", None) {
+            return payload.to_json().ok();
+        }
+
+        let bytes = self.get_http_response_body(0, body_size)?;
+        let content_type = self.debug.as_ref()?.response_body_content_type();
+        let payload = match content_type.as_deref() {
+            Some(ct) => Payload::from_bytes(bytes, Some(ct)),
+            None => Some(Payload::Raw(bytes.into(), None)),
+        };
+
+        payload.and_then(|p| p.to_json().ok())
+    }
+
+    /// Sends the trace of a request to the configured sink, when it is not
+    /// the response body.
+    fn debug_send_trace(&mut self) {
+        let Some(debug) = self.debug.as_ref().filter(|d| d.is_tracing()) else {
+            return;
+        };
+
+        let config = self.config.clone();
+        match config.trace_sink() {
+            TraceSink::Body => {}
+            TraceSink::Log { level } | TraceSink::Header { level } => {
+                log::log!((*level).into(), "trace: {}", debug.get_trace_record());
+            }
+            TraceSink::Collector { url, timeout } => {
+                let record = debug.get_trace_record();
+                self.post_json("trace collector", url, record.as_bytes(), *timeout);
+            }
+        }
+    }
+
+    /// Starts recording spans for node runs, when OpenTelemetry is enabled.
+    fn otel_init(&mut self) {
+        if self.config.otel().is_none() {
+            return;
+        }
+
+        let traceparent = self.get_http_request_header("traceparent");
+        let seed = self.random_seed();
+        self.tracer = Some(otel::Tracer::new(traceparent.as_deref(), seed));
+    }
+
+    /// A seed for random numbers, which differs between requests.
+    fn random_seed(&self) -> u64 {
+        let nanos = self
+            .get_current_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        nanos ^ (u64::from(self.context_id) << 32)
+    }
+
+    /// Exports the spans recorded for the request to the OTLP endpoint.
+    fn otel_export(&mut self) {
+        let config = self.config.clone();
+        let (Some(otel), Some(tracer)) = (config.otel(), self.tracer.as_ref()) else {
+            return;
+        };
+        if !tracer.is_sampled() || tracer.is_empty() {
+            return;
+        }
+
+        let body = tracer.to_otlp(&otel.service_name).to_string();
+        self.post_json("otel", &otel.endpoint, body.as_bytes(), otel.timeout);
+    }
+
+    /// Sends a JSON body in a `POST` request whose response is ignored.
+    fn post_json(&mut self, what: &str, url: &str, body: &[u8], timeout: u32) {
+        let call_url = match Url::parse(url) {
+            Ok(u) => u,
+            Err(e) => {
+                log::error!("{what}: invalid URL: {e}");
+                return;
+            }
+        };
+        let Some(host) = call_url.host_str() else {
+            log::error!("{what}: URL without host");
+            return;
+        };
+        let host_port = match call_url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_owned(),
+        };
+        let path = match call_url.query() {
+            Some(query) => format!("{}?{query}", call_url.path()),
+            None => call_url.path().to_string(),
+        };
+        let headers = vec![
+            (":method", "POST"),
+            (":path", path.as_str()),
+            ("Content-Type", "application/json"),
+        ];
+        let timeout = Duration::from_secs(timeout.into());
+        match self.dispatch_http_call(&host_port, headers, Some(body), vec![], timeout) {
+            Ok(token_id) => self.ignored_calls.push(token_id),
+            Err(status) => log::error!("{what}: dispatch failed: {status:?}"),
+        }
+    }
+
+    fn debug_done(&mut self, body_size: usize) {
+        if !self.is_tracing_to_body() {
+            return;
+        }
+
+        let is_wrapping = self.debug.as_ref().is_some_and(|d| d.is_wrapping());
+        let response = if is_wrapping {
+            self.debug_response_body(body_size)
+        } else {
+            None
+        };
+
         if let Some(ref mut debug) = self.debug {
             if debug.is_tracing() {
-                let trace = debug.get_trace();
+                let trace = if debug.is_wrapping() {
+                    debug.get_trace_envelope(response)
+                } else {
+                    debug.get_trace()
+                };
                 let bytes = trace.as_bytes();
                 self.set_http_response_body(0, bytes.len(), bytes);
             }
         }
     }
 
-    fn send_default_fail_response(&self) {
-        let body = data::to_json_error_body(
-            "An unexpected error ocurred",
-            self.get_property(vec!["ngx", "kong_request_id"]),
-        );
+    fn send_error_response(&self, status: u32, message: &str) {
+        let body =
+            data::to_json_error_body(message, self.get_property(vec!["ngx", "kong_request_id"]));
         self.send_http_response(
-            500,
+            status,
             vec![("Content-Type", "application/json")],
             Some(&body.into_bytes()),
         );
     }
 
+    /// Queues the nodes depending on the payloads set since the last call,
+    /// to visit them in `run_nodes`.
+    fn queue_dependents(&mut self) {
+        for name in self.data.take_updated() {
+            self.ready.extend(self.config.dependents(&name));
+        }
+    }
+
+    /// The response body is only buffered when it is read or replaced,
+    /// or when the trace is written to it: otherwise its chunks are passed
+    /// through as they are received.
+    fn needs_response_body(&self) -> bool {
+        self.do_service_response_body || self.do_response_body || self.is_tracing_to_body()
+    }
+
+    /// Parses a body for the given implicit node, unless it is only
+    /// passed through to other implicit nodes.
+    fn body_payload(
+        &self,
+        name: &str,
+        bytes: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Option<Payload> {
+        if self.config.is_passthrough(name) {
+            Payload::unparsed(bytes, content_type)
+        } else {
+            Payload::from_bytes(bytes, content_type)
+        }
+    }
+
+    fn send_fail_response(&self, status: u32) {
+        self.send_error_response(status, "An unexpected error ocurred");
+    }
+
+    /// Applies the `on_failure` policy of a node after it runs or resumes,
+    /// or the given default one, returning the state to record for it.
+    fn on_node_failure(&mut self, name: &str, state: State, default: OnFailure) -> State {
+        if !matches!(state, State::Fail(_)) {
+            return state;
+        }
+
+        match self.config.on_failure(name).unwrap_or(default) {
+            OnFailure::Continue => {
+                if let Some(roles) = self.config.output_roles(name) {
+                    self.data.set_entries(name, roles, None);
+                }
+                State::Done(None)
+            }
+            OnFailure::SkipDownstream => state,
+            OnFailure::Respond(status) => {
+                self.failed = true;
+                if !self.is_tracing_to_body() {
+                    match (&state, self.config.failure_status(name)) {
+                        (State::Fail(Some(Payload::Json(value))), Some(_)) => self
+                            .send_http_response(
+                                status,
+                                vec![("Content-Type", "application/json")],
+                                Some(value.to_string().as_bytes()),
+                            ),
+                        _ => self.send_fail_response(status),
+                    }
+                    self.responded = true;
+                }
+                state
+            }
+        }
+    }
+
+    /// Applies `max_request_body_bytes` to a request body of the given size,
+    /// returning `true` if the request was rejected.
+    fn limit_request_body(&mut self, size: usize) -> bool {
+        if self
+            .config
+            .max_request_body_bytes()
+            .is_none_or(|max| size <= max)
+        {
+            return false;
+        }
+
+        match self.config.oversized_body() {
+            OversizedBody::Passthrough => {
+                self.do_request_body = false;
+                false
+            }
+            OversizedBody::Reject => {
+                self.send_error_response(413, "Request body too large");
+                self.responded = true;
+                true
+            }
+        }
+    }
+
+    /// Applies `max_response_body_bytes` to a service response body of the
+    /// given size. Once the response headers have been sent, the body can
+    /// no longer be rejected with an error, so it is dropped instead.
+    fn limit_response_body(&mut self, size: usize, headers_sent: bool) -> bool {
+        if self
+            .config
+            .max_response_body_bytes()
+            .is_none_or(|max| size <= max)
+        {
+            return false;
+        }
+
+        match self.config.oversized_body() {
+            OversizedBody::Passthrough => {
+                self.do_service_response_body = false;
+                self.do_response_body = false;
+                self.streaming = false;
+                false
+            }
+            OversizedBody::Reject if !headers_sent => {
+                self.send_error_response(502, "Service response body too large");
+                self.responded = true;
+                true
+            }
+            OversizedBody::Reject => {
+                if !self.failed {
+                    log::error!("service response body too large, dropping it");
+                    self.failed = true;
+                }
+                true
+            }
+        }
+    }
+
     fn set_data(&mut self, name: &str, state: State) {
         if let Some(ref mut debug) = self.debug {
             debug.set_data(name, &state);
@@ -171,54 +770,239 @@ impl DataKitFilter {
         self.set_data(name, State::Done(Some(payload)));
     }
 
-    fn run_nodes(&mut self, phase: Phase) -> Action {
-        let mut ret = Action::Continue;
+    fn set_pseudo_header_data(&mut self, header: &str, name: &str) {
+        let value = self.get_http_request_header(header).unwrap_or_default();
+        let payload = Payload::Json(serde_json::Value::String(value));
+        self.set_data(name, State::Done(Some(payload)));
+    }
 
-        let mut debug_is_tracing = false;
-        if let Some(ref mut debug) = self.debug {
-            debug_is_tracing = debug.is_tracing();
-        }
-
-        while !self.failed {
-            let mut any_ran = false;
-            for name in self.config.get_node_names() {
-                let node: &dyn Node = self
-                    .nodes
-                    .get(name)
-                    .expect("self.nodes doesn't match self.node_names")
-                    .as_ref();
-                if let Some(inputs) = self.data.get_inputs_for(name, None) {
-                    any_ran = true;
-
-                    let input = Input {
-                        data: &inputs,
-                        phase,
-                    };
-                    let state = node.run(self as &dyn HttpContext, &input);
-
-                    if let Some(ref mut debug) = self.debug {
-                        debug.run(name, &inputs, &state, RunMode::Run);
-                    }
+    fn set_pseudo_header(&self, header: &str, name: &str) {
+        if let Some(payload) = self.data.first_input_for(name, None) {
+            match payload.to_plain_string() {
+                Ok(value) => self.set_http_request_header(header, Some(&value)),
+                Err(e) => log::warn!("{name}: {e}"),
+            }
+        }
+    }
 
-                    match state {
-                        State::Done(_) => {}
-                        State::Waiting(_) => {
-                            ret = Action::Pause;
-                        }
-                        State::Fail(_) => {
-                            self.failed = true;
-                            if !debug_is_tracing {
-                                self.send_default_fail_response();
-                            }
-                        }
+    /// In streaming mode, passes each chunk of the service response body
+    /// through the chain of streaming nodes as it is received.
+    fn stream_response_body(&mut self, body_size: usize, eof: bool) -> Action {
+        let config = self.config.clone();
+        let chain = config.streaming_chain().unwrap_or_default();
+
+        if self.failed {
+            self.set_http_response_body(0, body_size, &[]);
+            return Action::Continue;
+        }
+
+        let mut bytes = self
+            .get_http_response_body(0, body_size)
+            .unwrap_or_default();
+        for name in chain {
+            let i = config
+                .node_index(name)
+                .expect("streaming chain has configured nodes");
+            let node = &self.nodes[i];
+            let node_state = self.states[i].as_deref();
+            match node.stream_chunk(node_state, &bytes, eof) {
+                Ok(out) => bytes = out,
+                Err(e) => {
+                    // headers were already sent, so the body is cut short
+                    log::error!("node '{name}': {e}");
+                    self.failed = true;
+                    bytes = vec![];
+                    break;
+                }
+            }
+        }
+
+        self.set_http_response_body(0, body_size, &bytes);
+        Action::Continue
+    }
+
+    /// Applies the implicit nodes that modify the headers of the request
+    /// sent to the service. This happens in the request headers phase, as
+    /// requests without a body skip the body phase, and again in the body
+    /// phase for nodes that depend on the request body.
+    fn set_service_request_headers(&self) {
+        if self.do_service_request_headers {
+            if let Some(payload) = self.data.first_input_for("service_request_headers", None) {
+                apply_headers(
+                    data::to_pwm_headers(Some(payload)),
+                    data::null_header_names(payload),
+                    self.config.header_mode("service_request_headers"),
+                    self.get_http_request_headers(),
+                    |name, value| self.set_http_request_header(name, value),
+                    |name, value| self.add_http_request_header(name, value),
+                );
+            }
+        }
+
+        if self.do_service_request_method {
+            self.set_pseudo_header(":method", "service_request_method");
+        }
+
+        if self.do_service_request_path {
+            if let Some(payload) = self.data.first_input_for("service_request_path", None) {
+                let path = self.get_http_request_header(":path").unwrap_or_default();
+                match payload.to_plain_string() {
+                    Ok(new_path) => {
+                        let new_path = data::replace_path(&path, &new_path);
+                        self.set_http_request_header(":path", Some(&new_path));
                     }
+                    Err(e) => log::warn!("service_request_path: {e}"),
+                }
+            }
+        }
 
-                    self.data.set(name, state);
+        if self.do_service_request_query {
+            if let Some(payload) = self.data.first_input_for("service_request_query", None) {
+                let path = self.get_http_request_header(":path").unwrap_or_default();
+                match data::replace_query_string(&path, payload) {
+                    Ok(new_path) => self.set_http_request_header(":path", Some(&new_path)),
+                    Err(e) => log::warn!("service_request_query: {e}"),
                 }
             }
-            if !any_ran {
+        }
+    }
+
+    /// Checks `max_total_call_time_ms` and `max_node_executions` before
+    /// a node runs or resumes, returning the limit that was exceeded.
+    fn exceeded_budget(&self) -> Option<String> {
+        if let Some(max) = self.config.max_total_call_time() {
+            if self.spent > max {
+                return Some(format!("exceeded {} ms", max.as_millis()));
+            }
+        }
+        if let Some(max) = self.config.max_node_executions() {
+            if self.executions >= max {
+                return Some(format!("exceeded {max} node executions"));
+            }
+        }
+        None
+    }
+
+    /// Stops running nodes once the request exceeds its budget. Nodes that
+    /// have not completed are marked as failed, and the outputs of those
+    /// that have are dropped, so that the request goes on unchanged, unless
+    /// it is rejected.
+    fn abort(&mut self, reason: &str) {
+        log::warn!("DataKitFilter: {reason}, skipping the remaining nodes");
+        self.aborted = true;
+
+        let error = format!("aborted: {reason}");
+        self.data.fail_all(self.config.get_node_names(), &error);
+
+        if self.config.on_budget_exceeded() == BudgetExceeded::Reject {
+            self.send_error_response(503, "Request processing budget exceeded");
+            self.responded = true;
+        }
+    }
+
+    fn run_nodes(&mut self, phase: Phase) -> Action {
+        let mut ret = Action::Continue;
+
+        // HTTP call responses happen during the request or the response
+        let stage = phase.run_on().unwrap_or(if self.on_response {
+            RunOn::Response
+        } else {
+            RunOn::Request
+        });
+
+        let config = self.config.clone();
+        let names = config.get_node_names();
+        let order = config.execution_order();
+        let mut exceeded = None;
+
+        // nodes are visited in execution order, first all of them, and then
+        // only when some of their inputs are set; once a node has sent a
+        // response, the others are skipped, so that no further calls are
+        // dispatched
+        while !self.failed && !self.responded && !self.aborted {
+            self.queue_dependents();
+            let next = self.ready.iter().copied().find(|&pos| {
+                !config
+                    .run_on(&names[order[pos]])
+                    .is_some_and(|r| r != stage)
+            });
+            let Some(pos) = next else {
                 break;
+            };
+            self.ready.remove(&pos);
+
+            let i = order[pos];
+            let name = &names[i];
+            let node: &dyn Node = self.nodes[i].as_ref();
+            let Some(inputs) = self.data.get_inputs_for(name, None) else {
+                continue;
+            };
+            exceeded = self.exceeded_budget();
+            if exceeded.is_some() {
+                break;
+            }
+            self.executions += 1;
+
+            let node_state = self.states[i].as_deref();
+            let input = Input {
+                data: &inputs,
+                phase,
+                token_id: None,
+                dry_run: self.dry_run,
+                state: node_state,
+            };
+            let started = Instant::now();
+            let state = node.run(self as &dyn HttpContext, &input);
+            let duration = started.elapsed();
+            self.spent += duration;
+
+            if self.tracer.is_some() {
+                let start = self.get_current_time() - duration;
+                let node_type = config.node_type(name).unwrap_or_default();
+                if let Some(ref mut tracer) = self.tracer {
+                    tracer.record(name, node_type, start, duration, &state);
+                }
+            }
+
+            if let Some(ref mut debug) = self.debug {
+                let timing = Timing::new(started, None);
+                debug.run(name, &inputs, &state, RunMode::Run, timing);
+            }
+
+            match &state {
+                State::Done(p) => {
+                    if node.has_responded(node_state) {
+                        self.responded = true;
+                    }
+                    if let Some(output) = node.active_output(node_state) {
+                        let port = format!("{name}.{output}");
+                        self.data.set(&port, State::Done(p.clone()));
+                    }
+                    if let Some(roles) = config.output_roles(name) {
+                        self.data.set_entries(name, roles, p.as_ref());
+                    }
+                }
+                State::Waiting(token_id) => {
+                    self.dispatched.insert(*token_id, Instant::now());
+                    ret = Action::Pause;
+                }
+                State::Fail(_) => {}
             }
+
+            // without a policy, failing to run stops the request
+            let status = config.failure_status(name).unwrap_or(500);
+            let state = self.on_node_failure(name, state, OnFailure::Respond(status));
+            self.data.set(name, state);
+        }
+        if let Some(reason) = exceeded {
+            self.abort(&reason);
+        }
+
+        if self.responded {
+            return Action::Pause;
+        }
+        if self.aborted {
+            return Action::Continue;
         }
 
         ret
@@ -235,69 +1019,231 @@ impl Context for DataKitFilter {
     ) {
         log::debug!("DataKitFilter: on http call response, id = {:?}", token_id);
 
-        for name in self.config.get_node_names() {
-            let node: &dyn Node = self
-                .nodes
-                .get(name)
-                .expect("self.nodes doesn't match self.node_names")
-                .as_ref();
+        if self.responded || self.aborted || self.ignored_calls.contains(&token_id) {
+            // calls dispatched before a response was sent, or before the
+            // nodes were aborted, have no effect, and neither do the
+            // responses of trace and span exports
+            return;
+        }
+
+        self.debug_phase(HttpCallResponse);
+
+        if let Some(dispatched) = self.dispatched.remove(&token_id) {
+            self.spent += dispatched.elapsed();
+        }
+
+        let mut exceeded = None;
+        let config = self.config.clone();
+        for &i in config.execution_order() {
+            let name = &config.get_node_names()[i];
+            let node: &dyn Node = self.nodes[i].as_ref();
+            let node_state = self.states[i].as_deref();
+            if node.is_waiting_on(node_state, token_id) {
+                self.data.set(name, State::Waiting(token_id));
+            }
             if let Some(inputs) = self.data.get_inputs_for(name, Some(token_id)) {
+                exceeded = self.exceeded_budget();
+                if exceeded.is_some() {
+                    break;
+                }
+                self.executions += 1;
+
                 let input = Input {
                     data: &inputs,
                     phase: HttpCallResponse,
+                    token_id: Some(token_id),
+                    dry_run: self.dry_run,
+                    state: node_state,
                 };
+                let started = Instant::now();
                 let state = node.resume(self, &input);
+                let duration = started.elapsed();
+                self.spent += duration;
+                if let State::Waiting(next) = state {
+                    self.dispatched.insert(next, Instant::now());
+                }
+
+                if self.tracer.is_some() {
+                    let start = self.get_current_time() - duration;
+                    let node_type = self.config.node_type(name).unwrap_or_default();
+                    if let Some(ref mut tracer) = self.tracer {
+                        tracer.record(name, node_type, start, duration, &state);
+                    }
+                }
 
                 if let Some(ref mut debug) = self.debug {
-                    debug.run(name, &inputs, &state, RunMode::Resume);
+                    let timing = Timing::new(started, Some(token_id));
+                    debug.run(name, &inputs, &state, RunMode::Resume, timing);
                 }
 
+                if let (State::Done(p), Some(roles)) = (&state, self.config.output_roles(name)) {
+                    self.data.set_entries(name, roles, p.as_ref());
+                }
+                // without a policy, failing to resume only stops the
+                // nodes depending on the node
+                let state = self.on_node_failure(name, state, OnFailure::SkipDownstream);
                 self.data.set(name, state);
                 break;
             }
         }
 
-        self.run_nodes(HttpCallResponse);
+        match exceeded {
+            Some(reason) => self.abort(&reason),
+            None => {
+                self.run_nodes(HttpCallResponse);
+            }
+        }
 
-        self.resume_http_request();
+        // the request goes on once the last call in progress has completed
+        if !self.responded && !self.data.is_waiting() {
+            self.resume_http_request();
+        }
     }
 }
 
 impl HttpContext for DataKitFilter {
-    fn on_http_request_headers(&mut self, _nheaders: usize, _eof: bool) -> Action {
+    fn on_http_request_headers(&mut self, _nheaders: usize, eof: bool) -> Action {
         if self.debug.is_some() {
             self.debug_init()
         }
+        self.debug_phase(HttpRequestHeaders);
+
+        if self.config.debug() && self.debug_graph() {
+            return Action::Pause;
+        }
+
+        self.dry_run =
+            self.config.dry_run() || (self.config.debug() && self.is_dry_run_requested());
+
+        self.otel_init();
+
+        let content_length = self.get_http_request_header("Content-Length");
+        if let Some(size) = content_length.and_then(|s| s.parse().ok()) {
+            if self.limit_request_body(size) {
+                return Action::Pause;
+            }
+        }
+
+        if self.do_client_info {
+            let payload = properties::client_info(self);
+            self.set_data("client_info", State::Done(Some(payload)));
+        }
+
+        if self.do_kong_context {
+            let payload = properties::kong_context(self);
+            self.set_data("kong_context", State::Done(Some(payload)));
+        }
 
         if self.do_request_headers {
             let vec = self.get_http_request_headers();
             self.set_headers_data(vec, "request_headers");
         }
 
-        self.run_nodes(HttpRequestHeaders)
+        if self.do_request_body {
+            self.request_encoding = self.get_http_request_header("Content-Encoding");
+        }
+
+        if self.do_response_body && self.config.compress_response() {
+            let accept_encoding = self.get_http_request_header("Accept-Encoding");
+            self.compress_response = data::accepts_gzip(accept_encoding.as_deref());
+        }
+
+        if self.do_request_method {
+            self.set_pseudo_header_data(":method", "request_method");
+        }
+
+        if self.do_request_path {
+            let path = self.get_http_request_header(":path").unwrap_or_default();
+            let payload = Payload::Json(data::path_without_query(&path).into());
+            self.set_data("request_path", State::Done(Some(payload)));
+        }
+
+        if self.do_request_scheme {
+            self.set_pseudo_header_data(":scheme", "request_scheme");
+        }
+
+        if self.do_request_query {
+            let path = self.get_http_request_header(":path");
+            let payload = data::from_query_string(path.as_deref());
+            self.set_data("request_query", State::Done(Some(payload)));
+        }
+
+        if self.do_request_cookies {
+            let headers = self.get_http_request_headers();
+            let values: Vec<&str> = headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+                .map(|(_, v)| v.as_str())
+                .collect();
+            let payload = Payload::Json(cookies::parse(&values));
+            self.set_data("request_cookies", State::Done(Some(payload)));
+        }
+
+        if self.do_route_params {
+            let bytes = self.get_property(vec!["kong", "router", "uri_captures"]);
+            let payload = data::from_uri_captures(bytes);
+            self.set_data("route_params", State::Done(Some(payload)));
+        }
+
+        let action = self.run_nodes(HttpRequestHeaders);
+
+        if self.responded {
+            // a response was sent from the request headers:
+            // stop here, without waiting for the request body.
+            return Action::Pause;
+        }
+
+        if self.dry_run && (eof || !self.do_request_body) {
+            self.send_dry_run_report();
+            return Action::Pause;
+        }
+
+        self.set_service_request_headers();
+
+        action
     }
 
     fn on_http_request_body(&mut self, body_size: usize, eof: bool) -> Action {
+        if self.responded || self.limit_request_body(body_size) {
+            return Action::Pause;
+        }
+        self.debug_phase(HttpRequestBody);
+
         if eof && self.do_request_body {
             if let Some(bytes) = self.get_http_request_body(0, body_size) {
                 let content_type = self.get_http_request_header("Content-Type");
-                let body_payload = Payload::from_bytes(bytes, content_type.as_deref());
-                self.set_data("request_body", State::Done(body_payload));
+                let state = match data::decode_content(bytes, self.request_encoding.as_deref()) {
+                    Ok(bytes) => State::Done(self.body_payload(
+                        "request_body",
+                        bytes,
+                        content_type.as_deref(),
+                    )),
+                    Err(e) => State::Fail(Some(Payload::Error(format!("request_body: {e}")))),
+                };
+                self.set_data("request_body", state);
             }
         }
 
         let action = self.run_nodes(HttpRequestBody);
 
-        if self.do_service_request_headers {
-            if let Some(payload) = self.data.first_input_for("service_request_headers", None) {
-                let headers = data::to_pwm_headers(Some(payload));
-                self.set_http_request_headers(headers);
+        if self.responded {
+            return Action::Pause;
+        }
+
+        if self.dry_run {
+            if eof {
+                self.send_dry_run_report();
             }
+            return Action::Pause;
         }
 
+        self.set_service_request_headers();
+
         if self.do_service_request_body {
             if let Some(payload) = self.data.first_input_for("service_request_body", None) {
-                if let Ok(bytes) = payload.to_bytes() {
+                let content_type = self.get_http_request_header("Content-Type");
+                if let Ok(bytes) = payload.to_body(content_type.as_deref()) {
+                    self.set_http_request_header("Content-Encoding", None);
                     self.set_http_request_body(0, bytes.len(), &bytes);
                 }
             }
@@ -306,18 +1252,109 @@ impl HttpContext for DataKitFilter {
         action
     }
 
+    fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
+        if self.responded {
+            return Action::Pause;
+        }
+        self.debug_phase(HttpRequestTrailers);
+
+        if self.do_request_trailers {
+            let vec = self.get_http_request_trailers();
+            self.set_headers_data(vec, "request_trailers");
+        }
+
+        self.run_nodes(HttpRequestTrailers)
+    }
+
     fn on_http_response_headers(&mut self, _nheaders: usize, _eof: bool) -> Action {
-        if self.do_service_response_headers {
+        self.debug_phase(HttpResponseHeaders);
+
+        self.on_response = true;
+
+        let content_length = self.get_http_response_header("Content-Length");
+        if let Some(size) = content_length.and_then(|s| s.parse().ok()) {
+            if self.limit_response_body(size, false) {
+                return Action::Pause;
+            }
+        }
+
+        if self.do_service_response_body {
+            self.service_response_encoding = self.get_http_response_header("Content-Encoding");
+            if self.streaming && self.service_response_encoding.is_some() {
+                // streaming nodes cannot work on compressed chunks
+                self.streaming = false;
+            }
+        }
+
+        // the casing is needed to set `response_headers` even when no node
+        // reads `service_response_headers`
+        let preserve_case = self.do_response_headers && self.config.preserve_header_case();
+        if self.do_service_response_headers || preserve_case {
             let vec = self.get_http_response_headers();
-            self.set_headers_data(vec, "service_response_headers");
+            if preserve_case {
+                self.header_case = data::header_case_map(&vec);
+            }
+            if self.do_service_response_headers {
+                self.set_headers_data(vec, "service_response_headers");
+            }
+        }
+
+        if self.do_service_response_status {
+            let status = self
+                .get_http_response_header(":status")
+                .and_then(|s| s.parse::<u32>().ok());
+            let payload = status.map(|s| Payload::Json(s.into()));
+            self.set_data("service_response_status", State::Done(payload));
         }
 
         let action = self.run_nodes(HttpResponseHeaders);
 
         if self.do_response_headers {
             if let Some(payload) = self.data.first_input_for("response_headers", None) {
-                let headers = data::to_pwm_headers(Some(payload));
-                self.set_http_response_headers(headers);
+                let mut headers = data::to_pwm_headers(Some(payload));
+                let mut removed = data::null_header_names(payload);
+                if self.config.preserve_header_case() {
+                    headers = data::restore_header_case(headers, &self.header_case);
+                    removed = removed
+                        .into_iter()
+                        .map(|k| self.header_case.get(k).map_or(k, |orig| orig.as_str()))
+                        .collect();
+                }
+                apply_headers(
+                    headers,
+                    removed,
+                    self.config.header_mode("response_headers"),
+                    self.get_http_response_headers(),
+                    |name, value| self.set_http_response_header(name, value),
+                    |name, value| self.add_http_response_header(name, value),
+                );
+            }
+        }
+
+        if self.do_response_cookies {
+            if let Some(payload) = self.data.first_input_for("response_cookies", None) {
+                match payload
+                    .to_json()
+                    .and_then(|v| cookies::to_set_cookie_headers(&v))
+                {
+                    Ok(headers) => {
+                        for header in headers {
+                            self.add_http_response_header("Set-Cookie", &header);
+                        }
+                    }
+                    Err(e) => log::warn!("response_cookies: {e}"),
+                }
+            }
+        }
+
+        if self.do_response_status {
+            if let Some(payload) = self.data.first_input_for("response_status", None) {
+                match payload.to_status() {
+                    Ok(status) => {
+                        self.set_http_response_header(":status", Some(&status.to_string()))
+                    }
+                    Err(e) => log::warn!("response_status: {e}"),
+                }
             }
         }
 
@@ -329,7 +1366,12 @@ impl HttpContext for DataKitFilter {
             } else {
                 self.set_http_response_header("Content-Length", None);
             }
-            self.set_http_response_header("Content-Encoding", None);
+            if self.compress_response {
+                self.set_http_response_header("Content-Length", None);
+                self.set_http_response_header("Content-Encoding", Some("gzip"));
+            } else {
+                self.set_http_response_header("Content-Encoding", None);
+            }
         }
 
         if self.debug.is_some() {
@@ -340,15 +1382,38 @@ impl HttpContext for DataKitFilter {
     }
 
     fn on_http_response_body(&mut self, body_size: usize, eof: bool) -> Action {
+        if self.limit_response_body(body_size, true) {
+            self.set_http_response_body(0, body_size, &[]);
+            return Action::Continue;
+        }
+        self.debug_phase(HttpResponseBody);
+        if self.streaming {
+            return self.stream_response_body(body_size, eof);
+        }
+
         if !eof {
-            return Action::Pause;
+            return if self.needs_response_body() {
+                Action::Pause
+            } else {
+                Action::Continue
+            };
         }
 
         if eof && self.do_service_response_body {
             if let Some(bytes) = self.get_http_response_body(0, body_size) {
                 let content_type = self.get_http_response_header("Content-Type");
-                let payload = Payload::from_bytes(bytes, content_type.as_deref());
-                self.set_data("service_response_body", State::Done(payload));
+                let encoding = self.service_response_encoding.as_deref();
+                let state = match data::decode_content(bytes, encoding) {
+                    Ok(bytes) => State::Done(self.body_payload(
+                        "service_response_body",
+                        bytes,
+                        content_type.as_deref(),
+                    )),
+                    Err(e) => {
+                        State::Fail(Some(Payload::Error(format!("service_response_body: {e}"))))
+                    }
+                };
+                self.set_data("service_response_body", state);
             }
         }
 
@@ -356,7 +1421,10 @@ impl HttpContext for DataKitFilter {
 
         if self.do_response_body {
             if let Some(payload) = self.data.first_input_for("response_body", None) {
-                if let Ok(bytes) = payload.to_bytes() {
+                if let Ok(mut bytes) = payload.to_bytes() {
+                    if self.compress_response && !self.is_tracing_to_body() {
+                        bytes = data::gzip(&bytes).into();
+                    }
                     self.set_http_response_body(0, bytes.len(), &bytes);
                 } else {
                     self.set_http_response_body(0, 0, &[]);
@@ -371,23 +1439,504 @@ impl HttpContext for DataKitFilter {
         }
 
         if self.debug.is_some() {
-            self.debug_done()
+            self.debug_done(body_size)
         }
 
         action
     }
+
+    fn on_http_response_trailers(&mut self, _num_trailers: usize) -> Action {
+        self.debug_phase(HttpResponseTrailers);
+        if self.do_service_response_trailers {
+            let vec = self.get_http_response_trailers();
+            self.set_headers_data(vec, "service_response_trailers");
+        }
+
+        let action = self.run_nodes(HttpResponseTrailers);
+
+        if self.do_response_trailers {
+            if let Some(payload) = self.data.first_input_for("response_trailers", None) {
+                apply_headers(
+                    data::to_pwm_headers(Some(payload)),
+                    data::null_header_names(payload),
+                    HeaderMode::Patch,
+                    self.get_http_response_trailers(),
+                    |name, value| self.set_http_response_trailer(name, value),
+                    |name, value| self.add_http_response_trailer(name, value),
+                );
+            }
+        }
+
+        action
+    }
+
+    fn on_log(&mut self) {
+        self.debug_send_trace();
+        self.otel_export();
+    }
 }
 
-proxy_wasm::main! {{
-    nodes::register_node("template", Box::new(nodes::template::TemplateFactory {}));
-    nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
-    nodes::register_node("response", Box::new(nodes::response::ResponseFactory {}));
-    nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+/// Registers the node types that come with the filter.
+pub fn register_builtin_nodes() {
+    register_nodes! {
+        "template" => nodes::template::TemplateFactory {},
+        "call" => nodes::call::CallFactory {},
+        "response" => nodes::response::ResponseFactory {},
+        "jq" => nodes::jq::JqFactory {},
+        "codec" => nodes::codec::CodecFactory {},
+        "regex" => nodes::regex::RegexFactory {},
+        "xml" => nodes::xml::XmlFactory {},
+        "csv" => nodes::csv::CsvFactory {},
+        "protobuf" => nodes::protobuf::ProtobufFactory {},
+        "graphql" => nodes::graphql::GraphqlFactory {},
+        "validate" => nodes::validate::ValidateFactory {},
+        "rate_limit" => nodes::rate_limit::RateLimitFactory {},
+        "log" => nodes::log::LogFactory {},
+        "metrics" => nodes::metrics::MetricsFactory {},
+        "merge" => nodes::merge::MergeFactory {},
+        "select" => nodes::select::SelectFactory {},
+        "foreach" => nodes::foreach::ForeachFactory {},
+        "switch" => nodes::switch::SwitchFactory {},
+        "circuit_breaker" => nodes::circuit_breaker::CircuitBreakerFactory {},
+        "mock" => nodes::mock::MockFactory {},
+        "secret" => nodes::secret::SecretFactory {},
+        "datastore" => nodes::datastore::DatastoreFactory {},
+        "llm" => nodes::llm::LlmFactory {},
+        "foreign_function" => nodes::foreign_function::ForeignFunctionFactory {},
+        "expr" => nodes::expr::ExprFactory {},
+        "request_id" => nodes::request_id::RequestIdFactory {},
+        "clock" => nodes::clock::ClockFactory {},
+        "crypto" => nodes::crypto::CryptoFactory {},
+    }
+
+    #[cfg(feature = "lua")]
+    register_nodes! {
+        "lua" => nodes::lua::LuaFactory {},
+    }
+}
 
+/// Sets up the filter, once its node types are registered.
+pub fn start() {
     proxy_wasm::set_log_level(LogLevel::Debug);
-    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+    proxy_wasm::set_root_context(|context_id| -> Box<dyn RootContext> {
         Box::new(DataKitFilterRootContext {
+            context_id,
             config: None,
+            nodes: None,
+            config_error: None,
         })
     });
+}
+
+#[cfg(feature = "main")]
+proxy_wasm::main! {{
+    register_builtin_nodes();
+    start();
 }}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_host;
+
+    fn new_filter(config: &str) -> Box<dyn HttpContext> {
+        register_builtin_nodes();
+        test_host::set_buffer(BufferType::PluginConfiguration, config.as_bytes());
+        let mut root = DataKitFilterRootContext {
+            context_id: 1,
+            config: None,
+            nodes: None,
+            config_error: None,
+        };
+        assert!(root.on_configure(0));
+        root.create_http_context(2).unwrap()
+    }
+
+    fn request(filter: &mut dyn HttpContext) -> Action {
+        test_host::set_map(MapType::HttpRequestHeaders, &[(":path", "/")]);
+        filter.on_http_request_headers(1, true)
+    }
+
+    fn traced_request(filter: &mut dyn HttpContext, eof: bool) -> Action {
+        test_host::set_map(
+            MapType::HttpRequestHeaders,
+            &[
+                (":path", "/"),
+                ("Content-Type", "application/json"),
+                ("X-DataKit-Debug-Trace", "true"),
+            ],
+        );
+        filter.on_http_request_headers(3, eof)
+    }
+
+    /// Lets the response through the filter, and returns the nodes that
+    /// were run or resumed, in order, from the trace sent in its body.
+    fn traced_runs(filter: &mut dyn HttpContext) -> Vec<String> {
+        test_host::set_map(MapType::HttpResponseHeaders, &[(":status", "200")]);
+        filter.on_http_response_headers(1, false);
+        test_host::set_buffer(BufferType::HttpResponseBody, b"{}");
+        filter.on_http_response_body(2, true);
+
+        let trace = test_host::buffer(BufferType::HttpResponseBody).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let runs = trace.as_array().unwrap().iter();
+        runs.filter(|a| a["action"] == "run" || a["action"] == "resume")
+            .map(|a| {
+                format!(
+                    "{} {}",
+                    a["action"].as_str().unwrap(),
+                    a["name"].as_str().unwrap()
+                )
+            })
+            .collect()
+    }
+
+    fn owned_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// Applies headers over `current` in a map that records what was done
+    /// to it, returning the resulting headers and the operations.
+    fn apply(
+        mode: HeaderMode,
+        headers: &[(&str, &str)],
+        removed: &[&str],
+        current: &[(&str, &str)],
+    ) -> (Vec<(String, String)>, Vec<String>) {
+        let map = std::cell::RefCell::new(owned_pairs(current));
+        let ops = std::cell::RefCell::new(vec![]);
+        apply_headers(
+            headers.to_vec(),
+            removed.to_vec(),
+            mode,
+            owned_pairs(current),
+            |name, value| {
+                let mut map = map.borrow_mut();
+                map.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+                match value {
+                    Some(v) => {
+                        map.push((name.to_string(), v.to_string()));
+                        ops.borrow_mut().push(format!("set {name}: {v}"));
+                    }
+                    None => ops.borrow_mut().push(format!("remove {name}")),
+                }
+            },
+            |name, value| {
+                map.borrow_mut().push((name.to_string(), value.to_string()));
+                ops.borrow_mut().push(format!("add {name}: {value}"));
+            },
+        );
+        (map.into_inner(), ops.into_inner())
+    }
+
+    #[test]
+    fn apply_headers_replace() {
+        let (headers, ops) = apply(
+            HeaderMode::Replace,
+            &[
+                ("X-Keep", "same"),
+                ("X-Multi", "a"),
+                ("X-Multi", "b"),
+                ("X-New", "1"),
+            ],
+            &[],
+            &[
+                (":path", "/"),
+                ("X-Keep", "same"),
+                ("X-Old", "1"),
+                ("x-multi", "a"),
+            ],
+        );
+        // pseudo-headers are kept, and so are unchanged headers, as they were
+        assert_eq!(
+            ops,
+            vec![
+                "remove X-Old",
+                "set X-Multi: a",
+                "add X-Multi: b",
+                "set X-New: 1"
+            ]
+        );
+        assert_eq!(
+            headers,
+            owned_pairs(&[
+                (":path", "/"),
+                ("X-Keep", "same"),
+                ("X-Multi", "a"),
+                ("X-Multi", "b"),
+                ("X-New", "1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn apply_headers_merge() {
+        let current = [("X-Keep", "1"), ("x-multi", "a"), ("x-multi", "b")];
+
+        // the same values, under another case, leave the headers as they are
+        let multi = [("X-Multi", "a"), ("X-Multi", "b")];
+        let (headers, ops) = apply(HeaderMode::Merge, &multi, &[], &current);
+        assert!(ops.is_empty());
+        assert_eq!(headers, owned_pairs(&current));
+
+        // nothing is removed, not even headers given as `null`
+        let (headers, ops) = apply(
+            HeaderMode::Merge,
+            &[("x-multi", "c"), ("X-Added", "1")],
+            &["X-Keep"],
+            &current,
+        );
+        assert_eq!(ops, vec!["set x-multi: c", "set X-Added: 1"]);
+        assert_eq!(
+            headers,
+            owned_pairs(&[("X-Keep", "1"), ("x-multi", "c"), ("X-Added", "1")])
+        );
+    }
+
+    #[test]
+    fn apply_headers_patch() {
+        let (headers, ops) = apply(
+            HeaderMode::Patch,
+            &[("X-Stay", "1"), ("X-Multi", "a"), ("X-Multi", "b")],
+            &["x-gone", "X-GONE", "x-absent"],
+            &[("X-Gone", "1"), ("X-Gone", "2"), ("X-Stay", "1")],
+        );
+        // headers given as `null` are removed once, if they are present
+        assert_eq!(
+            ops,
+            vec!["remove x-gone", "set X-Multi: a", "add X-Multi: b"]
+        );
+        assert_eq!(
+            headers,
+            owned_pairs(&[("X-Stay", "1"), ("X-Multi", "a"), ("X-Multi", "b")])
+        );
+    }
+
+    #[test]
+    fn rate_limit_per_configuration() {
+        let config = |window: u32, on_failure: &str| {
+            format!(
+                r#"{{ "nodes": [
+                    {{ "name": "RL", "type": "rate_limit", "limit": 1, "window": {window}
+                       {on_failure} }}
+                ] }}"#
+            )
+        };
+        let a = config(60, "");
+        let b = config(30, "");
+
+        assert_eq!(request(&mut *new_filter(&a)), Action::Continue);
+        // the same node in another configuration has its own counter
+        assert_eq!(request(&mut *new_filter(&b)), Action::Continue);
+        assert!(test_host::take_local_response().is_none());
+
+        assert_eq!(request(&mut *new_filter(&a)), Action::Pause);
+        let response = test_host::take_local_response().unwrap();
+        assert_eq!(response.status, 429);
+        assert_eq!(
+            response.headers,
+            vec![("Content-Type".to_string(), "application/json".to_string())]
+        );
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["limit"], 1);
+        assert_eq!(body["remaining"], 0);
+        assert_eq!(body["message"], "rate limit exceeded");
+
+        let c = config(60, r#", "on_failure": "continue""#);
+        assert_eq!(request(&mut *new_filter(&c)), Action::Continue);
+        assert_eq!(request(&mut *new_filter(&c)), Action::Continue);
+        assert!(test_host::take_local_response().is_none());
+    }
+
+    #[test]
+    fn circuit_breaker_per_configuration() {
+        let config = |cooldown: u32| {
+            format!(
+                r#"{{ "nodes": [
+                    {{ "name": "CB", "type": "circuit_breaker", "url": "http://example.com/",
+                       "cooldown": {cooldown} }}
+                ] }}"#
+            )
+        };
+        let a = config(60);
+        let b = config(30);
+
+        let mut filter = new_filter(&a);
+        assert_eq!(request(&mut *filter), Action::Pause);
+        let calls = test_host::take_calls();
+        assert_eq!(calls.len(), 1);
+        test_host::set_call_response(503, &[], b"");
+        filter.on_http_call_response(calls[0].token, 0, 0, 0);
+
+        // the circuit is open for the node in this configuration...
+        assert_eq!(request(&mut *new_filter(&a)), Action::Pause);
+        assert!(test_host::take_calls().is_empty());
+        assert_eq!(test_host::take_local_response().unwrap().status, 500);
+
+        // ...but not for the node of the same name in another one
+        assert_eq!(request(&mut *new_filter(&b)), Action::Pause);
+        assert_eq!(test_host::take_calls().len(), 1);
+        assert!(test_host::take_local_response().is_none());
+    }
+
+    #[test]
+    fn call_headers_placeholders() {
+        std::env::set_var("DATAKIT_TEST_API_TOKEN", "t0k3n");
+        let mut filter = new_filter(
+            r#"{ "nodes": [
+                { "name": "H", "type": "jq", "jq": "{ \"x-tenant\": \"input\" }" },
+                { "name": "CALL", "type": "call", "url": "http://example.com/",
+                  "inputs": { "headers": "H" },
+                  "headers": { "authorization": "Bearer ${DATAKIT_TEST_API_TOKEN}",
+                               "x-tenant": "configured" } }
+            ] }"#,
+        );
+        assert_eq!(request(&mut *filter), Action::Pause);
+
+        let calls = test_host::take_calls();
+        assert_eq!(calls.len(), 1);
+        let header = |name: &str| {
+            let values = calls[0].headers.iter().filter(|(n, _)| n == name);
+            values.map(|(_, v)| v.as_str()).collect::<Vec<_>>()
+        };
+        assert_eq!(header("authorization"), vec!["Bearer t0k3n"]);
+        // the `headers` input takes precedence over the configured headers
+        assert_eq!(header("x-tenant"), vec!["input"]);
+    }
+
+    #[test]
+    fn trace_redacts_values_derived_from_secrets() {
+        let mut filter = new_filter(
+            r#"{ "debug": true, "nodes": [
+                { "name": "S", "type": "secret", "value": "hunter2" },
+                { "name": "J", "type": "jq", "input": "S", "output": "service_request_headers",
+                  "jq": "{ \"authorization\": (\"Bearer \" + $S) }" },
+                { "name": "P", "type": "jq", "output": "response_headers",
+                  "jq": "{ \"x-public\": \"public\" }" }
+            ] }"#,
+        );
+        test_host::set_map(
+            MapType::HttpRequestHeaders,
+            &[(":path", "/"), ("X-DataKit-Debug-Trace", "true")],
+        );
+        assert_eq!(filter.on_http_request_headers(2, true), Action::Continue);
+        assert!(test_host::map(MapType::HttpRequestHeaders)
+            .contains(&("authorization".to_string(), "Bearer hunter2".to_string())));
+
+        test_host::set_map(MapType::HttpResponseHeaders, &[(":status", "200")]);
+        filter.on_http_response_headers(1, false);
+        test_host::set_buffer(BufferType::HttpResponseBody, b"{}");
+        filter.on_http_response_body(2, true);
+
+        let trace = test_host::buffer(BufferType::HttpResponseBody).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let values: Vec<_> = trace
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|a| a["action"] == "value")
+            .map(|a| (a["name"].as_str().unwrap(), a["value"].clone()))
+            .collect();
+        let redacted = serde_json::json!("[REDACTED]");
+        assert!(values.contains(&("S", redacted.clone())));
+        assert!(values.contains(&("J", redacted)));
+        assert!(values.contains(&("P", serde_json::json!({ "x-public": "public" }))));
+        assert!(!trace.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn run_order_across_phases() {
+        let mut filter = new_filter(
+            r#"{ "debug": true, "nodes": [
+                { "name": "D", "type": "jq", "input": "service_response_body",
+                  "jq": "$service_response_body", "output": "response_body" },
+                { "name": "C", "type": "jq", "input": "service_response_headers",
+                  "jq": "{ \"x-c\": \"1\" }", "output": "response_headers" },
+                { "name": "B2", "type": "jq", "input": "B", "jq": "$B",
+                  "output": "service_request_body" },
+                { "name": "B", "type": "jq", "input": "request_body", "jq": "$request_body" },
+                { "name": "A", "type": "jq", "input": "request_headers",
+                  "jq": "{ \"x-a\": \"1\" }", "output": "service_request_headers" }
+            ] }"#,
+        );
+        traced_request(&mut *filter, false);
+        let x_a = ("x-a".to_string(), "1".to_string());
+        assert!(test_host::map(MapType::HttpRequestHeaders).contains(&x_a));
+
+        // B2 becomes ready once B has run, in the same phase
+        test_host::set_buffer(BufferType::HttpRequestBody, br#"{"n":1}"#);
+        assert_eq!(filter.on_http_request_body(7, true), Action::Continue);
+        let body = test_host::buffer(BufferType::HttpRequestBody).unwrap();
+        assert_eq!(body, br#"{"n":1}"#);
+
+        assert_eq!(
+            traced_runs(&mut *filter),
+            vec!["run A", "run B", "run B2", "run C", "run D"]
+        );
+        let x_c = ("x-c".to_string(), "1".to_string());
+        assert!(test_host::map(MapType::HttpResponseHeaders).contains(&x_c));
+    }
+
+    #[test]
+    fn resume_with_several_calls() {
+        let mut filter = new_filter(
+            r#"{ "debug": true, "nodes": [
+                { "name": "J", "type": "jq", "inputs": ["ONE", "TWO"],
+                  "jq": "{ \"x-one\": $ONE.v, \"x-two\": $TWO.v }",
+                  "output": "response_headers" },
+                { "name": "ONE", "type": "call", "url": "http://one.example.com/" },
+                { "name": "TWO", "type": "call", "url": "http://two.example.com/" }
+            ] }"#,
+        );
+        assert_eq!(traced_request(&mut *filter, true), Action::Pause);
+        let calls = test_host::take_calls();
+        let upstreams: Vec<_> = calls.iter().map(|c| c.upstream.as_str()).collect();
+        assert_eq!(upstreams, vec!["one.example.com", "two.example.com"]);
+
+        // J waits for both calls, whichever completes first, and so does the request
+        let json = [("Content-Type", "application/json")];
+        test_host::set_call_response(200, &json, br#"{ "v": "2" }"#);
+        filter.on_http_call_response(calls[1].token, 0, 0, 0);
+        assert!(!test_host::take_request_resumed());
+
+        test_host::set_call_response(200, &json, br#"{ "v": "1" }"#);
+        filter.on_http_call_response(calls[0].token, 0, 0, 0);
+        assert!(test_host::take_request_resumed());
+
+        assert_eq!(
+            traced_runs(&mut *filter),
+            vec!["run ONE", "run TWO", "resume TWO", "resume ONE", "run J"]
+        );
+        let headers = test_host::map(MapType::HttpResponseHeaders);
+        assert!(headers.contains(&("x-one".to_string(), "1".to_string())));
+        assert!(headers.contains(&("x-two".to_string(), "2".to_string())));
+    }
+
+    #[test]
+    fn preserve_header_case_without_dependents() {
+        let mut filter = new_filter(
+            r#"{ "preserve_header_case": true, "nodes": [
+                { "name": "A", "type": "jq", "output": "response_headers",
+                  "jq": "{ \"x-custom\": \"new\", \"x-added\": \"1\" }" }
+            ] }"#,
+        );
+        assert_eq!(request(&mut *filter), Action::Continue);
+
+        test_host::set_map(
+            MapType::HttpResponseHeaders,
+            &[(":status", "200"), ("X-Custom", "old")],
+        );
+        assert_eq!(filter.on_http_response_headers(2, false), Action::Continue);
+        assert_eq!(
+            test_host::map(MapType::HttpResponseHeaders),
+            vec![
+                (":status".to_string(), "200".to_string()),
+                ("X-Custom".to_string(), "new".to_string()),
+                ("x-added".to_string(), "1".to_string()),
+            ]
+        );
+    }
+}
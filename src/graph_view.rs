@@ -0,0 +1,224 @@
+use crate::config::Config;
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+/// Formats in which the dependency graph can be rendered, as requested
+/// with the `X-DataKit-Debug-Graph` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn parse(s: &str) -> Option<GraphFormat> {
+        match s {
+            "dot" => Some(GraphFormat::Dot),
+            "mermaid" => Some(GraphFormat::Mermaid),
+            "json" => Some(GraphFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::Mermaid => "text/plain",
+            GraphFormat::Json => "application/json",
+        }
+    }
+}
+
+struct GraphNode<'a> {
+    name: &'a str,
+    /// The node type, or `None` for implicit nodes.
+    node_type: Option<&'a str>,
+    /// For implicit nodes, whether they are sources of data.
+    is_source: bool,
+}
+
+struct GraphEdge<'a> {
+    from: &'a str,
+    /// The named output of the source node, as in `NODE.port`.
+    port: Option<&'a str>,
+    to: &'a str,
+}
+
+/// Collects the nodes of the configuration, followed by the implicit nodes
+/// connected to them, and the edges between them.
+fn collect(config: &Config) -> (Vec<GraphNode<'_>>, Vec<GraphEdge<'_>>) {
+    let mut nodes: Vec<GraphNode> = config
+        .node_types()
+        .map(|(name, node_type)| GraphNode {
+            name,
+            node_type: Some(node_type),
+            is_source: false,
+        })
+        .collect();
+
+    let mut edges = vec![];
+    for (src, to) in config.get_graph().edges() {
+        let (from, port) = match src.split_once('.') {
+            Some((from, port)) => (from, Some(port)),
+            None => (src, None),
+        };
+        for (name, is_source) in [(from, true), (to, false)] {
+            if !nodes.iter().any(|n| n.name == name) {
+                nodes.push(GraphNode {
+                    name,
+                    node_type: None,
+                    is_source,
+                });
+            }
+        }
+        edges.push(GraphEdge { from, port, to });
+    }
+
+    (nodes, edges)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+fn render_dot(nodes: &[GraphNode], edges: &[GraphEdge]) -> String {
+    let mut out = String::from("digraph datakit {\n  rankdir=LR;\n");
+    for node in nodes {
+        let _ = match node.node_type {
+            Some(t) => writeln!(
+                out,
+                "  {} [label=\"{}\\n({t})\"];",
+                quote(node.name),
+                escape(node.name)
+            ),
+            None => writeln!(out, "  {} [shape=box, style=dashed];", quote(node.name)),
+        };
+    }
+    for edge in edges {
+        let _ = match edge.port {
+            Some(port) => writeln!(
+                out,
+                "  {} -> {} [label={}];",
+                quote(edge.from),
+                quote(edge.to),
+                quote(port)
+            ),
+            None => writeln!(out, "  {} -> {};", quote(edge.from), quote(edge.to)),
+        };
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn render_mermaid(nodes: &[GraphNode], edges: &[GraphEdge]) -> String {
+    // node names may contain characters Mermaid ids cannot, so nodes are
+    // identified by their position and named in their labels
+    let id = |name: &str| nodes.iter().position(|n| n.name == name).unwrap_or(0);
+    let label = |s: &str| s.replace('"', "#quot;");
+
+    let mut out = String::from("flowchart LR\n");
+    for (i, node) in nodes.iter().enumerate() {
+        let _ = match node.node_type {
+            Some(t) => writeln!(out, "  n{i}[\"{} ({t})\"]", label(node.name)),
+            None => writeln!(out, "  n{i}([\"{}\"])", label(node.name)),
+        };
+    }
+    for edge in edges {
+        let (from, to) = (id(edge.from), id(edge.to));
+        let _ = match edge.port {
+            Some(port) => writeln!(out, "  n{from} -->|\"{}\"| n{to}", label(port)),
+            None => writeln!(out, "  n{from} --> n{to}"),
+        };
+    }
+    out
+}
+
+fn render_json(nodes: &[GraphNode], edges: &[GraphEdge]) -> String {
+    let nodes: Vec<Value> = nodes
+        .iter()
+        .map(|node| match node.node_type {
+            Some(t) => json!({ "name": node.name, "type": t }),
+            None => json!({
+                "name": node.name,
+                "type": "implicit",
+                "direction": if node.is_source { "source" } else { "sink" },
+            }),
+        })
+        .collect();
+    let edges: Vec<Value> = edges
+        .iter()
+        .map(|edge| match edge.port {
+            Some(port) => json!({ "from": edge.from, "port": port, "to": edge.to }),
+            None => json!({ "from": edge.from, "to": edge.to }),
+        })
+        .collect();
+
+    json!({ "nodes": nodes, "edges": edges }).to_string()
+}
+
+/// Renders the dependency graph of the configuration: its nodes and their
+/// types, the implicit nodes they are connected to, and the edges between
+/// them, labelled with the named outputs they come from.
+pub fn render(config: &Config, format: GraphFormat) -> String {
+    let (nodes, edges) = collect(config);
+    match format {
+        GraphFormat::Dot => render_dot(&nodes, &edges),
+        GraphFormat::Mermaid => render_mermaid(&nodes, &edges),
+        GraphFormat::Json => render_json(&nodes, &edges),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nodes;
+
+    fn config() -> Config {
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+
+        let config = br#"{ "nodes": [
+            { "name": "CALL", "type": "call", "input": "request_body", "url": "http://example.com" },
+            { "name": "JQ", "type": "jq", "input": "CALL", "jq": ".",
+              "outputs": { "headers": "response_headers", "body": "response_body" } }
+        ] }"#;
+        Config::new(config.to_vec(), &|_| None).unwrap()
+    }
+
+    #[test]
+    fn json() {
+        let graph: Value = serde_json::from_str(&render(&config(), GraphFormat::Json)).unwrap();
+        assert_eq!(graph["nodes"][0], json!({ "name": "CALL", "type": "call" }));
+        assert!(graph["nodes"].as_array().unwrap().contains(&json!({
+            "name": "request_body", "type": "implicit", "direction": "source"
+        })));
+        assert!(graph["nodes"].as_array().unwrap().contains(&json!({
+            "name": "response_headers", "type": "implicit", "direction": "sink"
+        })));
+        assert!(graph["edges"].as_array().unwrap().contains(&json!({
+            "from": "JQ", "port": "headers", "to": "response_headers"
+        })));
+        assert!(graph["edges"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "from": "CALL", "to": "JQ" })));
+    }
+
+    #[test]
+    fn dot_and_mermaid() {
+        let dot = render(&config(), GraphFormat::Dot);
+        assert!(dot.starts_with("digraph datakit {"));
+        assert!(dot.contains("  \"CALL\" [label=\"CALL\\n(call)\"];\n"));
+        assert!(dot.contains("  \"CALL\" -> \"JQ\";\n"));
+        assert!(dot.contains("  \"JQ\" -> \"response_body\" [label=\"body\"];\n"));
+
+        let mermaid = render(&config(), GraphFormat::Mermaid);
+        assert!(mermaid.starts_with("flowchart LR\n  n0[\"CALL (call)\"]\n  n1[\"JQ (jq)\"]\n"));
+        assert!(mermaid.contains("  n0 --> n1\n"));
+    }
+}
@@ -0,0 +1,186 @@
+use serde_json::{Map, Value};
+
+use crate::nodes;
+
+/// Versions of the configuration format understood by this filter.
+/// Configurations without a `version` field are assumed to be version 1.
+pub const CONFIG_FORMAT_VERSIONS: &[u32] = &[1, 2];
+
+const CURRENT_VERSION: u32 = 2;
+
+/// Brings a configuration in any supported format version up to the
+/// current one, logging a deprecation warning for each layout that
+/// needed to be converted.
+pub fn migrate(config: &mut Value) -> Result<(), String> {
+    let Value::Object(map) = config else {
+        return Ok(());
+    };
+
+    let version = match map.remove("version") {
+        None => 1,
+        Some(v) => match v.as_u64().and_then(|n| u32::try_from(n).ok()) {
+            Some(n) if CONFIG_FORMAT_VERSIONS.contains(&n) => n,
+            _ => {
+                return Err(format!(
+                    "unsupported configuration version {v}, expected one of: {CONFIG_FORMAT_VERSIONS:?}"
+                ))
+            }
+        },
+    };
+
+    let Some(Value::Array(node_list)) = map.get_mut("nodes") else {
+        return Ok(());
+    };
+
+    for node in node_list.iter_mut().filter_map(|n| n.as_object_mut()) {
+        if version < 2 {
+            v1_positional_inputs_to_roles(node)?;
+        } else {
+            check_positional_inputs(node)?;
+        }
+    }
+
+    if version < CURRENT_VERSION {
+        log::warn!("migrated configuration from version {version} to {CURRENT_VERSION}");
+    }
+
+    Ok(())
+}
+
+fn node_name(node: &Map<String, Value>) -> &str {
+    node.get("name").and_then(|n| n.as_str()).unwrap_or("")
+}
+
+/// Returns the input roles of the node's type, if its `inputs`
+/// are given as a list of more than one node.
+fn positional_roles(node: &Map<String, Value>) -> Option<&'static [&'static str]> {
+    let node_type = node.get("type")?.as_str()?;
+    match node.get("inputs") {
+        Some(Value::Array(inputs)) if inputs.len() > 1 => nodes::input_roles(node_type),
+        _ => None,
+    }
+}
+
+/// Version 1 connected several inputs of nodes such as `call` and
+/// `response` by position; they are now given as a map of roles.
+fn v1_positional_inputs_to_roles(node: &mut Map<String, Value>) -> Result<(), String> {
+    let Some(roles) = positional_roles(node) else {
+        return Ok(());
+    };
+    let Some(Value::Array(inputs)) = node.get("inputs") else {
+        return Ok(());
+    };
+    if inputs.len() > roles.len() {
+        return Err(format!(
+            "node '{}': cannot migrate {} inputs, this node type takes at most {} ({})",
+            node_name(node),
+            inputs.len(),
+            roles.len(),
+            roles.join(", ")
+        ));
+    }
+
+    let role_map: Map<String, Value> = roles
+        .iter()
+        .map(|r| r.to_string())
+        .zip(inputs.iter().cloned())
+        .collect();
+
+    log::warn!(
+        "node '{}': a list of inputs is deprecated for this node type, use a map of input roles instead: {}",
+        node_name(node),
+        Value::Object(role_map.clone())
+    );
+
+    node.insert("inputs".to_string(), Value::Object(role_map));
+    Ok(())
+}
+
+fn check_positional_inputs(node: &Map<String, Value>) -> Result<(), String> {
+    match positional_roles(node) {
+        Some(roles) => Err(format!(
+            "node '{}': inputs must be given as a map of input roles ({})",
+            node_name(node),
+            roles.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unsupported_version() {
+        let mut config = json!({ "version": 99, "nodes": [] });
+        assert!(migrate(&mut config).is_err());
+
+        let mut config = json!({ "version": "2", "nodes": [] });
+        assert!(migrate(&mut config).is_err());
+    }
+
+    fn register_nodes() {
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+        nodes::register_node("response", Box::new(nodes::response::ResponseFactory {}));
+    }
+
+    #[test]
+    fn v1_positional_inputs() {
+        register_nodes();
+
+        let mut config = json!({ "nodes": [
+            { "name": "CALL", "type": "call", "inputs": ["BODY", "HEADERS"] },
+            { "name": "EXIT", "type": "response", "inputs": ["CALL", "HEADERS", "STATUS"] },
+            { "name": "ONE", "type": "call", "inputs": ["BODY"] }
+        ] });
+        migrate(&mut config).unwrap();
+        assert_eq!(
+            config,
+            json!({ "nodes": [
+                { "name": "CALL", "type": "call",
+                  "inputs": { "body": "BODY", "headers": "HEADERS" } },
+                { "name": "EXIT", "type": "response",
+                  "inputs": { "body": "CALL", "headers": "HEADERS", "status": "STATUS" } },
+                { "name": "ONE", "type": "call", "inputs": ["BODY"] }
+            ] })
+        );
+    }
+
+    #[test]
+    fn v1_too_many_inputs() {
+        register_nodes();
+
+        let mut config = json!({ "nodes": [
+            { "name": "CALL", "type": "call", "inputs": ["BODY", "HEADERS", "EXTRA"] }
+        ] });
+        assert_eq!(
+            migrate(&mut config),
+            Err("node 'CALL': cannot migrate 3 inputs, this node type takes at most 2 (body, headers)".to_string())
+        );
+    }
+
+    #[test]
+    fn v2_positional_inputs() {
+        register_nodes();
+
+        let mut config = json!({ "version": 2, "nodes": [
+            { "name": "CALL", "type": "call", "inputs": ["BODY", "HEADERS"] }
+        ] });
+        assert_eq!(
+            migrate(&mut config),
+            Err(
+                "node 'CALL': inputs must be given as a map of input roles (body, headers)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn version_is_removed() {
+        let mut config = json!({ "version": 2, "nodes": [] });
+        migrate(&mut config).unwrap();
+        assert_eq!(config, json!({ "nodes": [] }));
+    }
+}
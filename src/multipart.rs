@@ -0,0 +1,209 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+
+use crate::data::header_params;
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
+
+struct Part<'a> {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    body: &'a [u8],
+}
+
+fn parse_part(part: &[u8]) -> Result<Part<'_>, String> {
+    let split = find(part, b"\r\n\r\n", 0).ok_or("part without headers")?;
+    let headers = std::str::from_utf8(&part[..split]).map_err(|e| e.to_string())?;
+
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in headers.split("\r\n") {
+        if let Some((k, v)) = line.split_once(':') {
+            match k.trim().to_ascii_lowercase().as_str() {
+                "content-disposition" => disposition = Some(v.trim()),
+                "content-type" => content_type = Some(v.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let params = header_params(disposition.ok_or("part without Content-Disposition")?);
+    Ok(Part {
+        name: param(&params, "name")
+            .ok_or("part without name")?
+            .to_string(),
+        filename: param(&params, "filename").map(str::to_string),
+        content_type,
+        body: &part[split + 4..],
+    })
+}
+
+fn insert_field(fields: &mut Map<String, Value>, name: String, value: Value) {
+    match fields.get_mut(&name) {
+        Some(Value::Array(vs)) => vs.push(value),
+        Some(first) => *first = Value::Array(vec![first.take(), value]),
+        None => {
+            fields.insert(name, value);
+        }
+    }
+}
+
+/// Parses a `multipart/form-data` body into an object with two entries:
+/// `fields`, the text parts, keyed by name, with arrays of values for names
+/// that appear more than once; and `files`, a list of the file parts, each
+/// with its `name`, `filename`, `content_type`, `size` and its `content`
+/// encoded in base64.
+pub fn parse(body: &[u8], boundary: &str) -> Result<Value, String> {
+    let delimiter = format!("--{boundary}").into_bytes();
+    let mut fields = Map::new();
+    let mut files = vec![];
+
+    let mut pos = find(body, &delimiter, 0).ok_or("missing boundary")? + delimiter.len();
+    loop {
+        if body[pos..].starts_with(b"--") {
+            break;
+        }
+        let start = find(body, b"\r\n", pos).ok_or("truncated body")? + 2;
+        let mut next_delimiter = b"\r\n".to_vec();
+        next_delimiter.extend(&delimiter);
+        let end = find(body, &next_delimiter, start).ok_or("missing closing boundary")?;
+
+        let part = parse_part(&body[start..end])?;
+        match part.filename {
+            Some(filename) => {
+                let mut file = Map::new();
+                file.insert("name".to_string(), part.name.into());
+                file.insert("filename".to_string(), filename.into());
+                file.insert("content_type".to_string(), part.content_type.into());
+                file.insert("size".to_string(), part.body.len().into());
+                file.insert("content".to_string(), STANDARD.encode(part.body).into());
+                files.push(Value::Object(file));
+            }
+            None => {
+                let value = String::from_utf8_lossy(part.body).to_string();
+                insert_field(&mut fields, part.name, value.into());
+            }
+        }
+
+        pos = end + next_delimiter.len();
+    }
+
+    let mut map = Map::new();
+    map.insert("fields".to_string(), Value::Object(fields));
+    map.insert("files".to_string(), Value::Array(files));
+    Ok(Value::Object(map))
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Encodes an object in the form produced by `parse` as a
+/// `multipart/form-data` body. File parts must have a `content`.
+pub fn encode(value: &Value, boundary: &str) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    let mut add_part = |headers: String, body: &[u8]| {
+        out.extend(format!("--{boundary}\r\n{headers}\r\n").into_bytes());
+        out.extend(body);
+        out.extend(b"\r\n");
+    };
+
+    if let Some(fields) = value.get("fields") {
+        let fields = fields.as_object().ok_or("'fields' must be an object")?;
+        for (name, v) in fields {
+            let values = match v {
+                Value::Array(vs) => vs.iter().collect(),
+                v => vec![v],
+            };
+            for v in values {
+                let text = match v {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                };
+                let headers = format!(
+                    "Content-Disposition: form-data; name=\"{}\"\r\n",
+                    quote(name)
+                );
+                add_part(headers, text.as_bytes());
+            }
+        }
+    }
+
+    if let Some(files) = value.get("files") {
+        let files = files.as_array().ok_or("'files' must be a list")?;
+        for file in files {
+            let get = |key: &str| file.get(key).and_then(|v| v.as_str());
+            let name = get("name").ok_or("file part without name")?;
+            let content = get("content")
+                .ok_or_else(|| format!("file part '{name}' without content"))
+                .and_then(|c| {
+                    STANDARD
+                        .decode(c)
+                        .map_err(|e| format!("file part '{name}': {e}"))
+                })?;
+
+            let mut headers = format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                quote(name),
+                quote(get("filename").unwrap_or(name))
+            );
+            if let Some(ct) = get("content_type") {
+                headers.push_str(&format!("Content-Type: {ct}\r\n"));
+            }
+            add_part(headers, &content);
+        }
+    }
+
+    out.extend(format!("--{boundary}--\r\n").into_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const BODY: &[u8] = b"--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"doc\"; filename=\"a;b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        abc\r\n\
+        --XyZ--\r\n";
+
+    #[test]
+    fn roundtrip() {
+        let value = parse(BODY, "XyZ").unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "fields": { "title": "Hello" },
+                "files": [{
+                    "name": "doc",
+                    "filename": "a;b.txt",
+                    "content_type": "text/plain",
+                    "size": 3,
+                    "content": "YWJj"
+                }]
+            })
+        );
+
+        assert_eq!(parse(&encode(&value, "XyZ").unwrap(), "XyZ"), Ok(value));
+    }
+}
@@ -1,19 +1,60 @@
 use proxy_wasm::traits::*;
 use serde_json::Value;
 use std::any::Any;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::sync::{Mutex, OnceLock};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use crate::data::{Input, State, State::*};
 
 pub mod call;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod codec;
+pub mod crypto;
+pub mod csv;
+pub mod datastore;
+pub mod disabled;
+pub mod expr;
+pub mod foreach;
+pub mod foreign_function;
+pub mod graphql;
 pub mod jq;
+pub mod llm;
+pub mod log;
+#[cfg(feature = "lua")]
+pub mod lua;
+pub mod merge;
+pub mod metrics;
+pub mod mock;
+pub mod protobuf;
+pub mod rate_limit;
+pub mod regex;
+pub mod request_id;
 pub mod response;
+pub mod secret;
+pub mod select;
+pub mod switch;
 pub mod template;
+pub mod validate;
+pub mod xml;
 
-pub type NodeMap = BTreeMap<String, Box<dyn Node>>;
+/// The nodes of a configuration, in the order of their names.
+pub type NodeList = Vec<Box<dyn Node>>;
 
+/// The per-request state of the nodes that keep any, by node index.
+pub type NodeStates = Vec<Option<Box<dyn Any>>>;
+
+/// Nodes are built once per configuration and shared by the requests
+/// it handles, so they keep what they need between the callbacks of a
+/// request in a separate state, given to them along with their input.
 pub trait Node {
+    /// Nodes that keep state between the callbacks of a request create
+    /// it here, once per request.
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+
     fn run(&self, _ctx: &dyn HttpContext, _input: &Input) -> State {
         Done(None)
     }
@@ -21,6 +62,50 @@ pub trait Node {
     fn resume(&self, _ctx: &dyn HttpContext, _input: &Input) -> State {
         Done(None)
     }
+
+    /// Nodes that dispatch several HTTP calls at once report whether
+    /// they are waiting on the call with the given token, in addition
+    /// to the one given in their `Waiting` state.
+    fn is_waiting_on(&self, _state: Option<&dyn Any>, _token_id: u32) -> bool {
+        false
+    }
+
+    /// Nodes with named outputs report which one was activated by their
+    /// last run. Other nodes can use it as an input by referring to it as
+    /// `node.output`, and only trigger if that output was activated.
+    fn active_output(&self, _state: Option<&dyn Any>) -> Option<String> {
+        None
+    }
+
+    /// Nodes that send a response directly to the client report it
+    /// after running, so that the filter stops processing the request.
+    fn has_responded(&self, _state: Option<&dyn Any>) -> bool {
+        false
+    }
+
+    /// Nodes whose config reports `supports_streaming` transform the body
+    /// chunk by chunk with this function, as it is received; `eof` is set
+    /// for the last chunk.
+    fn stream_chunk(
+        &self,
+        _state: Option<&dyn Any>,
+        _chunk: &[u8],
+        _eof: bool,
+    ) -> Result<Vec<u8>, String> {
+        Err("streaming is not supported".to_string())
+    }
+}
+
+/// Gets the state a node created with `Node::new_state`.
+pub fn node_state<T: 'static>(state: Option<&dyn Any>) -> &T {
+    state
+        .and_then(|s| s.downcast_ref())
+        .expect("node state was created by new_state")
+}
+
+/// Creates the state of the nodes that keep any, for a new request.
+pub fn new_states(nodes: &NodeList) -> NodeStates {
+    nodes.iter().map(|node| node.new_state()).collect()
 }
 
 pub trait NodeConfig {
@@ -33,9 +118,54 @@ pub trait NodeConfig {
     fn default_outputs(&self) -> Option<Vec<String>> {
         None
     }
+
+    /// Nodes that have effects of their own, such as making calls or
+    /// logging, are useful even if their output is not consumed.
+    fn is_sink(&self) -> bool {
+        false
+    }
+
+    /// Nodes that can transform a body incrementally, a chunk at a time,
+    /// can be used in streaming mode (see `Node::stream_chunk`).
+    fn supports_streaming(&self) -> bool {
+        false
+    }
+
+    /// Nodes whose failures carry a value meant for the client, such as
+    /// an exceeded rate limit, give the status of the response sent when
+    /// they fail without an `on_failure` policy, instead of a `500` with
+    /// a generic message; the response carries that value as its body.
+    fn failure_status(&self) -> Option<u32> {
+        None
+    }
 }
 
-pub trait NodeFactory: Send {
+/// Accumulates body chunks for nodes that process a body incrementally
+/// but need whole units of it at a time, such as lines of text.
+#[derive(Clone, Default)]
+pub struct ChunkBuffer {
+    pending: RefCell<Vec<u8>>,
+}
+
+impl ChunkBuffer {
+    /// Appends a chunk and takes the longest prefix of the buffered data
+    /// that can be processed, whose length is given by `split`, or all of
+    /// it at `eof`.
+    pub fn take(&self, chunk: &[u8], eof: bool, split: impl Fn(&[u8]) -> usize) -> Vec<u8> {
+        let mut pending = self.pending.borrow_mut();
+        pending.extend_from_slice(chunk);
+        let n = if eof { pending.len() } else { split(&pending) };
+        pending.drain(..n).collect()
+    }
+}
+
+/// Fills a buffer with random bytes from the host, for nodes that
+/// generate ids or secrets.
+pub fn fill_random(bytes: &mut [u8]) -> Result<(), String> {
+    getrandom::getrandom(bytes).map_err(|e| format!("cannot get random bytes: {e}"))
+}
+
+pub trait NodeFactory: Send + Sync {
     fn new_config(
         &self,
         name: &str,
@@ -44,9 +174,26 @@ pub trait NodeFactory: Send {
     ) -> Result<Box<dyn NodeConfig>, String>;
 
     fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node>;
+
+    /// The configuration keys accepted by the node type,
+    /// besides those common to all nodes.
+    fn config_keys(&self) -> &'static [&'static str];
+
+    /// The configuration keys in which `${...}` placeholders are resolved.
+    /// Other keys, such as filters and templates, can use `${` as part of
+    /// their own syntax.
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Node types with positional inputs list the roles of their
+    /// inputs, in order, so that they can be given by role in a map.
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        None
+    }
 }
 
-type NodeTypeMap = BTreeMap<String, Box<dyn NodeFactory>>;
+type NodeTypeMap = BTreeMap<String, Arc<dyn NodeFactory>>;
 
 fn node_types() -> &'static Mutex<NodeTypeMap> {
     static NODE_TYPES: OnceLock<Mutex<NodeTypeMap>> = OnceLock::new();
@@ -57,17 +204,57 @@ pub fn register_node(name: &str, factory: Box<dyn NodeFactory>) -> bool {
     node_types()
         .lock()
         .unwrap()
-        .insert(String::from(name), factory);
+        .insert(String::from(name), Arc::from(factory));
     true
 }
 
+pub fn node_type_names() -> Vec<String> {
+    node_types().lock().unwrap().keys().cloned().collect()
+}
+
+// The lock is released before calling into the factory,
+// so that factories can build nested nodes.
+fn get_factory(node_type: &str) -> Option<Arc<dyn NodeFactory>> {
+    node_types().lock().unwrap().get(node_type).cloned()
+}
+
+pub fn input_roles(node_type: &str) -> Option<&'static [&'static str]> {
+    get_factory(node_type).and_then(|nf| nf.input_roles())
+}
+
+pub fn placeholder_keys(node_type: &str) -> &'static [&'static str] {
+    get_factory(node_type).map_or(&[], |nf| nf.placeholder_keys())
+}
+
+pub fn has_config_key(node_type: &str, key: &str) -> bool {
+    get_factory(node_type).is_some_and(|nf| nf.config_keys().contains(&key))
+}
+
+pub fn check_config_keys(
+    node_type: &str,
+    name: &str,
+    bt: &BTreeMap<String, Value>,
+) -> Result<(), String> {
+    let Some(nf) = get_factory(node_type) else {
+        return Err(format!("no such node type: {node_type}"));
+    };
+    let keys = nf.config_keys();
+
+    match bt.keys().find(|k| !keys.contains(&k.as_str())) {
+        Some(key) => Err(format!(
+            "{node_type}: node '{name}': unknown configuration key '{key}'"
+        )),
+        None => Ok(()),
+    }
+}
+
 pub fn new_config(
     node_type: &str,
     name: &str,
     inputs: &[String],
     bt: &BTreeMap<String, Value>,
 ) -> Result<Box<dyn NodeConfig>, String> {
-    if let Some(nf) = node_types().lock().unwrap().get(node_type) {
+    if let Some(nf) = get_factory(node_type) {
         nf.new_config(name, inputs, bt)
     } else {
         Err(format!("no such node type: {node_type}"))
@@ -75,7 +262,7 @@ pub fn new_config(
 }
 
 pub fn new_node(node_type: &str, config: &dyn NodeConfig) -> Result<Box<dyn Node>, String> {
-    if let Some(nf) = node_types().lock().unwrap().get(node_type) {
+    if let Some(nf) = get_factory(node_type) {
         Ok(nf.new_node(config))
     } else {
         Err(format!("no such node type: {node_type}"))
@@ -20,12 +20,18 @@ pub struct CallConfig {
     url: String,
     method: String,
     timeout: u32,
+    headers: Vec<(String, String)>,
+    example: Option<Value>,
 }
 
 impl NodeConfig for CallConfig {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn is_sink(&self) -> bool {
+        true
+    }
 }
 
 pub struct Call {
@@ -36,6 +42,10 @@ impl Node for Call {
     fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
         log::debug!("call: run");
 
+        if input.dry_run {
+            return Done(self.config.example.clone().map(Payload::Json));
+        }
+
         let body = input.data.first().unwrap_or(&None);
         let headers = input.data.get(1).unwrap_or(&None);
 
@@ -56,10 +66,20 @@ impl Node for Call {
         };
 
         let mut headers_vec = data::to_pwm_headers(*headers);
+        // configured headers are sent unless the `headers` input gives them
+        let configured: Vec<(&str, &str)> = self
+            .config
+            .headers
+            .iter()
+            .filter(|(k, _)| data::find_header(&headers_vec, k).is_none())
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        headers_vec.extend(configured);
         headers_vec.push((":method", self.config.method.as_str()));
         headers_vec.push((":path", call_url.path()));
 
-        let body_slice = match data::to_pwm_body(*body) {
+        let content_type = data::find_header(&headers_vec, "content-type");
+        let body_slice = match data::to_pwm_body(*body, content_type) {
             Ok(slice) => slice,
             Err(e) => return Fail(Some(Payload::Error(e))),
         };
@@ -112,14 +132,26 @@ pub struct CallFactory {}
 impl NodeFactory for CallFactory {
     fn new_config(
         &self,
-        _name: &str,
+        name: &str,
         _inputs: &[String],
         bt: &BTreeMap<String, Value>,
     ) -> Result<Box<dyn NodeConfig>, String> {
+        let headers = match bt.get("headers") {
+            Some(value @ Value::Object(_)) => Payload::Json(value.clone())
+                .to_pwm_headers()
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            Some(_) => return Err(format!("call: node '{name}': 'headers' must be an object")),
+            None => vec![],
+        };
+
         Ok(Box::new(CallConfig {
             url: get_config_value(bt, "url").unwrap_or_else(|| String::from("")),
             method: get_config_value(bt, "method").unwrap_or_else(|| String::from("GET")),
             timeout: get_config_value(bt, "timeout").unwrap_or(60),
+            headers,
+            example: bt.get("example").cloned(),
         }))
     }
 
@@ -129,4 +161,16 @@ impl NodeFactory for CallFactory {
             None => panic!("incompatible NodeConfig"),
         }
     }
+
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        Some(&["body", "headers"])
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["url", "method", "timeout", "headers", "example"]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["url", "headers"]
+    }
 }
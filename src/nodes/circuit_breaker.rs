@@ -0,0 +1,391 @@
+use proxy_wasm::traits::*;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes;
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+/// Number of attempts to update the breaker state when racing
+/// with other workers updating the same shared data key.
+const MAX_CAS_ATTEMPTS: usize = 10;
+
+pub struct CircuitBreakerConfig {
+    name: String,
+    namespace: String,
+    failure_threshold: u32,
+    cooldown: u64,
+    fallback: Option<Value>,
+    call_config: Box<dyn NodeConfig>,
+}
+
+impl NodeConfig for CircuitBreakerConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_sink(&self) -> bool {
+        true
+    }
+}
+
+pub struct CircuitBreaker {
+    name: String,
+    namespace: String,
+    failure_threshold: u32,
+    cooldown: u64,
+    fallback: Option<Value>,
+    call: Box<dyn Node>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Outcome {
+    Success,
+    Failure,
+    /// The upstream asked us to back off, optionally for a given time.
+    Throttled(Option<u64>),
+}
+
+impl BreakerState {
+    fn is_open(&self, now: u64) -> bool {
+        self.open_until > now
+    }
+
+    /// Updates the state with the outcome of a call, returning whether it
+    /// changed. Once the cooldown is over, failures are still counted from
+    /// the threshold, so that a single failed call opens the circuit again.
+    /// The time given by `Retry-After` is capped at the cooldown.
+    fn record(
+        &mut self,
+        outcome: Outcome,
+        now: u64,
+        failure_threshold: u32,
+        cooldown: u64,
+    ) -> bool {
+        match outcome {
+            Outcome::Success => {
+                if self.failures == 0 {
+                    return false;
+                }
+                self.failures = 0;
+            }
+            Outcome::Failure => {
+                self.failures += 1;
+                if self.failures >= failure_threshold {
+                    self.open_until = now + cooldown;
+                }
+            }
+            Outcome::Throttled(retry_after) => {
+                self.failures += 1;
+                self.open_until = now + retry_after.map_or(cooldown, |r| r.min(cooldown));
+            }
+        }
+        true
+    }
+}
+
+fn now(ctx: &dyn HttpContext) -> u64 {
+    ctx.get_current_time()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl CircuitBreaker {
+    fn shm_key(&self) -> String {
+        format!("datakit:circuit_breaker:{}:{}", self.namespace, self.name)
+    }
+
+    fn load(&self, ctx: &dyn HttpContext) -> (BreakerState, Option<u32>) {
+        let (bytes, cas) = ctx.get_shared_data(&self.shm_key());
+        let state = bytes
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default();
+        (state, cas)
+    }
+
+    fn record(&self, ctx: &dyn HttpContext, outcome: Outcome) {
+        let now = now(ctx);
+
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let (mut state, cas) = self.load(ctx);
+            if !state.record(outcome, now, self.failure_threshold, self.cooldown) {
+                return;
+            }
+
+            let bytes = serde_json::to_vec(&state).expect("serializable breaker state");
+            match ctx.set_shared_data(&self.shm_key(), Some(&bytes), cas) {
+                Ok(()) => return,
+                Err(Status::CasMismatch) => continue,
+                Err(status) => {
+                    log::warn!(
+                        "circuit_breaker: node '{}': failed updating state: {:?}",
+                        self.name,
+                        status
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    fn is_open(&self, ctx: &dyn HttpContext) -> bool {
+        let (state, _) = self.load(ctx);
+        state.is_open(now(ctx))
+    }
+
+    fn short_circuit(&self) -> State {
+        match &self.fallback {
+            Some(value) => Done(Some(Payload::Json(value.clone()))),
+            None => Fail(Some(Payload::Error(format!(
+                "circuit_breaker: node '{}': circuit open",
+                self.name
+            )))),
+        }
+    }
+
+    fn call_outcome(ctx: &dyn HttpContext) -> Outcome {
+        let status = ctx
+            .get_http_call_response_header(":status")
+            .and_then(|s| s.parse::<u32>().ok());
+
+        match status {
+            Some(429) | Some(503) => {
+                let retry_after = ctx
+                    .get_http_call_response_header("Retry-After")
+                    .and_then(|s| s.parse().ok());
+                Outcome::Throttled(retry_after)
+            }
+            Some(s) if s < 500 => Outcome::Success,
+            // 5xx, or no status at all (e.g. the call timed out)
+            _ => Outcome::Failure,
+        }
+    }
+}
+
+impl Node for CircuitBreaker {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        if self.is_open(ctx) {
+            return self.short_circuit();
+        }
+
+        let state = self.call.run(ctx, input);
+        if let Fail(_) = state {
+            self.record(ctx, Outcome::Failure);
+        }
+        state
+    }
+
+    fn resume(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        let outcome = Self::call_outcome(ctx);
+        let failed = !matches!(outcome, Outcome::Success);
+        self.record(ctx, outcome);
+
+        if failed && self.fallback.is_some() {
+            return self.short_circuit();
+        }
+
+        self.call.resume(ctx, input)
+    }
+}
+
+pub struct CircuitBreakerFactory {}
+
+impl NodeFactory for CircuitBreakerFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        // the remaining fields configure the wrapped call
+        let call_config = nodes::new_config("call", name, inputs, bt)?;
+
+        Ok(Box::new(CircuitBreakerConfig {
+            name: name.to_string(),
+            // set from the configuration when not given (see `config::add_shared`)
+            namespace: get_config_value(bt, "namespace").unwrap_or_default(),
+            failure_threshold: get_config_value::<u32>(bt, "failure_threshold")
+                .unwrap_or(5)
+                .max(1),
+            cooldown: get_config_value(bt, "cooldown").unwrap_or(30),
+            fallback: bt.get("fallback").cloned(),
+            call_config,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<CircuitBreakerConfig>() {
+            Some(cc) => Box::new(CircuitBreaker {
+                name: cc.name.clone(),
+                namespace: cc.namespace.clone(),
+                failure_threshold: cc.failure_threshold,
+                cooldown: cc.cooldown,
+                fallback: cc.fallback.clone(),
+                call: nodes::new_node("call", &*cc.call_config)
+                    .expect("call node type is registered"),
+            }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        Some(&["body", "headers"])
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &[
+            "url",
+            "method",
+            "timeout",
+            "headers",
+            "example",
+            "failure_threshold",
+            "cooldown",
+            "fallback",
+            "namespace",
+        ]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["url", "headers"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_host::TestContext;
+    use serde_json::json;
+
+    const THRESHOLD: u32 = 3;
+    const COOLDOWN: u64 = 30;
+
+    fn record(state: &mut BreakerState, outcome: Outcome, now: u64) -> bool {
+        state.record(outcome, now, THRESHOLD, COOLDOWN)
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let mut state = BreakerState::default();
+
+        record(&mut state, Outcome::Failure, 100);
+        record(&mut state, Outcome::Failure, 101);
+        assert!(!state.is_open(101));
+
+        record(&mut state, Outcome::Failure, 102);
+        assert!(state.is_open(102));
+        assert!(state.is_open(102 + COOLDOWN - 1));
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut state = BreakerState::default();
+        for _ in 0..THRESHOLD {
+            record(&mut state, Outcome::Failure, 100);
+        }
+        assert!(state.is_open(100));
+
+        // once the cooldown is over, calls go through again...
+        assert!(!state.is_open(100 + COOLDOWN));
+
+        // ...and a single failure opens the circuit again
+        record(&mut state, Outcome::Failure, 200);
+        assert!(state.is_open(200));
+    }
+
+    #[test]
+    fn reset() {
+        let mut state = BreakerState::default();
+        assert!(!record(&mut state, Outcome::Success, 100));
+
+        record(&mut state, Outcome::Failure, 100);
+        record(&mut state, Outcome::Failure, 100);
+        assert!(record(&mut state, Outcome::Success, 100));
+        assert_eq!(state.failures, 0);
+
+        record(&mut state, Outcome::Failure, 100);
+        record(&mut state, Outcome::Failure, 100);
+        assert!(!state.is_open(100));
+    }
+
+    #[test]
+    fn throttled() {
+        let mut state = BreakerState::default();
+        record(&mut state, Outcome::Throttled(Some(5)), 100);
+        assert!(state.is_open(104));
+        assert!(!state.is_open(105));
+
+        let mut state = BreakerState::default();
+        record(&mut state, Outcome::Throttled(None), 100);
+        assert_eq!(state.open_until, 100 + COOLDOWN);
+
+        // Retry-After is capped at the cooldown
+        let mut state = BreakerState::default();
+        record(&mut state, Outcome::Throttled(Some(86_400)), 100);
+        assert_eq!(state.open_until, 100 + COOLDOWN);
+    }
+
+    fn breaker(namespace: &str, fallback: Option<Value>) -> CircuitBreaker {
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+
+        let mut bt = BTreeMap::from([
+            ("url".to_string(), json!("http://example.com")),
+            ("namespace".to_string(), json!(namespace)),
+        ]);
+        if let Some(f) = fallback {
+            bt.insert("fallback".to_string(), f);
+        }
+        let config = CircuitBreakerFactory {}.new_config("CB", &[], &bt).unwrap();
+        let cc = config
+            .as_any()
+            .downcast_ref::<CircuitBreakerConfig>()
+            .unwrap();
+
+        CircuitBreaker {
+            name: cc.name.clone(),
+            namespace: cc.namespace.clone(),
+            failure_threshold: cc.failure_threshold,
+            cooldown: cc.cooldown,
+            fallback: cc.fallback.clone(),
+            call: nodes::new_node("call", &*cc.call_config).unwrap(),
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let Done(Some(Payload::Json(value))) =
+            breaker("a", Some(json!({ "items": [] }))).short_circuit()
+        else {
+            panic!("fallback expected");
+        };
+        assert_eq!(value, json!({ "items": [] }));
+
+        let Fail(Some(Payload::Error(e))) = breaker("a", None).short_circuit() else {
+            panic!("failure expected");
+        };
+        assert_eq!(e, "circuit_breaker: node 'CB': circuit open");
+    }
+
+    #[test]
+    fn namespaces() {
+        let a = breaker("a", None);
+        a.record(&TestContext, Outcome::Throttled(None));
+        assert!(a.is_open(&TestContext));
+
+        // a breaker of the same name in another namespace is not affected
+        assert!(!breaker("b", None).is_open(&TestContext));
+        assert!(breaker("a", None).is_open(&TestContext));
+    }
+}
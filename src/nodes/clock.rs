@@ -0,0 +1,232 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A date and time in UTC.
+struct DateTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+    millis: i64,
+    /// Days since Sunday.
+    weekday: i64,
+    /// Days since January 1st.
+    yearday: i64,
+}
+
+impl DateTime {
+    fn from_millis(millis: i64) -> DateTime {
+        let secs = millis.div_euclid(1000);
+        let days = secs.div_euclid(86400);
+        let time = secs.rem_euclid(86400);
+
+        // days to civil date, from Howard Hinnant's date algorithms
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        // the civil year starts in March, the calendar one in January
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let yearday = if mp < 10 {
+            doy + 59 + i64::from(leap)
+        } else {
+            doy - 306
+        };
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: time / 3600,
+            minute: time % 3600 / 60,
+            second: time % 60,
+            millis: millis.rem_euclid(1000),
+            // 1970-01-01 was a Thursday
+            weekday: (days + 4).rem_euclid(7),
+            yearday,
+        }
+    }
+}
+
+/// Formats a time given in milliseconds since the Unix epoch, in UTC, with
+/// the `strftime` conversions `%Y`, `%y`, `%m`, `%d`, `%H`, `%M`, `%S`, `%j`,
+/// `%a`, `%b`, `%s`, `%z`, `%Z` and `%%`, along with `%3f` for milliseconds.
+pub fn strftime(pattern: &str, millis: i64) -> Result<String, String> {
+    let t = DateTime::from_millis(millis);
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let s = match chars.next() {
+            Some('Y') => format!("{:04}", t.year),
+            Some('y') => format!("{:02}", t.year.rem_euclid(100)),
+            Some('m') => format!("{:02}", t.month),
+            Some('d') => format!("{:02}", t.day),
+            Some('H') => format!("{:02}", t.hour),
+            Some('M') => format!("{:02}", t.minute),
+            Some('S') => format!("{:02}", t.second),
+            Some('j') => format!("{:03}", t.yearday + 1),
+            Some('a') => WEEKDAYS[t.weekday as usize].to_string(),
+            Some('b') => MONTHS[t.month as usize - 1].to_string(),
+            Some('s') => millis.div_euclid(1000).to_string(),
+            Some('z') => "+0000".to_string(),
+            Some('Z') => "UTC".to_string(),
+            Some('%') => "%".to_string(),
+            Some('3') if chars.next() == Some('f') => format!("{:03}", t.millis),
+            Some(other) => return Err(format!("unsupported conversion '%{other}'")),
+            None => return Err("incomplete conversion at the end".to_string()),
+        };
+        out.push_str(&s);
+    }
+
+    Ok(out)
+}
+
+/// Formats a Unix timestamp as an ISO 8601 date and time in UTC.
+pub fn iso8601(secs: i64) -> String {
+    strftime("%Y-%m-%dT%H:%M:%SZ", secs * 1000).expect("valid pattern")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Format {
+    Unix,
+    UnixMs,
+    Pattern(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClockConfig {
+    name: String,
+    format: Format,
+}
+
+impl NodeConfig for ClockConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Clock {
+    config: ClockConfig,
+}
+
+impl Node for Clock {
+    fn run(&self, ctx: &dyn HttpContext, _input: &Input) -> State {
+        let millis = ctx
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+
+        let value = match &self.config.format {
+            Format::Unix => Value::from(millis.div_euclid(1000)),
+            Format::UnixMs => Value::from(millis),
+            Format::Pattern(pattern) => match strftime(pattern, millis) {
+                Ok(s) => Value::String(s),
+                Err(e) => {
+                    let name = &self.config.name;
+                    return Fail(Some(Payload::Error(format!("clock: node '{name}': {e}"))));
+                }
+            },
+        };
+        Done(Some(Payload::Json(value)))
+    }
+}
+
+pub struct ClockFactory {}
+
+impl NodeFactory for ClockFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let format = match get_config_value::<String>(bt, "format").as_deref() {
+            Some("rfc3339") | None => Format::Pattern("%Y-%m-%dT%H:%M:%SZ".to_string()),
+            Some("rfc3339_ms") => Format::Pattern("%Y-%m-%dT%H:%M:%S.%3fZ".to_string()),
+            Some("http") => Format::Pattern("%a, %d %b %Y %H:%M:%S GMT".to_string()),
+            Some("unix") => Format::Unix,
+            Some("unix_ms") => Format::UnixMs,
+            Some(pattern) if pattern.contains('%') => {
+                strftime(pattern, 0).map_err(|e| format!("clock: node '{name}': {e}"))?;
+                Format::Pattern(pattern.to_string())
+            }
+            Some(other) => {
+                return Err(format!("clock: node '{name}': invalid format '{other}'"));
+            }
+        };
+
+        Ok(Box::new(ClockConfig {
+            name: name.to_string(),
+            format,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ClockConfig>() {
+            Some(cc) => Box::new(Clock { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["format"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn formats() {
+        let t = 784_111_777_042;
+        assert_eq!(
+            strftime("%a, %d %b %Y %H:%M:%S GMT", t),
+            Ok("Sun, 06 Nov 1994 08:49:37 GMT".to_string())
+        );
+        assert_eq!(
+            strftime("%Y-%m-%dT%H:%M:%S.%3f%z", t),
+            Ok("1994-11-06T08:49:37.042+0000".to_string())
+        );
+        assert_eq!(
+            strftime("%y%j %s %Z 100%%", t),
+            Ok("94310 784111777 UTC 100%".to_string())
+        );
+        assert_eq!(strftime("%j", 951_868_800_000), Ok("061".to_string()));
+        assert!(strftime("%Q", t).is_err());
+        assert!(strftime("%", t).is_err());
+    }
+}
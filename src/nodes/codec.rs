@@ -0,0 +1,271 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use percent_encoding::{percent_decode, percent_encode, NON_ALPHANUMERIC};
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{node_state, ChunkBuffer, Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Encode,
+    Decode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Base64,
+    Base64Url,
+    Url,
+    Hex,
+}
+
+#[derive(Clone, Debug)]
+pub struct CodecConfig {
+    action: Action,
+    codec: Encoding,
+}
+
+impl NodeConfig for CodecConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Codec {
+    config: CodecConfig,
+}
+
+pub fn hex_encode(bytes: &[u8]) -> Vec<u8> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        out.push(DIGITS[(b >> 4) as usize]);
+        out.push(DIGITS[(b & 0xf) as usize]);
+    }
+    out
+}
+
+fn hex_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    fn digit(c: u8) -> Result<u8, String> {
+        match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(format!("invalid hex digit: {:?}", c as char)),
+        }
+    }
+
+    if !bytes.len().is_multiple_of(2) {
+        return Err("invalid hex string: odd length".to_string());
+    }
+
+    bytes
+        .chunks(2)
+        .map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+impl CodecConfig {
+    fn apply(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match (self.action, self.codec) {
+            (Action::Encode, Encoding::Base64) => Ok(STANDARD.encode(bytes).into_bytes()),
+            (Action::Encode, Encoding::Base64Url) => Ok(URL_SAFE_NO_PAD.encode(bytes).into_bytes()),
+            (Action::Encode, Encoding::Url) => Ok(percent_encode(bytes, NON_ALPHANUMERIC)
+                .to_string()
+                .into_bytes()),
+            (Action::Encode, Encoding::Hex) => Ok(hex_encode(bytes)),
+            (Action::Decode, Encoding::Base64) => STANDARD.decode(bytes).map_err(|e| e.to_string()),
+            (Action::Decode, Encoding::Base64Url) => {
+                URL_SAFE_NO_PAD.decode(bytes).map_err(|e| e.to_string())
+            }
+            (Action::Decode, Encoding::Url) => Ok(percent_decode(bytes).collect()),
+            (Action::Decode, Encoding::Hex) => hex_decode(bytes),
+        }
+    }
+}
+
+impl CodecConfig {
+    /// Length of the longest prefix of `bytes` which can be encoded or
+    /// decoded on its own, so that the rest can be joined with the next chunk.
+    fn complete_len(&self, bytes: &[u8]) -> usize {
+        let len = bytes.len();
+        match (self.action, self.codec) {
+            (Action::Encode, Encoding::Base64 | Encoding::Base64Url) => len - len % 3,
+            (Action::Decode, Encoding::Base64 | Encoding::Base64Url) => len - len % 4,
+            (Action::Encode, Encoding::Url | Encoding::Hex) => len,
+            (Action::Decode, Encoding::Hex) => len - len % 2,
+            (Action::Decode, Encoding::Url) => {
+                // keep an escape sequence cut short at the end for the next chunk
+                let tail = len.saturating_sub(2);
+                match bytes[tail..].iter().position(|b| *b == b'%') {
+                    Some(i) => tail + i,
+                    None => len,
+                }
+            }
+        }
+    }
+}
+
+impl Node for Codec {
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(ChunkBuffer::default()))
+    }
+
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let bytes = match input.data.first().unwrap_or(&None) {
+            Some(Payload::Raw(bytes, _)) => bytes.to_vec(),
+            Some(Payload::Json(Value::String(s))) => s.clone().into_bytes(),
+            Some(Payload::Json(_)) => {
+                return Fail(Some(Payload::Error(
+                    "codec: input must be a string".to_string(),
+                )))
+            }
+            Some(Payload::Error(e)) => return Fail(Some(Payload::Error(e.clone()))),
+            None => return Done(None),
+        };
+
+        match self.config.apply(&bytes) {
+            Ok(out) => Done(Some(Payload::Raw(out.into(), None))),
+            Err(e) => Fail(Some(Payload::Error(format!("codec: {e}")))),
+        }
+    }
+
+    fn stream_chunk(
+        &self,
+        state: Option<&dyn Any>,
+        chunk: &[u8],
+        eof: bool,
+    ) -> Result<Vec<u8>, String> {
+        let bytes =
+            node_state::<ChunkBuffer>(state).take(chunk, eof, |b| self.config.complete_len(b));
+        self.config.apply(&bytes).map_err(|e| format!("codec: {e}"))
+    }
+}
+
+pub struct CodecFactory {}
+
+impl NodeFactory for CodecFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let action = match get_config_value::<String>(bt, "action").as_deref() {
+            Some("encode") | None => Action::Encode,
+            Some("decode") => Action::Decode,
+            Some(other) => {
+                return Err(format!("codec: node '{name}': invalid action '{other}'"));
+            }
+        };
+
+        let codec = match get_config_value::<String>(bt, "codec").as_deref() {
+            Some("base64") | None => Encoding::Base64,
+            Some("base64url") => Encoding::Base64Url,
+            Some("url") => Encoding::Url,
+            Some("hex") => Encoding::Hex,
+            Some(other) => {
+                return Err(format!("codec: node '{name}': invalid codec '{other}'"));
+            }
+        };
+
+        Ok(Box::new(CodecConfig { action, codec }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<CodecConfig>() {
+            Some(cc) => Box::new(Codec { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["action", "codec"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(codec: Encoding, input: &[u8], encoded: &str) {
+        let enc = CodecConfig {
+            action: Action::Encode,
+            codec,
+        };
+        let dec = CodecConfig {
+            action: Action::Decode,
+            codec,
+        };
+
+        assert_eq!(enc.apply(input).unwrap(), encoded.as_bytes());
+        assert_eq!(dec.apply(encoded.as_bytes()).unwrap(), input);
+    }
+
+    #[test]
+    fn codecs_roundtrip() {
+        roundtrip(Encoding::Base64, b"hello?\xff", "aGVsbG8//w==");
+        roundtrip(Encoding::Base64Url, b"hello?\xff", "aGVsbG8__w");
+        roundtrip(Encoding::Url, b"a b/c", "a%20b%2Fc");
+        roundtrip(Encoding::Hex, b"\x00\x1fA\xff", "001f41ff");
+    }
+
+    #[test]
+    fn streaming() {
+        let input = b"streaming a body, a few bytes at a time";
+        for codec in [
+            Encoding::Base64,
+            Encoding::Base64Url,
+            Encoding::Url,
+            Encoding::Hex,
+        ] {
+            for action in [Action::Encode, Action::Decode] {
+                let config = CodecConfig { action, codec };
+                let data = match action {
+                    Action::Encode => input.to_vec(),
+                    Action::Decode => CodecConfig {
+                        action: Action::Encode,
+                        codec,
+                    }
+                    .apply(input)
+                    .unwrap(),
+                };
+
+                let node = Codec {
+                    config: config.clone(),
+                };
+                let state = node.new_state();
+                let mut out = vec![];
+                let chunks: Vec<&[u8]> = data.chunks(5).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let eof = i == chunks.len() - 1;
+                    out.extend(node.stream_chunk(state.as_deref(), chunk, eof).unwrap());
+                }
+
+                assert_eq!(out, config.apply(&data).unwrap(), "{action:?} {codec:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_hex() {
+        let dec = CodecConfig {
+            action: Action::Decode,
+            codec: Encoding::Hex,
+        };
+
+        assert!(dec.apply(b"abc").is_err());
+        assert!(dec.apply(b"zz").is_err());
+    }
+}
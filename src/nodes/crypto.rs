@@ -0,0 +1,294 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::codec::hex_encode;
+use crate::nodes::{fill_random, Node, NodeConfig, NodeFactory};
+
+/// Random values are limited to this many bytes.
+const MAX_RANDOM_BYTES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Random,
+    Hash,
+    Hmac,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Hex,
+    Base64,
+    Base64Url,
+}
+
+#[derive(Clone, Debug)]
+pub struct CryptoConfig {
+    name: String,
+    op: Op,
+    algorithm: Algorithm,
+    encoding: Encoding,
+    bytes: usize,
+    key: Option<String>,
+}
+
+impl NodeConfig for CryptoConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Crypto {
+    config: CryptoConfig,
+}
+
+fn encode(bytes: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Hex => String::from_utf8(hex_encode(bytes)).expect("hex digits are ASCII"),
+        Encoding::Base64 => STANDARD.encode(bytes),
+        Encoding::Base64Url => URL_SAFE_NO_PAD.encode(bytes),
+    }
+}
+
+/// Hashes the data, preceded by the salt, if any.
+fn hash(algorithm: Algorithm, salt: &[u8], data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        Algorithm::Sha256 => Sha256::new()
+            .chain_update(salt)
+            .chain_update(data)
+            .finalize()
+            .to_vec(),
+        Algorithm::Sha512 => Sha512::new()
+            .chain_update(salt)
+            .chain_update(data)
+            .finalize()
+            .to_vec(),
+    }
+}
+
+fn hmac(algorithm: Algorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    match algorithm {
+        Algorithm::Sha256 => Hmac::<Sha256>::new_from_slice(key)
+            .expect("any key length")
+            .chain_update(data)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        Algorithm::Sha512 => Hmac::<Sha512>::new_from_slice(key)
+            .expect("any key length")
+            .chain_update(data)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+    }
+}
+
+impl Crypto {
+    /// The key (or the salt, for hashes) is given by the second input,
+    /// or otherwise by the configuration.
+    fn key(&self, input: &Input) -> Result<Option<Vec<u8>>, String> {
+        match input.data.get(1).copied().flatten() {
+            Some(payload) => Ok(Some(payload.to_bytes()?.into_vec())),
+            None => Ok(self.config.key.clone().map(String::into_bytes)),
+        }
+    }
+
+    fn compute(&self, input: &Input) -> Result<Vec<u8>, String> {
+        let config = &self.config;
+        if config.op == Op::Random {
+            let mut bytes = vec![0; config.bytes];
+            fill_random(&mut bytes)?;
+            return Ok(bytes);
+        }
+
+        let data = match input.data.first().copied().flatten() {
+            Some(payload) => payload.to_bytes()?,
+            None => return Err("no input to hash".to_string()),
+        };
+        let key = self.key(input)?;
+        match (config.op, key) {
+            (Op::Hmac, Some(key)) if !key.is_empty() => Ok(hmac(config.algorithm, &key, &data)),
+            (Op::Hmac, _) => Err("no key for hmac".to_string()),
+            (_, key) => Ok(hash(config.algorithm, &key.unwrap_or_default(), &data)),
+        }
+    }
+}
+
+impl Node for Crypto {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match self.compute(input) {
+            Ok(bytes) => Done(Some(Payload::Json(Value::String(encode(
+                &bytes,
+                self.config.encoding,
+            ))))),
+            Err(e) => {
+                let name = &self.config.name;
+                Fail(Some(Payload::Error(format!("crypto: node '{name}': {e}"))))
+            }
+        }
+    }
+}
+
+pub struct CryptoFactory {}
+
+impl NodeFactory for CryptoFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let op = match get_config_value::<String>(bt, "op").as_deref() {
+            Some("random") | None => Op::Random,
+            Some("hash") => Op::Hash,
+            Some("hmac") => Op::Hmac,
+            Some(other) => {
+                return Err(format!("crypto: node '{name}': invalid op '{other}'"));
+            }
+        };
+
+        let algorithm = match get_config_value::<String>(bt, "algorithm").as_deref() {
+            Some("sha256") | None => Algorithm::Sha256,
+            Some("sha512") => Algorithm::Sha512,
+            Some(other) => {
+                return Err(format!(
+                    "crypto: node '{name}': invalid algorithm '{other}'"
+                ));
+            }
+        };
+
+        let encoding = match get_config_value::<String>(bt, "encoding").as_deref() {
+            Some("hex") | None => Encoding::Hex,
+            Some("base64") => Encoding::Base64,
+            Some("base64url") => Encoding::Base64Url,
+            Some(other) => {
+                return Err(format!("crypto: node '{name}': invalid encoding '{other}'"));
+            }
+        };
+
+        let bytes = get_config_value(bt, "bytes").unwrap_or(32);
+        if !(1..=MAX_RANDOM_BYTES).contains(&bytes) {
+            return Err(format!(
+                "crypto: node '{name}': 'bytes' must be between 1 and {MAX_RANDOM_BYTES}"
+            ));
+        }
+
+        // HMAC with an empty key would silently sign with a well-known key
+        let key: Option<String> = get_config_value(bt, "key");
+        let has_key_input = inputs.len() > 1 || inputs.iter().any(|i| i == "key");
+        if op == Op::Hmac && key.as_deref().is_none_or(str::is_empty) && !has_key_input {
+            return Err(format!(
+                "crypto: node '{name}': 'hmac' requires a 'key' option or input"
+            ));
+        }
+
+        Ok(Box::new(CryptoConfig {
+            name: name.to_string(),
+            op,
+            algorithm,
+            encoding,
+            bytes,
+            key,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<CryptoConfig>() {
+            Some(cc) => Box::new(Crypto { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        Some(&["data", "key"])
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["op", "algorithm", "encoding", "bytes", "key"]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["key"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn digests() {
+        let sha256 = |salt: &str, data: &str| {
+            encode(
+                &hash(Algorithm::Sha256, salt.as_bytes(), data.as_bytes()),
+                Encoding::Hex,
+            )
+        };
+        assert_eq!(
+            sha256("", "abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256("a", "bc"), sha256("", "abc"));
+
+        assert_eq!(
+            encode(
+                &hmac(
+                    Algorithm::Sha256,
+                    b"key",
+                    b"The quick brown fox jumps over the lazy dog"
+                ),
+                Encoding::Hex
+            ),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert_eq!(hash(Algorithm::Sha512, b"", b"").len(), 64);
+    }
+
+    #[test]
+    fn hmac_key() {
+        let new_config = |inputs: &[&str], bt: Value| {
+            let Value::Object(map) = bt else {
+                panic!("config must be an object");
+            };
+            let inputs: Vec<String> = inputs.iter().map(|i| i.to_string()).collect();
+            CryptoFactory {}
+                .new_config("SIGN", &inputs, &map.into_iter().collect())
+                .map(|_| ())
+        };
+
+        let expected =
+            Err("crypto: node 'SIGN': 'hmac' requires a 'key' option or input".to_string());
+        assert_eq!(new_config(&["data"], json!({ "op": "hmac" })), expected);
+        assert_eq!(
+            new_config(&["data"], json!({ "op": "hmac", "key": "" })),
+            expected
+        );
+
+        assert!(new_config(&["data"], json!({ "op": "hmac", "key": "k" })).is_ok());
+        assert!(new_config(&["data", "key"], json!({ "op": "hmac" })).is_ok());
+        assert!(new_config(&["data"], json!({ "op": "hash" })).is_ok());
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(encode(&[0xfb, 0xff], Encoding::Hex), "fbff");
+        assert_eq!(encode(&[0xfb, 0xff], Encoding::Base64), "+/8=");
+        assert_eq!(encode(&[0xfb, 0xff], Encoding::Base64Url), "-_8");
+    }
+}
@@ -0,0 +1,318 @@
+use proxy_wasm::traits::*;
+use serde_json::{Map, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    FromCsv,
+    ToCsv,
+}
+
+#[derive(Clone, Debug)]
+pub struct CsvConfig {
+    action: Action,
+    delimiter: char,
+    header: bool,
+}
+
+impl NodeConfig for CsvConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Csv {
+    config: CsvConfig,
+}
+
+/// Splits CSV text into records of fields. Fields may be quoted with `"`,
+/// in which case they can contain delimiters, line breaks and doubled
+/// quotes. Empty lines are skipped.
+fn parse_records(s: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+fn write_field(out: &mut String, field: &str, delimiter: char) {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+fn write_record<'a>(out: &mut String, fields: impl Iterator<Item = &'a str>, delimiter: char) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            out.push(delimiter);
+        }
+        write_field(out, field, delimiter);
+    }
+    out.push_str("\r\n");
+}
+
+fn to_field(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+impl CsvConfig {
+    fn decode(&self, s: &str) -> Result<Value, String> {
+        let mut records = parse_records(s, self.delimiter)?.into_iter();
+
+        if !self.header {
+            return Ok(records
+                .map(|r| Value::Array(r.into_iter().map(Value::String).collect()))
+                .collect());
+        }
+
+        let header = records.next().unwrap_or_default();
+        let mut rows = vec![];
+        for (i, record) in records.enumerate() {
+            if record.len() > header.len() {
+                return Err(format!(
+                    "row {} has {} fields, the header has {}",
+                    i + 1,
+                    record.len(),
+                    header.len()
+                ));
+            }
+            let row: Map<String, Value> = header
+                .iter()
+                .cloned()
+                .zip(record.into_iter().map(Value::String))
+                .collect();
+            rows.push(Value::Object(row));
+        }
+        Ok(Value::Array(rows))
+    }
+
+    fn encode(&self, value: &Value) -> Result<String, String> {
+        let Value::Array(rows) = value else {
+            return Err("input must be an array".to_string());
+        };
+        let mut out = String::new();
+
+        if !self.header {
+            for row in rows {
+                let Value::Array(fields) = row else {
+                    return Err("rows must be arrays".to_string());
+                };
+                let fields: Vec<String> = fields.iter().map(|v| to_field(Some(v))).collect();
+                write_record(&mut out, fields.iter().map(|f| f.as_str()), self.delimiter);
+            }
+            return Ok(out);
+        }
+
+        // columns are taken from the keys of all rows, in the order first seen
+        let mut columns: Vec<&str> = vec![];
+        for row in rows {
+            let Value::Object(map) = row else {
+                return Err("rows must be objects".to_string());
+            };
+            for k in map.keys() {
+                if !columns.contains(&k.as_str()) {
+                    columns.push(k);
+                }
+            }
+        }
+
+        write_record(&mut out, columns.iter().copied(), self.delimiter);
+        for row in rows {
+            let fields: Vec<String> = columns.iter().map(|c| to_field(row.get(c))).collect();
+            write_record(&mut out, fields.iter().map(|f| f.as_str()), self.delimiter);
+        }
+        Ok(out)
+    }
+
+    fn apply(&self, payload: &Payload) -> Result<Payload, String> {
+        match self.action {
+            Action::FromCsv => {
+                let value = match payload {
+                    Payload::Raw(bytes, _) => {
+                        let s = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+                        self.decode(s)?
+                    }
+                    Payload::Json(Value::String(s)) => self.decode(s)?,
+                    Payload::Json(_) => return Err("input must be a string".to_string()),
+                    Payload::Error(e) => return Err(e.clone()),
+                };
+                Ok(Payload::Json(value))
+            }
+            Action::ToCsv => {
+                let value = payload.to_json()?;
+                let content_type = match self.delimiter {
+                    '\t' => "text/tab-separated-values",
+                    _ => "text/csv",
+                };
+                let csv = self.encode(&value)?.into_bytes();
+                Ok(Payload::Raw(csv.into(), Some(content_type.to_string())))
+            }
+        }
+    }
+}
+
+impl Node for Csv {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match input.data.first().unwrap_or(&None) {
+            Some(payload) => match self.config.apply(payload) {
+                Ok(p) => Done(Some(p)),
+                Err(e) => Fail(Some(Payload::Error(format!("csv: {e}")))),
+            },
+            None => Done(None),
+        }
+    }
+}
+
+pub struct CsvFactory {}
+
+impl NodeFactory for CsvFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let action = match get_config_value::<String>(bt, "action").as_deref() {
+            Some("from_csv") | None => Action::FromCsv,
+            Some("to_csv") => Action::ToCsv,
+            Some(other) => {
+                return Err(format!("csv: node '{name}': invalid action '{other}'"));
+            }
+        };
+
+        let delimiter = match get_config_value::<String>(bt, "delimiter") {
+            None => ',',
+            Some(d) => {
+                let mut chars = d.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c != '"' && c != '\n' && c != '\r' => c,
+                    _ => {
+                        return Err(format!(
+                            "csv: node '{name}': 'delimiter' must be a single character"
+                        ))
+                    }
+                }
+            }
+        };
+
+        Ok(Box::new(CsvConfig {
+            action,
+            delimiter,
+            header: get_config_value(bt, "header").unwrap_or(true),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<CsvConfig>() {
+            Some(cc) => Box::new(Csv { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["action", "delimiter", "header"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config(delimiter: char, header: bool) -> CsvConfig {
+        CsvConfig {
+            action: Action::FromCsv,
+            delimiter,
+            header,
+        }
+    }
+
+    #[test]
+    fn with_header() {
+        let cc = config(',', true);
+        let csv = "id,name,note\r\n1,\"Doe, Jane\",\"said \"\"hi\"\"\"\n2,Bob\n";
+        let value = cc.decode(csv).unwrap();
+        assert_eq!(
+            value,
+            json!([
+                { "id": "1", "name": "Doe, Jane", "note": "said \"hi\"" },
+                { "id": "2", "name": "Bob" }
+            ])
+        );
+
+        let out = cc.encode(&value).unwrap();
+        assert_eq!(
+            out,
+            "id,name,note\r\n1,\"Doe, Jane\",\"said \"\"hi\"\"\"\r\n2,Bob,\r\n"
+        );
+    }
+
+    #[test]
+    fn without_header() {
+        let cc = config('\t', false);
+        let value = cc.decode("a\tb\n\nc\td").unwrap();
+        assert_eq!(value, json!([["a", "b"], ["c", "d"]]));
+        assert_eq!(cc.encode(&value).unwrap(), "a\tb\r\nc\td\r\n");
+    }
+
+    #[test]
+    fn invalid() {
+        let cc = config(',', true);
+        assert!(cc.decode("a,b\n1,2,3").is_err());
+        assert!(cc.decode("a\n\"1").is_err());
+    }
+}
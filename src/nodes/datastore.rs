@@ -0,0 +1,293 @@
+use proxy_wasm::traits::*;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+/// Number of attempts to update an entry when racing
+/// with other workers updating the same shared data key.
+const MAX_CAS_ATTEMPTS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Get,
+    Set,
+    Incr,
+}
+
+#[derive(Clone, Debug)]
+pub struct DatastoreConfig {
+    name: String,
+    mode: Mode,
+    key: String,
+    ttl: Option<u64>,
+    if_absent: bool,
+}
+
+impl NodeConfig for DatastoreConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_sink(&self) -> bool {
+        self.mode != Mode::Get
+    }
+}
+
+#[derive(Clone)]
+pub struct Datastore {
+    config: DatastoreConfig,
+}
+
+/// Shared data has no expiry of its own, so entries
+/// are stored along with their expiration time.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+/// Returns the value of a stored entry, unless it has expired.
+fn entry_value(bytes: &[u8], now: u64) -> Option<Value> {
+    serde_json::from_slice::<Entry>(bytes)
+        .ok()
+        .filter(|e| e.expires.is_none_or(|t| t > now))
+        .map(|e| e.value)
+}
+
+fn payload_to_key(payload: Option<&Payload>) -> Result<Option<String>, String> {
+    match payload {
+        None => Ok(None),
+        Some(Payload::Raw(bytes, _)) => Ok(Some(String::from_utf8_lossy(bytes).to_string())),
+        Some(Payload::Json(Value::String(s))) => Ok(Some(s.clone())),
+        Some(Payload::Json(v)) => Ok(Some(v.to_string())),
+        Some(Payload::Error(e)) => Err(e.clone()),
+    }
+}
+
+impl Datastore {
+    fn shm_key(&self, suffix: Option<String>) -> String {
+        match suffix {
+            Some(s) => format!("datakit:datastore:{}:{}", self.config.key, s),
+            None => format!("datakit:datastore:{}", self.config.key),
+        }
+    }
+
+    fn load(&self, ctx: &dyn HttpContext, key: &str, now: u64) -> (Option<Value>, Option<u32>) {
+        let (bytes, cas) = ctx.get_shared_data(key);
+        (bytes.and_then(|b| entry_value(&b, now)), cas)
+    }
+
+    /// Updates the entry with the given function, retrying if another
+    /// worker updated it concurrently. The function returns the value to
+    /// store, or `None` to leave the entry as it is.
+    fn update<F>(&self, ctx: &dyn HttpContext, key: &str, now: u64, f: F) -> Result<Value, String>
+    where
+        F: Fn(Option<Value>) -> Result<(Value, bool), String>,
+    {
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let (current, cas) = self.load(ctx, key, now);
+            let (value, store) = f(current)?;
+            if !store {
+                return Ok(value);
+            }
+
+            let entry = Entry {
+                value,
+                expires: self.config.ttl.map(|ttl| now + ttl),
+            };
+            let bytes = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
+
+            match ctx.set_shared_data(key, Some(&bytes), cas) {
+                Ok(()) => return Ok(entry.value),
+                Err(Status::CasMismatch) => continue,
+                Err(status) => return Err(format!("failed storing '{key}': {:?}", status)),
+            }
+        }
+
+        Err(format!("failed storing '{key}': too much contention"))
+    }
+
+    fn exec(&self, ctx: &dyn HttpContext, input: &Input) -> Result<Option<Value>, String> {
+        let arg = |i: usize| *input.data.get(i).unwrap_or(&None);
+        let now = ctx
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        match self.config.mode {
+            Mode::Get => {
+                let key = self.shm_key(payload_to_key(arg(0))?);
+                Ok(self.load(ctx, &key, now).0)
+            }
+            Mode::Incr => {
+                let key = self.shm_key(payload_to_key(arg(0))?);
+                let value = self.update(ctx, &key, now, |current| {
+                    let n = current.and_then(|v| v.as_i64()).unwrap_or(0);
+                    Ok((Value::from(n + 1), true))
+                })?;
+                Ok(Some(value))
+            }
+            Mode::Set => {
+                let key = self.shm_key(payload_to_key(arg(1))?);
+                let new_value = match arg(0) {
+                    Some(p) => p.to_json()?,
+                    None => Value::Null,
+                };
+                let value = self.update(ctx, &key, now, |current| match current {
+                    Some(v) if self.config.if_absent => Ok((v, false)),
+                    _ => Ok((new_value.clone(), true)),
+                })?;
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+impl Node for Datastore {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        match self.exec(ctx, input) {
+            Ok(value) => Done(value.map(Payload::Json)),
+            Err(e) => Fail(Some(Payload::Error(format!(
+                "datastore: node '{}': {e}",
+                self.config.name
+            )))),
+        }
+    }
+}
+
+pub struct DatastoreFactory {}
+
+impl NodeFactory for DatastoreFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let mode = match get_config_value::<String>(bt, "mode").as_deref() {
+            Some("get") | None => Mode::Get,
+            Some("set") => Mode::Set,
+            Some("incr") => Mode::Incr,
+            Some(other) => {
+                return Err(format!("datastore: node '{name}': invalid mode '{other}'"));
+            }
+        };
+
+        let Some(key) = get_config_value(bt, "key") else {
+            return Err(format!("datastore: node '{name}': missing 'key'"));
+        };
+
+        Ok(Box::new(DatastoreConfig {
+            name: name.to_string(),
+            mode,
+            key,
+            ttl: get_config_value(bt, "ttl"),
+            if_absent: get_config_value(bt, "if_absent").unwrap_or(false),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<DatastoreConfig>() {
+            Some(cc) => Box::new(Datastore { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["mode", "key", "ttl", "if_absent"]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["key"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn new_config(bt: Value) -> Result<DatastoreConfig, String> {
+        let Value::Object(map) = bt else {
+            panic!("config must be an object");
+        };
+        let bt = map.into_iter().collect();
+        let config = DatastoreFactory {}.new_config("STORE", &[], &bt)?;
+        Ok(config
+            .as_any()
+            .downcast_ref::<DatastoreConfig>()
+            .unwrap()
+            .clone())
+    }
+
+    #[test]
+    fn config_modes() {
+        let config = new_config(json!({ "key": "hits" })).unwrap();
+        assert_eq!(config.mode, Mode::Get);
+        assert!(!config.is_sink());
+
+        let config = new_config(json!({ "key": "hits", "mode": "incr", "ttl": 60 })).unwrap();
+        assert_eq!(config.mode, Mode::Incr);
+        assert_eq!(config.ttl, Some(60));
+        assert!(config.is_sink());
+
+        assert_eq!(
+            new_config(json!({ "key": "hits", "mode": "delete" })).err(),
+            Some("datastore: node 'STORE': invalid mode 'delete'".to_string())
+        );
+        assert_eq!(
+            new_config(json!({ "mode": "set" })).err(),
+            Some("datastore: node 'STORE': missing 'key'".to_string())
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let node = Datastore {
+            config: new_config(json!({ "key": "users" })).unwrap(),
+        };
+        assert_eq!(node.shm_key(None), "datakit:datastore:users");
+        assert_eq!(
+            node.shm_key(Some("alice".to_string())),
+            "datakit:datastore:users:alice"
+        );
+
+        let raw = Payload::Raw(b"alice".to_vec().into(), None);
+        assert_eq!(payload_to_key(Some(&raw)), Ok(Some("alice".to_string())));
+        let json = Payload::Json(json!(42));
+        assert_eq!(payload_to_key(Some(&json)), Ok(Some("42".to_string())));
+        assert_eq!(payload_to_key(None), Ok(None));
+        let error = Payload::Error("boom".to_string());
+        assert_eq!(payload_to_key(Some(&error)), Err("boom".to_string()));
+    }
+
+    #[test]
+    fn entries() {
+        let entry = Entry {
+            value: json!({ "n": 1 }),
+            expires: None,
+        };
+        let bytes = serde_json::to_vec(&entry).unwrap();
+        assert_eq!(bytes, br#"{"value":{"n":1}}"#);
+        assert_eq!(entry_value(&bytes, 1_000), Some(json!({ "n": 1 })));
+
+        let entry = Entry {
+            value: json!("v"),
+            expires: Some(1_000),
+        };
+        let bytes = serde_json::to_vec(&entry).unwrap();
+        assert_eq!(entry_value(&bytes, 999), Some(json!("v")));
+        assert_eq!(entry_value(&bytes, 1_000), None);
+
+        assert_eq!(entry_value(b"not json", 0), None);
+    }
+}
@@ -0,0 +1,40 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig};
+
+/// Stands in for a node disabled with `enabled: false`. It does not
+/// look at its inputs, and produces the node's `fallback` value, if any.
+#[derive(Clone, Debug)]
+pub struct DisabledConfig {
+    fallback: Option<Value>,
+}
+
+impl NodeConfig for DisabledConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Disabled {
+    config: DisabledConfig,
+}
+
+impl Node for Disabled {
+    fn run(&self, _ctx: &dyn HttpContext, _input: &Input) -> State {
+        Done(self.config.fallback.clone().map(Payload::Json))
+    }
+}
+
+pub fn new_config(fallback: Option<Value>) -> Box<dyn NodeConfig> {
+    Box::new(DisabledConfig { fallback })
+}
+
+pub fn new_node(config: &dyn NodeConfig) -> Box<dyn Node> {
+    match config.as_any().downcast_ref::<DisabledConfig>() {
+        Some(cc) => Box::new(Disabled { config: cc.clone() }),
+        None => panic!("incompatible NodeConfig"),
+    }
+}
@@ -0,0 +1,768 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::In => "in",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Func {
+    Len,
+    Lower,
+    Upper,
+    Trim,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Split,
+    Join,
+    ToString,
+    ToNumber,
+    Default,
+}
+
+/// The functions of the language, with their number of arguments.
+const FUNCTIONS: [(&str, Func, usize); 12] = [
+    ("len", Func::Len, 1),
+    ("lower", Func::Lower, 1),
+    ("upper", Func::Upper, 1),
+    ("trim", Func::Trim, 1),
+    ("contains", Func::Contains, 2),
+    ("starts_with", Func::StartsWith, 2),
+    ("ends_with", Func::EndsWith, 2),
+    ("split", Func::Split, 2),
+    ("join", Func::Join, 2),
+    ("string", Func::ToString, 1),
+    ("number", Func::ToNumber, 1),
+    ("default", Func::Default, 2),
+];
+
+impl Func {
+    fn name(self) -> &'static str {
+        FUNCTIONS
+            .iter()
+            .find(|(_, f, _)| *f == self)
+            .map_or("?", |(name, _, _)| *name)
+    }
+}
+
+/// An expression, compiled from its source when the node is configured.
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    /// An input, by position.
+    Input(usize),
+    /// The object of all inputs, keyed by name.
+    Inputs,
+    Field(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    List(Vec<Expr>),
+    Object(Vec<(String, Expr)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPS: [&str; 24] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")", "[", "]",
+    "{", "}", ",", ".", ":", "?",
+];
+
+/// Splits the source into tokens, along with their positions.
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.parse() {
+                Ok(n) => Token::Num(n),
+                Err(_) => return Err(format!("invalid number '{text}' at position {start}")),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Token::Ident(chars[start..i].iter().collect())
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("unterminated string at position {start}")),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        s.push(match chars.get(i) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(&e @ ('\\' | '"' | '\'')) => e,
+                            _ => return Err(format!("invalid escape at position {i}")),
+                        });
+                    }
+                    Some(&ch) => s.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            Token::Str(s)
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match OPS.iter().find(|op| rest.starts_with(*op)) {
+                Some(&op) => {
+                    i += op.len();
+                    Token::Op(op)
+                }
+                None => return Err(format!("unexpected '{c}' at position {i}")),
+            }
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    len: usize,
+    inputs: &'a [String],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, msg: &str) -> String {
+        let at = self.tokens.get(self.pos).map_or(self.len, |(at, _)| *at);
+        format!("{msg} at position {at}")
+    }
+
+    fn eat(&mut self, op: &'static str) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), String> {
+        if !self.eat(op) {
+            return Err(self.error(&format!("expected '{op}'")));
+        }
+        Ok(())
+    }
+
+    fn parse(mut self) -> Result<Expr, String> {
+        let expr = self.cond()?;
+        match self.peek() {
+            None => Ok(expr),
+            Some(_) => Err(self.error("unexpected token")),
+        }
+    }
+
+    fn cond(&mut self) -> Result<Expr, String> {
+        let expr = self.or()?;
+        if !self.eat("?") {
+            return Ok(expr);
+        }
+        let then = self.cond()?;
+        self.expect(":")?;
+        let otherwise = self.cond()?;
+        Ok(Expr::Cond(
+            Box::new(expr),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Op("==")) => BinOp::Eq,
+            Some(Token::Op("!=")) => BinOp::Ne,
+            Some(Token::Op("<")) => BinOp::Lt,
+            Some(Token::Op("<=")) => BinOp::Le,
+            Some(Token::Op(">")) => BinOp::Gt,
+            Some(Token::Op(">=")) => BinOp::Ge,
+            Some(Token::Ident(id)) if id == "in" => BinOp::In,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.sum()?;
+        Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => BinOp::Add,
+                Some(Token::Op("-")) => BinOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("*")) => BinOp::Mul,
+                Some(Token::Op("/")) => BinOp::Div,
+                Some(Token::Op("%")) => BinOp::Rem,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat(".") {
+                match self.next() {
+                    Some(Token::Ident(field)) => expr = Expr::Field(Box::new(expr), field),
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.error("expected a field name"));
+                    }
+                }
+            } else if self.eat("[") {
+                let index = self.cond()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// Parses expressions separated by commas, up to the closing `end`.
+    fn list<T>(
+        &mut self,
+        end: &'static str,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut items = vec![];
+        if self.eat(end) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.eat(end) {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let Some(token) = self.next() else {
+            return Err(self.error("unexpected end of expression"));
+        };
+        Ok(match token {
+            Token::Num(n) => Expr::Literal(number(n)),
+            Token::Str(s) => Expr::Literal(Value::String(s)),
+            Token::Op("(") => {
+                let expr = self.cond()?;
+                self.expect(")")?;
+                expr
+            }
+            Token::Op("[") => Expr::List(self.list("]", Self::cond)?),
+            Token::Op("{") => Expr::Object(self.list("}", |p| {
+                let key = match p.next() {
+                    Some(Token::Str(key) | Token::Ident(key)) => key,
+                    _ => {
+                        p.pos -= 1;
+                        return Err(p.error("expected a key"));
+                    }
+                };
+                p.expect(":")?;
+                Ok((key, p.cond()?))
+            })?),
+            Token::Ident(id) => match id.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" => Expr::Literal(Value::Null),
+                _ if self.peek() == Some(&Token::Op("(")) => {
+                    self.pos += 1;
+                    let Some((_, func, arity)) = FUNCTIONS.iter().find(|(name, _, _)| *name == id)
+                    else {
+                        return Err(format!("unknown function '{id}'"));
+                    };
+                    let args = self.list(")", Self::cond)?;
+                    if args.len() != *arity {
+                        return Err(format!(
+                            "function '{id}' takes {arity} arguments, got {}",
+                            args.len()
+                        ));
+                    }
+                    Expr::Call(*func, args)
+                }
+                _ => match self.inputs.iter().position(|name| *name == id) {
+                    Some(i) => Expr::Input(i),
+                    None if id == "inputs" => Expr::Inputs,
+                    None => return Err(format!("unknown input '{id}'")),
+                },
+            },
+            Token::Op(_) => {
+                self.pos -= 1;
+                return Err(self.error("unexpected token"));
+            }
+        })
+    }
+}
+
+fn compile(src: &str, inputs: &[String]) -> Result<Expr, String> {
+    Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+        len: src.chars().count(),
+        inputs,
+    }
+    .parse()
+}
+
+/// Numbers without a fractional part are given as integers.
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9e15 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
+
+fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// `null` and `false` are false, as in `jq`; any other value is true.
+fn truthy(v: &Value) -> bool {
+    !matches!(v, Value::Null | Value::Bool(false))
+}
+
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
+}
+
+/// Strings are given as they are, other values as JSON.
+fn to_text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn binary(op: BinOp, a: Value, b: Value) -> Result<Value, String> {
+    let mismatch = |a: &Value, b: &Value| {
+        format!(
+            "cannot apply '{}' to {} and {}",
+            op.symbol(),
+            type_name(a),
+            type_name(b)
+        )
+    };
+
+    Ok(match (op, a, b) {
+        (BinOp::Eq, a, b) => Value::Bool(equals(&a, &b)),
+        (BinOp::Ne, a, b) => Value::Bool(!equals(&a, &b)),
+        (BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge, a, b) => {
+            let ord = compare(&a, &b).ok_or_else(|| mismatch(&a, &b))?;
+            Value::Bool(match op {
+                BinOp::Lt => ord.is_lt(),
+                BinOp::Le => ord.is_le(),
+                BinOp::Gt => ord.is_gt(),
+                _ => ord.is_ge(),
+            })
+        }
+        (BinOp::In, v, Value::Array(items)) => Value::Bool(items.iter().any(|x| equals(x, &v))),
+        (BinOp::In, Value::String(k), Value::Object(map)) => Value::Bool(map.contains_key(&k)),
+        (BinOp::In, Value::String(s), Value::String(t)) => Value::Bool(t.contains(&s)),
+        (BinOp::Add, Value::String(x), Value::String(y)) => Value::String(x + &y),
+        (BinOp::Add, Value::Array(mut x), Value::Array(y)) => {
+            x.extend(y);
+            Value::Array(x)
+        }
+        (op, Value::Number(x), Value::Number(y)) => {
+            let (x, y) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+            number(match op {
+                BinOp::Add => x + y,
+                BinOp::Sub => x - y,
+                BinOp::Mul => x * y,
+                BinOp::Div | BinOp::Rem if y == 0.0 => return Err("division by zero".to_string()),
+                BinOp::Div => x / y,
+                BinOp::Rem => x % y,
+                _ => return Err(mismatch(&x.into(), &y.into())),
+            })
+        }
+        (_, a, b) => return Err(mismatch(&a, &b)),
+    })
+}
+
+fn call(func: Func, args: &[Value]) -> Result<Value, String> {
+    Ok(match (func, args) {
+        (Func::Len, [Value::String(s)]) => Value::from(s.chars().count()),
+        (Func::Len, [Value::Array(items)]) => Value::from(items.len()),
+        (Func::Len, [Value::Object(map)]) => Value::from(map.len()),
+        (Func::Len, [Value::Null]) => Value::from(0),
+        (Func::Lower, [Value::String(s)]) => Value::String(s.to_lowercase()),
+        (Func::Upper, [Value::String(s)]) => Value::String(s.to_uppercase()),
+        (Func::Trim, [Value::String(s)]) => Value::from(s.trim()),
+        (Func::Contains, [Value::String(s), Value::String(t)]) => Value::Bool(s.contains(t)),
+        (Func::Contains, [Value::Array(items), v]) => {
+            Value::Bool(items.iter().any(|x| equals(x, v)))
+        }
+        (Func::StartsWith, [Value::String(s), Value::String(t)]) => Value::Bool(s.starts_with(t)),
+        (Func::EndsWith, [Value::String(s), Value::String(t)]) => Value::Bool(s.ends_with(t)),
+        (Func::Split, [Value::String(s), Value::String(sep)]) => {
+            Value::Array(s.split(sep.as_str()).map(Value::from).collect())
+        }
+        (Func::Join, [Value::Array(items), Value::String(sep)]) => Value::String(
+            items
+                .iter()
+                .map(to_text)
+                .collect::<Vec<_>>()
+                .join(sep.as_str()),
+        ),
+        (Func::ToString, [v]) => Value::String(to_text(v)),
+        (Func::ToNumber, [Value::Number(n)]) => Value::Number(n.clone()),
+        (Func::ToNumber, [Value::String(s)]) => match s.trim().parse() {
+            Ok(n) => number(n),
+            Err(_) => return Err(format!("number: invalid number '{s}'")),
+        },
+        (Func::Default, [Value::Null, v]) => v.clone(),
+        (Func::Default, [v, _]) => v.clone(),
+        (func, args) => {
+            let types: Vec<&str> = args.iter().map(type_name).collect();
+            return Err(format!(
+                "{}: unexpected arguments of type {}",
+                func.name(),
+                types.join(", ")
+            ));
+        }
+    })
+}
+
+impl Expr {
+    fn eval(&self, names: &[String], values: &[Value]) -> Result<Value, String> {
+        let eval = |expr: &Expr| expr.eval(names, values);
+
+        Ok(match self {
+            Expr::Literal(v) => v.clone(),
+            Expr::Input(i) => values[*i].clone(),
+            Expr::Inputs => {
+                Value::Object(names.iter().cloned().zip(values.iter().cloned()).collect())
+            }
+            Expr::Field(expr, field) => match eval(expr)? {
+                Value::Object(mut map) => map.remove(field).unwrap_or_default(),
+                Value::Null => Value::Null,
+                v => return Err(format!("cannot get '{field}' of {}", type_name(&v))),
+            },
+            Expr::Index(expr, index) => match (eval(expr)?, eval(index)?) {
+                (Value::Array(mut items), Value::Number(n)) => match n.as_u64() {
+                    Some(i) if (i as usize) < items.len() => items.swap_remove(i as usize),
+                    _ => Value::Null,
+                },
+                (Value::Object(mut map), Value::String(key)) => {
+                    map.remove(&key).unwrap_or_default()
+                }
+                (Value::Null, _) => Value::Null,
+                (v, i) => {
+                    return Err(format!(
+                        "cannot index {} with {}",
+                        type_name(&v),
+                        type_name(&i)
+                    ))
+                }
+            },
+            Expr::Call(func, args) => {
+                let args = args.iter().map(eval).collect::<Result<Vec<_>, _>>()?;
+                call(*func, &args)?
+            }
+            Expr::Not(expr) => Value::Bool(!truthy(&eval(expr)?)),
+            Expr::Neg(expr) => match eval(expr)? {
+                Value::Number(n) => number(-n.as_f64().unwrap_or(0.0)),
+                v => return Err(format!("cannot negate {}", type_name(&v))),
+            },
+            Expr::And(a, b) => Value::Bool(truthy(&eval(a)?) && truthy(&eval(b)?)),
+            Expr::Or(a, b) => Value::Bool(truthy(&eval(a)?) || truthy(&eval(b)?)),
+            Expr::Binary(op, a, b) => binary(*op, eval(a)?, eval(b)?)?,
+            Expr::Cond(c, then, otherwise) => {
+                if truthy(&eval(c)?) {
+                    eval(then)?
+                } else {
+                    eval(otherwise)?
+                }
+            }
+            Expr::List(items) => {
+                Value::Array(items.iter().map(eval).collect::<Result<Vec<_>, _>>()?)
+            }
+            Expr::Object(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, expr)| Ok((key.clone(), eval(expr)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExprConfig {
+    name: String,
+    expr: Expr,
+    inputs: Vec<String>,
+}
+
+impl NodeConfig for ExprConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Expression {
+    config: ExprConfig,
+}
+
+impl Node for Expression {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let config = &self.config;
+        let values = input
+            .data
+            .iter()
+            .map(|payload| payload.map_or(Ok(Value::Null), Payload::to_json_or_base64))
+            .collect::<Result<Vec<_>, _>>();
+
+        match values.and_then(|values| config.expr.eval(&config.inputs, &values)) {
+            Ok(value) => Done(Some(Payload::Json(value))),
+            Err(e) => {
+                let name = &config.name;
+                Fail(Some(Payload::Error(format!("expr: node '{name}': {e}"))))
+            }
+        }
+    }
+}
+
+pub struct ExprFactory {}
+
+impl NodeFactory for ExprFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(src) = get_config_value::<String>(bt, "expr") else {
+            return Err(format!("expr: node '{name}': 'expr' is required"));
+        };
+        let expr = compile(&src, inputs).map_err(|e| format!("expr: node '{name}': {e}"))?;
+
+        Ok(Box::new(ExprConfig {
+            name: name.to_string(),
+            expr,
+            inputs: inputs.to_vec(),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ExprConfig>() {
+            Some(cc) => Box::new(Expression { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["expr"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(src: &str, inputs: Value) -> Result<Value, String> {
+        let Value::Object(inputs) = inputs else {
+            panic!("inputs must be an object");
+        };
+        let names: Vec<String> = inputs.keys().cloned().collect();
+        let values: Vec<Value> = inputs.values().cloned().collect();
+        compile(src, &names)?.eval(&names, &values)
+    }
+
+    #[test]
+    fn expressions() {
+        let inputs = json!({
+            "request_headers": { "x-tier": "Gold", "x-count": "3" },
+            "body": { "items": [1, 2, 3], "user": { "name": "ana" } },
+        });
+        let cases = [
+            ("1 + 2 * 3", json!(7)),
+            ("(1 + 2) * 3 / 2", json!(4.5)),
+            ("-body.items[0] % 2", json!(-1)),
+            (
+                "body.user.name == 'ana' && len(body.items) >= 3",
+                json!(true),
+            ),
+            (
+                "!(2 in body.items) || body.missing.field == null",
+                json!(true),
+            ),
+            ("lower(request_headers['x-tier']) == \"gold\"", json!(true)),
+            (
+                "number(request_headers['x-count']) > 2 ? 'many' : 'few'",
+                json!("many"),
+            ),
+            ("upper(body.user.name) + '!'", json!("ANA!")),
+            ("join(split('a,b,c', ','), '-')", json!("a-b-c")),
+            (
+                "starts_with('bearer x', 'bearer') && contains(body.items, 2)",
+                json!(true),
+            ),
+            ("'user' in body && 'x' in 'xyz'", json!(true)),
+            ("default(body.missing, 'none')", json!("none")),
+            (
+                "{ id: body.user.name, 'n': len(inputs) }",
+                json!({ "id": "ana", "n": 2 }),
+            ),
+            ("[string(1), trim(' a ')]", json!(["1", "a"])),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(eval(src, inputs.clone()), Ok(expected), "{src}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let inputs = json!({ "body": { "n": 1 } });
+        assert!(eval("1 +", inputs.clone()).is_err());
+        assert!(eval("nope + 1", inputs.clone()).is_err());
+        assert!(eval("nope(1)", inputs.clone()).is_err());
+        assert!(eval("len(1, 2)", inputs.clone()).is_err());
+        assert!(eval("'a' < 1", inputs.clone()).is_err());
+        assert!(eval("body.n / 0", inputs.clone()).is_err());
+        assert!(eval("body.n.x", inputs.clone()).is_err());
+        assert_eq!(
+            eval("1 ) 2", inputs),
+            Err("unexpected token at position 2".to_string())
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes;
+use crate::nodes::{node_state, Node, NodeConfig, NodeFactory};
+
+/// Name under which each array element is passed to the first node
+/// of the sub-chain, and each intermediate result to the next one.
+const ITEM_INPUT: &str = "item";
+
+struct SubNodeConfig {
+    node_type: String,
+    config: Box<dyn NodeConfig>,
+}
+
+#[derive(Clone)]
+pub struct ForeachConfig {
+    name: String,
+    chain: Rc<Vec<SubNodeConfig>>,
+    max_parallel: usize,
+}
+
+impl NodeConfig for ForeachConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Progress of a single array element through the sub-chain, with the
+/// state of the sub-chain nodes for it.
+struct Item {
+    step: usize,
+    value: Option<Payload>,
+    token: Option<u32>,
+    states: Vec<Option<Box<dyn Any>>>,
+}
+
+pub struct Foreach {
+    config: ForeachConfig,
+    chain: Vec<Box<dyn Node>>,
+}
+
+#[derive(Default)]
+struct ForeachState {
+    items: RefCell<Vec<Item>>,
+}
+
+impl Foreach {
+    fn is_started(item: &Item) -> bool {
+        item.step > 0 || item.token.is_some()
+    }
+
+    fn is_finished(&self, item: &Item) -> bool {
+        item.step == self.chain.len()
+    }
+
+    /// Runs the sub-chain for as many items as possible, starting at most
+    /// `max_parallel` items at a time, until every item is either finished
+    /// or waiting on an HTTP call. Items start in order, so the ones held
+    /// back are only held back while others are waiting.
+    fn advance(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        let state = node_state::<ForeachState>(input.state);
+        let mut items = state.items.borrow_mut();
+
+        let mut active = items
+            .iter()
+            .filter(|i| Self::is_started(i) && !self.is_finished(i))
+            .count();
+
+        let mut failure = None;
+        'items: for item in items.iter_mut() {
+            if self.is_finished(item) || item.token.is_some() {
+                continue;
+            }
+            if !Self::is_started(item) {
+                if active >= self.config.max_parallel {
+                    continue;
+                }
+                active += 1;
+            }
+
+            while !self.is_finished(item) {
+                let data = [item.value.as_ref()];
+                let sub_input = Input {
+                    data: &data,
+                    phase: input.phase,
+                    token_id: None,
+                    dry_run: input.dry_run,
+                    state: item.states[item.step].as_deref(),
+                };
+
+                match self.chain[item.step].run(ctx, &sub_input) {
+                    Done(value) => {
+                        item.value = value;
+                        item.step += 1;
+                    }
+                    Waiting(token) => {
+                        item.token = Some(token);
+                        break;
+                    }
+                    Fail(p) => {
+                        failure = Some(p);
+                        break 'items;
+                    }
+                }
+            }
+
+            if self.is_finished(item) {
+                active -= 1;
+            }
+        }
+
+        if let Some(p) = failure {
+            drop(items);
+            return self.fail(input, p);
+        }
+
+        let pending = items.iter().find_map(|i| i.token);
+        drop(items);
+
+        match pending {
+            Some(token) => Waiting(token),
+            None => self.collect(input),
+        }
+    }
+
+    /// Fails the node, dropping the items: the responses of the calls
+    /// still in flight for other items are then ignored.
+    fn fail(&self, input: &Input, p: Option<Payload>) -> State {
+        node_state::<ForeachState>(input.state)
+            .items
+            .borrow_mut()
+            .clear();
+        Fail(p)
+    }
+
+    fn collect(&self, input: &Input) -> State {
+        let items = node_state::<ForeachState>(input.state).items.borrow();
+
+        let mut results = Vec::with_capacity(items.len());
+        for item in items.iter() {
+            match &item.value {
+                Some(p) => match p.to_json() {
+                    Ok(v) => results.push(v),
+                    Err(e) => {
+                        return Fail(Some(Payload::Error(format!(
+                            "foreach: node '{}': {e}",
+                            self.config.name
+                        ))))
+                    }
+                },
+                None => results.push(Value::Null),
+            }
+        }
+
+        Done(Some(Payload::Json(Value::Array(results))))
+    }
+}
+
+impl Node for Foreach {
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(ForeachState::default()))
+    }
+
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        let elements = match input.data.first().unwrap_or(&None) {
+            Some(Payload::Json(Value::Array(elements))) => elements.clone(),
+            Some(Payload::Json(Value::Null)) | None => vec![],
+            Some(_) => {
+                return Fail(Some(Payload::Error(format!(
+                    "foreach: node '{}': input must be an array",
+                    self.config.name
+                ))))
+            }
+        };
+
+        *node_state::<ForeachState>(input.state).items.borrow_mut() = elements
+            .into_iter()
+            .map(|v| Item {
+                step: 0,
+                value: Some(Payload::Json(v)),
+                token: None,
+                states: self.chain.iter().map(|node| node.new_state()).collect(),
+            })
+            .collect();
+
+        self.advance(ctx, input)
+    }
+
+    fn resume(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        {
+            let mut items = node_state::<ForeachState>(input.state).items.borrow_mut();
+            let Some(item) = items
+                .iter_mut()
+                .find(|i| i.token.is_some() && i.token == input.token_id)
+            else {
+                return Fail(Some(Payload::Error(format!(
+                    "foreach: node '{}': unexpected call response",
+                    self.config.name
+                ))));
+            };
+
+            let data = [item.value.as_ref()];
+            let sub_input = Input {
+                data: &data,
+                phase: input.phase,
+                token_id: input.token_id,
+                dry_run: input.dry_run,
+                state: item.states[item.step].as_deref(),
+            };
+
+            item.token = None;
+            match self.chain[item.step].resume(ctx, &sub_input) {
+                Done(value) => {
+                    item.value = value;
+                    item.step += 1;
+                }
+                Waiting(token) => {
+                    item.token = Some(token);
+                }
+                Fail(p) => {
+                    drop(items);
+                    return self.fail(input, p);
+                }
+            }
+        }
+
+        self.advance(ctx, input)
+    }
+
+    fn is_waiting_on(&self, state: Option<&dyn Any>, token_id: u32) -> bool {
+        node_state::<ForeachState>(state)
+            .items
+            .borrow()
+            .iter()
+            .any(|i| i.token == Some(token_id))
+    }
+}
+
+pub struct ForeachFactory {}
+
+impl NodeFactory for ForeachFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(Value::Array(sub_nodes)) = bt.get("nodes") else {
+            return Err(format!("foreach: node '{name}': missing 'nodes' list"));
+        };
+        if sub_nodes.is_empty() {
+            return Err(format!("foreach: node '{name}': 'nodes' list is empty"));
+        }
+
+        let inputs = vec![ITEM_INPUT.to_string()];
+        let mut chain = vec![];
+        for (i, sub_node) in sub_nodes.iter().enumerate() {
+            let Value::Object(map) = sub_node else {
+                return Err(format!("foreach: node '{name}': invalid node at {i}"));
+            };
+            let Some(Value::String(node_type)) = map.get("type") else {
+                return Err(format!("foreach: node '{name}': missing 'type' at {i}"));
+            };
+
+            let sub_name = format!("{name}.{i}");
+            let sub_bt: BTreeMap<String, Value> = map
+                .iter()
+                .filter(|(k, _)| k.as_str() != "type")
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+
+            nodes::check_config_keys(node_type, &sub_name, &sub_bt)?;
+            let config = nodes::new_config(node_type, &sub_name, &inputs, &sub_bt)?;
+            chain.push(SubNodeConfig {
+                node_type: node_type.clone(),
+                config,
+            });
+        }
+
+        Ok(Box::new(ForeachConfig {
+            name: name.to_string(),
+            chain: Rc::new(chain),
+            max_parallel: get_config_value::<usize>(bt, "max_parallel")
+                .unwrap_or(4)
+                .max(1),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ForeachConfig>() {
+            Some(cc) => {
+                let chain = cc
+                    .chain
+                    .iter()
+                    .map(|sub| {
+                        nodes::new_node(&sub.node_type, &*sub.config)
+                            .expect("node type was validated by new_config")
+                    })
+                    .collect();
+
+                Box::new(Foreach {
+                    config: cc.clone(),
+                    chain,
+                })
+            }
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["nodes", "max_parallel"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::Phase;
+    use crate::test_host::{self, TestContext};
+    use serde_json::json;
+
+    fn new_config(config: Value) -> Result<ForeachConfig, String> {
+        nodes::register_node("jq", Box::new(nodes::jq::JqFactory {}));
+        nodes::register_node("call", Box::new(nodes::call::CallFactory {}));
+
+        let Value::Object(map) = config else {
+            panic!("config must be an object");
+        };
+        let bt = map.into_iter().collect();
+        let config = ForeachFactory {}.new_config("EACH", &[], &bt)?;
+        Ok(config
+            .as_any()
+            .downcast_ref::<ForeachConfig>()
+            .unwrap()
+            .clone())
+    }
+
+    #[test]
+    fn chain() {
+        let config = new_config(json!({
+            "nodes": [
+                { "type": "jq", "jq": "$item.id" },
+                { "type": "jq", "jq": "$item * 2" }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(config.chain.len(), 2);
+        assert!(config.chain.iter().all(|sub| sub.node_type == "jq"));
+        assert_eq!(config.max_parallel, 4);
+    }
+
+    #[test]
+    fn max_parallel() {
+        let nodes = json!([{ "type": "jq", "jq": "." }]);
+
+        let config = new_config(json!({ "nodes": nodes, "max_parallel": 16 })).unwrap();
+        assert_eq!(config.max_parallel, 16);
+
+        let config = new_config(json!({ "nodes": nodes, "max_parallel": 0 })).unwrap();
+        assert_eq!(config.max_parallel, 1);
+    }
+
+    #[test]
+    fn invalid_chain() {
+        assert_eq!(
+            new_config(json!({})).err(),
+            Some("foreach: node 'EACH': missing 'nodes' list".to_string())
+        );
+        assert_eq!(
+            new_config(json!({ "nodes": [] })).err(),
+            Some("foreach: node 'EACH': 'nodes' list is empty".to_string())
+        );
+        assert_eq!(
+            new_config(json!({ "nodes": [{ "jq": "." }] })).err(),
+            Some("foreach: node 'EACH': missing 'type' at 0".to_string())
+        );
+        assert_eq!(
+            new_config(json!({ "nodes": [{ "type": "jq", "jq": ".", "bogus": 1 }] })).err(),
+            Some("jq: node 'EACH.0': unknown configuration key 'bogus'".to_string())
+        );
+        assert_eq!(
+            new_config(json!({ "nodes": [{ "type": "nope" }] })).err(),
+            Some("no such node type: nope".to_string())
+        );
+    }
+
+    fn new_node(config: Value) -> (Box<dyn Node>, Box<dyn Any>) {
+        let config = new_config(config).unwrap();
+        let node = ForeachFactory {}.new_node(&config);
+        let state = node.new_state().unwrap();
+        (node, state)
+    }
+
+    fn input<'a>(
+        data: &'a [Option<&'a Payload>],
+        state: &'a dyn Any,
+        token: Option<u32>,
+    ) -> Input<'a> {
+        Input {
+            data,
+            phase: if token.is_some() {
+                Phase::HttpCallResponse
+            } else {
+                Phase::HttpRequestHeaders
+            },
+            token_id: token,
+            dry_run: false,
+            state: Some(state),
+        }
+    }
+
+    /// Dispatches a call per item, and doubles the `n` of its response.
+    fn call_chain(max_parallel: usize) -> Value {
+        json!({
+            "nodes": [
+                { "type": "call", "url": "http://example.com/" },
+                { "type": "jq", "jq": "if $item.n < 0 then error(\"negative\") else $item.n * 2 end" }
+            ],
+            "max_parallel": max_parallel
+        })
+    }
+
+    /// Responds to the call with the given token, resuming the node.
+    fn respond(node: &dyn Node, state: &dyn Any, token: u32, n: i64) -> State {
+        let body = json!({ "n": n }).to_string();
+        test_host::set_call_response(
+            200,
+            &[("Content-Type", "application/json")],
+            body.as_bytes(),
+        );
+        assert!(node.is_waiting_on(Some(state), token));
+        node.resume(&TestContext, &input(&[], state, Some(token)))
+    }
+
+    /// The tokens of the calls dispatched since the last time, by item body.
+    fn dispatched() -> Vec<(String, u32)> {
+        test_host::take_calls()
+            .into_iter()
+            .map(|c| {
+                assert_eq!(c.upstream, "example.com");
+                assert!(c
+                    .headers
+                    .contains(&(":method".to_string(), "GET".to_string())));
+                (String::from_utf8(c.body).unwrap(), c.token)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_without_calls() {
+        let (node, state) = new_node(json!({
+            "nodes": [{ "type": "jq", "jq": "$item + 1" }, { "type": "jq", "jq": "$item * 10" }],
+            "max_parallel": 1
+        }));
+        let items = Payload::Json(json!([1, 2, 3]));
+
+        // items that run without waiting all finish in a single run,
+        // whatever the window
+        let state = node.run(&TestContext, &input(&[Some(&items)], &*state, None));
+        assert!(matches!(state, Done(Some(Payload::Json(v))) if v == json!([20, 30, 40])));
+    }
+
+    #[test]
+    fn run_with_max_parallel() {
+        let (node, state) = new_node(call_chain(2));
+        let items = Payload::Json(json!([1, 2, 3]));
+
+        let Waiting(first) = node.run(&TestContext, &input(&[Some(&items)], &*state, None)) else {
+            panic!("expected to wait on the calls");
+        };
+        let calls = dispatched();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0], ("1".to_string(), first));
+        let (t1, t2) = (calls[0].1, calls[1].1);
+
+        // the response of the second item starts the third one, while
+        // the node keeps waiting on the first
+        assert!(matches!(respond(&*node, &*state, t2, 20), Waiting(t) if t == t1));
+        let calls = dispatched();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, "3");
+        let t3 = calls[0].1;
+
+        assert!(matches!(respond(&*node, &*state, t1, 10), Waiting(t) if t == t3));
+        assert!(dispatched().is_empty());
+
+        let done = respond(&*node, &*state, t3, 30);
+        assert!(matches!(done, Done(Some(Payload::Json(v))) if v == json!([20, 40, 60])));
+        assert!(!node.is_waiting_on(Some(&*state), t3));
+    }
+
+    #[test]
+    fn failure_with_calls_in_flight() {
+        let (node, state) = new_node(call_chain(4));
+        let items = Payload::Json(json!([1, 2, 3]));
+
+        assert!(matches!(
+            node.run(&TestContext, &input(&[Some(&items)], &*state, None)),
+            Waiting(_)
+        ));
+        let calls = dispatched();
+        assert_eq!(calls.len(), 3);
+        let (t1, t2, t3) = (calls[0].1, calls[1].1, calls[2].1);
+
+        assert!(matches!(respond(&*node, &*state, t1, 10), Waiting(_)));
+        assert!(matches!(
+            respond(&*node, &*state, t2, -1),
+            Fail(Some(Payload::Error(e))) if e.contains("negative")
+        ));
+
+        // the calls of the other items no longer resume the node
+        assert!(!node.is_waiting_on(Some(&*state), t1));
+        assert!(!node.is_waiting_on(Some(&*state), t3));
+        assert!(dispatched().is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug)]
+pub struct ForeignFunctionConfig {
+    name: String,
+    function: String,
+    content_type: Option<String>,
+}
+
+impl NodeConfig for ForeignFunctionConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Calls a foreign function provided by the host, such as one backed by a
+/// user-supplied module that the host loads: it gets the bytes of the
+/// input, and returns the bytes of the output. The filter does not load
+/// modules itself.
+#[derive(Clone)]
+pub struct ForeignFunction {
+    config: ForeignFunctionConfig,
+}
+
+impl Node for ForeignFunction {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let config = &self.config;
+        let name = &config.name;
+        let function = &config.function;
+
+        let bytes = match input.data.first().copied().flatten().map(Payload::to_bytes) {
+            Some(Ok(bytes)) => Some(bytes),
+            Some(Err(e)) => return Fail(Some(Payload::Error(e))),
+            None => None,
+        };
+
+        match hostcalls::call_foreign_function(function, bytes.as_deref()) {
+            Ok(output) => Done(Some(Payload::Raw(
+                output.unwrap_or_default().into(),
+                config.content_type.clone(),
+            ))),
+            Err(status) => Fail(Some(Payload::Error(format!(
+                "foreign_function: node '{name}': function '{function}' failed: {status:?}"
+            )))),
+        }
+    }
+}
+
+pub struct ForeignFunctionFactory {}
+
+impl NodeFactory for ForeignFunctionFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(function) = get_config_value::<String>(bt, "function") else {
+            return Err(format!(
+                "foreign_function: node '{name}': 'function' is required"
+            ));
+        };
+
+        Ok(Box::new(ForeignFunctionConfig {
+            name: name.to_string(),
+            function,
+            content_type: get_config_value(bt, "content_type"),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ForeignFunctionConfig>() {
+            Some(cc) => Box::new(ForeignFunction { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["function", "content_type"]
+    }
+}
@@ -0,0 +1,264 @@
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+};
+use proxy_wasm::traits::*;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug)]
+pub struct GraphqlConfig {
+    max_depth: Option<usize>,
+    max_complexity: Option<usize>,
+}
+
+impl NodeConfig for GraphqlConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Graphql {
+    config: GraphqlConfig,
+}
+
+type Fragments<'a> = BTreeMap<&'a str, &'a FragmentDefinition<'a, String>>;
+
+/// Measures a selection set, expanding fragments: returns its depth,
+/// counting nested fields, and its complexity, the number of fields.
+fn measure<'a>(
+    set: &'a SelectionSet<'a, String>,
+    fragments: &Fragments<'a>,
+    visiting: &mut Vec<&'a str>,
+) -> Result<(usize, usize), String> {
+    let mut depth = 0;
+    let mut complexity = 0;
+
+    for selection in &set.items {
+        let (d, c) = match selection {
+            Selection::Field(field) => {
+                let (d, c) = measure(&field.selection_set, fragments, visiting)?;
+                (d + 1, c + 1)
+            }
+            Selection::InlineFragment(inline) => {
+                measure(&inline.selection_set, fragments, visiting)?
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.fragment_name.as_str();
+                if visiting.contains(&name) {
+                    return Err(format!("fragment '{name}' spreads itself"));
+                }
+                let fragment = fragments
+                    .get(name)
+                    .ok_or_else(|| format!("unknown fragment '{name}'"))?;
+                visiting.push(name);
+                let m = measure(&fragment.selection_set, fragments, visiting)?;
+                visiting.pop();
+                m
+            }
+        };
+        depth = depth.max(d);
+        complexity += c;
+    }
+
+    Ok((depth, complexity))
+}
+
+fn select_operation<'a>(
+    doc: &'a Document<'a, String>,
+    operation_name: Option<&str>,
+) -> Result<&'a OperationDefinition<'a, String>, String> {
+    let mut operations = doc.definitions.iter().filter_map(|d| match d {
+        Definition::Operation(op) => Some(op),
+        Definition::Fragment(_) => None,
+    });
+
+    match operation_name {
+        Some(wanted) => operations
+            .find(|op| {
+                let name = match op {
+                    OperationDefinition::Query(q) => q.name.as_deref(),
+                    OperationDefinition::Mutation(m) => m.name.as_deref(),
+                    OperationDefinition::Subscription(s) => s.name.as_deref(),
+                    OperationDefinition::SelectionSet(_) => None,
+                };
+                name == Some(wanted)
+            })
+            .ok_or_else(|| format!("unknown operation '{wanted}'")),
+        None => match (operations.next(), operations.next()) {
+            (Some(op), None) => Ok(op),
+            (None, _) => Err("no operation in query".to_string()),
+            (Some(_), Some(_)) => {
+                Err("operationName is required for queries with several operations".to_string())
+            }
+        },
+    }
+}
+
+impl GraphqlConfig {
+    /// Parses a GraphQL request, given as a JSON object with `query` and
+    /// optional `operationName` and `variables`, or as a plain query string.
+    fn analyze(&self, request: &Value) -> Result<Value, String> {
+        let (query, operation_name, variables) = match request {
+            Value::String(query) => (query.as_str(), None, Value::Null),
+            Value::Object(map) => (
+                map.get("query")
+                    .and_then(|q| q.as_str())
+                    .ok_or("request without 'query'")?,
+                map.get("operationName").and_then(|n| n.as_str()),
+                map.get("variables").cloned().unwrap_or(Value::Null),
+            ),
+            _ => return Err("input must be a GraphQL request".to_string()),
+        };
+
+        let doc = graphql_parser::parse_query::<String>(query).map_err(|e| e.to_string())?;
+        let fragments: Fragments = doc
+            .definitions
+            .iter()
+            .filter_map(|d| match d {
+                Definition::Fragment(f) => Some((f.name.as_str(), f)),
+                Definition::Operation(_) => None,
+            })
+            .collect();
+
+        let operation = select_operation(&doc, operation_name)?;
+        let (kind, name, set) = match operation {
+            OperationDefinition::SelectionSet(s) => ("query", None, s),
+            OperationDefinition::Query(q) => ("query", q.name.as_deref(), &q.selection_set),
+            OperationDefinition::Mutation(m) => ("mutation", m.name.as_deref(), &m.selection_set),
+            OperationDefinition::Subscription(s) => {
+                ("subscription", s.name.as_deref(), &s.selection_set)
+            }
+        };
+
+        let (depth, complexity) = measure(set, &fragments, &mut vec![])?;
+        if let Some(max) = self.max_depth.filter(|max| depth > *max) {
+            return Err(format!("query depth {depth} exceeds the limit of {max}"));
+        }
+        if let Some(max) = self.max_complexity.filter(|max| complexity > *max) {
+            return Err(format!(
+                "query complexity {complexity} exceeds the limit of {max}"
+            ));
+        }
+
+        let fields: Vec<&str> = set
+            .items
+            .iter()
+            .filter_map(|s| match s {
+                Selection::Field(f) => Some(f.name.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(json!({
+            "operation": kind,
+            "operation_name": name,
+            "fields": fields,
+            "depth": depth,
+            "complexity": complexity,
+            "variables": variables,
+        }))
+    }
+}
+
+impl Node for Graphql {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let request = match input.data.first().unwrap_or(&None) {
+            Some(Payload::Error(e)) => return Fail(Some(Payload::Error(e.clone()))),
+            Some(p) => match p.to_json() {
+                Ok(v) => v,
+                Err(e) => return Fail(Some(Payload::Error(format!("graphql: {e}")))),
+            },
+            None => return Done(None),
+        };
+
+        match self.config.analyze(&request) {
+            Ok(v) => Done(Some(Payload::Json(v))),
+            Err(e) => Fail(Some(Payload::Error(format!("graphql: {e}")))),
+        }
+    }
+}
+
+pub struct GraphqlFactory {}
+
+impl NodeFactory for GraphqlFactory {
+    fn new_config(
+        &self,
+        _name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        Ok(Box::new(GraphqlConfig {
+            max_depth: get_config_value(bt, "max_depth"),
+            max_complexity: get_config_value(bt, "max_complexity"),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<GraphqlConfig>() {
+            Some(cc) => Box::new(Graphql { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["max_depth", "max_complexity"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const QUERY: &str = "
+        query Items($n: Int) { items(first: $n) { id ...Owner } viewer { id } }
+        fragment Owner on Item { owner { name } }
+        mutation Remove { remove(id: 1) }
+    ";
+
+    #[test]
+    fn analyze() {
+        let config = GraphqlConfig {
+            max_depth: None,
+            max_complexity: None,
+        };
+        let request = json!({ "query": QUERY, "operationName": "Items", "variables": { "n": 2 } });
+        assert_eq!(
+            config.analyze(&request),
+            Ok(json!({
+                "operation": "query",
+                "operation_name": "Items",
+                "fields": ["items", "viewer"],
+                "depth": 3,
+                "complexity": 6,
+                "variables": { "n": 2 },
+            }))
+        );
+
+        assert!(config.analyze(&json!({ "query": QUERY })).is_err());
+        assert!(config
+            .analyze(&json!("{ a ...F } fragment F on A { ...F }"))
+            .is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let config = GraphqlConfig {
+            max_depth: Some(2),
+            max_complexity: None,
+        };
+        assert!(config.analyze(&json!("{ a { b } }")).is_ok());
+        assert!(config.analyze(&json!("{ a { b { c } } }")).is_err());
+
+        let config = GraphqlConfig {
+            max_depth: None,
+            max_complexity: Some(2),
+        };
+        assert!(config.analyze(&json!("{ a b c }")).is_err());
+    }
+}
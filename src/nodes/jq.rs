@@ -5,15 +5,22 @@ use proxy_wasm::traits::*;
 use serde_json::Value as JsonValue;
 use std::any::Any;
 use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 use crate::config::get_config_value;
 use crate::data::{Input, Payload, State};
 use crate::nodes::{Node, NodeConfig, NodeFactory};
+use crate::properties;
 
-#[derive(Clone, Debug)]
+/// Variables bound in every filter with metadata about the request:
+/// the current phase, the name of the matched route and the request id.
+const METADATA: [&str; 3] = ["__phase", "__route", "__request_id"];
+
+/// The filter is compiled once, when the configuration is loaded,
+/// and shared by the nodes of every request.
+#[derive(Clone)]
 pub struct JqConfig {
-    jq: String,
-    inputs: Vec<String>,
+    jq: Jq,
 }
 
 impl NodeConfig for JqConfig {
@@ -26,14 +33,20 @@ impl NodeConfig for JqConfig {
 pub struct Jq {
     inputs: Vec<String>,
     filter: Filter,
+    raw_output: bool,
+    uses_metadata: bool,
+    limits: Limits,
+    constants: Vec<Val>,
 }
 
-impl TryFrom<&JqConfig> for Jq {
-    type Error = String;
-
-    fn try_from(config: &JqConfig) -> Result<Self, Self::Error> {
-        Jq::new(&config.jq, config.inputs.clone())
-    }
+/// Limits on the execution of a filter. They are checked each time the
+/// filter produces a result, which also stops filters producing endless
+/// streams, such as `range(infinite)`; a single result that takes long
+/// to compute cannot be interrupted.
+#[derive(Clone, Copy, Debug, Default)]
+struct Limits {
+    max_results: Option<usize>,
+    max_time: Option<Duration>,
 }
 
 struct Errors(Vec<String>);
@@ -78,8 +91,40 @@ impl From<Errors> for State {
 }
 
 impl Jq {
-    fn new(jq: &str, inputs: Vec<String>) -> Result<Self, String> {
-        let mut defs = ParseCtx::new(inputs.clone());
+    /// Whether the object of all inputs is bound to `$inputs`, which
+    /// is not the case when one of the inputs is itself named `inputs`.
+    fn binds_inputs(inputs: &[String]) -> bool {
+        !inputs.iter().any(|name| name == "inputs")
+    }
+
+    pub fn new(jq: &str, inputs: Vec<String>) -> Result<Self, String> {
+        Self::compile(jq, "", &BTreeMap::new(), inputs)
+    }
+
+    /// Compiles a filter that can use the functions defined in
+    /// `definitions`, a sequence of jq `def` statements, and the
+    /// `constants`, each bound to a variable named after its key.
+    pub fn compile(
+        jq: &str,
+        definitions: &str,
+        constants: &BTreeMap<String, JsonValue>,
+        inputs: Vec<String>,
+    ) -> Result<Self, String> {
+        let is_identifier = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if let Some(name) = constants.keys().find(|name| !is_identifier(name)) {
+            return Err(format!("invalid variable name '{name}'"));
+        }
+
+        let mut vars = inputs.clone();
+        if Self::binds_inputs(&inputs) {
+            vars.push("inputs".to_string());
+        }
+        vars.extend(METADATA.map(String::from));
+        vars.extend(constants.keys().cloned());
+        let mut defs = ParseCtx::new(vars);
 
         defs.insert_natives(jaq_core::core());
         defs.insert_defs(jaq_std::std());
@@ -91,6 +136,17 @@ impl Jq {
             return Err("failed parsing filter inputs".to_string());
         }
 
+        if !definitions.trim().is_empty() {
+            let (parsed, errs) = jaq_parse::parse(definitions, jaq_parse::defs());
+            if !errs.is_empty() {
+                for err in errs {
+                    log::error!("definitions parse error: {err}");
+                }
+                return Err("invalid definitions".to_string());
+            }
+            defs.insert_defs(parsed.unwrap_or_default());
+        }
+
         let (parsed, errs) = jaq_parse::parse(jq, jaq_parse::main());
         if !errs.is_empty() {
             for err in errs {
@@ -114,10 +170,42 @@ impl Jq {
 
         let inputs = inputs.clone();
 
-        Ok(Jq { inputs, filter })
+        Ok(Jq {
+            inputs,
+            filter,
+            raw_output: false,
+            limits: Limits::default(),
+            constants: constants.values().cloned().map(Val::from).collect(),
+            // metadata is only read from the host for filters that use it
+            uses_metadata: jq.contains("$__") || definitions.contains("$__"),
+        })
+    }
+
+    /// Runs the filter, with errors joined into a single message.
+    pub fn eval(&self, inputs: &[Option<&Payload>]) -> Result<Vec<JsonValue>, String> {
+        self.exec(inputs).map_err(|errs| errs.0.join(", "))
     }
 
     fn exec(&self, inputs: &[Option<&Payload>]) -> Result<Vec<JsonValue>, Errors> {
+        self.exec_with(inputs, Default::default())
+    }
+
+    fn metadata(&self, ctx: &dyn HttpContext, input: &Input) -> [JsonValue; 3] {
+        if !self.uses_metadata {
+            return Default::default();
+        }
+        [
+            input.phase.name().into(),
+            properties::route_name(ctx).unwrap_or_default(),
+            properties::request_id(ctx).unwrap_or_default(),
+        ]
+    }
+
+    fn exec_with(
+        &self,
+        inputs: &[Option<&Payload>],
+        metadata: [JsonValue; 3],
+    ) -> Result<Vec<JsonValue>, Errors> {
         if inputs.len() != self.inputs.len() {
             return Err(Errors::from(format!(
                 "invalid number of inputs, expected: {}, got: {}",
@@ -128,43 +216,70 @@ impl Jq {
 
         let mut errs = Errors::new();
 
-        let vars_iter = self
+        let values: Vec<JsonValue> = self
             .inputs
             .iter()
             .zip(inputs.iter())
-            .map(|(name, input)| -> Val {
-                match input {
-                    Some(input) => match input.to_json() {
-                        Ok(value) => value.into(),
-                        Err(e) => {
-                            errs.push(format!("jq: input error at {name}: {e}"));
-                            Val::Null
-                        }
-                    },
-                    None => Val::Null,
-                }
-            });
+            .map(|(name, input)| match input {
+                Some(input) => match input.to_json_or_base64() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        errs.push(format!("jq: input error at {name}: {e}"));
+                        JsonValue::Null
+                    }
+                },
+                None => JsonValue::Null,
+            })
+            .collect();
+
+        // all inputs, keyed by name, are the input of the filter (`.`)
+        // and are also available as `$inputs`
+        let all: serde_json::Map<String, JsonValue> = self
+            .inputs
+            .iter()
+            .cloned()
+            .zip(values.iter().cloned())
+            .collect();
+        let input = Val::from(JsonValue::Object(all));
+
+        let mut vars: Vec<Val> = values.into_iter().map(Val::from).collect();
+        if Self::binds_inputs(&self.inputs) {
+            vars.push(input.clone());
+        }
+        vars.extend(metadata.map(Val::from));
+        vars.extend(self.constants.iter().cloned());
 
         let input_iter = {
             let iter = std::iter::empty::<Result<Val, String>>();
             let iter = Box::new(iter) as Box<dyn Iterator<Item = Result<Val, String>>>;
             RcIter::new(iter)
         };
-        let input = Val::Null;
 
-        let ctx = Ctx::new(vars_iter, &input_iter);
+        let ctx = Ctx::new(vars, &input_iter);
 
-        let results: Vec<JsonValue> = self
-            .filter
-            .run((ctx, input))
-            .map(|item| match item {
+        let started = Instant::now();
+        let mut results: Vec<JsonValue> = vec![];
+        for item in self.filter.run((ctx, input)) {
+            results.push(match item {
                 Ok(v) => v.into(),
                 Err(e) => {
                     errs.push(e.to_string());
                     JsonValue::Null
                 }
-            })
-            .collect();
+            });
+
+            if let Some(max) = self.limits.max_results.filter(|max| results.len() > *max) {
+                errs.push(format!("jq: filter produced more than {max} results"));
+                break;
+            }
+            if let Some(max) = self.limits.max_time.filter(|max| started.elapsed() > *max) {
+                errs.push(format!(
+                    "jq: filter exceeded its time limit of {} ms",
+                    max.as_millis()
+                ));
+                break;
+            }
+        }
 
         if !errs.is_empty() {
             return Err(errs);
@@ -174,9 +289,29 @@ impl Jq {
     }
 }
 
+/// Renders results as text, as `jq --raw-output` does: strings are given
+/// without quotes, other values as JSON, one result per line.
+fn raw_text(results: &[JsonValue]) -> String {
+    results
+        .iter()
+        .map(|v| match v {
+            JsonValue::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 impl Node for Jq {
-    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
-        match self.exec(input.data) {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        match self.exec_with(input.data, self.metadata(ctx, input)) {
+            Ok(results) if self.raw_output => State::Done(match results.len() {
+                0 => None,
+                _ => Some(Payload::Raw(
+                    raw_text(&results).into_bytes().into(),
+                    Some("text/plain".to_string()),
+                )),
+            }),
             Ok(mut results) => {
                 State::Done(match results.len() {
                     // empty
@@ -204,22 +339,53 @@ pub struct JqFactory {}
 impl NodeFactory for JqFactory {
     fn new_config(
         &self,
-        _name: &str,
+        name: &str,
         inputs: &[String],
         bt: &BTreeMap<String, JsonValue>,
     ) -> Result<Box<dyn NodeConfig>, String> {
+        let jq: String = get_config_value(bt, "jq").unwrap_or(".".to_string());
+        let definitions: String = get_config_value(bt, "definitions").unwrap_or_default();
+        let raw_output: bool = get_config_value(bt, "raw_output").unwrap_or(false);
+        let limits = Limits {
+            max_results: get_config_value(bt, "max_results"),
+            max_time: get_config_value(bt, "max_time_ms").map(Duration::from_millis),
+        };
+
+        let constants: BTreeMap<String, JsonValue> =
+            get_config_value(bt, "vars").unwrap_or_default();
+        let jq = Jq::compile(&jq, &definitions, &constants, inputs.to_vec())
+            .map_err(|e| format!("jq: node '{name}': {e}"))?;
+
         Ok(Box::new(JqConfig {
-            jq: get_config_value(bt, "jq").unwrap_or(".".to_string()),
-            inputs: inputs.to_vec(),
+            jq: Jq {
+                raw_output,
+                limits,
+                ..jq
+            },
         }))
     }
 
     fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
         match config.as_any().downcast_ref::<JqConfig>() {
-            Some(cc) => Box::new(Jq::try_from(cc).unwrap()),
+            Some(cc) => Box::new(cc.jq.clone()),
             None => panic!("incompatible NodeConfig"),
         }
     }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &[
+            "jq",
+            "definitions",
+            "vars",
+            "raw_output",
+            "max_results",
+            "max_time_ms",
+        ]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["vars"]
+    }
 }
 
 #[cfg(test)]
@@ -262,6 +428,97 @@ mod test {
         );
     }
 
+    #[test]
+    fn all_inputs() {
+        let jq = Jq::new("[., $inputs.b]", vec!["a".to_string(), "b".to_string()]).unwrap();
+
+        let a = Payload::Json(json!(1));
+        let res = jq.exec(&[Some(&a), None]);
+        let Ok(results) = res else {
+            panic!("unexpected jq error");
+        };
+
+        assert_eq!(results, vec![json!([{ "a": 1, "b": null }, null])]);
+    }
+
+    #[test]
+    fn definitions() {
+        let defs = "def double: . * 2; def quad: double | double;";
+        let jq = Jq::compile("$a | quad", defs, &BTreeMap::new(), vec!["a".to_string()]).unwrap();
+
+        let a = Payload::Json(json!(3));
+        let Ok(results) = jq.exec(&[Some(&a)]) else {
+            panic!("unexpected jq error");
+        };
+        assert_eq!(results, vec![json!(12)]);
+
+        assert!(Jq::compile(".", "def nope", &BTreeMap::new(), vec![]).is_err());
+    }
+
+    #[test]
+    fn raw_output() {
+        let results = [json!("a=1&b=2"), json!({ "c": 3 })];
+        assert_eq!(raw_text(&results[..1]), "a=1&b=2");
+        assert_eq!(raw_text(&results), "a=1&b=2\n{\"c\":3}");
+    }
+
+    #[test]
+    fn metadata() {
+        let jq = Jq::new("[$__phase, $__route, $__request_id]", vec![]).unwrap();
+        assert!(jq.uses_metadata);
+
+        let metadata = [json!("request_body"), json!("orders"), json!("abc")];
+        let Ok(results) = jq.exec_with(&[], metadata) else {
+            panic!("unexpected jq error");
+        };
+        assert_eq!(results, vec![json!(["request_body", "orders", "abc"])]);
+    }
+
+    #[test]
+    fn constants() {
+        let vars = BTreeMap::from([
+            ("api_version".to_string(), json!("v2")),
+            ("regions".to_string(), json!(["eu", "us"])),
+        ]);
+        let jq = Jq::compile("\"/\\($api_version)/\\($regions[0])\"", "", &vars, vec![]).unwrap();
+        let Ok(results) = jq.exec(&[]) else {
+            panic!("unexpected jq error");
+        };
+        assert_eq!(results, vec![json!("/v2/eu")]);
+
+        let vars = BTreeMap::from([("api-version".to_string(), json!("v2"))]);
+        assert!(Jq::compile(".", "", &vars, vec![]).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let jq = Jq::new("range(0; 1000)", vec![]).unwrap();
+        let jq = Jq {
+            limits: Limits {
+                max_results: Some(3),
+                max_time: None,
+            },
+            ..jq
+        };
+
+        let Err(errs) = jq.exec(&[]) else {
+            panic!("expected a failure");
+        };
+        assert_eq!(
+            errs.into_inner(),
+            vec!["jq: filter produced more than 3 results"]
+        );
+
+        let jq = Jq {
+            limits: Limits {
+                max_results: None,
+                max_time: Some(Duration::ZERO),
+            },
+            ..jq
+        };
+        assert!(jq.exec(&[]).is_err());
+    }
+
     #[test]
     fn invalid_filter_text() {
         let jq = Jq::new("nope!", Vec::new());
@@ -0,0 +1,271 @@
+use proxy_wasm::traits::*;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes;
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+pub struct LlmConfig {
+    name: String,
+    model: String,
+    system: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<u64>,
+    call_config: Box<dyn NodeConfig>,
+}
+
+impl NodeConfig for LlmConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Llm {
+    name: String,
+    model: String,
+    system: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<u64>,
+    call: Box<dyn Node>,
+}
+
+impl Llm {
+    /// Builds the `messages` list from the prompt input, which can be
+    /// a string, a list of messages, or an object with a `messages` field.
+    fn messages(&self, prompt: &Payload) -> Result<Vec<Value>, String> {
+        let mut messages = vec![];
+        if let Some(system) = &self.system {
+            messages.push(json!({ "role": "system", "content": system }));
+        }
+
+        match prompt.to_json()? {
+            Value::String(content) => {
+                messages.push(json!({ "role": "user", "content": content }));
+            }
+            Value::Array(list) => messages.extend(list),
+            Value::Object(mut obj) => match obj.remove("messages") {
+                Some(Value::Array(list)) => messages.extend(list),
+                _ => return Err("prompt object must have a 'messages' list".to_string()),
+            },
+            _ => return Err("prompt must be a string or a list of messages".to_string()),
+        }
+
+        Ok(messages)
+    }
+
+    fn request_body(&self, prompt: &Payload) -> Result<Payload, String> {
+        let mut body = json!({
+            "model": self.model,
+            "messages": self.messages(prompt)?,
+        });
+        if let Some(t) = self.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(n) = self.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
+
+        Ok(Payload::Json(body))
+    }
+
+    fn request_headers(headers: Option<&Payload>) -> Payload {
+        let mut map = match headers.and_then(|h| h.to_json().ok()) {
+            Some(Value::Object(map)) => map,
+            _ => serde_json::Map::new(),
+        };
+        if !map.keys().any(|k| k.eq_ignore_ascii_case("content-type")) {
+            map.insert("content-type".to_string(), json!("application/json"));
+        }
+
+        Payload::Json(Value::Object(map))
+    }
+
+    /// Extracts the completion and token usage from a chat completion response.
+    fn shape_response(response: &Value) -> Result<Value, String> {
+        if let Some(error) = response.get("error") {
+            let msg = error
+                .get("message")
+                .and_then(|m| m.as_str())
+                .map_or_else(|| error.to_string(), |m| m.to_string());
+            return Err(msg);
+        }
+
+        let choice = response
+            .get("choices")
+            .and_then(|c| c.get(0))
+            .ok_or_else(|| "response has no choices".to_string())?;
+
+        Ok(json!({
+            "content": choice.pointer("/message/content").cloned().unwrap_or(Value::Null),
+            "finish_reason": choice.get("finish_reason").cloned().unwrap_or(Value::Null),
+            "model": response.get("model").cloned().unwrap_or(Value::Null),
+            "usage": response.get("usage").cloned().unwrap_or(Value::Null),
+        }))
+    }
+
+    /// Shapes the state of the completed call into the node output.
+    fn response(&self, state: State) -> State {
+        match state {
+            Done(Some(payload)) => match payload.to_json() {
+                Ok(response) => match Self::shape_response(&response) {
+                    Ok(v) => Done(Some(Payload::Json(v))),
+                    Err(e) => self.fail(e),
+                },
+                Err(e) => self.fail(e),
+            },
+            Done(None) => self.fail("empty response".to_string()),
+            state => state,
+        }
+    }
+
+    fn fail(&self, msg: String) -> State {
+        Fail(Some(Payload::Error(format!(
+            "llm: node '{}': {msg}",
+            self.name
+        ))))
+    }
+}
+
+impl Node for Llm {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        let Some(prompt) = input.data.first().unwrap_or(&None) else {
+            return Done(None);
+        };
+        let headers = *input.data.get(1).unwrap_or(&None);
+
+        let body = match self.request_body(prompt) {
+            Ok(b) => b,
+            Err(e) => return self.fail(e),
+        };
+        let headers = Self::request_headers(headers);
+
+        let data = [Some(&body), Some(&headers)];
+        let call_input = Input {
+            data: &data,
+            phase: input.phase,
+            token_id: input.token_id,
+            dry_run: input.dry_run,
+            state: None,
+        };
+
+        match self.call.run(ctx, &call_input) {
+            // in dry runs, the call completes at once with its example
+            Done(response) if input.dry_run => self.response(Done(response)),
+            state => state,
+        }
+    }
+
+    fn resume(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        self.response(self.call.resume(ctx, input))
+    }
+}
+
+pub struct LlmFactory {}
+
+impl NodeFactory for LlmFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(model) = get_config_value(bt, "model") else {
+            return Err(format!("llm: node '{name}': missing 'model'"));
+        };
+
+        // the remaining fields configure the underlying call
+        let mut call_bt = bt.clone();
+        call_bt
+            .entry("method".to_string())
+            .or_insert_with(|| json!("POST"));
+        let call_config = nodes::new_config("call", name, inputs, &call_bt)?;
+
+        Ok(Box::new(LlmConfig {
+            name: name.to_string(),
+            model,
+            system: get_config_value(bt, "system"),
+            temperature: get_config_value(bt, "temperature"),
+            max_tokens: get_config_value(bt, "max_tokens"),
+            call_config,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<LlmConfig>() {
+            Some(cc) => Box::new(Llm {
+                name: cc.name.clone(),
+                model: cc.model.clone(),
+                system: cc.system.clone(),
+                temperature: cc.temperature,
+                max_tokens: cc.max_tokens,
+                call: nodes::new_node("call", &*cc.call_config)
+                    .expect("call node type is registered"),
+            }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        Some(&["prompt", "headers"])
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &[
+            "url",
+            "method",
+            "timeout",
+            "headers",
+            "example",
+            "model",
+            "system",
+            "temperature",
+            "max_tokens",
+        ]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["url", "headers", "model"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shapes_chat_completion() {
+        let response = json!({
+            "id": "chatcmpl-1",
+            "model": "gpt-4o-mini",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hello!" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 9, "completion_tokens": 3, "total_tokens": 12 }
+        });
+
+        assert_eq!(
+            Llm::shape_response(&response).unwrap(),
+            json!({
+                "content": "Hello!",
+                "finish_reason": "stop",
+                "model": "gpt-4o-mini",
+                "usage": { "prompt_tokens": 9, "completion_tokens": 3, "total_tokens": 12 }
+            })
+        );
+    }
+
+    #[test]
+    fn reports_provider_errors() {
+        let response = json!({ "error": { "message": "invalid api key" } });
+
+        assert_eq!(
+            Llm::shape_response(&response),
+            Err("invalid api key".to_string())
+        );
+    }
+}
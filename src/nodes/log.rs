@@ -0,0 +1,159 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::secret::REDACTED;
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    name: String,
+    level: log::Level,
+    prefix: Option<String>,
+    redact: Vec<String>,
+}
+
+impl NodeConfig for LogConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_sink(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Log {
+    config: LogConfig,
+}
+
+fn redact(value: &mut Value, keys: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if keys.contains(k) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact(v, keys);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redact(item, keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl LogConfig {
+    fn format(&self, payload: Option<&Payload>) -> String {
+        let text = match payload {
+            Some(Payload::Json(value)) => {
+                let mut value = value.clone();
+                redact(&mut value, &self.redact);
+                value.to_string()
+            }
+            Some(Payload::Raw(bytes, _)) => String::from_utf8_lossy(bytes).to_string(),
+            Some(Payload::Error(e)) => format!("error: {e}"),
+            None => "null".to_string(),
+        };
+
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}{text}"),
+            None => text,
+        }
+    }
+}
+
+impl Node for Log {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        for payload in input.data {
+            let msg = self.config.format(*payload);
+            log::log!(self.config.level, "log: node '{}': {msg}", self.config.name);
+        }
+
+        Done(None)
+    }
+}
+
+pub struct LogFactory {}
+
+impl NodeFactory for LogFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let level = match get_config_value::<String>(bt, "level").as_deref() {
+            Some("debug") => log::Level::Debug,
+            Some("info") | None => log::Level::Info,
+            Some("warn") => log::Level::Warn,
+            Some("error") => log::Level::Error,
+            Some(other) => {
+                return Err(format!("log: node '{name}': invalid level '{other}'"));
+            }
+        };
+
+        Ok(Box::new(LogConfig {
+            name: name.to_string(),
+            level,
+            prefix: get_config_value(bt, "prefix"),
+            redact: get_config_value(bt, "redact").unwrap_or_default(),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<LogConfig>() {
+            Some(cc) => Box::new(Log { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["level", "prefix", "redact"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_nested_keys() {
+        let config = LogConfig {
+            name: "test".to_string(),
+            level: log::Level::Info,
+            prefix: Some("> ".to_string()),
+            redact: vec!["password".to_string(), "token".to_string()],
+        };
+
+        let payload = Payload::Json(json!({
+            "user": "bob",
+            "password": "hunter2",
+            "sessions": [{ "token": "abc", "id": 1 }]
+        }));
+
+        let msg = config.format(Some(&payload));
+        let Some(logged) = msg.strip_prefix("> ") else {
+            panic!("expected prefix in '{msg}'");
+        };
+
+        assert!(!logged.contains(' '));
+        assert_eq!(
+            serde_json::from_str::<Value>(logged).unwrap(),
+            json!({
+                "user": "bob",
+                "password": "[REDACTED]",
+                "sessions": [{ "token": "[REDACTED]", "id": 1 }]
+            })
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use mlua::{HookTriggers, LuaOptions, LuaSerdeExt, StdLib, Table};
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+/// Scripts fail if they run for longer than this, unless configured otherwise.
+const DEFAULT_MAX_TIME: Duration = Duration::from_millis(100);
+
+/// The interpreter of a node uses at most this much memory, unless
+/// configured otherwise.
+const DEFAULT_MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// The functions of the base library available to scripts; the others
+/// load code or have effects outside of the interpreter.
+const BASE_FUNCTIONS: [&str; 17] = [
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "xpcall",
+];
+
+const LIBRARIES: [&str; 4] = ["math", "string", "table", "utf8"];
+
+#[derive(Clone, Debug)]
+pub struct LuaConfig {
+    name: String,
+    script: String,
+    inputs: Vec<String>,
+    max_time: Duration,
+    max_memory: usize,
+}
+
+impl NodeConfig for LuaConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Script {
+    config: LuaConfig,
+    lua: mlua::Lua,
+}
+
+/// Creates an interpreter with only the libraries that have no effects
+/// outside of it: no `io`, `os`, `package` or `debug`. Scripts do not run
+/// with its globals, but with those made by `new_env`.
+fn new_vm(max_memory: usize) -> mlua::Result<mlua::Lua> {
+    let lua = mlua::Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    // the metatable of strings leads to the string library
+    lua.load(r#"getmetatable("").__metatable = false"#).exec()?;
+    lua.set_memory_limit(max_memory)?;
+    Ok(lua)
+}
+
+/// Creates the globals of a run, with the functions of `BASE_FUNCTIONS`
+/// and read-only views of the libraries, so that scripts cannot keep
+/// state between requests, in globals or in the libraries.
+fn new_env(lua: &mlua::Lua) -> mlua::Result<Table<'_>> {
+    let globals = lua.globals();
+    let env = lua.create_table()?;
+    for name in BASE_FUNCTIONS {
+        env.set(name, globals.get::<_, mlua::Value>(name)?)?;
+    }
+
+    let read_only = lua.create_function(|_, _: mlua::MultiValue| -> mlua::Result<()> {
+        Err(mlua::Error::RuntimeError(
+            "libraries cannot be modified".to_string(),
+        ))
+    })?;
+    for name in LIBRARIES {
+        let meta = lua.create_table()?;
+        meta.set("__index", globals.get::<_, Table>(name)?)?;
+        meta.set("__newindex", read_only.clone())?;
+        meta.set("__metatable", false)?;
+        let library = lua.create_table()?;
+        library.set_metatable(Some(meta));
+        env.set(name, library)?;
+    }
+
+    env.set("_G", env.clone())?;
+    Ok(env)
+}
+
+impl Script {
+    fn eval(&self, inputs: &[Option<&Payload>]) -> mlua::Result<Option<Value>> {
+        let lua = &self.lua;
+        let env = new_env(lua)?;
+
+        // inputs are available by name, and as the `inputs` table
+        let all = lua.create_table()?;
+        for (name, input) in self.config.inputs.iter().zip(inputs) {
+            if let Some(payload) = input {
+                let value = payload
+                    .to_json_or_base64()
+                    .map_err(mlua::Error::RuntimeError)?;
+                let value = lua.to_value(&value)?;
+                all.set(name.as_str(), value.clone())?;
+                env.set(name.as_str(), value)?;
+            }
+        }
+        env.set("inputs", all)?;
+
+        let max = self.config.max_time;
+        let started = Instant::now();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(1000),
+            move |_, _| {
+                if started.elapsed() > max {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "script exceeded its time limit of {} ms",
+                        max.as_millis()
+                    )));
+                }
+                Ok(())
+            },
+        );
+
+        let result = lua
+            .load(&self.config.script)
+            .set_name(&self.config.name)
+            .set_environment(env)
+            .call::<_, mlua::Value>(());
+        lua.remove_hook();
+
+        match result? {
+            mlua::Value::Nil => Ok(None),
+            value => lua.from_value(value).map(Some),
+        }
+    }
+}
+
+impl Node for Script {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match self.eval(input.data) {
+            Ok(value) => Done(value.map(Payload::Json)),
+            Err(e) => {
+                let name = &self.config.name;
+                Fail(Some(Payload::Error(format!("lua: node '{name}': {e}"))))
+            }
+        }
+    }
+}
+
+pub struct LuaFactory {}
+
+impl NodeFactory for LuaFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(script) = get_config_value::<String>(bt, "script") else {
+            return Err(format!("lua: node '{name}': 'script' is required"));
+        };
+
+        let max_memory =
+            get_config_value(bt, "max_memory_bytes").unwrap_or(DEFAULT_MAX_MEMORY_BYTES);
+
+        // the script is compiled here only to report syntax errors
+        new_vm(max_memory)
+            .and_then(|lua| lua.load(&script).set_name(name).into_function().map(|_| ()))
+            .map_err(|e| format!("lua: node '{name}': {e}"))?;
+
+        Ok(Box::new(LuaConfig {
+            name: name.to_string(),
+            script,
+            inputs: inputs.to_vec(),
+            max_time: get_config_value(bt, "max_time_ms")
+                .map_or(DEFAULT_MAX_TIME, Duration::from_millis),
+            max_memory,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<LuaConfig>() {
+            Some(cc) => Box::new(Script {
+                config: cc.clone(),
+                lua: new_vm(cc.max_memory).expect("interpreter was created by new_config"),
+            }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["script", "max_time_ms", "max_memory_bytes"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn script(script: &str, inputs: &[&str]) -> Script {
+        let bt = BTreeMap::from([("script".to_string(), json!(script))]);
+        let inputs: Vec<String> = inputs.iter().map(|s| s.to_string()).collect();
+        let config = LuaFactory {}.new_config("test", &inputs, &bt).unwrap();
+        let config = config.as_any().downcast_ref::<LuaConfig>().unwrap();
+        Script {
+            config: config.clone(),
+            lua: new_vm(config.max_memory).unwrap(),
+        }
+    }
+
+    #[test]
+    fn eval() {
+        let body = Payload::Json(json!({ "items": [1, 2, 3] }));
+        let sum = script(
+            "local n = 0 for _, v in ipairs(inputs.body.items) do n = n + v end \
+             return { sum = n, first = body.items[1] }",
+            &["body"],
+        );
+        assert_eq!(
+            sum.eval(&[Some(&body)]).unwrap(),
+            Some(json!({ "sum": 6, "first": 1 }))
+        );
+
+        assert_eq!(script("return nil", &[]).eval(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn sandbox() {
+        assert!(script("return os.time()", &[]).eval(&[]).is_err());
+        assert!(script("return io.open('/etc/passwd')", &[])
+            .eval(&[])
+            .is_err());
+
+        assert!(script("return load('return 1')", &[]).eval(&[]).is_err());
+
+        // globals set by a run are not seen by the next one
+        for source in [
+            "count = (count or 0) + 1 return count",
+            "_G.count = (_G.count or 0) + 1 return _G.count",
+            "string.n = (string.n or 0) + 1 return string.n",
+            "getmetatable('').__index.n = 1 return 1",
+        ] {
+            let counter = script(source, &[]);
+            assert_eq!(counter.eval(&[]).ok(), counter.eval(&[]).ok(), "{source}");
+        }
+        assert!(script("string.n = 1", &[]).eval(&[]).is_err());
+        assert_eq!(
+            script("return ('abc'):upper()", &[]).eval(&[]).unwrap(),
+            Some(json!("ABC"))
+        );
+    }
+
+    #[test]
+    fn limits() {
+        // scripts are limited in time and memory by default
+        assert!(script("while true do end", &[]).eval(&[]).is_err());
+        assert!(script("local t = {} for i = 1, 1e8 do t[i] = i end", &[])
+            .eval(&[])
+            .is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Strategy {
+    LastWins,
+    FirstWins,
+    ArrayConcat,
+}
+
+#[derive(Clone, Debug)]
+pub struct MergeConfig {
+    strategy: Strategy,
+}
+
+impl NodeConfig for MergeConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Merge {
+    config: MergeConfig,
+}
+
+/// Deep-merges `src` into `dst`. Objects are merged key by key;
+/// other conflicting values are resolved according to the strategy.
+fn merge(dst: &mut Value, src: Value, strategy: Strategy) {
+    match (dst, src) {
+        (Value::Object(d), Value::Object(s)) => {
+            for (k, v) in s {
+                match d.get_mut(&k) {
+                    Some(existing) => merge(existing, v, strategy),
+                    None => {
+                        d.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(d), Value::Array(s)) if strategy == Strategy::ArrayConcat => {
+            d.extend(s);
+        }
+        (d, s) => {
+            if strategy != Strategy::FirstWins {
+                *d = s;
+            }
+        }
+    }
+}
+
+impl Node for Merge {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let mut result: Option<Value> = None;
+
+        for payload in input.data.iter().flatten() {
+            let value = match payload.to_json() {
+                Ok(v) => v,
+                Err(e) => return Fail(Some(Payload::Error(format!("merge: {e}")))),
+            };
+
+            match &mut result {
+                Some(r) => merge(r, value, self.config.strategy),
+                None => result = Some(value),
+            }
+        }
+
+        Done(result.map(Payload::Json))
+    }
+}
+
+pub struct MergeFactory {}
+
+impl NodeFactory for MergeFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let strategy = match get_config_value::<String>(bt, "strategy").as_deref() {
+            Some("last-wins") | None => Strategy::LastWins,
+            Some("first-wins") => Strategy::FirstWins,
+            Some("array-concat") => Strategy::ArrayConcat,
+            Some(other) => {
+                return Err(format!("merge: node '{name}': invalid strategy '{other}'"));
+            }
+        };
+
+        Ok(Box::new(MergeConfig { strategy }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<MergeConfig>() {
+            Some(cc) => Box::new(Merge { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["strategy"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn merged(strategy: Strategy) -> Value {
+        let mut v = json!({ "a": 1, "tags": ["x"], "obj": { "b": 2, "c": 3 } });
+        merge(
+            &mut v,
+            json!({ "a": 10, "tags": ["y"], "obj": { "c": 30, "d": 40 } }),
+            strategy,
+        );
+        v
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(
+            merged(Strategy::LastWins),
+            json!({ "a": 10, "tags": ["y"], "obj": { "b": 2, "c": 30, "d": 40 } })
+        );
+        assert_eq!(
+            merged(Strategy::FirstWins),
+            json!({ "a": 1, "tags": ["x"], "obj": { "b": 2, "c": 3, "d": 40 } })
+        );
+        assert_eq!(
+            merged(Strategy::ArrayConcat),
+            json!({ "a": 10, "tags": ["x", "y"], "obj": { "b": 2, "c": 30, "d": 40 } })
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::*;
+use proxy_wasm::types::MetricType;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn metric_type(&self) -> MetricType {
+        match self {
+            Kind::Counter => MetricType::Counter,
+            Kind::Gauge => MetricType::Gauge,
+            Kind::Histogram => MetricType::Histogram,
+        }
+    }
+}
+
+/// The label value used for input values that are not among
+/// the values configured for a label.
+const OTHER: &str = "other";
+
+/// The maximum number of label combinations of a metric, as each one
+/// is a metric of its own in the host.
+const MAX_SERIES: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct MetricsConfig {
+    metric: String,
+    kind: Kind,
+    /// Label names, with the values each one can take.
+    labels: Vec<(String, Vec<String>)>,
+    value: Option<String>,
+    /// Metric ids by full metric name, including labels.
+    ids: BTreeMap<String, u32>,
+}
+
+impl NodeConfig for MetricsConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_sink(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    config: MetricsConfig,
+}
+
+/// Metric ids by full metric name, including labels, so that reloading
+/// the configuration does not define the same metrics again.
+fn metric_ids() -> &'static Mutex<BTreeMap<String, u32>> {
+    static METRIC_IDS: OnceLock<Mutex<BTreeMap<String, u32>>> = OnceLock::new();
+    METRIC_IDS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+fn metric_id(kind: Kind, name: &str) -> Result<u32, String> {
+    let mut ids = metric_ids().lock().unwrap();
+    if let Some(id) = ids.get(name) {
+        return Ok(*id);
+    }
+
+    match hostcalls::define_metric(kind.metric_type(), name) {
+        Ok(id) => {
+            ids.insert(name.to_string(), id);
+            Ok(id)
+        }
+        Err(status) => Err(format!("failed defining metric '{name}': {:?}", status)),
+    }
+}
+
+fn value_to_label(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(v) => v.to_string(),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_name(metric: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return metric.to_string();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, v)| format!("{label}=\"{}\"", escape_label(v)))
+        .collect();
+
+    format!("{metric}{{{}}}", labels.join(","))
+}
+
+impl MetricsConfig {
+    /// Builds the full metric name in the form `name{label="value",...}`.
+    /// Values that are not configured for their label are replaced with
+    /// `other`, so that the input cannot create new metrics.
+    fn full_name(&self, input: Option<&Value>) -> String {
+        let values: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(label, allowed)| {
+                let v = value_to_label(input.and_then(|i| i.get(label)));
+                if allowed.contains(&v) {
+                    v
+                } else {
+                    OTHER.to_string()
+                }
+            })
+            .collect();
+
+        let labels: Vec<(&str, &str)> = self
+            .labels
+            .iter()
+            .zip(&values)
+            .map(|((label, _), v)| (label.as_str(), v.as_str()))
+            .collect();
+
+        format_name(&self.metric, &labels)
+    }
+
+    /// The full names of all the metrics the node can update,
+    /// one for each combination of label values.
+    fn all_names(&self) -> Vec<String> {
+        let mut combinations: Vec<Vec<(&str, &str)>> = vec![vec![]];
+        for (label, allowed) in &self.labels {
+            combinations = combinations
+                .iter()
+                .flat_map(|prefix| {
+                    allowed
+                        .iter()
+                        .map(String::as_str)
+                        .chain([OTHER])
+                        .map(move |v| {
+                            let mut labels = prefix.clone();
+                            labels.push((label.as_str(), v));
+                            labels
+                        })
+                })
+                .collect();
+        }
+
+        combinations
+            .iter()
+            .map(|labels| format_name(&self.metric, labels))
+            .collect()
+    }
+
+    fn value(&self, input: Option<&Value>) -> Result<u64, String> {
+        let Some(key) = &self.value else {
+            return Ok(1);
+        };
+
+        match input.and_then(|i| i.get(key)) {
+            Some(Value::Number(n)) => n
+                .as_u64()
+                .or_else(|| n.as_f64().map(|f| f.max(0.0) as u64))
+                .ok_or_else(|| format!("invalid value in '{key}'")),
+            Some(Value::String(s)) => s.parse().map_err(|_| format!("invalid value in '{key}'")),
+            _ => Err(format!("missing value in '{key}'")),
+        }
+    }
+}
+
+impl Node for Metrics {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let config = &self.config;
+
+        let json = match input.data.first().unwrap_or(&None) {
+            Some(Payload::Error(e)) => return Fail(Some(Payload::Error(e.clone()))),
+            Some(p) => p.to_json().ok(),
+            None => None,
+        };
+
+        let value = match config.value(json.as_ref()) {
+            Ok(v) => v,
+            Err(e) => return Fail(Some(Payload::Error(format!("metrics: {e}")))),
+        };
+
+        let name = config.full_name(json.as_ref());
+        let Some(id) = config.ids.get(&name) else {
+            log::warn!("metrics: undefined metric '{name}'");
+            return Done(None);
+        };
+
+        let result = match config.kind {
+            Kind::Counter => hostcalls::increment_metric(*id, value as i64),
+            Kind::Gauge | Kind::Histogram => hostcalls::record_metric(*id, value),
+        }
+        .map_err(|status| format!("failed updating metric '{name}': {:?}", status));
+
+        match result {
+            Ok(()) => Done(None),
+            Err(e) => {
+                // metrics are best-effort and should not fail the request
+                log::warn!("metrics: {e}");
+                Done(None)
+            }
+        }
+    }
+}
+
+pub struct MetricsFactory {}
+
+impl NodeFactory for MetricsFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(metric) = get_config_value::<String>(bt, "metric") else {
+            return Err(format!("metrics: node '{name}': missing 'metric'"));
+        };
+
+        let kind = match get_config_value::<String>(bt, "kind").as_deref() {
+            Some("counter") | None => Kind::Counter,
+            Some("gauge") => Kind::Gauge,
+            Some("histogram") => Kind::Histogram,
+            Some(other) => {
+                return Err(format!("metrics: node '{name}': invalid kind '{other}'"));
+            }
+        };
+
+        let labels: BTreeMap<String, Vec<String>> = match bt.get("labels") {
+            None => BTreeMap::new(),
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                format!("metrics: node '{name}': 'labels' must map label names to lists of values")
+            })?,
+        };
+
+        let mut config = MetricsConfig {
+            metric,
+            kind,
+            labels: labels.into_iter().collect(),
+            value: get_config_value(bt, "value"),
+            ids: BTreeMap::new(),
+        };
+
+        // All metrics are defined upfront, when the configuration
+        // is loaded by the root context.
+        let names = config.all_names();
+        if names.len() > MAX_SERIES {
+            return Err(format!(
+                "metrics: node '{name}': too many label combinations ({}, at most {MAX_SERIES})",
+                names.len()
+            ));
+        }
+        for full_name in names {
+            let id = metric_id(config.kind, &full_name)
+                .map_err(|e| format!("metrics: node '{name}': {e}"))?;
+            config.ids.insert(full_name, id);
+        }
+
+        Ok(Box::new(config))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<MetricsConfig>() {
+            Some(cc) => Box::new(Metrics { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["metric", "kind", "labels", "value"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config(labels: &[(&str, &[&str])], value: Option<&str>) -> MetricsConfig {
+        MetricsConfig {
+            metric: "upstream_calls".to_string(),
+            kind: Kind::Counter,
+            labels: labels
+                .iter()
+                .map(|(l, vs)| (l.to_string(), vs.iter().map(|v| v.to_string()).collect()))
+                .collect(),
+            value: value.map(|v| v.to_string()),
+            ids: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn names_with_labels() {
+        let input = json!({ "status": 200, "host": "example.com" });
+
+        assert_eq!(config(&[], None).full_name(Some(&input)), "upstream_calls");
+        assert_eq!(
+            config(
+                &[
+                    ("status", &["200", "500"]),
+                    ("host", &["example.com"]),
+                    ("missing", &["x"])
+                ],
+                None
+            )
+            .full_name(Some(&input)),
+            r#"upstream_calls{status="200",host="example.com",missing="other"}"#
+        );
+    }
+
+    #[test]
+    fn unknown_label_values() {
+        let c = config(&[("host", &["example.com"])], None);
+
+        let input = json!({ "host": "attacker\"} 1\nupstream_calls{host=\"x" });
+        assert_eq!(c.full_name(Some(&input)), r#"upstream_calls{host="other"}"#);
+        assert!(c.all_names().contains(&c.full_name(Some(&input))));
+    }
+
+    #[test]
+    fn all_label_combinations() {
+        assert_eq!(config(&[], None).all_names(), vec!["upstream_calls"]);
+        assert_eq!(
+            config(&[("status", &["200"]), ("method", &["GET", "a\"b"])], None).all_names(),
+            vec![
+                r#"upstream_calls{status="200",method="GET"}"#,
+                r#"upstream_calls{status="200",method="a\"b"}"#,
+                r#"upstream_calls{status="200",method="other"}"#,
+                r#"upstream_calls{status="other",method="GET"}"#,
+                r#"upstream_calls{status="other",method="a\"b"}"#,
+                r#"upstream_calls{status="other",method="other"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn values_from_input() {
+        let input = json!({ "latency": 35, "size": "1024" });
+
+        assert_eq!(config(&[], None).value(Some(&input)), Ok(1));
+        assert_eq!(config(&[], Some("latency")).value(Some(&input)), Ok(35));
+        assert_eq!(config(&[], Some("size")).value(Some(&input)), Ok(1024));
+        assert!(config(&[], Some("nope")).value(Some(&input)).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, MediaType, Payload, State, State::*};
+use crate::nodes::select::{lookup, parse_path, Step};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug)]
+struct MockResponse {
+    when: Vec<(Vec<Step>, Value)>,
+    payload: Value,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    responses: Vec<MockResponse>,
+    payload: Option<Value>,
+    content_type: Option<String>,
+}
+
+impl NodeConfig for MockConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Mock {
+    config: MockConfig,
+}
+
+impl MockResponse {
+    fn matches(&self, input: Option<&Value>) -> bool {
+        self.when.iter().all(|(steps, expected)| {
+            let found = input.and_then(|v| lookup(v, steps));
+            match (found, expected) {
+                // header values may come as arrays of repeated headers
+                (Some(Value::Array(items)), Value::String(_)) => items.contains(expected),
+                (Some(v), _) => v == expected,
+                (None, Value::Null) => true,
+                (None, _) => false,
+            }
+        })
+    }
+}
+
+impl MockConfig {
+    fn select(&self, input: Option<&Value>) -> Option<&Value> {
+        self.responses
+            .iter()
+            .find(|r| r.matches(input))
+            .map(|r| &r.payload)
+            .or(self.payload.as_ref())
+    }
+
+    fn to_payload(&self, value: &Value) -> Payload {
+        match (&self.content_type, value) {
+            (Some(ct), Value::String(s))
+                if !MediaType::parse(ct).is_some_and(|mt| mt.is_json()) =>
+            {
+                Payload::Raw(s.clone().into_bytes().into(), Some(ct.clone()))
+            }
+            _ => Payload::Json(value.clone()),
+        }
+    }
+}
+
+impl Node for Mock {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let json = match input.data.first().unwrap_or(&None) {
+            Some(p) => p.to_json().ok(),
+            None => None,
+        };
+
+        let payload = self
+            .config
+            .select(json.as_ref())
+            .map(|v| self.config.to_payload(v));
+
+        Done(payload)
+    }
+}
+
+pub struct MockFactory {}
+
+fn parse_response(name: &str, i: usize, value: &Value) -> Result<MockResponse, String> {
+    let err = |msg: String| format!("mock: node '{name}': response {i}: {msg}");
+
+    let Some(payload) = value.get("payload") else {
+        return Err(err("missing 'payload'".to_string()));
+    };
+
+    let mut when = vec![];
+    match value.get("when") {
+        Some(Value::Object(conditions)) => {
+            for (path, expected) in conditions {
+                when.push((parse_path(path).map_err(err)?, expected.clone()));
+            }
+        }
+        Some(_) => return Err(err("'when' must be an object".to_string())),
+        None => {}
+    }
+
+    Ok(MockResponse {
+        when,
+        payload: payload.clone(),
+    })
+}
+
+impl NodeFactory for MockFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let responses = match bt.get("responses") {
+            Some(Value::Array(list)) => list
+                .iter()
+                .enumerate()
+                .map(|(i, v)| parse_response(name, i, v))
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => {
+                return Err(format!("mock: node '{name}': 'responses' must be a list"));
+            }
+            None => vec![],
+        };
+
+        Ok(Box::new(MockConfig {
+            responses,
+            payload: bt.get("payload").cloned(),
+            content_type: get_config_value(bt, "content_type"),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<MockConfig>() {
+            Some(cc) => Box::new(Mock { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["responses", "payload", "content_type"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn selects_matching_response() {
+        let responses = json!([
+            { "when": { "x-tenant": "acme" }, "payload": { "tenant": "acme" } },
+            { "when": { "x-tenant": "beta", "x-debug": null }, "payload": "beta" }
+        ]);
+        let config = MockConfig {
+            responses: responses
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .map(|(i, v)| parse_response("test", i, v).unwrap())
+                .collect(),
+            payload: Some(json!({ "tenant": "default" })),
+            content_type: None,
+        };
+
+        let select = |v: Value| config.select(Some(&v)).cloned();
+
+        assert_eq!(
+            select(json!({ "x-tenant": "acme" })),
+            Some(json!({ "tenant": "acme" }))
+        );
+        assert_eq!(
+            select(json!({ "x-tenant": ["other", "acme"] })),
+            Some(json!({ "tenant": "acme" }))
+        );
+        assert_eq!(select(json!({ "x-tenant": "beta" })), Some(json!("beta")));
+        assert_eq!(
+            select(json!({ "x-tenant": "beta", "x-debug": "1" })),
+            Some(json!({ "tenant": "default" }))
+        );
+        assert_eq!(config.select(None), Some(&json!({ "tenant": "default" })));
+    }
+}
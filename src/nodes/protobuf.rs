@@ -0,0 +1,175 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Decode,
+    Encode,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProtobufConfig {
+    action: Action,
+    message: MessageDescriptor,
+    grpc: bool,
+}
+
+impl NodeConfig for ProtobufConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Protobuf {
+    config: ProtobufConfig,
+}
+
+/// Removes the 5-byte prefix of a gRPC message: a compression flag
+/// and the length of the message.
+fn grpc_unframe(bytes: &[u8]) -> Result<&[u8], String> {
+    if bytes.len() < 5 {
+        return Err("gRPC message too short".to_string());
+    }
+    let (prefix, message) = bytes.split_at(5);
+    if prefix[0] != 0 {
+        return Err("compressed gRPC messages are not supported".to_string());
+    }
+    let len = u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+    message
+        .get(..len)
+        .ok_or_else(|| "truncated gRPC message".to_string())
+}
+
+fn grpc_frame(message: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len() + 5);
+    out.push(0);
+    out.extend((message.len() as u32).to_be_bytes());
+    out.extend(message);
+    out
+}
+
+impl ProtobufConfig {
+    fn decode(&self, bytes: &[u8]) -> Result<Value, String> {
+        let bytes = if self.grpc {
+            grpc_unframe(bytes)?
+        } else {
+            bytes
+        };
+        let message =
+            DynamicMessage::decode(self.message.clone(), bytes).map_err(|e| e.to_string())?;
+        serde_json::to_value(&message).map_err(|e| e.to_string())
+    }
+
+    fn encode(&self, value: Value) -> Result<Vec<u8>, String> {
+        let message =
+            DynamicMessage::deserialize(self.message.clone(), value).map_err(|e| e.to_string())?;
+        let bytes = message.encode_to_vec();
+        Ok(if self.grpc { grpc_frame(bytes) } else { bytes })
+    }
+
+    fn apply(&self, payload: &Payload) -> Result<Payload, String> {
+        match self.action {
+            Action::Decode => match payload {
+                Payload::Raw(bytes, _) => Ok(Payload::Json(self.decode(bytes)?)),
+                Payload::Json(_) => Err("input must be a raw protobuf message".to_string()),
+                Payload::Error(e) => Err(e.clone()),
+            },
+            Action::Encode => {
+                let content_type = if self.grpc {
+                    "application/grpc"
+                } else {
+                    "application/x-protobuf"
+                };
+                let bytes = self.encode(payload.to_json()?)?;
+                Ok(Payload::Raw(bytes.into(), Some(content_type.to_string())))
+            }
+        }
+    }
+}
+
+impl Node for Protobuf {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match input.data.first().unwrap_or(&None) {
+            Some(payload) => match self.config.apply(payload) {
+                Ok(p) => Done(Some(p)),
+                Err(e) => Fail(Some(Payload::Error(format!("protobuf: {e}")))),
+            },
+            None => Done(None),
+        }
+    }
+}
+
+pub struct ProtobufFactory {}
+
+impl NodeFactory for ProtobufFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let action = match get_config_value::<String>(bt, "action").as_deref() {
+            Some("decode") | None => Action::Decode,
+            Some("encode") => Action::Encode,
+            Some(other) => {
+                return Err(format!("protobuf: node '{name}': invalid action '{other}'"));
+            }
+        };
+
+        let descriptor_set: String = get_config_value(bt, "descriptor_set")
+            .ok_or_else(|| format!("protobuf: node '{name}': missing 'descriptor_set'"))?;
+        let descriptor_set = STANDARD
+            .decode(descriptor_set.trim())
+            .map_err(|e| format!("protobuf: node '{name}': invalid 'descriptor_set': {e}"))?;
+        let pool = DescriptorPool::decode(descriptor_set.as_slice())
+            .map_err(|e| format!("protobuf: node '{name}': invalid 'descriptor_set': {e}"))?;
+
+        let message_name: String = get_config_value(bt, "message")
+            .ok_or_else(|| format!("protobuf: node '{name}': missing 'message'"))?;
+        let message = pool.get_message_by_name(&message_name).ok_or_else(|| {
+            format!("protobuf: node '{name}': unknown message type '{message_name}'")
+        })?;
+
+        Ok(Box::new(ProtobufConfig {
+            action,
+            message,
+            grpc: get_config_value(bt, "grpc").unwrap_or(false),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ProtobufConfig>() {
+            Some(cc) => Box::new(Protobuf { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["action", "descriptor_set", "message", "grpc"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn grpc_framing() {
+        let framed = grpc_frame(b"\x08\x96\x01".to_vec());
+        assert_eq!(framed, b"\x00\x00\x00\x00\x03\x08\x96\x01");
+        assert_eq!(grpc_unframe(&framed), Ok(&b"\x08\x96\x01"[..]));
+        assert!(grpc_unframe(&framed[..6]).is_err());
+        assert!(grpc_unframe(b"\x01\x00\x00\x00\x00").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use proxy_wasm::traits::*;
+use proxy_wasm::types::Status;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+/// Number of attempts to update a counter when racing
+/// with other workers updating the same shared data key.
+const MAX_CAS_ATTEMPTS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    name: String,
+    namespace: String,
+    limit: u64,
+    window: u64,
+}
+
+impl NodeConfig for RateLimitConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_sink(&self) -> bool {
+        true
+    }
+
+    fn failure_status(&self) -> Option<u32> {
+        Some(429)
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit {
+    config: RateLimitConfig,
+}
+
+fn key_from_payload(payload: Option<&Payload>) -> Result<String, String> {
+    match payload {
+        None => Ok(String::new()),
+        Some(Payload::Raw(bytes, _)) => Ok(String::from_utf8_lossy(bytes).to_string()),
+        Some(Payload::Json(Value::String(s))) => Ok(s.clone()),
+        Some(Payload::Json(v)) => Ok(v.to_string()),
+        Some(Payload::Error(e)) => Err(e.clone()),
+    }
+}
+
+/// Returns the count following the one stored for an identity. Each identity
+/// has a single counter, stored as `<window start>:<count>`, which starts over
+/// when a new window begins.
+fn next_count(stored: Option<&[u8]>, window_start: u64) -> u64 {
+    let counter = stored
+        .and_then(|b| std::str::from_utf8(b).ok())
+        .and_then(|s| s.split_once(':'))
+        .and_then(|(start, count)| Some((start.parse::<u64>().ok()?, count.parse::<u64>().ok()?)));
+
+    match counter {
+        Some((start, count)) if start == window_start => count + 1,
+        _ => 1,
+    }
+}
+
+impl RateLimit {
+    /// Increments the counter for the given key in the current window,
+    /// returning the updated count.
+    fn increment(
+        &self,
+        ctx: &dyn HttpContext,
+        key: &str,
+        window_start: u64,
+    ) -> Result<u64, String> {
+        let shm_key = format!(
+            "datakit:rate_limit:{}:{}:{}",
+            self.config.namespace, self.config.name, key
+        );
+
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let (bytes, cas) = ctx.get_shared_data(&shm_key);
+            let count = next_count(bytes.as_deref(), window_start);
+            let value = format!("{window_start}:{count}");
+
+            match ctx.set_shared_data(&shm_key, Some(value.as_bytes()), cas) {
+                Ok(()) => return Ok(count),
+                Err(Status::CasMismatch) => continue,
+                Err(status) => return Err(format!("failed updating counter: {:?}", status)),
+            }
+        }
+
+        Err("failed updating counter: too much contention".to_string())
+    }
+}
+
+impl Node for RateLimit {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        let key = match key_from_payload(*input.data.first().unwrap_or(&None)) {
+            Ok(k) => k,
+            Err(e) => return Fail(Some(Payload::Error(format!("rate_limit: {e}")))),
+        };
+
+        let now = ctx
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let window = self.config.window;
+        let window_start = now - now % window;
+
+        let count = match self.increment(ctx, &key, window_start) {
+            Ok(c) => c,
+            Err(e) => return Fail(Some(Payload::Error(format!("rate_limit: {e}")))),
+        };
+
+        let limit = self.config.limit;
+        let info = serde_json::json!({
+            "limit": limit,
+            "remaining": limit.saturating_sub(count),
+            "reset": window_start + window - now,
+        });
+
+        if count > limit {
+            let mut info = info;
+            info["message"] = "rate limit exceeded".into();
+            Fail(Some(Payload::Json(info)))
+        } else {
+            Done(Some(Payload::Json(info)))
+        }
+    }
+}
+
+pub struct RateLimitFactory {}
+
+impl NodeFactory for RateLimitFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(limit) = get_config_value(bt, "limit") else {
+            return Err(format!("rate_limit: node '{name}': missing 'limit'"));
+        };
+
+        let window = get_config_value(bt, "window").unwrap_or(60);
+        if window == 0 {
+            return Err(format!(
+                "rate_limit: node '{name}': 'window' must be positive"
+            ));
+        }
+
+        // set from the configuration when not given (see `config::add_shared`)
+        let namespace = get_config_value(bt, "namespace").unwrap_or_default();
+
+        Ok(Box::new(RateLimitConfig {
+            name: name.to_string(),
+            namespace,
+            limit,
+            window,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<RateLimitConfig>() {
+            Some(cc) => Box::new(RateLimit { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["limit", "window", "namespace"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::Phase;
+    use crate::test_host::TestContext;
+
+    fn rate_limit(namespace: &str) -> Box<dyn Node> {
+        let bt = BTreeMap::from([
+            ("limit".to_string(), 1.into()),
+            ("namespace".to_string(), namespace.into()),
+        ]);
+        let config = RateLimitFactory {}.new_config("RL", &[], &bt).unwrap();
+        RateLimitFactory {}.new_node(&*config)
+    }
+
+    fn run(node: &dyn Node) -> State {
+        let input = Input {
+            data: &[],
+            phase: Phase::HttpRequestHeaders,
+            token_id: None,
+            dry_run: false,
+            state: None,
+        };
+        node.run(&TestContext, &input)
+    }
+
+    #[test]
+    fn namespaces() {
+        let a = rate_limit("a");
+        let b = rate_limit("b");
+
+        assert!(matches!(run(&*a), Done(_)));
+        // nodes of the same name count separately in other namespaces
+        assert!(matches!(run(&*b), Done(_)));
+        assert!(matches!(run(&*a), Fail(Some(Payload::Json(_)))));
+        // and share their counters in the same one
+        assert!(matches!(run(&*rate_limit("a")), Fail(_)));
+    }
+
+    #[test]
+    fn next_count_in_window() {
+        assert_eq!(next_count(None, 60), 1);
+        assert_eq!(next_count(Some(b"60:1"), 60), 2);
+        assert_eq!(next_count(Some(b"60:41"), 60), 42);
+    }
+
+    #[test]
+    fn next_count_window_rollover() {
+        // the counter of a previous window is replaced, not kept alongside
+        assert_eq!(next_count(Some(b"60:41"), 120), 1);
+        assert_eq!(next_count(Some(b"0:5"), 600), 1);
+    }
+
+    #[test]
+    fn next_count_invalid_value() {
+        assert_eq!(next_count(Some(b"garbage"), 60), 1);
+        assert_eq!(next_count(Some(b"60:x"), 60), 1);
+        assert_eq!(next_count(Some(b"\xff"), 60), 1);
+    }
+}
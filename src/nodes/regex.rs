@@ -0,0 +1,196 @@
+use proxy_wasm::traits::*;
+use regex::Regex;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{node_state, ChunkBuffer, Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug)]
+enum Mode {
+    Match,
+    Capture,
+    Replace(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct RegexConfig {
+    regex: Regex,
+    mode: Mode,
+}
+
+impl NodeConfig for RegexConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn supports_streaming(&self) -> bool {
+        matches!(self.mode, Mode::Replace(_))
+    }
+}
+
+#[derive(Clone)]
+pub struct RegexNode {
+    config: RegexConfig,
+}
+
+impl RegexConfig {
+    fn apply(&self, s: &str) -> Value {
+        match &self.mode {
+            Mode::Match => Value::Bool(self.regex.is_match(s)),
+            Mode::Capture => match self.regex.captures(s) {
+                Some(caps) => {
+                    let mut map = serde_json::Map::new();
+                    for (i, name) in self.regex.capture_names().enumerate() {
+                        let key = name.map_or_else(|| i.to_string(), |n| n.to_string());
+                        let value = caps
+                            .get(i)
+                            .map_or(Value::Null, |m| Value::String(m.as_str().to_string()));
+                        map.insert(key, value);
+                    }
+                    Value::Object(map)
+                }
+                None => Value::Null,
+            },
+            Mode::Replace(replacement) => {
+                Value::String(self.regex.replace_all(s, replacement.as_str()).into_owned())
+            }
+        }
+    }
+}
+
+impl Node for RegexNode {
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(ChunkBuffer::default()))
+    }
+
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let s = match input.data.first().unwrap_or(&None) {
+            Some(Payload::Raw(bytes, _)) => match std::str::from_utf8(bytes) {
+                Ok(s) => s.to_string(),
+                Err(e) => return Fail(Some(Payload::Error(format!("regex: {e}")))),
+            },
+            Some(Payload::Json(Value::String(s))) => s.clone(),
+            Some(Payload::Json(_)) => {
+                return Fail(Some(Payload::Error(
+                    "regex: input must be a string".to_string(),
+                )))
+            }
+            Some(Payload::Error(e)) => return Fail(Some(Payload::Error(e.clone()))),
+            None => return Done(None),
+        };
+
+        Done(Some(Payload::Json(self.config.apply(&s))))
+    }
+
+    /// In streaming mode, replacements are made a line at a time,
+    /// so matches cannot span more than one line.
+    fn stream_chunk(
+        &self,
+        state: Option<&dyn Any>,
+        chunk: &[u8],
+        eof: bool,
+    ) -> Result<Vec<u8>, String> {
+        let Mode::Replace(replacement) = &self.config.mode else {
+            return Err("regex: streaming is only supported in replace mode".to_string());
+        };
+
+        let bytes = node_state::<ChunkBuffer>(state).take(chunk, eof, |b| {
+            b.iter().rposition(|c| *c == b'\n').map_or(0, |i| i + 1)
+        });
+        let s = std::str::from_utf8(&bytes).map_err(|e| format!("regex: {e}"))?;
+
+        Ok(self
+            .config
+            .regex
+            .replace_all(s, replacement.as_str())
+            .into_owned()
+            .into_bytes())
+    }
+}
+
+pub struct RegexFactory {}
+
+impl NodeFactory for RegexFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let pattern: String = get_config_value(bt, "pattern")
+            .ok_or_else(|| format!("regex: node '{name}': missing 'pattern'"))?;
+
+        let regex = Regex::new(&pattern)
+            .map_err(|e| format!("regex: node '{name}': invalid pattern: {e}"))?;
+
+        let mode = match get_config_value::<String>(bt, "mode").as_deref() {
+            Some("match") => Mode::Match,
+            Some("capture") | None => Mode::Capture,
+            Some("replace") => Mode::Replace(
+                get_config_value(bt, "replacement").unwrap_or_else(|| String::from("")),
+            ),
+            Some(other) => {
+                return Err(format!("regex: node '{name}': invalid mode '{other}'"));
+            }
+        };
+
+        Ok(Box::new(RegexConfig { regex, mode }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<RegexConfig>() {
+            Some(cc) => Box::new(RegexNode { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["pattern", "mode", "replacement"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config(pattern: &str, mode: Mode) -> RegexConfig {
+        RegexConfig {
+            regex: Regex::new(pattern).unwrap(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn match_mode() {
+        let rc = config(r"^/users/\d+$", Mode::Match);
+
+        assert_eq!(rc.apply("/users/42"), json!(true));
+        assert_eq!(rc.apply("/users/me"), json!(false));
+    }
+
+    #[test]
+    fn capture_mode() {
+        let rc = config(r"^/(\w+)/(?<id>\d+)?", Mode::Capture);
+
+        assert_eq!(
+            rc.apply("/users/42"),
+            json!({ "0": "/users/42", "1": "users", "id": "42" })
+        );
+        assert_eq!(
+            rc.apply("/users/"),
+            json!({ "0": "/users/", "1": "users", "id": null })
+        );
+        assert_eq!(rc.apply("nope"), json!(null));
+    }
+
+    #[test]
+    fn replace_mode() {
+        let rc = config(r"/v(\d+)/", Mode::Replace("/api/v$1/".to_string()));
+
+        assert_eq!(rc.apply("/v1/users"), json!("/api/v1/users"));
+    }
+}
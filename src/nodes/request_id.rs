@@ -0,0 +1,190 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+use crate::config::get_config_value;
+use crate::data;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{fill_random, Node, NodeConfig, NodeFactory};
+
+/// Incoming ids longer than this are replaced rather than propagated.
+const MAX_ID_LEN: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Uuid,
+    Ulid,
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestIdConfig {
+    name: String,
+    format: Format,
+    header: Option<String>,
+}
+
+impl NodeConfig for RequestIdConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn default_inputs(&self) -> Option<Vec<String>> {
+        self.header
+            .as_ref()
+            .map(|_| vec!["request_headers".to_string()])
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestId {
+    config: RequestIdConfig,
+}
+
+/// Formats random bytes as a version 4 UUID.
+fn uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Formats a timestamp in milliseconds and random bytes as a ULID,
+/// which sorts in the order the ids were generated.
+fn ulid(millis: u64, bytes: [u8; 10]) -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    let random = bytes.iter().fold(0u128, |n, b| (n << 8) | u128::from(*b));
+    let value = (u128::from(millis & 0xffff_ffff_ffff) << 80) | random;
+    (0..26)
+        .map(|i| ALPHABET[((value >> (5 * (25 - i))) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Incoming ids are propagated as long as they are safe to pass along.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+impl RequestId {
+    fn generate(&self, ctx: &dyn HttpContext) -> Result<String, String> {
+        Ok(match self.config.format {
+            Format::Uuid => {
+                let mut bytes = [0; 16];
+                fill_random(&mut bytes)?;
+                uuid(bytes)
+            }
+            Format::Ulid => {
+                let millis = ctx
+                    .get_current_time()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64);
+                let mut bytes = [0; 10];
+                fill_random(&mut bytes)?;
+                ulid(millis, bytes)
+            }
+        })
+    }
+}
+
+impl Node for RequestId {
+    fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
+        if let Some(header) = &self.config.header {
+            let headers = data::to_pwm_headers(input.data.first().copied().flatten());
+            if let Some(id) = data::find_header(&headers, header).filter(|id| is_valid_id(id)) {
+                return Done(Some(Payload::Json(Value::from(id))));
+            }
+        }
+
+        match self.generate(ctx) {
+            Ok(id) => Done(Some(Payload::Json(Value::String(id)))),
+            Err(e) => {
+                let name = &self.config.name;
+                Fail(Some(Payload::Error(format!(
+                    "request_id: node '{name}': {e}"
+                ))))
+            }
+        }
+    }
+}
+
+pub struct RequestIdFactory {}
+
+impl NodeFactory for RequestIdFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let format = match get_config_value::<String>(bt, "format").as_deref() {
+            Some("uuid") | None => Format::Uuid,
+            Some("ulid") => Format::Ulid,
+            Some(other) => {
+                return Err(format!(
+                    "request_id: node '{name}': invalid format '{other}'"
+                ));
+            }
+        };
+
+        // `header: null` disables the propagation of incoming ids
+        let header = match bt.get("header") {
+            None => Some("x-request-id".to_string()),
+            Some(Value::Null) => None,
+            Some(Value::String(header)) => Some(header.clone()),
+            Some(_) => {
+                return Err(format!(
+                    "request_id: node '{name}': 'header' must be a string or null"
+                ));
+            }
+        };
+
+        Ok(Box::new(RequestIdConfig {
+            name: name.to_string(),
+            format,
+            header,
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<RequestIdConfig>() {
+            Some(cc) => Box::new(RequestId { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["format", "header"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        assert_eq!(uuid([0xff; 16]), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(uuid([0; 16]), "00000000-0000-4000-8000-000000000000");
+
+        assert_eq!(ulid(1469918176385, [0; 10]), "01ARYZ6S410000000000000000");
+        assert_eq!(ulid(0, [0xff; 10]), "0000000000ZZZZZZZZZZZZZZZZ");
+    }
+
+    #[test]
+    fn valid_ids() {
+        assert!(is_valid_id("f47ac10b-58cc-4372-a567-0e02b2c3d479"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("a b"));
+        assert!(!is_valid_id("a\r\nx-injected: 1"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+}
@@ -1,6 +1,7 @@
 use proxy_wasm::traits::*;
 use serde_json::Value;
 use std::any::Any;
+use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
@@ -8,12 +9,14 @@ use std::sync::atomic::Ordering::Relaxed;
 use crate::config::get_config_value;
 use crate::data;
 use crate::data::{Input, Payload, Phase, State, State::*};
-use crate::nodes::{Node, NodeConfig, NodeFactory};
+use crate::nodes::{node_state, Node, NodeConfig, NodeFactory};
 
 #[derive(Debug)]
 pub struct ResponseConfig {
     name: String,
     status: Option<u32>,
+    skip_if_absent: bool,
+    redirect: bool,
     warn_headers_sent: AtomicBool,
 }
 
@@ -22,6 +25,8 @@ impl Clone for ResponseConfig {
         ResponseConfig {
             name: self.name.clone(),
             status: self.status,
+            skip_if_absent: self.skip_if_absent,
+            redirect: self.redirect,
             warn_headers_sent: AtomicBool::new(self.warn_headers_sent.load(Relaxed)),
         }
     }
@@ -32,6 +37,10 @@ impl NodeConfig for ResponseConfig {
         self
     }
 
+    fn is_sink(&self) -> bool {
+        true
+    }
+
     fn default_outputs(&self) -> Option<Vec<String>> {
         Some(vec!["response_body".to_string()])
     }
@@ -42,9 +51,8 @@ pub struct Response {
     config: ResponseConfig,
 }
 
-fn warn_headers_sent(config: &ResponseConfig, set_headers: bool) {
+fn warn_headers_sent(config: &ResponseConfig, set_status: bool, set_headers: bool) {
     let name = &config.name;
-    let set_status = config.status.is_some();
 
     if set_status || set_headers {
         let what = if set_headers && set_status {
@@ -63,40 +71,173 @@ fn warn_headers_sent(config: &ResponseConfig, set_headers: bool) {
     config.warn_headers_sent.store(false, Relaxed);
 }
 
+/// Builds the `Location` of a redirect from the body input: either a URL,
+/// or an object with a `url` and a `query` object, whose entries are
+/// URL-encoded and added to the query string of the URL.
+fn redirect_location(payload: &Payload) -> Result<String, String> {
+    let location = match payload.to_json()? {
+        Value::String(url) => url,
+        Value::Object(map) => match (map.get("url"), map.get("query")) {
+            (Some(Value::String(url)), None | Some(Value::Null)) => url.clone(),
+            (Some(Value::String(url)), Some(query)) => add_query(url, &data::encode_form(query)?),
+            _ => return Err("redirect without 'url'".to_string()),
+        },
+        _ => return Err("redirect must be a URL or an object with a 'url'".to_string()),
+    };
+
+    // a line break would let the input add headers to the response
+    if location.chars().any(char::is_control) {
+        return Err("redirect location contains control characters".to_string());
+    }
+    Ok(location)
+}
+
+fn add_query(url: &str, query: &str) -> String {
+    if query.is_empty() {
+        return url.to_string();
+    }
+
+    // the query goes before the fragment, if any
+    let (base, fragment) = match url.split_once('#') {
+        Some((base, fragment)) => (base, format!("#{fragment}")),
+        None => (url, String::new()),
+    };
+    let sep = if base.contains('?') { '&' } else { '?' };
+    format!("{base}{sep}{query}{fragment}")
+}
+
+/// In dry runs, the response that would have been sent is the node output.
+fn dry_run_response(status: u32, headers: &[(&str, &str)], body: Option<&Payload>) -> State {
+    let headers: serde_json::Map<String, Value> = headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), Value::from(*v)))
+        .collect();
+    let body = match body.map(Payload::to_json_or_base64) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => return Fail(Some(Payload::Error(e))),
+        None => Value::Null,
+    };
+    Done(Some(Payload::Json(serde_json::json!({
+        "status": status,
+        "headers": headers,
+        "body": body,
+    }))))
+}
+
+impl Response {
+    fn redirect(
+        &self,
+        ctx: &dyn HttpContext,
+        input: &Input,
+        status: Option<u32>,
+        headers: Vec<(&str, &str)>,
+        body: Option<&Payload>,
+    ) -> State {
+        let name = &self.config.name;
+        let location = match body.map(redirect_location) {
+            Some(Ok(location)) => location,
+            Some(Err(e)) => {
+                return Fail(Some(Payload::Error(format!(
+                    "response: node '{name}': {e}"
+                ))))
+            }
+            None => {
+                return Fail(Some(Payload::Error(format!(
+                    "response: node '{name}': redirect without location"
+                ))))
+            }
+        };
+
+        if input.phase == Phase::HttpResponseBody || input.phase == Phase::HttpResponseTrailers {
+            return Fail(Some(Payload::Error(format!(
+                "response: node '{name}': cannot redirect, headers already sent"
+            ))));
+        }
+
+        let mut headers: Vec<(&str, &str)> = headers
+            .into_iter()
+            .filter(|(k, _)| !k.eq_ignore_ascii_case("location"))
+            .collect();
+        headers.push(("Location", &location));
+        if input.dry_run {
+            return dry_run_response(status.unwrap_or(302), &headers, None);
+        }
+        ctx.send_http_response(status.unwrap_or(302), headers, None);
+        node_state::<Cell<bool>>(input.state).set(true);
+        Done(None)
+    }
+}
+
 impl Node for Response {
+    /// Whether the node sent the response.
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(Cell::new(false)))
+    }
+
     fn run(&self, ctx: &dyn HttpContext, input: &Input) -> State {
         let config = &self.config;
         let body = input.data.first().unwrap_or(&None).as_deref();
         let headers = input.data.get(1).unwrap_or(&None).as_deref();
+        let status_input = input.data.get(2).unwrap_or(&None).as_deref();
+
+        // without a body, let the request continue
+        if config.skip_if_absent && body.is_none() {
+            return Done(None);
+        }
+
+        // a status given as input takes precedence over the configured one
+        let status = match status_input.map(Payload::to_status) {
+            Some(Ok(status)) => Some(status),
+            Some(Err(e)) => {
+                let name = &config.name;
+                return Fail(Some(Payload::Error(format!(
+                    "response: node '{name}': {e}"
+                ))));
+            }
+            None => config.status,
+        };
 
         let mut headers_vec = data::to_pwm_headers(headers);
+        if config.redirect {
+            return self.redirect(ctx, input, status, headers_vec, body);
+        }
 
-        if let Some(payload) = body {
-            if let Some(content_type) = payload.content_type() {
-                headers_vec.push(("Content-Type", content_type));
+        let mut content_type = data::find_header(&headers_vec, "content-type");
+        if content_type.is_none() {
+            content_type = body.and_then(|payload| payload.content_type());
+            if let Some(ct) = content_type {
+                headers_vec.push(("Content-Type", ct));
             }
         }
 
-        let body_slice = match data::to_pwm_body(body) {
+        let body_slice = match data::to_pwm_body(body, content_type) {
             Ok(slice) => slice,
             Err(e) => return Fail(Some(Payload::Error(e))),
         };
 
-        if input.phase == Phase::HttpResponseBody {
+        if input.phase == Phase::HttpResponseBody || input.phase == Phase::HttpResponseTrailers {
             if config.warn_headers_sent.load(Relaxed) {
-                warn_headers_sent(config, headers.is_some());
+                warn_headers_sent(config, status.is_some(), headers.is_some());
             }
 
             if let Some(b) = body_slice {
                 ctx.set_http_response_body(0, b.len(), &b);
             }
         } else {
-            let status = config.status.unwrap_or(200);
+            let status = status.unwrap_or(200);
+            if input.dry_run {
+                return dry_run_response(status, &headers_vec, body);
+            }
             ctx.send_http_response(status, headers_vec, body_slice.as_deref());
+            node_state::<Cell<bool>>(input.state).set(true);
         }
 
         Done(None)
     }
+
+    fn has_responded(&self, state: Option<&dyn Any>) -> bool {
+        node_state::<Cell<bool>>(state).get()
+    }
 }
 
 pub struct ResponseFactory {}
@@ -108,9 +249,19 @@ impl NodeFactory for ResponseFactory {
         _inputs: &[String],
         bt: &BTreeMap<String, Value>,
     ) -> Result<Box<dyn NodeConfig>, String> {
+        let status: Option<u32> = get_config_value(bt, "status");
+        let redirect = get_config_value(bt, "redirect").unwrap_or(false);
+        if let Some(s) = status.filter(|s| redirect && !(300..400).contains(s)) {
+            return Err(format!(
+                "response: node '{name}': invalid redirect status {s}"
+            ));
+        }
+
         Ok(Box::new(ResponseConfig {
             name: name.to_string(),
-            status: get_config_value(bt, "status"),
+            status,
+            skip_if_absent: get_config_value(bt, "skip_if_absent").unwrap_or(false),
+            redirect,
             warn_headers_sent: AtomicBool::new(
                 get_config_value(bt, "warn_headers_sent").unwrap_or(true),
             ),
@@ -123,4 +274,72 @@ impl NodeFactory for ResponseFactory {
             None => panic!("incompatible NodeConfig"),
         }
     }
+
+    fn input_roles(&self) -> Option<&'static [&'static str]> {
+        Some(&["body", "headers", "status"])
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["status", "skip_if_absent", "redirect", "warn_headers_sent"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_host::{self, TestContext};
+    use serde_json::json;
+
+    #[test]
+    fn redirects() {
+        let location = |v: Value| redirect_location(&Payload::Json(v));
+
+        assert_eq!(location(json!("/login")), Ok("/login".to_string()));
+        assert_eq!(
+            location(json!({ "url": "/login", "query": { "next": "/a b?c=d" } })),
+            Ok("/login?next=%2Fa%20b%3Fc%3Dd".to_string())
+        );
+        assert_eq!(
+            location(json!({ "url": "/x?lang=en#top", "query": { "q": "é" } })),
+            Ok("/x?lang=en&q=%C3%A9#top".to_string())
+        );
+        assert!(location(json!({ "query": {} })).is_err());
+        assert!(location(json!(3)).is_err());
+
+        let injected = Err("redirect location contains control characters".to_string());
+        assert_eq!(location(json!("/a\r\nSet-Cookie: x=1")), injected);
+        assert_eq!(location(json!({ "url": "/a\nb", "query": {} })), injected);
+        assert_eq!(
+            redirect_location(&Payload::Raw(b"/a\r\nb".to_vec().into(), None)),
+            injected
+        );
+    }
+
+    #[test]
+    fn redirect_after_headers_sent() {
+        let bt = BTreeMap::from([("redirect".to_string(), json!(true))]);
+        let config = ResponseFactory {}.new_config("R", &[], &bt).unwrap();
+        let node = ResponseFactory {}.new_node(&*config);
+        let state = node.new_state();
+        let body = Payload::Json(json!("/login"));
+
+        for phase in [Phase::HttpResponseBody, Phase::HttpResponseTrailers] {
+            let input = Input {
+                data: &[Some(&body)],
+                phase,
+                token_id: None,
+                dry_run: false,
+                state: state.as_deref(),
+            };
+            match node.run(&TestContext, &input) {
+                Fail(Some(Payload::Error(e))) => assert_eq!(
+                    e,
+                    "response: node 'R': cannot redirect, headers already sent"
+                ),
+                _ => panic!("redirect did not fail"),
+            }
+        }
+        assert!(test_host::take_local_response().is_none());
+        assert!(!node.has_responded(state.as_deref()));
+    }
 }
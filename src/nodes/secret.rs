@@ -0,0 +1,142 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+/// Text used in place of secret values in debug traces and logs.
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone)]
+pub struct SecretConfig {
+    value: String,
+}
+
+impl NodeConfig for SecretConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Secret {
+    config: SecretConfig,
+}
+
+impl Node for Secret {
+    fn run(&self, _ctx: &dyn HttpContext, _input: &Input) -> State {
+        Done(Some(Payload::Raw(
+            self.config.value.clone().into_bytes().into(),
+            None,
+        )))
+    }
+}
+
+pub struct SecretFactory {}
+
+impl NodeFactory for SecretFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let value = match (
+            get_config_value::<String>(bt, "value"),
+            get_config_value::<String>(bt, "env"),
+        ) {
+            (Some(value), None) => {
+                // Kong resolves vault references before handing us the
+                // configuration; if one is still here, it failed to resolve.
+                if value.starts_with("{vault://") {
+                    return Err(format!("secret: node '{name}': unresolved vault reference"));
+                }
+                value
+            }
+            (None, Some(var)) => std::env::var(&var).map_err(|_| {
+                format!("secret: node '{name}': environment variable '{var}' is not set")
+            })?,
+            _ => {
+                return Err(format!(
+                    "secret: node '{name}': either 'value' or 'env' must be given"
+                ));
+            }
+        };
+
+        Ok(Box::new(SecretConfig { value }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<SecretConfig>() {
+            Some(cc) => Box::new(Secret { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["value", "env"]
+    }
+
+    fn placeholder_keys(&self) -> &'static [&'static str] {
+        &["value"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn secret_value(bt: &[(&str, Value)]) -> Result<String, String> {
+        let bt = bt.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        let config = SecretFactory {}.new_config("SECRET", &[], &bt)?;
+        Ok(config
+            .as_any()
+            .downcast_ref::<SecretConfig>()
+            .unwrap()
+            .value
+            .clone())
+    }
+
+    #[test]
+    fn from_value() {
+        assert_eq!(
+            secret_value(&[("value", json!("s3cr3t"))]),
+            Ok("s3cr3t".to_string())
+        );
+        assert_eq!(
+            secret_value(&[("value", json!("{vault://env/api-key}"))]),
+            Err("secret: node 'SECRET': unresolved vault reference".to_string())
+        );
+    }
+
+    #[test]
+    fn from_env() {
+        std::env::set_var("DATAKIT_TEST_SECRET", "from-env");
+        assert_eq!(
+            secret_value(&[("env", json!("DATAKIT_TEST_SECRET"))]),
+            Ok("from-env".to_string())
+        );
+        assert_eq!(
+            secret_value(&[("env", json!("DATAKIT_TEST_UNSET_SECRET"))]),
+            Err(
+                "secret: node 'SECRET': environment variable 'DATAKIT_TEST_UNSET_SECRET' is not set"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn value_or_env() {
+        let expected =
+            Err("secret: node 'SECRET': either 'value' or 'env' must be given".to_string());
+        assert_eq!(secret_value(&[]), expected);
+        assert_eq!(
+            secret_value(&[("value", json!("a")), ("env", json!("B"))]),
+            expected
+        );
+    }
+}
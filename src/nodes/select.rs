@@ -0,0 +1,214 @@
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug)]
+enum Selection {
+    Single(Vec<Step>),
+    Many(Vec<(String, Vec<Step>)>),
+}
+
+#[derive(Clone, Debug)]
+pub struct SelectConfig {
+    selection: Selection,
+}
+
+impl NodeConfig for SelectConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Select {
+    config: SelectConfig,
+}
+
+/// Parses a dot-path such as `.data.items[0].id`. A leading `$` or `.`
+/// is optional, so `$.data.items[0].id` and `data.items[0].id` are
+/// equivalent.
+pub fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let rest = path.strip_prefix('$').unwrap_or(path);
+    let rest = rest.strip_prefix('.').unwrap_or(rest);
+
+    let mut steps = vec![];
+    if rest.is_empty() {
+        return Ok(steps);
+    }
+
+    for part in rest.split('.') {
+        let (key, mut indexes) = match part.find('[') {
+            Some(i) => (&part[..i], &part[i..]),
+            None => (part, ""),
+        };
+
+        if !key.is_empty() {
+            steps.push(Step::Key(key.to_string()));
+        } else if indexes.is_empty() {
+            return Err(format!("invalid path '{path}': empty key"));
+        }
+
+        while !indexes.is_empty() {
+            let Some(end) = indexes.find(']') else {
+                return Err(format!("invalid path '{path}': missing ']'"));
+            };
+            let Ok(n) = indexes[1..end].parse::<usize>() else {
+                return Err(format!("invalid path '{path}': bad index"));
+            };
+            steps.push(Step::Index(n));
+
+            indexes = &indexes[end + 1..];
+            if !indexes.is_empty() && !indexes.starts_with('[') {
+                return Err(format!("invalid path '{path}': unexpected '{indexes}'"));
+            }
+        }
+    }
+
+    Ok(steps)
+}
+
+pub fn lookup<'a>(value: &'a Value, steps: &[Step]) -> Option<&'a Value> {
+    steps.iter().try_fold(value, |v, step| match step {
+        Step::Key(k) => v.get(k),
+        Step::Index(i) => v.get(i),
+    })
+}
+
+impl SelectConfig {
+    fn apply(&self, value: &Value) -> Value {
+        match &self.selection {
+            Selection::Single(steps) => lookup(value, steps).cloned().unwrap_or(Value::Null),
+            Selection::Many(paths) => {
+                let mut map = serde_json::Map::new();
+                for (name, steps) in paths {
+                    let v = lookup(value, steps).cloned().unwrap_or(Value::Null);
+                    map.insert(name.clone(), v);
+                }
+                Value::Object(map)
+            }
+        }
+    }
+}
+
+impl Node for Select {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match input.data.first().unwrap_or(&None) {
+            Some(Payload::Json(value)) => Done(Some(Payload::Json(self.config.apply(value)))),
+            Some(payload) => match payload.to_json() {
+                Ok(value) => Done(Some(Payload::Json(self.config.apply(&value)))),
+                Err(e) => Fail(Some(Payload::Error(format!("select: {e}")))),
+            },
+            None => Done(None),
+        }
+    }
+}
+
+pub struct SelectFactory {}
+
+impl NodeFactory for SelectFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let err = |e: String| format!("select: node '{name}': {e}");
+
+        let selection = match (bt.get("path"), bt.get("paths")) {
+            (Some(Value::String(path)), None) => Selection::Single(parse_path(path).map_err(err)?),
+            (None, Some(Value::Array(paths))) => {
+                let mut many = vec![];
+                for p in paths {
+                    let Value::String(path) = p else {
+                        return Err(err("'paths' must contain strings".to_string()));
+                    };
+                    many.push((path.clone(), parse_path(path).map_err(err)?));
+                }
+                Selection::Many(many)
+            }
+            (None, Some(Value::Object(paths))) => {
+                let mut many = vec![];
+                for (key, p) in paths {
+                    let Value::String(path) = p else {
+                        return Err(err("'paths' must contain strings".to_string()));
+                    };
+                    many.push((key.clone(), parse_path(path).map_err(err)?));
+                }
+                Selection::Many(many)
+            }
+            _ => {
+                return Err(err("either 'path' or 'paths' must be given".to_string()));
+            }
+        };
+
+        Ok(Box::new(SelectConfig { selection }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<SelectConfig>() {
+            Some(cc) => Box::new(Select { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["path", "paths"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_paths() {
+        let expected = vec![
+            Step::Key("data".to_string()),
+            Step::Key("items".to_string()),
+            Step::Index(0),
+            Step::Key("id".to_string()),
+        ];
+
+        assert_eq!(parse_path(".data.items[0].id").unwrap(), expected);
+        assert_eq!(parse_path("$.data.items[0].id").unwrap(), expected);
+        assert_eq!(parse_path("data.items[0].id").unwrap(), expected);
+        assert_eq!(parse_path(".").unwrap(), vec![]);
+        assert_eq!(
+            parse_path("[1][2]").unwrap(),
+            vec![Step::Index(1), Step::Index(2)]
+        );
+
+        assert!(parse_path("a..b").is_err());
+        assert!(parse_path("a[x]").is_err());
+        assert!(parse_path("a[0").is_err());
+    }
+
+    #[test]
+    fn select_values() {
+        let value = json!({ "data": { "items": [{ "id": 7 }], "total": 1 } });
+
+        let single = SelectConfig {
+            selection: Selection::Single(parse_path(".data.items[0].id").unwrap()),
+        };
+        assert_eq!(single.apply(&value), json!(7));
+
+        let many = SelectConfig {
+            selection: Selection::Many(vec![
+                ("id".to_string(), parse_path("data.items[0].id").unwrap()),
+                ("missing".to_string(), parse_path("data.items[5]").unwrap()),
+            ]),
+        };
+        assert_eq!(many.apply(&value), json!({ "id": 7, "missing": null }));
+    }
+}
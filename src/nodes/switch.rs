@@ -0,0 +1,205 @@
+use proxy_wasm::traits::*;
+use regex::Regex;
+use serde_json::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::jq::Jq;
+use crate::nodes::{node_state, Node, NodeConfig, NodeFactory};
+
+#[derive(Clone)]
+enum Condition {
+    Equals(Value),
+    Match(Regex),
+    Jq(Jq),
+}
+
+#[derive(Clone)]
+struct Case {
+    output: String,
+    condition: Condition,
+}
+
+#[derive(Clone)]
+pub struct SwitchConfig {
+    cases: Vec<Case>,
+    default: String,
+}
+
+impl NodeConfig for SwitchConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Switch {
+    config: SwitchConfig,
+}
+
+/// The output selected by the last run of the node.
+#[derive(Default)]
+struct SwitchState {
+    active: RefCell<Option<String>>,
+}
+
+impl Condition {
+    fn matches(&self, payload: &Payload) -> Result<bool, String> {
+        match self {
+            Condition::Equals(expected) => Ok(payload.to_json()? == *expected),
+            Condition::Match(regex) => match payload {
+                Payload::Raw(bytes, _) => Ok(regex.is_match(&String::from_utf8_lossy(bytes))),
+                Payload::Json(Value::String(s)) => Ok(regex.is_match(s)),
+                Payload::Json(v) => Ok(regex.is_match(&v.to_string())),
+                Payload::Error(e) => Err(e.clone()),
+            },
+            Condition::Jq(jq) => {
+                let results = jq.eval(&[Some(payload)])?;
+                Ok(matches!(
+                    results.first(),
+                    Some(v) if !v.is_null() && *v != Value::Bool(false)
+                ))
+            }
+        }
+    }
+}
+
+impl SwitchConfig {
+    fn select(&self, payload: Option<&Payload>) -> Result<&str, String> {
+        if let Some(p) = payload {
+            for case in &self.cases {
+                if case.condition.matches(p)? {
+                    return Ok(&case.output);
+                }
+            }
+        }
+
+        Ok(&self.default)
+    }
+}
+
+impl Node for Switch {
+    fn new_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(SwitchState::default()))
+    }
+
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let payload = *input.data.first().unwrap_or(&None);
+
+        match self.config.select(payload) {
+            Ok(output) => {
+                *node_state::<SwitchState>(input.state).active.borrow_mut() =
+                    Some(output.to_string());
+                Done(payload.cloned())
+            }
+            Err(e) => Fail(Some(Payload::Error(format!("switch: {e}")))),
+        }
+    }
+
+    fn active_output(&self, state: Option<&dyn Any>) -> Option<String> {
+        node_state::<SwitchState>(state).active.borrow().clone()
+    }
+}
+
+pub struct SwitchFactory {}
+
+fn parse_case(name: &str, i: usize, value: &Value) -> Result<Case, String> {
+    let err = |msg: &str| format!("switch: node '{name}': case {i}: {msg}");
+
+    let Some(output) = value.get("output").and_then(|o| o.as_str()) else {
+        return Err(err("missing 'output'"));
+    };
+
+    let condition = if let Some(v) = value.get("equals") {
+        Condition::Equals(v.clone())
+    } else if let Some(Value::String(pattern)) = value.get("match") {
+        Condition::Match(Regex::new(pattern).map_err(|e| err(&e.to_string()))?)
+    } else if let Some(Value::String(filter)) = value.get("jq") {
+        Condition::Jq(Jq::new(filter, vec!["value".to_string()]).map_err(|e| err(&e))?)
+    } else {
+        return Err(err("one of 'equals', 'match' or 'jq' is required"));
+    };
+
+    Ok(Case {
+        output: output.to_string(),
+        condition,
+    })
+}
+
+impl NodeFactory for SwitchFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(Value::Array(case_list)) = bt.get("cases") else {
+            return Err(format!("switch: node '{name}': missing 'cases' list"));
+        };
+
+        let cases = case_list
+            .iter()
+            .enumerate()
+            .map(|(i, v)| parse_case(name, i, v))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Box::new(SwitchConfig {
+            cases,
+            default: get_config_value(bt, "default").unwrap_or_else(|| String::from("default")),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<SwitchConfig>() {
+            Some(cc) => Box::new(Switch { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["cases", "default"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn selects_first_matching_case() {
+        let cases = json!([
+            { "output": "acme", "equals": "acme" },
+            { "output": "beta", "match": "^beta-" },
+            { "output": "big", "jq": "$value > 10" }
+        ]);
+        let cases: Vec<Case> = cases
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| parse_case("test", i, v).unwrap())
+            .collect();
+        let config = SwitchConfig {
+            cases,
+            default: "other".to_string(),
+        };
+
+        let select = |v: Value| config.select(Some(&Payload::Json(v))).unwrap().to_string();
+
+        assert_eq!(select(json!("acme")), "acme");
+        assert_eq!(select(json!("beta-1")), "beta");
+        assert_eq!(select(json!(42)), "big");
+        assert_eq!(select(json!(3)), "other");
+        assert_eq!(config.select(None).unwrap(), "other");
+    }
+
+    #[test]
+    fn invalid_cases() {
+        assert!(parse_case("test", 0, &json!({ "equals": 1 })).is_err());
+        assert!(parse_case("test", 0, &json!({ "output": "a" })).is_err());
+        assert!(parse_case("test", 0, &json!({ "output": "a", "match": "(" })).is_err());
+    }
+}
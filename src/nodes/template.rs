@@ -1,17 +1,50 @@
-use handlebars::Handlebars;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperResult, RenderContext, RenderErrorReason,
+};
+use percent_encoding::utf8_percent_encode;
 use proxy_wasm::traits::*;
 use serde_json::Value;
 use std::any::Any;
 use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::config::get_config_value;
-use crate::data::{Input, Payload, State};
+use crate::data::{Input, Payload, State, QUERY_ENCODE_SET};
+use crate::nodes::clock::iso8601;
 use crate::nodes::{Node, NodeConfig, NodeFactory};
 
+/// How values interpolated with `{{...}}` are escaped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Escape {
+    Html,
+    Json,
+}
+
+#[derive(Clone)]
+enum Compiled {
+    Handlebars(handlebars::Template),
+    Liquid(Rc<liquid::Template>),
+}
+
+impl std::fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compiled::Handlebars(t) => f.debug_tuple("Handlebars").field(t).finish(),
+            Compiled::Liquid(_) => f.write_str("Liquid"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct TemplateConfig {
-    template: String,
+    template: Compiled,
     content_type: String,
+    escape: Escape,
+    strict: bool,
+    partials: BTreeMap<String, handlebars::Template>,
     inputs: Vec<String>,
 }
 
@@ -27,15 +60,148 @@ pub struct Template<'a> {
     handlebars: Handlebars<'a>,
 }
 
+fn now_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> HelperResult {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let s = match h.param(0).and_then(|p| p.value().as_str()) {
+        Some("iso8601") | None => iso8601(now.as_secs() as i64),
+        Some("unix") => now.as_secs().to_string(),
+        Some("unix_ms") => now.as_millis().to_string(),
+        Some(other) => {
+            return Err(RenderErrorReason::Other(format!("now: unknown format '{other}'")).into())
+        }
+    };
+    out.write(&s)?;
+    Ok(())
+}
+
+/// Joins the items of an array, such as the values of a header that
+/// appears several times; other values are rendered as themselves.
+fn join_values(v: &Value, sep: &str) -> String {
+    let item = |v: &Value| match v {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    };
+    match v {
+        Value::Array(vs) => vs.iter().map(item).collect::<Vec<_>>().join(sep),
+        v => item(v),
+    }
+}
+
+fn join(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> HelperResult {
+    let value = h.param(0).map_or(&Value::Null, |p| p.value());
+    let sep = h.param(1).and_then(|p| p.value().as_str()).unwrap_or(", ");
+    out.write(&join_values(value, sep))?;
+    Ok(())
+}
+
+fn to_number(v: &Value) -> Option<serde_json::Number> {
+    match v {
+        Value::Number(n) => Some(n.clone()),
+        Value::String(s) => serde_json::from_str(s.trim()).ok(),
+        _ => None,
+    }
+}
+
+/// Applies an arithmetic operation, keeping integer results
+/// as integers. Invalid operations produce `null`.
+fn arith(a: &Value, b: &Value, op: char) -> Value {
+    let (Some(a), Some(b)) = (to_number(a), to_number(b)) else {
+        return Value::Null;
+    };
+
+    if let (Some(x), Some(y)) = (a.as_i64(), b.as_i64()) {
+        let r = match op {
+            '+' => x.checked_add(y),
+            '-' => x.checked_sub(y),
+            '*' => x.checked_mul(y),
+            _ => None,
+        };
+        if let Some(r) = r {
+            return r.into();
+        }
+    }
+
+    let (Some(x), Some(y)) = (a.as_f64(), b.as_f64()) else {
+        return Value::Null;
+    };
+    let r = match op {
+        '+' => x + y,
+        '-' => x - y,
+        '*' => x * y,
+        '/' if y != 0.0 => x / y,
+        _ => return Value::Null,
+    };
+    serde_json::Number::from_f64(r).map_or(Value::Null, Value::Number)
+}
+
+handlebars_helper!(base64_helper: |s: str| STANDARD.encode(s));
+handlebars_helper!(lower: |s: str| s.to_lowercase());
+handlebars_helper!(upper: |s: str| s.to_uppercase());
+handlebars_helper!(trim: |s: str| s.trim().to_string());
+handlebars_helper!(replace: |s: str, from: str, to: str| s.replace(from, to));
+handlebars_helper!(url_encode: |s: str| utf8_percent_encode(s, QUERY_ENCODE_SET).to_string());
+handlebars_helper!(json: |v: Json| v.to_string());
+handlebars_helper!(default: |v: Json, d: Json| {
+    if v.is_null() || v.as_str() == Some("") { d.clone() } else { v.clone() }
+});
+handlebars_helper!(add: |a: Json, b: Json| arith(a, b, '+'));
+handlebars_helper!(sub: |a: Json, b: Json| arith(a, b, '-'));
+handlebars_helper!(mul: |a: Json, b: Json| arith(a, b, '*'));
+handlebars_helper!(div: |a: Json, b: Json| arith(a, b, '/'));
+
+/// Escapes a value for use inside a JSON string, so that quotes,
+/// backslashes and control characters such as newlines are kept.
+fn json_escape(s: &str) -> String {
+    let quoted = serde_json::to_string(s).expect("serializable string");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn register_helpers(hb: &mut Handlebars) {
+    hb.register_helper("base64", Box::new(base64_helper));
+    hb.register_helper("lower", Box::new(lower));
+    hb.register_helper("upper", Box::new(upper));
+    hb.register_helper("trim", Box::new(trim));
+    hb.register_helper("replace", Box::new(replace));
+    hb.register_helper("url_encode", Box::new(url_encode));
+    hb.register_helper("json", Box::new(json));
+    hb.register_helper("join", Box::new(join));
+    hb.register_helper("default", Box::new(default));
+    hb.register_helper("now", Box::new(now_helper));
+    hb.register_helper("add", Box::new(add));
+    hb.register_helper("sub", Box::new(sub));
+    hb.register_helper("mul", Box::new(mul));
+    hb.register_helper("div", Box::new(div));
+}
+
 impl Template<'_> {
     fn new(config: TemplateConfig) -> Self {
         let mut hb = Handlebars::new();
+        register_helpers(&mut hb);
+        if config.escape == Escape::Json {
+            hb.register_escape_fn(json_escape);
+        }
+        hb.set_strict_mode(config.strict);
 
-        match hb.register_template_string("template", &config.template) {
-            Ok(()) => {}
-            Err(err) => {
-                log::error!("template: error registering template: {err}");
+        if let Compiled::Handlebars(template) = &config.template {
+            for (name, partial) in &config.partials {
+                hb.register_template(name, partial.clone());
             }
+            hb.register_template("template", template.clone());
         }
 
         Template {
@@ -45,6 +211,16 @@ impl Template<'_> {
     }
 }
 
+impl Template<'_> {
+    fn output(&self, output: String) -> State {
+        log::debug!("output: {output}");
+        match Payload::from_bytes(output.into(), Some(&self.config.content_type)) {
+            p @ Some(Payload::Error(_)) => State::Fail(p),
+            p => State::Done(p),
+        }
+    }
+}
+
 impl Node for Template<'_> {
     fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
         let mut vs = Vec::new();
@@ -55,17 +231,10 @@ impl Node for Template<'_> {
                 Some(Payload::Json(value)) => {
                     data.insert(input_name, value);
                 }
-                Some(Payload::Raw(vec_bytes)) => {
-                    match std::str::from_utf8(vec_bytes) {
-                        Ok(s) => {
-                            let v = serde_json::to_value::<String>(s.into())
-                                .expect("valid UTF-8 string");
-                            vs.push((input_name, v));
-                        }
-                        Err(err) => {
-                            log::error!("template: input string is not valid UTF-8: {err}");
-                        }
-                    };
+                Some(p @ Payload::Raw(_, _)) => {
+                    // binary inputs are given to templates in base64
+                    let v = p.to_json_or_base64().expect("raw payload");
+                    vs.push((input_name, v));
                 }
                 Some(Payload::Error(error)) => {
                     vs.push((input_name, serde_json::json!(error)));
@@ -78,33 +247,92 @@ impl Node for Template<'_> {
             data.insert(input_name, v);
         }
 
-        match self.handlebars.render("template", &data) {
-            Ok(output) => {
-                log::debug!("output: {output}");
-                match Payload::from_bytes(output.into(), Some(&self.config.content_type)) {
-                    p @ Some(Payload::Error(_)) => State::Fail(p),
-                    p => State::Done(p),
-                }
+        let rendered = match &self.config.template {
+            Compiled::Handlebars(_) => self.handlebars.render("template", &data),
+            Compiled::Liquid(template) => {
+                let globals = liquid::model::to_object(&data);
+                return match globals.and_then(|g| template.render(&g)) {
+                    Ok(output) => self.output(output),
+                    Err(err) => State::Fail(Some(Payload::Error(format!(
+                        "error rendering template: {err}"
+                    )))),
+                };
+            }
+        };
+
+        match rendered {
+            Ok(output) => self.output(output),
+            Err(err) => {
+                let msg = match err.reason() {
+                    RenderErrorReason::MissingVariable(Some(var)) => {
+                        format!("template: missing variable '{var}'")
+                    }
+                    _ => format!("error rendering template: {err}"),
+                };
+                State::Fail(Some(Payload::Error(msg)))
             }
-            Err(err) => State::Fail(Some(Payload::Error(format!(
-                "error rendering template: {err}"
-            )))),
         }
     }
 }
 
+fn compile(name: &str, source: &str) -> Result<handlebars::Template, String> {
+    let mut template = handlebars::Template::compile(source).map_err(|e| e.to_string())?;
+    template.name = Some(name.to_string());
+    Ok(template)
+}
+
 pub struct TemplateFactory {}
 
 impl NodeFactory for TemplateFactory {
     fn new_config(
         &self,
-        _name: &str,
+        name: &str,
         inputs: &[String],
         bt: &BTreeMap<String, Value>,
     ) -> Result<Box<dyn NodeConfig>, String> {
+        let source: String = get_config_value(bt, "template").unwrap_or_else(|| String::from(""));
+        let template = match get_config_value::<String>(bt, "engine").as_deref() {
+            Some("handlebars") | None => compile("template", &source).map(Compiled::Handlebars),
+            Some("liquid") => liquid::ParserBuilder::with_stdlib()
+                .build()
+                .and_then(|parser| parser.parse(&source))
+                .map(|t| Compiled::Liquid(Rc::new(t)))
+                .map_err(|e| e.to_string()),
+            Some(other) => {
+                return Err(format!("template: node '{name}': invalid engine '{other}'"));
+            }
+        }
+        .map_err(|err| format!("template: node '{name}': {err}"))?;
+
+        let sources: BTreeMap<String, String> =
+            get_config_value(bt, "partials").unwrap_or_default();
+        let mut partials = BTreeMap::new();
+        for (partial_name, source) in &sources {
+            let partial = compile(partial_name, source).map_err(|err| {
+                format!("template: node '{name}': partial '{partial_name}': {err}")
+            })?;
+            partials.insert(partial_name.clone(), partial);
+        }
+
+        let escape = match get_config_value::<String>(bt, "escape").as_deref() {
+            Some("html") | None => Escape::Html,
+            Some("json") => Escape::Json,
+            Some(other) => {
+                return Err(format!("template: node '{name}': invalid escape '{other}'"));
+            }
+        };
+        if escape == Escape::Json && matches!(template, Compiled::Liquid(_)) {
+            return Err(format!(
+                "template: node '{name}': 'escape' is only supported by the handlebars engine"
+            ));
+        }
+
         Ok(Box::new(TemplateConfig {
             inputs: inputs.to_vec(),
-            template: get_config_value(bt, "template").unwrap_or_else(|| String::from("")),
+            template,
+            escape,
+            strict: get_config_value(bt, "strict").unwrap_or(false),
+            partials,
             content_type: get_config_value(bt, "content_type")
                 .unwrap_or_else(|| String::from("application/json")),
         }))
@@ -116,4 +344,89 @@ impl NodeFactory for TemplateFactory {
             None => panic!("incompatible NodeConfig"),
         }
     }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &[
+            "template",
+            "engine",
+            "content_type",
+            "escape",
+            "strict",
+            "partials",
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: Value) -> String {
+        let mut hb = Handlebars::new();
+        register_helpers(&mut hb);
+        hb.render_template(template, &data).unwrap()
+    }
+
+    #[test]
+    fn helpers() {
+        let data = json!({ "name": " Jane Doe ", "n": 7, "s": "2" });
+        assert_eq!(render("{{upper (trim name)}}", data.clone()), "JANE DOE");
+        assert_eq!(
+            render("{{url_encode name}}", data.clone()),
+            "%20Jane%20Doe%20"
+        );
+        assert_eq!(render("{{add n s}} {{div n 2}}", data.clone()), "9 3.5");
+        assert_eq!(render("{{default missing \"x\"}}", data.clone()), "x");
+        assert_eq!(render("{{{json name}}}", data), "\" Jane Doe \"");
+    }
+
+    #[test]
+    fn header_iteration() {
+        let headers = crate::data::from_pwm_headers(vec![
+            ("X-B".to_string(), "2".to_string()),
+            ("Accept".to_string(), "text/html".to_string()),
+            ("x-b".to_string(), "3".to_string()),
+        ]);
+        let data = json!({ "headers": headers.to_json().unwrap() });
+        assert_eq!(
+            render("{{#each headers}}{{@key}}: {{join this}};{{/each}}", data),
+            "accept: text/html;x-b: 2, 3;"
+        );
+    }
+
+    #[test]
+    fn json_escaping() {
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(json_escape);
+        let data = json!({ "msg": "say \"hi\"\n<ok>", "n": 3 });
+        let out = hb
+            .render_template(r#"{"msg": "{{msg}}", "n": {{n}} }"#, &data)
+            .unwrap();
+        let value: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value, data);
+    }
+
+    #[test]
+    fn liquid_engine() {
+        let bt = BTreeMap::from([
+            ("engine".to_string(), json!("liquid")),
+            ("content_type".to_string(), json!("text/plain")),
+            (
+                "template".to_string(),
+                json!("Hello, {{ user.name | upcase }}!"),
+            ),
+        ]);
+        let config = TemplateFactory {}
+            .new_config("greet", &["user".to_string()], &bt)
+            .unwrap();
+        let config = config.as_any().downcast_ref::<TemplateConfig>().unwrap();
+        let Compiled::Liquid(template) = &config.template else {
+            panic!("expected a liquid template");
+        };
+
+        let data = BTreeMap::from([("user", json!({ "name": "Jane" }))]);
+        let globals = liquid::model::to_object(&data).unwrap();
+        assert_eq!(template.render(&globals).unwrap(), "Hello, JANE!");
+    }
 }
@@ -0,0 +1,220 @@
+use jsonschema::JSONSchema;
+use proxy_wasm::traits::*;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone)]
+pub struct ValidateConfig {
+    schema: Rc<JSONSchema>,
+}
+
+impl NodeConfig for ValidateConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Validate {
+    config: ValidateConfig,
+}
+
+impl ValidateConfig {
+    fn violations(&self, value: &Value) -> Vec<Value> {
+        match self.schema.validate(value) {
+            Ok(()) => vec![],
+            Err(errors) => errors
+                .map(|e| {
+                    serde_json::json!({
+                        "path": e.instance_path.to_string(),
+                        "message": e.to_string(),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Node for Validate {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        let Some(payload) = input.data.first().unwrap_or(&None) else {
+            return Done(None);
+        };
+
+        let value = match payload.to_json() {
+            Ok(v) => v,
+            Err(e) => return Fail(Some(Payload::Error(format!("validate: {e}")))),
+        };
+
+        let violations = self.config.violations(&value);
+        if violations.is_empty() {
+            Done(Some((*payload).clone()))
+        } else {
+            Fail(Some(Payload::Json(serde_json::json!({
+                "message": "validation failed",
+                "errors": violations,
+            }))))
+        }
+    }
+}
+
+/// Schemas are given either as JSON values in the configuration, or as
+/// strings holding JSON text, such as the contents of a `schema_file`.
+fn parse_schema(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::String(s) => serde_json::from_str(s).map_err(|e| e.to_string()),
+        v => Ok(v.clone()),
+    }
+}
+
+pub struct ValidateFactory {}
+
+impl NodeFactory for ValidateFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let Some(schema) = bt.get("schema") else {
+            return Err(format!("validate: node '{name}': missing 'schema'"));
+        };
+        let schema = parse_schema(schema)
+            .map_err(|e| format!("validate: node '{name}': invalid schema: {e}"))?;
+
+        let mut options = JSONSchema::options();
+        if let Some(schemas) = bt.get("schemas") {
+            let Value::Object(schemas) = parse_schema(schemas)
+                .map_err(|e| format!("validate: node '{name}': invalid schemas: {e}"))?
+            else {
+                return Err(format!(
+                    "validate: node '{name}': 'schemas' must map URIs to schemas"
+                ));
+            };
+            for (uri, document) in schemas {
+                let document = parse_schema(&document)
+                    .map_err(|e| format!("validate: node '{name}': invalid schema '{uri}': {e}"))?;
+                options.with_document(uri, document);
+            }
+        }
+
+        let compiled = options
+            .compile(&schema)
+            .map_err(|e| format!("validate: node '{name}': invalid schema: {e}"))?;
+
+        Ok(Box::new(ValidateConfig {
+            schema: Rc::new(compiled),
+        }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<ValidateConfig>() {
+            Some(cc) => Box::new(Validate { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["schema", "schemas"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_violations() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id"],
+            "properties": {
+                "id": { "type": "integer" }
+            }
+        });
+        let config = ValidateConfig {
+            schema: Rc::new(JSONSchema::compile(&schema).unwrap()),
+        };
+
+        assert!(config.violations(&json!({ "id": 1 })).is_empty());
+
+        let violations = config.violations(&json!({ "id": "x" }));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0]["path"], json!("/id"));
+    }
+
+    fn new_config(bt: BTreeMap<String, Value>) -> Result<ValidateConfig, String> {
+        let config = ValidateFactory {}.new_config("test", &[], &bt)?;
+        Ok(config
+            .as_any()
+            .downcast_ref::<ValidateConfig>()
+            .unwrap()
+            .clone())
+    }
+
+    #[test]
+    fn schema_as_text() {
+        let config = new_config(BTreeMap::from([(
+            "schema".to_string(),
+            json!(r#"{ "type": "integer" }"#),
+        )]))
+        .unwrap();
+
+        assert!(config.violations(&json!(1)).is_empty());
+        assert_eq!(config.violations(&json!("x")).len(), 1);
+
+        let bad = new_config(BTreeMap::from([(
+            "schema".to_string(),
+            json!("{ not json"),
+        )]));
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn referenced_schemas() {
+        let config = new_config(BTreeMap::from([
+            (
+                "schema".to_string(),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "address": { "$ref": "https://example.com/address.json" }
+                    }
+                }),
+            ),
+            (
+                "schemas".to_string(),
+                json!({
+                    "https://example.com/address.json": r#"{
+                        "type": "object",
+                        "required": ["city"]
+                    }"#
+                }),
+            ),
+        ]))
+        .unwrap();
+
+        assert!(config
+            .violations(&json!({ "address": { "city": "Paris" } }))
+            .is_empty());
+        assert_eq!(config.violations(&json!({ "address": {} })).len(), 1);
+    }
+
+    #[test]
+    fn unknown_reference() {
+        let config = new_config(BTreeMap::from([(
+            "schema".to_string(),
+            json!({ "$ref": "https://example.com/missing.json" }),
+        )]))
+        .unwrap();
+
+        // schemas are not fetched, so the input cannot be valid
+        assert_eq!(config.violations(&json!({})).len(), 1);
+    }
+}
@@ -0,0 +1,464 @@
+use proxy_wasm::traits::*;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+
+use crate::config::get_config_value;
+use crate::data::{Input, Payload, State, State::*};
+use crate::nodes::{Node, NodeConfig, NodeFactory};
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    FromXml,
+    ToXml,
+}
+
+#[derive(Clone, Debug)]
+pub struct XmlConfig {
+    action: Action,
+    path: Option<Vec<String>>,
+}
+
+impl NodeConfig for XmlConfig {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Xml {
+    config: XmlConfig,
+}
+
+// -----------------------------------------------------------------------------
+// XML to JSON
+// -----------------------------------------------------------------------------
+//
+// The canonical JSON form of an XML document is an object keyed by the name
+// of the root element. Each element becomes:
+//
+// * a string with its text, if it has no attributes and no child elements
+//   (or `null`, if it is also empty);
+// * otherwise, an object in which attributes are keyed as `@name`, child
+//   elements are keyed by their name (repeated elements become arrays) and
+//   text content is keyed as `#text`.
+
+#[derive(Default)]
+struct Element {
+    name: String,
+    map: Map<String, Value>,
+    text: String,
+}
+
+impl Element {
+    fn new(start: &BytesStart) -> Result<Element, String> {
+        let mut elem = Element {
+            name: String::from_utf8_lossy(start.name().as_ref()).to_string(),
+            ..Default::default()
+        };
+
+        for attr in start.attributes() {
+            let attr = attr.map_err(|e| e.to_string())?;
+            let key = String::from_utf8_lossy(attr.key.as_ref());
+            let value = attr.unescape_value().map_err(|e| e.to_string())?;
+            elem.map
+                .insert(format!("@{key}"), Value::String(value.to_string()));
+        }
+
+        Ok(elem)
+    }
+
+    fn into_value(self) -> Value {
+        let text = self.text.trim();
+
+        if self.map.is_empty() {
+            if text.is_empty() {
+                Value::Null
+            } else {
+                Value::String(text.to_string())
+            }
+        } else {
+            let mut map = self.map;
+            if !text.is_empty() {
+                map.insert("#text".to_string(), Value::String(text.to_string()));
+            }
+            Value::Object(map)
+        }
+    }
+
+    fn add_child(&mut self, name: String, value: Value) {
+        match self.map.get_mut(&name) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None => {
+                self.map.insert(name, value);
+            }
+        }
+    }
+}
+
+fn xml_to_json(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut stack = vec![Element::default()];
+    let mut buf = Vec::new();
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("invalid XML at position {}: {e}", reader.buffer_position()))?;
+
+        match event {
+            Event::Start(start) => {
+                stack.push(Element::new(&start)?);
+            }
+            Event::Empty(start) => {
+                let elem = Element::new(&start)?;
+                let parent = stack.last_mut().expect("document element");
+                parent.add_child(elem.name.clone(), elem.into_value());
+            }
+            Event::End(_) => {
+                if stack.len() < 2 {
+                    return Err("invalid XML: unbalanced closing tag".to_string());
+                }
+                let elem = stack.pop().expect("open element");
+                let parent = stack.last_mut().expect("document element");
+                parent.add_child(elem.name.clone(), elem.into_value());
+            }
+            Event::Text(text) => {
+                let s = text.unescape().map_err(|e| e.to_string())?;
+                stack.last_mut().expect("element").text.push_str(&s);
+            }
+            Event::CData(cdata) => {
+                let s = String::from_utf8_lossy(&cdata);
+                stack.last_mut().expect("element").text.push_str(&s);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    if stack.len() != 1 {
+        return Err("invalid XML: unclosed element".to_string());
+    }
+
+    let doc = stack.pop().expect("document element");
+    Ok(Value::Object(doc.map))
+}
+
+// -----------------------------------------------------------------------------
+// JSON to XML
+// -----------------------------------------------------------------------------
+
+fn escape(s: &str) -> String {
+    quick_xml::escape::escape(s).to_string()
+}
+
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Checks a name against the `Name` production of the XML specification,
+/// so that keys of the input cannot produce malformed markup.
+fn is_xml_name(name: &str) -> bool {
+    let is_start = |c: char| {
+        matches!(c,
+            ':' | 'A'..='Z' | '_' | 'a'..='z'
+            | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}'
+            | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}'
+            | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}'
+            | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' | '\u{10000}'..='\u{EFFFF}')
+    };
+    let is_char = |c: char| {
+        is_start(c)
+            || matches!(c,
+                '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}')
+    };
+
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_start) && chars.all(is_char)
+}
+
+fn write_element(out: &mut String, name: &str, value: &Value) -> Result<(), String> {
+    if !is_xml_name(name) {
+        return Err(format!("invalid element name: '{name}'"));
+    }
+
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                write_element(out, name, item)?;
+            }
+        }
+        Value::Null => {
+            out.push_str(&format!("<{name}/>"));
+        }
+        Value::Object(map) => {
+            out.push('<');
+            out.push_str(name);
+            for (k, v) in map {
+                if let Some(attr) = k.strip_prefix('@') {
+                    if !is_xml_name(attr) {
+                        return Err(format!("invalid attribute name: '{attr}'"));
+                    }
+                    let v = escape(&scalar_to_string(v));
+                    out.push_str(&format!(" {attr}=\"{v}\""));
+                }
+            }
+            out.push('>');
+            for (k, v) in map {
+                if k == "#text" {
+                    out.push_str(&escape(&scalar_to_string(v)));
+                } else if !k.starts_with('@') {
+                    write_element(out, k, v)?;
+                }
+            }
+            out.push_str(&format!("</{name}>"));
+        }
+        v => {
+            let text = escape(&scalar_to_string(v));
+            out.push_str(&format!("<{name}>{text}</{name}>"));
+        }
+    }
+    Ok(())
+}
+
+fn json_to_xml(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Object(map) => {
+            let mut out = String::new();
+            for (k, v) in map {
+                write_element(&mut out, k, v)?;
+            }
+            Ok(out)
+        }
+        _ => Err("input must be an object keyed by the root element name".to_string()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Path extraction
+// -----------------------------------------------------------------------------
+
+/// Navigates the canonical JSON form of a document using a simplified
+/// XPath-style expression such as `/root/items/item/@id`. Arrays found
+/// along the way are traversed, so the result collects all matches.
+fn extract(value: Value, path: &[String]) -> Value {
+    let mut current = vec![value];
+
+    for step in path {
+        let mut next = vec![];
+        for v in current {
+            let items = match v {
+                Value::Array(items) => items,
+                v => vec![v],
+            };
+            for item in items {
+                match (item, step.as_str()) {
+                    (s @ Value::String(_), "text()") => next.push(s),
+                    (Value::Object(mut map), "text()") => {
+                        if let Some(t) = map.remove("#text") {
+                            next.push(t);
+                        }
+                    }
+                    (Value::Object(mut map), key) => match map.remove(key) {
+                        Some(Value::Array(vs)) => next.extend(vs),
+                        Some(v) => next.push(v),
+                        None => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        current = next;
+    }
+
+    match current.len() {
+        0 => Value::Null,
+        1 => current.pop().expect("one item"),
+        _ => Value::Array(current),
+    }
+}
+
+impl XmlConfig {
+    fn apply(&self, payload: &Payload) -> Result<Payload, String> {
+        match self.action {
+            Action::FromXml => {
+                let value = match payload {
+                    Payload::Raw(bytes, _) => xml_to_json(bytes)?,
+                    // a JSON string holding an XML document
+                    Payload::Json(Value::String(s)) => xml_to_json(s.as_bytes())?,
+                    Payload::Json(_) => return Err("input must be an XML document".to_string()),
+                    Payload::Error(e) => return Err(e.clone()),
+                };
+                Ok(Payload::Json(match &self.path {
+                    Some(path) => extract(value, path),
+                    None => value,
+                }))
+            }
+            Action::ToXml => {
+                let value = payload.to_json()?;
+                let xml = json_to_xml(&value)?.into_bytes();
+                Ok(Payload::Raw(
+                    xml.into(),
+                    Some("application/xml".to_string()),
+                ))
+            }
+        }
+    }
+}
+
+impl Node for Xml {
+    fn run(&self, _ctx: &dyn HttpContext, input: &Input) -> State {
+        match input.data.first().unwrap_or(&None) {
+            Some(payload) => match self.config.apply(payload) {
+                Ok(p) => Done(Some(p)),
+                Err(e) => Fail(Some(Payload::Error(format!("xml: {e}")))),
+            },
+            None => Done(None),
+        }
+    }
+}
+
+pub struct XmlFactory {}
+
+impl NodeFactory for XmlFactory {
+    fn new_config(
+        &self,
+        name: &str,
+        _inputs: &[String],
+        bt: &BTreeMap<String, Value>,
+    ) -> Result<Box<dyn NodeConfig>, String> {
+        let action = match get_config_value::<String>(bt, "action").as_deref() {
+            Some("from_xml") | None => Action::FromXml,
+            Some("to_xml") => Action::ToXml,
+            Some(other) => {
+                return Err(format!("xml: node '{name}': invalid action '{other}'"));
+            }
+        };
+
+        let path = get_config_value::<String>(bt, "path").map(|p| {
+            p.split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        });
+
+        Ok(Box::new(XmlConfig { action, path }))
+    }
+
+    fn new_node(&self, config: &dyn NodeConfig) -> Box<dyn Node> {
+        match config.as_any().downcast_ref::<XmlConfig>() {
+            Some(cc) => Box::new(Xml { config: cc.clone() }),
+            None => panic!("incompatible NodeConfig"),
+        }
+    }
+
+    fn config_keys(&self) -> &'static [&'static str] {
+        &["action", "path"]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const DOC: &str = r#"<?xml version="1.0"?>
+        <order id="7">
+          <item sku="a1">Apple</item>
+          <item sku="b2">Banana &amp; co</item>
+          <note/>
+          <total>12.5</total>
+        </order>"#;
+
+    #[test]
+    fn parse_document() {
+        let value = xml_to_json(DOC.as_bytes()).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "order": {
+                    "@id": "7",
+                    "item": [
+                        { "@sku": "a1", "#text": "Apple" },
+                        { "@sku": "b2", "#text": "Banana & co" }
+                    ],
+                    "note": null,
+                    "total": "12.5"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let value = xml_to_json(DOC.as_bytes()).unwrap();
+        let xml = json_to_xml(&value).unwrap();
+
+        assert_eq!(xml_to_json(xml.as_bytes()).unwrap(), value);
+    }
+
+    #[test]
+    fn extract_path() {
+        let value = xml_to_json(DOC.as_bytes()).unwrap();
+        let path = |p: &str| -> Vec<String> {
+            p.split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            extract(value.clone(), &path("/order/item/@sku")),
+            json!(["a1", "b2"])
+        );
+        assert_eq!(
+            extract(value.clone(), &path("/order/total/text()")),
+            json!("12.5")
+        );
+        assert_eq!(extract(value, &path("/order/missing")), json!(null));
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(is_xml_name("ns:item-2.b_c"));
+        assert!(is_xml_name("größe"));
+        assert!(!is_xml_name(""));
+        assert!(!is_xml_name("2nd"));
+        assert!(!is_xml_name("-a"));
+
+        assert_eq!(
+            json_to_xml(&json!({ "a b": "x" })).err(),
+            Some("invalid element name: 'a b'".to_string())
+        );
+        assert_eq!(
+            json_to_xml(&json!({ "a": { "x><evil/": "1" } })).err(),
+            Some("invalid element name: 'x><evil/'".to_string())
+        );
+        assert_eq!(
+            json_to_xml(&json!({ "a": { "@id=\"1\" x": "1" } })).err(),
+            Some("invalid attribute name: 'id=\"1\" x'".to_string())
+        );
+        assert_eq!(
+            json_to_xml(&json!({ "a": [{ "b": 1 }, { "#c": 2 }] })).err(),
+            Some("invalid element name: '#c'".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_document() {
+        assert!(xml_to_json(b"<a><b></a>").is_err());
+        assert!(xml_to_json(b"<a>").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::data::{Payload, State};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A span recorded for the run or resume of a node.
+struct Span {
+    span_id: String,
+    name: String,
+    node_type: String,
+    start: SystemTime,
+    duration: Duration,
+    error: Option<String>,
+}
+
+/// Records a span per node execution, as children of the span given in
+/// the request's `traceparent` header, if any, to export them with OTLP.
+pub struct Tracer {
+    trace_id: String,
+    parent_span_id: Option<String>,
+    sampled: bool,
+    seed: u64,
+    spans: Vec<Span>,
+}
+
+/// Parses a W3C `traceparent` header, of the form
+/// `00-<trace id>-<parent span id>-<flags>`.
+fn parse_traceparent(value: &str) -> Option<(String, String, bool)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+    if !is_hex(version, 2) || version == "ff" || !is_hex(flags, 2) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || is_zero(trace_id) || !is_hex(span_id, 16) || is_zero(span_id) {
+        return None;
+    }
+
+    let sampled = u8::from_str_radix(flags, 16).ok()? & 1 == 1;
+    Some((trace_id.to_string(), span_id.to_string(), sampled))
+}
+
+/// SplitMix64, to generate ids that only need to be unique.
+pub fn next_id(seed: &mut u64) -> u64 {
+    *seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// The status message of a span for a node that failed.
+fn error_message(state: &State) -> Option<String> {
+    match state {
+        State::Fail(Some(Payload::Error(e))) => Some(e.clone()),
+        State::Fail(Some(p)) => Some(p.to_json().map_or_else(|e| e, |v| v.to_string())),
+        State::Fail(None) => Some("failed".to_string()),
+        _ => None,
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+impl Tracer {
+    /// Creates a tracer joining the trace of the `traceparent` header, or
+    /// starting a new trace. The seed makes the ids of each request differ.
+    pub fn new(traceparent: Option<&str>, seed: u64) -> Tracer {
+        let mut seed = seed;
+        match traceparent.and_then(parse_traceparent) {
+            Some((trace_id, parent_span_id, sampled)) => Tracer {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                sampled,
+                seed,
+                spans: vec![],
+            },
+            None => {
+                let trace_id = format!("{:016x}{:016x}", next_id(&mut seed), next_id(&mut seed));
+                Tracer {
+                    trace_id,
+                    parent_span_id: None,
+                    sampled: true,
+                    seed,
+                    spans: vec![],
+                }
+            }
+        }
+    }
+
+    /// Whether spans are exported: not when the caller's trace is not sampled.
+    pub fn is_sampled(&self) -> bool {
+        self.sampled
+    }
+
+    /// Records the span of a node run or resume that started at `start`
+    /// and ended with `state`.
+    pub fn record(
+        &mut self,
+        name: &str,
+        node_type: &str,
+        start: SystemTime,
+        duration: Duration,
+        state: &State,
+    ) {
+        let span_id = format!("{:016x}", next_id(&mut self.seed));
+        self.spans.push(Span {
+            span_id,
+            name: name.to_string(),
+            node_type: node_type.to_string(),
+            start,
+            duration,
+            error: error_message(state),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Builds an OTLP/HTTP JSON export request with the recorded spans.
+    pub fn to_otlp(&self, service_name: &str) -> Value {
+        let spans: Vec<Value> = self
+            .spans
+            .iter()
+            .map(|span| {
+                let status = match &span.error {
+                    Some(message) => json!({ "code": 2, "message": message }),
+                    None => json!({ "code": 1 }),
+                };
+                json!({
+                    "traceId": self.trace_id,
+                    "spanId": span.span_id,
+                    "parentSpanId": self.parent_span_id.as_deref().unwrap_or(""),
+                    "name": span.name,
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.start + span.duration),
+                    "attributes": [
+                        attribute("datakit.node.name", &span.name),
+                        attribute("datakit.node.type", &span.node_type),
+                    ],
+                    "status": status,
+                })
+            })
+            .collect();
+
+        json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", service_name)] },
+                "scopeSpans": [{ "scope": { "name": "datakit" }, "spans": spans }],
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent() {
+        assert_eq!(
+            parse_traceparent(TRACEPARENT),
+            Some((
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                "00f067aa0ba902b7".to_string(),
+                true
+            ))
+        );
+        assert!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none()
+        );
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none()
+        );
+    }
+
+    #[test]
+    fn export() {
+        let mut tracer = Tracer::new(Some(TRACEPARENT), 1);
+        let done = State::Done(None);
+        let fail = State::Fail(Some(Payload::Error("oops".into())));
+        tracer.record("CALL", "call", UNIX_EPOCH, Duration::from_micros(5), &done);
+        tracer.record("JQ", "jq", UNIX_EPOCH, Duration::ZERO, &fail);
+
+        let otlp = tracer.to_otlp("datakit");
+        let spans = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(spans[0]["endTimeUnixNano"], "5000");
+        assert_ne!(spans[0]["spanId"], spans[1]["spanId"]);
+        assert_eq!(spans[1]["status"], json!({ "code": 2, "message": "oops" }));
+
+        let tracer = Tracer::new(None, 1);
+        assert_eq!(tracer.trace_id.len(), 32);
+        assert!(tracer.is_sampled());
+    }
+}
@@ -0,0 +1,128 @@
+use proxy_wasm::traits::*;
+use serde_json::{Map, Value};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::data::Payload;
+
+/// Fields of the `client_info` node and the properties they are read from.
+const CLIENT_INFO: &[(&str, &str)] = &[
+    ("address", "source.address"),
+    ("port", "source.port"),
+    ("tls_version", "connection.tls_version"),
+    ("sni", "connection.requested_server_name"),
+    ("client_cert_subject", "connection.subject_peer_certificate"),
+];
+
+/// Reads a property as a JSON value. Integer properties may be encoded
+/// as their decimal representation or as little-endian 64-bit integers,
+/// depending on the host.
+fn get_value<C: Context + ?Sized>(ctx: &C, path: &str, integer: bool) -> Option<Value> {
+    let bytes = ctx.get_property(path.split('.').collect())?;
+    if bytes.is_empty() {
+        return None;
+    }
+
+    if integer {
+        if let Some(n) = std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        {
+            return Some(n.into());
+        }
+        if let Ok(le) = <[u8; 8]>::try_from(bytes.as_slice()) {
+            return Some(i64::from_le_bytes(le).into());
+        }
+        return None;
+    }
+
+    String::from_utf8(bytes).ok().map(Value::String)
+}
+
+/// Builds the `client_info` payload, an object with the client's
+/// address and port and, for TLS connections, the TLS version, the
+/// requested server name and the subject of the client certificate.
+/// Fields that are not available are omitted.
+pub fn client_info(ctx: &dyn Context) -> Payload {
+    let mut map = Map::new();
+
+    for (key, path) in CLIENT_INFO {
+        if let Some(v) = get_value(ctx, path, *key == "port") {
+            map.insert(key.to_string(), v);
+        }
+    }
+
+    Payload::Json(Value::Object(map))
+}
+
+/// Entities of the `kong_context` node, the properties they are read
+/// from (as JSON objects), and the fields kept from each of them.
+const KONG_CONTEXT: &[(&str, &str, &[&str])] = &[
+    ("route", "kong.router.route", &["id", "name"]),
+    ("service", "kong.router.service", &["id", "name"]),
+    (
+        "consumer",
+        "kong.client.consumer",
+        &["id", "username", "custom_id"],
+    ),
+];
+
+fn get_json<C: Context + ?Sized>(ctx: &C, path: &str) -> Option<Map<String, Value>> {
+    let bytes = ctx.get_property(path.split('.').collect())?;
+    match serde_json::from_slice(&bytes) {
+        Ok(Value::Object(map)) => Some(map),
+        _ => None,
+    }
+}
+
+/// Builds the `kong_context` payload, an object with the `id` and `name`
+/// of the matched route and service, the `id`, `username` and `custom_id`
+/// of the authenticated consumer, and the `id` of the route's workspace.
+/// Entities that are not available, such as the consumer of an
+/// unauthenticated request, are omitted.
+pub fn kong_context(ctx: &dyn Context) -> Payload {
+    let mut map = Map::new();
+
+    for (key, path, fields) in KONG_CONTEXT {
+        let Some(entity) = get_json(ctx, path) else {
+            continue;
+        };
+
+        if *key == "route" {
+            if let Some(ws_id) = entity.get("ws_id") {
+                let workspace = Map::from_iter([("id".to_string(), ws_id.clone())]);
+                map.insert("workspace".to_string(), Value::Object(workspace));
+            }
+        }
+
+        let entity: Map<String, Value> = entity
+            .into_iter()
+            .filter(|(k, v)| fields.contains(&k.as_str()) && !v.is_null())
+            .collect();
+        map.insert(key.to_string(), Value::Object(entity));
+    }
+
+    Payload::Json(Value::Object(map))
+}
+
+/// Reads the name of the matched route, if any.
+pub fn route_name(ctx: &dyn HttpContext) -> Option<Value> {
+    get_json(ctx, "kong.router.route")?
+        .remove("name")
+        .filter(|name| !name.is_null())
+}
+
+/// Reads the address of the client, which some hosts give with its port.
+pub fn client_address(ctx: &dyn HttpContext) -> Option<IpAddr> {
+    let Some(Value::String(address)) = get_value(ctx, "source.address", false) else {
+        return None;
+    };
+    address
+        .parse()
+        .ok()
+        .or_else(|| address.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+/// Reads the id Kong assigns to the request, if any.
+pub fn request_id(ctx: &dyn HttpContext) -> Option<Value> {
+    get_value(ctx, "ngx.kong_request_id", false)
+}
@@ -0,0 +1,61 @@
+use proxy_wasm::traits::*;
+use serde_json::json;
+
+/// Prefix of the shared data keys under which the outcome of the last
+/// configuration is published, so that the host can report invalid
+/// configurations.
+pub const SHARED_DATA_KEY_PREFIX: &str = "datakit_config_status";
+
+/// Shared data is common to all filter instances, so each one publishes
+/// its status under its own key, `datakit_config_status:<root context id>`.
+pub fn shared_data_key(root_context_id: u32) -> String {
+    format!("{SHARED_DATA_KEY_PREFIX}:{root_context_id}")
+}
+
+/// A short id for a configuration error, which is the same for the same
+/// error, to match the responses of a failed filter with the logged error.
+pub fn error_id(err: &str) -> String {
+    // FNV-1a
+    let hash = err.bytes().fold(0x811c9dc5_u32, |hash, b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x01000193)
+    });
+    format!("{hash:08x}")
+}
+
+/// Publishes the outcome of a configuration: `{"status": "ok"}`, or
+/// `{"status": "error", "error_id": ..., "message": ...}`.
+pub fn publish(ctx: &dyn Context, root_context_id: u32, result: Result<(), &str>) {
+    let status = match result {
+        Ok(()) => json!({ "status": "ok" }),
+        Err(err) => json!({
+            "status": "error",
+            "error_id": error_id(err),
+            "message": err,
+        }),
+    };
+    let json = status.to_string();
+
+    let key = shared_data_key(root_context_id);
+    if let Err(status) = ctx.set_shared_data(&key, Some(json.as_bytes()), None) {
+        log::warn!("config status: failed publishing to shared data: {status:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_ids() {
+        assert_eq!(error_id(""), "811c9dc5");
+        assert_eq!(error_id("a"), "e40c292c");
+        assert_eq!(error_id("invalid"), error_id("invalid"));
+        assert_ne!(error_id("invalid"), error_id("invalid!"));
+    }
+
+    #[test]
+    fn shared_data_keys() {
+        assert_eq!(shared_data_key(1), "datakit_config_status:1");
+        assert_ne!(shared_data_key(1), shared_data_key(2));
+    }
+}
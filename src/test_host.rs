@@ -0,0 +1,625 @@
+//! A fake proxy-wasm host, to run the filter and its nodes in the tests.
+//!
+//! It implements the hostcalls of the ABI over thread-local state: tests
+//! set up the request, the response and the results of HTTP calls with the
+//! functions of this module, drive the contexts through their callbacks,
+//! and then inspect what the filter did. Each test runs in its own thread,
+//! so its state starts out empty.
+
+use proxy_wasm::types::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// An HTTP call dispatched by the filter.
+#[derive(Debug, Clone)]
+pub struct HttpCall {
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A response sent by the filter instead of the one of the service.
+#[derive(Debug, Clone)]
+pub struct LocalResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A context for running nodes on their own, outside of the filter.
+pub struct TestContext;
+
+impl proxy_wasm::traits::Context for TestContext {}
+
+impl proxy_wasm::traits::HttpContext for TestContext {}
+
+struct Host {
+    time_nanos: u64,
+    maps: BTreeMap<u32, Vec<(String, String)>>,
+    buffers: BTreeMap<u32, Vec<u8>>,
+    properties: BTreeMap<Vec<u8>, Vec<u8>>,
+    shared_data: BTreeMap<String, (Vec<u8>, u32)>,
+    calls: Vec<HttpCall>,
+    local_response: Option<LocalResponse>,
+    request_resumed: bool,
+    metrics: Vec<(String, u64)>,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Host {
+            time_nanos: 1_700_000_000_000_000_000,
+            maps: BTreeMap::new(),
+            buffers: BTreeMap::new(),
+            properties: BTreeMap::new(),
+            shared_data: BTreeMap::new(),
+            calls: vec![],
+            local_response: None,
+            request_resumed: false,
+            metrics: vec![],
+        }
+    }
+}
+
+thread_local! {
+    static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+/// Tokens are unique across tests, as the dispatcher of the SDK keeps
+/// them for as long as the thread lives.
+static NEXT_TOKEN: AtomicU32 = AtomicU32::new(1);
+
+fn with<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+pub fn set_map(map_type: MapType, pairs: &[(&str, &str)]) {
+    let pairs = pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    with(|host| host.maps.insert(map_type as u32, pairs));
+}
+
+pub fn map(map_type: MapType) -> Vec<(String, String)> {
+    with(|host| {
+        host.maps
+            .get(&(map_type as u32))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+pub fn set_buffer(buffer_type: BufferType, bytes: &[u8]) {
+    with(|host| host.buffers.insert(buffer_type as u32, bytes.to_vec()));
+}
+
+pub fn buffer(buffer_type: BufferType) -> Option<Vec<u8>> {
+    with(|host| host.buffers.get(&(buffer_type as u32)).cloned())
+}
+
+/// Sets up the response of an HTTP call, to be read from the context
+/// in `on_http_call_response`.
+pub fn set_call_response(status: u16, headers: &[(&str, &str)], body: &[u8]) {
+    let status = status.to_string();
+    let mut pairs = vec![(":status", status.as_str())];
+    pairs.extend_from_slice(headers);
+    set_map(MapType::HttpCallResponseHeaders, &pairs);
+    set_buffer(BufferType::HttpCallResponseBody, body);
+}
+
+/// Takes the HTTP calls dispatched since the last time.
+pub fn take_calls() -> Vec<HttpCall> {
+    with(|host| std::mem::take(&mut host.calls))
+}
+
+/// Takes the response sent by the filter, if any.
+pub fn take_local_response() -> Option<LocalResponse> {
+    with(|host| host.local_response.take())
+}
+
+/// Takes whether the filter resumed the request since the last time.
+pub fn take_request_resumed() -> bool {
+    with(|host| std::mem::take(&mut host.request_resumed))
+}
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(slice(data, size)).into_owned()
+}
+
+/// Hands bytes over to the SDK, which frees them as a `Vec`.
+unsafe fn give(bytes: Vec<u8>, return_data: *mut *mut u8, return_size: *mut usize) {
+    let bytes = bytes.into_boxed_slice();
+    *return_size = bytes.len();
+    *return_data = Box::into_raw(bytes) as *mut u8;
+}
+
+/// Reads a map as serialized by the SDK, whose sizes are `usize`s.
+unsafe fn read_map(data: *const u8, size: usize) -> Vec<(String, String)> {
+    let bytes = slice(data, size);
+    if bytes.is_empty() {
+        return vec![];
+    }
+    const W: usize = std::mem::size_of::<usize>();
+    let word = |at: usize| usize::from_le_bytes(bytes[at..at + W].try_into().unwrap());
+    let count = word(0);
+    let mut p = W + count * 2 * W;
+    let mut text = |len: usize| {
+        let s = String::from_utf8_lossy(&bytes[p..p + len]).into_owned();
+        p += len + 1;
+        s
+    };
+    (0..count)
+        .map(|n| {
+            let key = text(word(W + n * 2 * W));
+            let value = text(word(W + n * 2 * W + W));
+            (key, value)
+        })
+        .collect()
+}
+
+/// Writes a map as deserialized by the SDK, whose sizes are `u32`s.
+fn write_map(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = (pairs.len() as u32).to_le_bytes().to_vec();
+    for (k, v) in pairs {
+        bytes.extend_from_slice(&(k.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+    }
+    for (k, v) in pairs {
+        bytes.extend_from_slice(k.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(v.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+#[no_mangle]
+extern "C" fn proxy_log(_level: LogLevel, _data: *const u8, _size: usize) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    *return_level = LogLevel::Debug;
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    *return_time = with(|host| host.time_nanos);
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_tick_period_milliseconds(_period: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    match buffer(buffer_type) {
+        Some(bytes) => {
+            let start = start.min(bytes.len());
+            let end = start.saturating_add(max_size).min(bytes.len());
+            give(bytes[start..end].to_vec(), return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    size: usize,
+    data: *const u8,
+    data_size: usize,
+) -> Status {
+    let value = slice(data, data_size);
+    with(|host| {
+        let bytes = host.buffers.entry(buffer_type as u32).or_default();
+        let start = start.min(bytes.len());
+        let end = start.saturating_add(size).min(bytes.len());
+        bytes.splice(start..end, value.iter().copied());
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    give(write_map(&map(map_type)), return_data, return_size);
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: MapType,
+    data: *const u8,
+    size: usize,
+) -> Status {
+    let pairs = read_map(data, size);
+    with(|host| host.maps.insert(map_type as u32, pairs));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    match map(map_type)
+        .into_iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+    {
+        Some((_, value)) => {
+            give(value.into_bytes(), return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+/// Replaces all the values of a header with one, in place of the first.
+#[no_mangle]
+unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with(|host| {
+        let pairs = host.maps.entry(map_type as u32).or_default();
+        let at = pairs.iter().position(|(k, _)| k.eq_ignore_ascii_case(&key));
+        pairs.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        pairs.insert(at.unwrap_or(pairs.len()), (key, value));
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    with(|host| {
+        let pairs = host.maps.entry(map_type as u32).or_default();
+        pairs.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with(|host| {
+        host.maps
+            .entry(map_type as u32)
+            .or_default()
+            .push((key, value))
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+) -> Status {
+    let path = slice(path_data, path_size).to_vec();
+    match with(|host| host.properties.get(&path).cloned()) {
+        Some(value) => {
+            give(value, return_data, return_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = slice(path_data, path_size).to_vec();
+    let value = slice(value_data, value_size).to_vec();
+    with(|host| host.properties.insert(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_data: *mut *mut u8,
+    return_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    match with(|host| host.shared_data.get(&key).cloned()) {
+        Some((value, cas)) => {
+            give(value, return_data, return_size);
+            *return_cas = cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with(|host| {
+        let current = host.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current {
+            return Status::CasMismatch;
+        }
+        host.shared_data.insert(key, (value, current + 1));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_continue_stream(stream_type: StreamType) -> Status {
+    if stream_type == StreamType::HttpRequest {
+        with(|host| host.request_resumed = true);
+    }
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_close_stream(_stream_type: StreamType) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn proxy_send_local_response(
+    status: u32,
+    _details_data: *const u8,
+    _details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    let response = LocalResponse {
+        status,
+        headers: read_map(headers_data, headers_size),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    with(|host| host.local_response = Some(response));
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    _timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let call = HttpCall {
+        token: NEXT_TOKEN.fetch_add(1, Ordering::Relaxed),
+        upstream: string(upstream_data, upstream_size),
+        headers: read_map(headers_data, headers_size),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    *return_token = call.token;
+    with(|host| host.calls.push(call));
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_effective_context(_context_id: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_call_foreign_function(
+    _name_data: *const u8,
+    _name_size: usize,
+    _arguments_data: *const u8,
+    _arguments_size: usize,
+    _return_data: *mut *mut u8,
+    _return_size: *mut usize,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_define_metric(
+    _metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with(
+        |host| match host.metrics.iter().position(|(n, _)| *n == name) {
+            Some(id) => id as u32,
+            None => {
+                host.metrics.push((name, 0));
+                host.metrics.len() as u32 - 1
+            }
+        },
+    );
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    match with(|host| host.metrics.get(metric_id as usize).map(|(_, v)| *v)) {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, v)) => {
+            *v = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, v)) => {
+            *v = v.saturating_add_signed(offset);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+// The node types of the filter make no gRPC calls and use no queues.
+
+#[no_mangle]
+extern "C" fn proxy_register_shared_queue(_: *const u8, _: usize, _: *mut u32) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_resolve_shared_queue(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_dequeue_shared_queue(_: u32, _: *mut *mut u8, _: *mut usize) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_enqueue_shared_queue(_: u32, _: *const u8, _: usize) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+extern "C" fn proxy_grpc_call(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: u32,
+    _: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+extern "C" fn proxy_grpc_stream(
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *const u8,
+    _: usize,
+    _: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_send(_: u32, _: *const u8, _: usize, _: bool) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_cancel(_: u32) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_close(_: u32) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_status(_: *mut u32, _: *mut *mut u8, _: *mut usize) -> Status {
+    Status::NotFound
+}